@@ -0,0 +1,212 @@
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, OptionalExtension};
+use std::sync::OnceLock;
+
+use crate::config::env_or;
+use crate::retry;
+use crate::schema::{quote_identifier, TableInfo};
+
+// Schemaless attributes: a table may keep arbitrary nested fields as a JSON
+// object in a column named `attributes`. Row lists filter on them with
+// `?attr.<path>=<value>`, e.g. `?attr.team=infra` or `?attr.address.city=Oslo`,
+// which compare `json_extract(attributes, '$.<path>')`.
+//
+// Hot paths can be indexed through a virtual generated column per path,
+// `attr_<path>` with dots as underscores; filters on an indexed path use the
+// column so SQLite can use its index.
+pub const COLUMN: &str = "attributes";
+
+// Declared so that only JSON objects are stored
+pub const COLUMN_DEFINITION: &str =
+    "attributes TEXT CHECK (attributes IS NULL OR (json_valid(attributes) AND json_type(attributes) = 'object'))";
+
+const FILTER_PREFIX: &str = "attr.";
+
+#[derive(Debug, Clone)]
+pub struct AttributesConfig {
+    // Comma-separated TINYSQL_ATTRIBUTE_INDEXES, e.g. `team,address.city`.
+    // Indexes are only ever added; dropping a path from the list leaves its
+    // column and index in place.
+    pub indexes: Vec<String>,
+}
+
+impl AttributesConfig {
+    pub fn from_env() -> Self {
+        let indexes = env_or("TINYSQL_ATTRIBUTE_INDEXES", String::new());
+        AttributesConfig {
+            indexes: indexes.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect(),
+        }
+    }
+}
+
+pub fn config() -> &'static AttributesConfig {
+    static CONFIG: OnceLock<AttributesConfig> = OnceLock::new();
+    CONFIG.get_or_init(AttributesConfig::from_env)
+}
+
+// `team.lead` as the JSON path `$.team.lead`; keys other than plain
+// identifiers are quoted, as in `$."cost center"`
+pub fn json_path(path: &str) -> Result<String, String> {
+    let mut json_path = String::from("$");
+    for key in path.split('.') {
+        if key.is_empty() || key.contains('"') {
+            return Err(format!("Invalid attribute path {:?}", path));
+        }
+        if key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            json_path.push('.');
+            json_path.push_str(key);
+        } else {
+            json_path.push_str(&format!(".\"{}\"", key));
+        }
+    }
+    Ok(json_path)
+}
+
+// The generated column indexing `path`
+pub fn generated_column(path: &str) -> String {
+    let name: String = path.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    format!("attr_{}", name)
+}
+
+// Adds the generated column and index for every configured path `table`
+// lacks
+pub fn create_indexes(conn: &Connection, table: &str) -> rusqlite::Result<()> {
+    for path in &config().indexes {
+        let json_path = match self::json_path(path) {
+            Ok(json_path) => json_path,
+            Err(e) => {
+                eprintln!("Not indexing attributes: {}", e);
+                continue;
+            }
+        };
+        let column = generated_column(path);
+        let exists = conn
+            .query_row("SELECT 1 FROM pragma_table_xinfo(?1) WHERE name = ?2", [table, &column], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if !exists {
+            let sql = format!(
+                "ALTER TABLE {} ADD COLUMN {} AS (json_extract({}, '{}'))",
+                quote_identifier(table),
+                quote_identifier(&column),
+                COLUMN,
+                json_path.replace('\'', "''"),
+            );
+            retry::policy().run(|| conn.execute(&sql, []))?;
+        }
+        let sql = format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {} ({})",
+            quote_identifier(&format!("{}_{}", table, column)),
+            quote_identifier(table),
+            quote_identifier(&column),
+        );
+        retry::policy().run(|| conn.execute(&sql, []))?;
+    }
+    Ok(())
+}
+
+// The `attr.` pairs of a query string, with the prefix stripped
+pub fn filters<K: AsRef<str>, V: AsRef<str>>(pairs: impl IntoIterator<Item = (K, V)>) -> Vec<(String, String)> {
+    pairs
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let path = name.as_ref().strip_prefix(FILTER_PREFIX)?;
+            Some((path.to_string(), value.as_ref().to_string()))
+        })
+        .collect()
+}
+
+// A WHERE condition for one filter and its parameters. Query string values
+// are untyped, so they match both as JSON scalars (`30`, `true`) and as
+// strings (`"30"`). SQLite extracts JSON booleans as 1 and 0, so the JSON
+// type keeps `true` from matching a stored 1 and `1` from matching `true`.
+pub fn condition(table: &TableInfo, path: &str, value: &str) -> Result<(String, Vec<SqlValue>), String> {
+    if table.column(COLUMN).is_none() {
+        return Err(format!("Table {} has no {} column to filter on", table.name, COLUMN));
+    }
+    let json_path = json_path(path)?;
+    let (typed, json_types) = match value {
+        "true" => (SqlValue::Integer(1), "'true', 'text'"),
+        "false" => (SqlValue::Integer(0), "'false', 'text'"),
+        _ => {
+            let typed = value
+                .parse()
+                .map(SqlValue::Integer)
+                .or_else(|_| value.parse().map(SqlValue::Real))
+                .unwrap_or_else(|_| SqlValue::Text(value.to_string()));
+            (typed, "'integer', 'real', 'text'")
+        }
+    };
+    let text = SqlValue::Text(value.to_string());
+    let json_type = format!("json_type({}, ?) IN ({})", quote_identifier(COLUMN), json_types);
+
+    // Paths can share a column name, `a.b` and `a_b`, so only configured
+    // ones are trusted to be what the column holds
+    let column = generated_column(path);
+    let indexed = config().indexes.iter().any(|p| p == path);
+    if indexed && table.generated.iter().any(|g| g.eq_ignore_ascii_case(&column)) {
+        let sql = format!("{} IN (?, ?) AND {}", quote_identifier(&column), json_type);
+        Ok((sql, vec![typed, text, SqlValue::Text(json_path)]))
+    } else {
+        let sql = format!("json_extract({}, ?) IN (?, ?) AND {}", quote_identifier(COLUMN), json_type);
+        Ok((sql, vec![SqlValue::Text(json_path.clone()), typed, text, SqlValue::Text(json_path)]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema;
+
+    #[test]
+    fn paths_quote_keys_that_are_not_identifiers() {
+        assert_eq!(json_path("team").unwrap(), "$.team");
+        assert_eq!(json_path("address.city").unwrap(), "$.address.city");
+        assert_eq!(json_path("cost center.code").unwrap(), "$.\"cost center\".code");
+        for invalid in ["", "a..b", ".a", "a.", "say \"hi\""] {
+            assert!(json_path(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    fn matching(filter: &str, value: &str) -> Vec<String> {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            r#"CREATE TABLE person (name TEXT, {});
+               INSERT INTO person VALUES
+                   ('on', '{{"flag": true}}'), ('off', '{{"flag": false}}'),
+                   ('one', '{{"flag": 1}}'), ('zero', '{{"flag": 0}}'),
+                   ('text', '{{"flag": "true"}}'), ('thirty', '{{"flag": "30"}}'),
+                   ('nested', '{{"a": {{"b": 30}}}}'), ('none', NULL);"#,
+            COLUMN_DEFINITION,
+        ))
+        .unwrap();
+        let schema = schema::schema(&conn).unwrap();
+        let (condition, params) = super::condition(schema.table("person").unwrap(), filter, value).unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT name FROM person WHERE {} ORDER BY rowid", condition)).unwrap();
+        let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| row.get(0)).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn booleans_and_numbers_match_only_their_own_json_type() {
+        assert_eq!(matching("flag", "true"), ["on", "text"]);
+        assert_eq!(matching("flag", "false"), ["off"]);
+        assert_eq!(matching("flag", "1"), ["one"]);
+        assert_eq!(matching("flag", "0"), ["zero"]);
+    }
+
+    #[test]
+    fn values_match_as_scalars_or_strings_along_nested_paths() {
+        assert_eq!(matching("flag", "30"), ["thirty"]);
+        assert_eq!(matching("a.b", "30"), ["nested"]);
+        assert!(matching("a.c", "30").is_empty());
+    }
+
+    #[test]
+    fn tables_without_attributes_cannot_be_filtered() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE team (name TEXT)").unwrap();
+        let schema = schema::schema(&conn).unwrap();
+        assert!(condition(schema.table("team").unwrap(), "lead", "ada").is_err());
+    }
+}
@@ -0,0 +1,403 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::env_or;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+    // Read-write plus the admin routes
+    Admin,
+}
+
+impl Access {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ro" => Some(Access::ReadOnly),
+            "rw" => Some(Access::ReadWrite),
+            "admin" => Some(Access::Admin),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Access::ReadOnly => "ro",
+            Access::ReadWrite => "rw",
+            Access::Admin => "admin",
+        }
+    }
+}
+
+// The authenticated caller, stored on the request for handlers and logs
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    pub access: Access,
+    // Unix seconds after which a bearer token stops being valid; API keys
+    // don't expire
+    pub expiry: Option<u64>,
+}
+
+impl Principal {
+    // Time left before the credentials expire, zero once they have
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.expiry.map(|expiry| Duration::from_secs(expiry.saturating_sub(unix_now())))
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.access.as_str())
+    }
+}
+
+// Credentials pulled out of the request headers by either HTTP stack
+pub struct Credentials<'a> {
+    pub api_key: Option<&'a str>,
+    pub authorization: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Expired,
+    ReadOnly,
+    NotAdmin,
+}
+
+impl AuthError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            AuthError::ReadOnly | AuthError::NotAdmin => 403,
+            _ => 401,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            AuthError::Missing => "Missing API key or bearer token",
+            AuthError::Invalid => "Invalid API key or bearer token",
+            AuthError::Expired => "Bearer token has expired",
+            AuthError::ReadOnly => "Principal has read-only access",
+            AuthError::NotAdmin => "Admin access required",
+        }
+    }
+}
+
+pub trait Authenticator: Send + Sync {
+    // Ok(None) means the credentials are not meant for this authenticator
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<Principal>, AuthError>;
+}
+
+// Static keys from TINYSQL_API_KEYS, formatted as `name:key[:ro|rw|admin]` and
+// separated by commas. Keys are sent in the `X-API-Key` header.
+pub struct ApiKeys {
+    keys: Vec<(String, Principal)>,
+}
+
+impl ApiKeys {
+    pub fn parse(config: &str) -> Self {
+        let mut keys = Vec::new();
+        for entry in config.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(3, ':');
+            let (Some(name), Some(key)) = (parts.next(), parts.next()) else {
+                eprintln!("Ignoring malformed API key entry for {}", entry.split(':').next().unwrap_or(""));
+                continue;
+            };
+            let access = match parts.next() {
+                None => Access::ReadWrite,
+                Some(value) => match Access::parse(value) {
+                    Some(access) => access,
+                    None => {
+                        eprintln!("Ignoring API key for {} with unknown access {}", name, value);
+                        continue;
+                    }
+                },
+            };
+            keys.push((key.to_string(), Principal { name: name.to_string(), access, expiry: None }));
+        }
+        ApiKeys { keys }
+    }
+}
+
+impl Authenticator for ApiKeys {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<Principal>, AuthError> {
+        let Some(presented) = credentials.api_key else {
+            return Ok(None);
+        };
+        self.keys
+            .iter()
+            .find(|(key, _)| constant_time_eq(key.as_bytes(), presented.as_bytes()))
+            .map(|(_, principal)| Some(principal.clone()))
+            .ok_or(AuthError::Invalid)
+    }
+}
+
+// Bearer tokens of the form `base64url(name:access:expiry).base64url(mac)`
+// where the MAC is HMAC-SHA256 over the encoded payload with
+// TINYSQL_TOKEN_SECRET, and expiry is in Unix seconds.
+pub struct HmacTokens {
+    secret: Vec<u8>,
+}
+
+impl HmacTokens {
+    pub fn new(secret: &[u8]) -> Self {
+        HmacTokens { secret: secret.to_vec() }
+    }
+
+    pub fn issue(&self, name: &str, access: Access, ttl_secs: u64) -> String {
+        let expiry = unix_now() + ttl_secs;
+        let payload = URL_SAFE_NO_PAD.encode(format!("{}:{}:{}", name, access.as_str(), expiry));
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn verify(&self, token: &str) -> Result<Principal, AuthError> {
+        let (payload, signature) = token.split_once('.').ok_or(AuthError::Invalid)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| AuthError::Invalid)?;
+        self.mac(payload).verify_slice(&signature).map_err(|_| AuthError::Invalid)?;
+
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| AuthError::Invalid)?;
+        let payload = String::from_utf8(payload).map_err(|_| AuthError::Invalid)?;
+        let mut parts = payload.rsplitn(3, ':');
+        let expiry: u64 = parts.next().and_then(|e| e.parse().ok()).ok_or(AuthError::Invalid)?;
+        let access = parts.next().and_then(Access::parse).ok_or(AuthError::Invalid)?;
+        let name = parts.next().ok_or(AuthError::Invalid)?;
+        if expiry <= unix_now() {
+            return Err(AuthError::Expired);
+        }
+        Ok(Principal { name: name.to_string(), access, expiry: Some(expiry) })
+    }
+}
+
+impl Authenticator for HmacTokens {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<Principal>, AuthError> {
+        match credentials.authorization.and_then(|h| h.strip_prefix("Bearer ")) {
+            Some(token) => self.verify(token.trim()).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct Auth {
+    authenticators: Vec<Box<dyn Authenticator>>,
+    // Lets anyone use the admin routes while authentication is disabled
+    admin_open: bool,
+}
+
+impl Auth {
+    pub fn from_env() -> Self {
+        let mut authenticators: Vec<Box<dyn Authenticator>> = Vec::new();
+        if let Ok(keys) = env::var("TINYSQL_API_KEYS") {
+            authenticators.push(Box::new(ApiKeys::parse(&keys)));
+        }
+        if let Some(tokens) = token_issuer() {
+            authenticators.push(Box::new(tokens));
+        }
+        let admin_open = env_or("TINYSQL_ADMIN_OPEN", false);
+        if authenticators.is_empty() {
+            println!("Authentication disabled: set TINYSQL_API_KEYS or TINYSQL_TOKEN_SECRET");
+            if !admin_open {
+                println!("Admin routes are closed: set TINYSQL_ADMIN_OPEN=true to open them without authentication");
+            }
+        }
+        Auth { authenticators, admin_open }
+    }
+
+    // Ok(None) when authentication is disabled. Writes need read-write access.
+    pub fn authenticate(&self, credentials: &Credentials, is_write: bool) -> Result<Option<Principal>, AuthError> {
+        if self.authenticators.is_empty() {
+            return Ok(None);
+        }
+        for authenticator in &self.authenticators {
+            if let Some(principal) = authenticator.authenticate(credentials)? {
+                if is_write && principal.access == Access::ReadOnly {
+                    return Err(AuthError::ReadOnly);
+                }
+                return Ok(Some(principal));
+            }
+        }
+        Err(AuthError::Missing)
+    }
+
+    // Admin routes need an admin principal. Without authentication there is
+    // none, so they stay closed unless TINYSQL_ADMIN_OPEN opens them.
+    pub fn authorize_admin(&self, principal: Option<&Principal>) -> Result<(), AuthError> {
+        match principal {
+            Some(principal) if principal.access == Access::Admin => Ok(()),
+            None if self.authenticators.is_empty() && self.admin_open => Ok(()),
+            _ => Err(AuthError::NotAdmin),
+        }
+    }
+}
+
+// Whether a request with HTTP `method` changes anything, so needs read-write
+// access and counts against the write rate limit
+pub fn is_write(method: &str) -> bool {
+    !matches!(method, "GET" | "HEAD" | "OPTIONS")
+}
+
+pub fn auth() -> &'static Auth {
+    static AUTH: OnceLock<Auth> = OnceLock::new();
+    AUTH.get_or_init(Auth::from_env)
+}
+
+pub fn token_issuer() -> Option<HmacTokens> {
+    env::var("TINYSQL_TOKEN_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .map(|secret| HmacTokens::new(secret.as_bytes()))
+}
+
+// Prompts for a principal and lifetime, then prints a signed bearer token
+pub fn issue_token() {
+    let Some(tokens) = token_issuer() else {
+        println!("Set TINYSQL_TOKEN_SECRET to issue bearer tokens");
+        return;
+    };
+    let name = prompt("Principal name:");
+    let access = Access::parse(&prompt("Access (ro/rw/admin):")).unwrap_or(Access::ReadOnly);
+    let ttl_secs = prompt("Lifetime in seconds:").parse().unwrap_or(3600);
+    println!("{}", tokens.issue(&name, access, ttl_secs));
+}
+
+fn prompt(label: &str) -> String {
+    use std::io::{self, Write};
+
+    println!("{}", label);
+    io::stdout().flush().unwrap();
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).expect("Failed to read input");
+    input.trim().to_string()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> String {
+        format!("Bearer {}", token)
+    }
+
+    fn with_token(authorization: &str) -> Credentials<'_> {
+        Credentials { api_key: None, authorization: Some(authorization) }
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        let tokens = HmacTokens::new(b"secret");
+        let principal = tokens.verify(&tokens.issue("ci:bot", Access::ReadOnly, 60)).unwrap();
+        assert_eq!(principal.name, "ci:bot");
+        assert_eq!(principal.access, Access::ReadOnly);
+        assert!(principal.expires_in().is_some_and(|left| left <= Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn tokens_signed_with_another_secret_are_invalid() {
+        let token = HmacTokens::new(b"other").issue("ci", Access::ReadWrite, 60);
+        assert!(matches!(HmacTokens::new(b"secret").verify(&token), Err(AuthError::Invalid)));
+    }
+
+    #[test]
+    fn tampered_payloads_are_invalid() {
+        let tokens = HmacTokens::new(b"secret");
+        let token = tokens.issue("ci", Access::ReadOnly, 60);
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(format!("ci:rw:{}", unix_now() + 60));
+        assert!(matches!(tokens.verify(&format!("{}.{}", forged, signature)), Err(AuthError::Invalid)));
+        assert!(matches!(tokens.verify("no-signature"), Err(AuthError::Invalid)));
+        assert!(matches!(tokens.verify("a.!!"), Err(AuthError::Invalid)));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let tokens = HmacTokens::new(b"secret");
+        assert!(matches!(tokens.verify(&tokens.issue("ci", Access::ReadWrite, 0)), Err(AuthError::Expired)));
+    }
+
+    #[test]
+    fn tokens_only_answer_bearer_credentials() {
+        let tokens = HmacTokens::new(b"secret");
+        assert!(tokens.authenticate(&with_token("Basic abc")).unwrap().is_none());
+        let header = bearer(&tokens.issue("ci", Access::ReadWrite, 60));
+        assert!(tokens.authenticate(&with_token(&header)).unwrap().is_some());
+    }
+
+    #[test]
+    fn api_keys_parse_access_and_skip_malformed_entries() {
+        let keys = ApiKeys::parse("ci:k1:ro, ops:k2, broken, bad:k3:xx");
+        assert_eq!(keys.keys.len(), 2);
+        let lookup = |key| keys.authenticate(&Credentials { api_key: Some(key), authorization: None });
+        assert_eq!(lookup("k1").unwrap().unwrap().access, Access::ReadOnly);
+        assert_eq!(lookup("k2").unwrap().unwrap().access, Access::ReadWrite);
+        assert!(matches!(lookup("k3"), Err(AuthError::Invalid)));
+    }
+
+    #[test]
+    fn read_only_principals_cannot_write() {
+        let tokens = HmacTokens::new(b"secret");
+        let auth = Auth { authenticators: vec![Box::new(HmacTokens::new(b"secret"))], admin_open: false };
+        let header = bearer(&tokens.issue("ci", Access::ReadOnly, 60));
+        assert!(auth.authenticate(&with_token(&header), false).is_ok());
+        assert!(matches!(auth.authenticate(&with_token(&header), true), Err(AuthError::ReadOnly)));
+        assert!(matches!(auth.authenticate(&Credentials { api_key: None, authorization: None }, false), Err(AuthError::Missing)));
+    }
+
+    #[test]
+    fn only_admin_principals_pass_the_admin_check() {
+        let principal = |access| Principal { name: "ci".to_string(), access, expiry: None };
+        let auth = Auth { authenticators: vec![Box::new(HmacTokens::new(b"secret"))], admin_open: true };
+        assert!(auth.authorize_admin(Some(&principal(Access::Admin))).is_ok());
+        assert!(matches!(auth.authorize_admin(Some(&principal(Access::ReadWrite))), Err(AuthError::NotAdmin)));
+        assert!(matches!(auth.authorize_admin(None), Err(AuthError::NotAdmin)));
+    }
+
+    #[test]
+    fn admin_routes_stay_closed_without_authentication_unless_opened() {
+        assert!(Auth { authenticators: Vec::new(), admin_open: false }.authorize_admin(None).is_err());
+        assert!(Auth { authenticators: Vec::new(), admin_open: true }.authorize_admin(None).is_ok());
+    }
+
+    #[test]
+    fn admin_keys_can_write() {
+        let keys = ApiKeys::parse("ops:k1:admin");
+        let auth = Auth { authenticators: vec![Box::new(keys)], admin_open: false };
+        let principal = auth.authenticate(&Credentials { api_key: Some("k1"), authorization: None }, true).unwrap();
+        assert_eq!(principal.unwrap().access, Access::Admin);
+    }
+
+    #[test]
+    fn only_safe_methods_are_reads() {
+        for method in ["GET", "HEAD", "OPTIONS"] {
+            assert!(!is_write(method));
+        }
+        for method in ["POST", "PUT", "PATCH", "DELETE"] {
+            assert!(is_write(method));
+        }
+    }
+}
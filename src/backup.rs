@@ -0,0 +1,434 @@
+use flate2::read::GzDecoder;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::backup_schedule::{self, ScheduleStatus};
+use crate::changes;
+use crate::config::env_or;
+use crate::errors::ErrorBody;
+
+// Online backups copy the database a few pages at a time through SQLite's
+// backup API. The source is only read-locked during each step, so writers
+// get in between steps instead of waiting for the whole copy.
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    // Backups written to a path always land in this directory
+    pub dir: PathBuf,
+    pub pages_per_step: i32,
+    pub step_pause: Duration,
+    // How long a restore waits for in-flight work to drain before giving up
+    pub restore_timeout: Duration,
+    // Writes from other connections restart a running backup; it gives up
+    // after this many restarts or once it has run this long
+    pub max_restarts: u32,
+    pub timeout: Duration,
+}
+
+impl BackupConfig {
+    pub fn from_env() -> Self {
+        BackupConfig {
+            dir: PathBuf::from(env_or("TINYSQL_BACKUP_DIR", "backups".to_string())),
+            pages_per_step: env_or("TINYSQL_BACKUP_PAGES_PER_STEP", 256),
+            step_pause: Duration::from_millis(env_or("TINYSQL_BACKUP_STEP_PAUSE_MS", 10)),
+            restore_timeout: Duration::from_millis(env_or("TINYSQL_RESTORE_TIMEOUT_MS", 30_000)),
+            max_restarts: env_or("TINYSQL_BACKUP_MAX_RESTARTS", 20),
+            timeout: Duration::from_millis(env_or("TINYSQL_BACKUP_TIMEOUT_MS", 600_000)),
+        }
+    }
+}
+
+pub fn config() -> &'static BackupConfig {
+    static CONFIG: OnceLock<BackupConfig> = OnceLock::new();
+    CONFIG.get_or_init(BackupConfig::from_env)
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct BackupReport {
+    // Where the backup was written; `None` when it was streamed
+    pub path: Option<String>,
+    pub pages: i32,
+    pub steps: u32,
+    pub bytes: u64,
+    // Unix seconds
+    pub started_at: u64,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RestoreReport {
+    pub pages: i32,
+    pub bytes: u64,
+    // The snapshot's `PRAGMA user_version`, the application's schema version
+    pub user_version: i64,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct BackupStatus {
+    // A backup or a restore is running
+    pub running: bool,
+    // Progress of the running backup
+    pub pages_copied: i32,
+    pub page_count: i32,
+    pub last: Option<BackupReport>,
+    pub last_error: Option<String>,
+    // Present when TINYSQL_BACKUP_SCHEDULE is set
+    pub schedule: Option<ScheduleStatus>,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    // Only one backup or restore runs at a time
+    InProgress,
+    InvalidPath(String),
+    Exists(String),
+    NotFound(String),
+    // A snapshot that failed validation
+    Invalid(String),
+    // In-flight work did not drain within the restore timeout, or writes
+    // kept restarting a backup
+    Busy,
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl BackupError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            BackupError::InProgress | BackupError::Exists(_) => 409,
+            BackupError::InvalidPath(_) => 400,
+            BackupError::NotFound(_) => 404,
+            BackupError::Invalid(_) => 422,
+            BackupError::Busy => 503,
+            BackupError::Io(_) | BackupError::Sqlite(_) => 500,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            BackupError::InProgress => "A backup or restore is already running".to_string(),
+            BackupError::InvalidPath(path) => format!("Invalid backup path {:?}: use a file name", path),
+            BackupError::Exists(path) => format!("Backup {:?} already exists", path),
+            BackupError::NotFound(path) => format!("Backup {:?} not found", path),
+            BackupError::Invalid(reason) => format!("Invalid snapshot: {}", reason),
+            BackupError::Busy => "The database stayed busy; try again later".to_string(),
+            BackupError::Io(e) => format!("Backup failed: {}", e),
+            BackupError::Sqlite(e) => format!("Backup failed: {}", e),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        matches!(self, BackupError::Busy)
+    }
+
+    pub fn body(&self) -> serde_json::Value {
+        json!(ErrorBody::new(self.message()))
+    }
+}
+
+impl From<std::io::Error> for BackupError {
+    fn from(err: std::io::Error) -> Self {
+        BackupError::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for BackupError {
+    fn from(err: rusqlite::Error) -> Self {
+        BackupError::Sqlite(err)
+    }
+}
+
+fn status() -> &'static Mutex<BackupStatus> {
+    static STATUS: OnceLock<Mutex<BackupStatus>> = OnceLock::new();
+    STATUS.get_or_init(|| Mutex::new(BackupStatus::default()))
+}
+
+pub fn current_status() -> BackupStatus {
+    let mut current = status().lock().unwrap().clone();
+    current.schedule = backup_schedule::current_status();
+    current
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// A backup file named by a client: a plain file name inside the backup
+// directory
+fn backup_file(name: &str) -> Result<PathBuf, BackupError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        return Err(BackupError::InvalidPath(name.to_string()));
+    }
+    Ok(config().dir.join(name))
+}
+
+// Where to write a new backup; never an existing file. `copy` creates it
+// exclusively, so a file appearing in between is still refused.
+pub fn destination(name: &str) -> Result<PathBuf, BackupError> {
+    let path = backup_file(name)?;
+    if path.exists() {
+        return Err(BackupError::Exists(name.to_string()));
+    }
+    Ok(path)
+}
+
+// An earlier backup to restore from
+pub fn existing(name: &str) -> Result<PathBuf, BackupError> {
+    let path = backup_file(name)?;
+    if !path.is_file() {
+        return Err(BackupError::NotFound(name.to_string()));
+    }
+    Ok(path)
+}
+
+// A hidden scratch file in the backup directory for snapshots that are
+// streamed rather than kept
+pub fn scratch_path() -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    config().dir.join(format!(".snapshot-{}-{}.db", std::process::id(), nanos))
+}
+
+// Marks a backup or restore as running; dropped when it ends, either way
+struct Running;
+
+impl Running {
+    fn start() -> Result<Self, BackupError> {
+        let mut status = status().lock().unwrap();
+        if status.running {
+            return Err(BackupError::InProgress);
+        }
+        status.running = true;
+        status.pages_copied = 0;
+        status.page_count = 0;
+        Ok(Running)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        status().lock().unwrap().running = false;
+    }
+}
+
+// Copies the database behind `source` to `dest` step by step. Busy or locked
+// steps are retried after the pause.
+pub fn run(source: &Connection, dest: &Path, keep_path: bool) -> Result<BackupReport, BackupError> {
+    let _running = Running::start()?;
+    let result = copy(source, dest, keep_path);
+    let mut status = status().lock().unwrap();
+    match &result {
+        Ok(report) => {
+            status.last = Some(report.clone());
+            status.last_error = None;
+        }
+        Err(e) => status.last_error = Some(e.message()),
+    }
+    result
+}
+
+fn copy(source: &Connection, dest: &Path, keep_path: bool) -> Result<BackupReport, BackupError> {
+    let config = config();
+    fs::create_dir_all(&config.dir)?;
+    let started_at = unix_now();
+    let start = Instant::now();
+
+    // Created exclusively, so from here on the file is ours to remove
+    if let Err(e) = OpenOptions::new().write(true).create_new(true).open(dest) {
+        return Err(match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                BackupError::Exists(dest.file_name().unwrap_or_default().to_string_lossy().into_owned())
+            }
+            _ => e.into(),
+        });
+    }
+    let result = copy_pages(source, dest, start);
+    if result.is_err() {
+        let _ = fs::remove_file(dest);
+    }
+    let (pages, steps) = result?;
+
+    Ok(BackupReport {
+        path: keep_path.then(|| dest.display().to_string()),
+        pages,
+        steps,
+        bytes: fs::metadata(dest)?.len(),
+        started_at,
+        duration_ms: start.elapsed().as_millis() as u64,
+    })
+}
+
+// Steps the backup into the freshly created `dest`, returning the pages and
+// steps taken. SQLite starts over whenever another connection writes to the
+// source, so restarts and the total time are capped.
+fn copy_pages(source: &Connection, dest: &Path, start: Instant) -> Result<(i32, u32), BackupError> {
+    let config = config();
+    let mut target = Connection::open_with_flags(dest, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let backup = Backup::new(source, &mut target)?;
+    let mut steps = 0;
+    let mut restarts = 0;
+    let mut copied = 0;
+    loop {
+        let step = backup.step(config.pages_per_step)?;
+        steps += 1;
+        let progress = backup.progress();
+        let now_copied = progress.pagecount - progress.remaining;
+        if now_copied < copied {
+            restarts += 1;
+        }
+        copied = now_copied;
+        {
+            let mut status = status().lock().unwrap();
+            status.page_count = progress.pagecount;
+            status.pages_copied = copied;
+        }
+        // More, or Busy/Locked while a writer holds the source
+        match step {
+            StepResult::Done => return Ok((progress.pagecount, steps)),
+            _ if restarts > config.max_restarts || start.elapsed() >= config.timeout => return Err(BackupError::Busy),
+            _ => thread::sleep(config.step_pause),
+        }
+    }
+}
+
+// Runs a backup of the database at `db_path` on its own connection, so
+// callers don't tie up one of theirs for the duration
+pub fn run_from_path(db_path: &str, dest: &Path, keep_path: bool) -> Result<BackupReport, BackupError> {
+    let source = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI)?;
+    run(&source, dest, keep_path)
+}
+
+// Uploaded snapshots are raw database files. Bodies without a type, or sent
+// as forms by `curl --data-binary`, are taken as snapshots too.
+pub fn is_snapshot_content_type(content_type: Option<&str>) -> bool {
+    let media_type = content_type.and_then(|ct| ct.split(';').next()).unwrap_or("").trim();
+    matches!(
+        media_type.to_ascii_lowercase().as_str(),
+        ""
+            | "application/vnd.sqlite3"
+            | "application/x-sqlite3"
+            | "application/octet-stream"
+            | "application/x-www-form-urlencoded"
+    )
+}
+
+// Writes an uploaded snapshot to a scratch file for `restore`; the caller
+// removes it afterwards
+pub fn save_upload(input: &mut impl Read) -> Result<PathBuf, BackupError> {
+    fs::create_dir_all(&config().dir)?;
+    let path = scratch_path();
+    let result = File::create(&path).and_then(|mut file| io::copy(input, &mut file));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&path);
+        return Err(e.into());
+    }
+    Ok(path)
+}
+
+// Checks a snapshot before it replaces anything: it has to pass
+// `PRAGMA integrity_check`, have a schema at all, and carry the
+// `PRAGMA user_version` the caller expects, if any. Returns the user version.
+fn validate(conn: &Connection, expected_version: Option<i64>) -> Result<i64, BackupError> {
+    let unusable = |e: rusqlite::Error| BackupError::Invalid(format!("not a usable SQLite database ({})", e));
+    let problems = conn
+        .prepare("PRAGMA integrity_check")
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(unusable)?;
+    if problems != ["ok"] {
+        return Err(BackupError::Invalid(format!("integrity check failed: {}", problems.join("; "))));
+    }
+
+    // The schema cookie is only 0 for a database that never had a schema
+    let schema_cookie: i64 = conn.pragma_query_value(None, "schema_version", |row| row.get(0)).map_err(unusable)?;
+    let user_version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0)).map_err(unusable)?;
+    if schema_cookie == 0 {
+        return Err(BackupError::Invalid("it has no schema".to_string()));
+    }
+    if let Some(expected) = expected_version.filter(|expected| *expected != user_version) {
+        return Err(BackupError::Invalid(format!(
+            "user_version {} does not match the expected {}",
+            user_version, expected
+        )));
+    }
+    Ok(user_version)
+}
+
+// Checks a finished backup reads back intact
+pub fn verify(path: &Path) -> Result<(), BackupError> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    validate(&conn, None).map(|_| ())
+}
+
+// Replaces the database at `db_path` with the snapshot in one backup step, so
+// other connections see either the old or the new contents, never a mix.
+// Callers quiesce their own connections first; connections that still hold a
+// lock are waited for up to the restore timeout. Never runs alongside a
+// backup, and tells change feed subscribers to reload once done.
+pub fn restore(db_path: &str, snapshot: &Path, expected_version: Option<i64>) -> Result<RestoreReport, BackupError> {
+    let _running = Running::start()?;
+    let config = config();
+    let start = Instant::now();
+    // Compressed scheduled backups are unpacked to a scratch file first
+    let unpacked = match snapshot.extension().is_some_and(|ext| ext == "gz") {
+        true => Some(ScratchFile(decompress(snapshot)?)),
+        false => None,
+    };
+    let source_path = unpacked.as_ref().map_or(snapshot, |file| file.0.as_path());
+    let source = Connection::open_with_flags(source_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let user_version = validate(&source, expected_version)?;
+
+    let mut target = Connection::open(db_path)?;
+    let pages = {
+        let backup = Backup::new(&source, &mut target)?;
+        loop {
+            match backup.step(-1)? {
+                StepResult::Done => break backup.progress().pagecount,
+                _ if start.elapsed() >= config.restore_timeout => return Err(BackupError::Busy),
+                _ => thread::sleep(config.step_pause),
+            }
+        }
+    };
+
+    changes::restored();
+
+    Ok(RestoreReport {
+        pages,
+        bytes: fs::metadata(snapshot)?.len(),
+        user_version,
+        duration_ms: start.elapsed().as_millis() as u64,
+    })
+}
+
+// Removed when dropped
+struct ScratchFile(PathBuf);
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn decompress(path: &Path) -> Result<PathBuf, BackupError> {
+    fs::create_dir_all(&config().dir)?;
+    let target = scratch_path();
+    let result = File::open(path).and_then(|file| {
+        let mut decoder = GzDecoder::new(io::BufReader::new(file));
+        io::copy(&mut decoder, &mut File::create(&target)?)
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&target);
+        return Err(BackupError::Invalid(format!("cannot decompress ({})", e)));
+    }
+    Ok(target)
+}
@@ -0,0 +1,523 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::backup::{self, BackupError};
+use crate::config::env_or;
+
+// Scheduled backups land in the backup directory as
+// `scheduled-YYYYMMDDTHHMMSSZ.db`, or `.db.gz` when compressed. Only files
+// named that way are ever pruned; backups taken by hand are left alone.
+const PREFIX: &str = "scheduled-";
+
+#[derive(Debug, Clone)]
+pub struct ScheduleConfig {
+    // Unset disables the scheduler
+    pub schedule: Option<String>,
+    pub compress: bool,
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+}
+
+impl ScheduleConfig {
+    pub fn from_env() -> Self {
+        let schedule = env_or("TINYSQL_BACKUP_SCHEDULE", String::new());
+        ScheduleConfig {
+            schedule: (!schedule.trim().is_empty()).then_some(schedule),
+            compress: env_or("TINYSQL_BACKUP_COMPRESS", false),
+            keep_last: env_or("TINYSQL_BACKUP_KEEP_LAST", 7),
+            keep_daily: env_or("TINYSQL_BACKUP_KEEP_DAILY", 7),
+            keep_weekly: env_or("TINYSQL_BACKUP_KEEP_WEEKLY", 4),
+        }
+    }
+}
+
+pub fn config() -> &'static ScheduleConfig {
+    static CONFIG: OnceLock<ScheduleConfig> = OnceLock::new();
+    CONFIG.get_or_init(ScheduleConfig::from_env)
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ScheduledRun {
+    pub file: String,
+    // Unix seconds
+    pub started_at: u64,
+    pub bytes: u64,
+    pub compressed: bool,
+    // Whether the copy passed `PRAGMA integrity_check`
+    pub verified: bool,
+    // Older scheduled backups removed by the retention policy
+    pub pruned: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ScheduleStatus {
+    pub schedule: String,
+    // Unix seconds
+    pub next_run: Option<u64>,
+    pub last_run: Option<ScheduledRun>,
+}
+
+fn status() -> &'static Mutex<Option<ScheduleStatus>> {
+    static STATUS: OnceLock<Mutex<Option<ScheduleStatus>>> = OnceLock::new();
+    STATUS.get_or_init(|| Mutex::new(None))
+}
+
+// `None` while the scheduler is off
+pub fn current_status() -> Option<ScheduleStatus> {
+    status().lock().unwrap().clone()
+}
+
+// Starts the scheduler thread for the database at `db_path` when
+// TINYSQL_BACKUP_SCHEDULE is set
+pub fn start(db_path: &'static str) {
+    let Some(expression) = config().schedule.clone() else {
+        return;
+    };
+    let schedule = match Schedule::parse(&expression) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            eprintln!("Scheduled backups disabled: {}", e);
+            return;
+        }
+    };
+    println!("Scheduled backups: {} into {}", expression, backup::config().dir.display());
+    *status().lock().unwrap() = Some(ScheduleStatus { schedule: expression, next_run: None, last_run: None });
+
+    thread::spawn(move || loop {
+        let now = backup::unix_now();
+        let Some(next) = schedule.next_after(now) else {
+            eprintln!("Scheduled backups stopped: the schedule never fires again");
+            return;
+        };
+        if let Some(status) = status().lock().unwrap().as_mut() {
+            status.next_run = Some(next);
+        }
+        thread::sleep(Duration::from_secs(next.saturating_sub(now)));
+
+        let run = run_once(db_path, next);
+        if let Some(e) = &run.error {
+            eprintln!("Scheduled backup {} failed: {}", run.file, e);
+        }
+        if let Some(status) = status().lock().unwrap().as_mut() {
+            status.last_run = Some(run);
+        }
+    });
+}
+
+fn run_once(db_path: &str, started_at: u64) -> ScheduledRun {
+    let config = config();
+    let name = format!("{}{}.db", PREFIX, timestamp(started_at));
+    let mut run = ScheduledRun {
+        file: name.clone(),
+        started_at,
+        bytes: 0,
+        compressed: false,
+        verified: false,
+        pruned: Vec::new(),
+        error: None,
+    };
+
+    let result = backup::destination(&name)
+        .and_then(|dest| backup::run_from_path(db_path, &dest, true).map(|_| dest))
+        .and_then(|dest| {
+            let verified = backup::verify(&dest);
+            if verified.is_err() {
+                let _ = fs::remove_file(&dest);
+            }
+            verified?;
+            run.verified = true;
+            if config.compress {
+                // Should compression fail, the verified copy is kept as is
+                let compressed = compress(&dest)?;
+                let _ = fs::remove_file(&dest);
+                run.compressed = true;
+                return Ok(compressed);
+            }
+            Ok(dest)
+        });
+    match result {
+        Ok(path) => {
+            run.file = path.file_name().map_or(name, |n| n.to_string_lossy().into_owned());
+            run.bytes = fs::metadata(&path).map_or(0, |m| m.len());
+        }
+        Err(e) => run.error = Some(e.message()),
+    }
+
+    // Prune even after a failed run, so a full disk can recover
+    match prune(config, &backup::config().dir) {
+        Ok(pruned) => run.pruned = pruned,
+        Err(e) => eprintln!("Failed to prune scheduled backups: {}", e),
+    }
+    run
+}
+
+// Gzips `path` next to itself and checks the result reads back in full
+fn compress(path: &Path) -> Result<PathBuf, BackupError> {
+    let mut target = path.as_os_str().to_owned();
+    target.push(".gz");
+    let target = PathBuf::from(target);
+
+    let written = (|| {
+        let mut encoder = GzEncoder::new(BufWriter::new(File::create(&target)?), Compression::default());
+        io::copy(&mut BufReader::new(File::open(path)?), &mut encoder)?;
+        encoder.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        // The gzip trailer carries a CRC, so reading it all back verifies it
+        io::copy(&mut GzDecoder::new(BufReader::new(File::open(&target)?)), &mut io::sink())
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&target);
+        return Err(e.into());
+    }
+    Ok(target)
+}
+
+// Keeps the newest `keep_last` scheduled backups, plus the newest one of
+// each of the last `keep_daily` days and `keep_weekly` weeks that have one,
+// and removes the rest from `dir`. Returns the names removed.
+fn prune(config: &ScheduleConfig, dir: &Path) -> io::Result<Vec<String>> {
+    let mut backups: Vec<(u64, String)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(taken_at) = parse_name(&name) {
+            backups.push((taken_at, name));
+        }
+    }
+    backups.sort_by(|a, b| b.cmp(a));
+
+    let mut keep: BTreeSet<&str> = backups.iter().take(config.keep_last).map(|(_, name)| name.as_str()).collect();
+    for (period, count) in [(day as fn(u64) -> u64, config.keep_daily), (week, config.keep_weekly)] {
+        let mut periods = BTreeSet::new();
+        for (taken_at, name) in &backups {
+            if periods.len() == count {
+                break;
+            }
+            if periods.insert(period(*taken_at)) {
+                keep.insert(name);
+            }
+        }
+    }
+
+    let mut pruned = Vec::new();
+    for (_, name) in &backups {
+        if !keep.contains(name.as_str()) {
+            fs::remove_file(dir.join(name))?;
+            pruned.push(name.clone());
+        }
+    }
+    Ok(pruned)
+}
+
+// Days since the epoch
+fn day(unix_secs: u64) -> u64 {
+    unix_secs / 86_400
+}
+
+// Weeks starting on Monday since the epoch. 1970-01-01 was a Thursday, so
+// shifting by three days lines weeks up with Mondays.
+fn week(unix_secs: u64) -> u64 {
+    (day(unix_secs) + 3) / 7
+}
+
+// `YYYYMMDDTHHMMSSZ` in UTC
+fn timestamp(unix_secs: u64) -> String {
+    let (year, month, day) = civil_from_days((unix_secs / 86_400) as i64);
+    let secs = unix_secs % 86_400;
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn parse_name(name: &str) -> Option<u64> {
+    let stamp = name.strip_prefix(PREFIX)?;
+    let stamp = stamp.strip_suffix(".db").or_else(|| stamp.strip_suffix(".db.gz"))?;
+    if stamp.len() != 16 || !stamp.is_char_boundary(8) || &stamp[8..9] != "T" || !stamp.ends_with('Z') {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| stamp.get(range)?.parse::<u64>().ok();
+    let days = days_from_civil(number(0..4)? as i64, number(4..6)? as u32, number(6..8)? as u32);
+    let secs = number(9..11)? * 3600 + number(11..13)? * 60 + number(13..15)?;
+    u64::try_from(days).ok().map(|days| days * 86_400 + secs)
+}
+
+// Proleptic Gregorian calendar conversions, after Howard Hinnant's
+// `days_from_civil` and `civil_from_days`
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// When backups run. Either a fixed interval, `@every 30m` (units s, m, h or
+// d), or a five-field cron expression in UTC: minute, hour, day of month,
+// month and day of week (0 or 7 is Sunday), each `*`, `*/n`, a number, a
+// range `a-b[/n]` or a comma-separated list of those. `@hourly`, `@daily`
+// and `@weekly` are shorthands.
+enum Schedule {
+    Every(u64),
+    Cron(Cron),
+}
+
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Cron matches either day field when both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    fn parse(expression: &str) -> Result<Self, String> {
+        let expression = expression.trim();
+        let cron = match expression {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            _ => expression,
+        };
+        if let Some(interval) = cron.strip_prefix("@every") {
+            return parse_interval(interval.trim()).map(Schedule::Every);
+        }
+
+        let fields: Vec<&str> = cron.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("expected five cron fields in {:?}", expression));
+        };
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // 7 is another name for Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Schedule::Cron(Cron {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        }))
+    }
+
+    // The first time strictly after `now`, in unix seconds
+    fn next_after(&self, now: u64) -> Option<u64> {
+        match self {
+            Schedule::Every(secs) => now.checked_add(*secs),
+            Schedule::Cron(cron) => cron.next_after(now),
+        }
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        let weekday = (days + 4).rem_euclid(7);
+        let day_ok = self.days & (1 << day) != 0;
+        let weekday_ok = self.weekdays & (1 << weekday) != 0;
+        let day_ok = match (self.any_day, self.any_weekday) {
+            (false, false) => day_ok || weekday_ok,
+            _ => day_ok && weekday_ok,
+        };
+        self.months & (1 << month) != 0 && day_ok
+    }
+
+    fn next_after(&self, now: u64) -> Option<u64> {
+        let start_days = (now / 86_400) as i64;
+        let first_minute = now / 60 + 1;
+        // Five years covers every satisfiable expression, leap days included
+        for days in start_days..start_days + 5 * 366 {
+            if !self.matches_day(days) {
+                continue;
+            }
+            for minute_of_day in 0..24 * 60 {
+                let minute = days as u64 * 1440 + minute_of_day;
+                if minute >= first_minute
+                    && self.hours & (1 << (minute_of_day / 60)) != 0
+                    && self.minutes & (1 << (minute_of_day % 60)) != 0
+                {
+                    return Some(minute * 60);
+                }
+            }
+        }
+        None
+    }
+}
+
+fn parse_interval(interval: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid interval {:?}", interval);
+    let split = interval.find(|c: char| !c.is_ascii_digit()).unwrap_or(interval.len());
+    let (count, unit) = interval.split_at(split);
+    let count: u64 = count.parse().map_err(|_| invalid())?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(invalid()),
+    };
+    match count.checked_mul(unit) {
+        None | Some(0) => Err(invalid()),
+        Some(secs) => Ok(secs),
+    }
+}
+
+// A bit set of the values `field` allows within `min..=max`
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("invalid cron field {:?}", field);
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (low, high) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((low, high)) => (low.parse().map_err(|_| invalid())?, high.parse().map_err(|_| invalid())?),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if low < min || high > max || low > high {
+            return Err(invalid());
+        }
+        for value in (low..=high).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Monday 2024-01-01T00:00:00Z
+    const MONDAY: u64 = 1_704_067_200;
+    const HOUR: u64 = 3600;
+    const DAY: u64 = 86_400;
+
+    fn next(expression: &str, now: u64) -> Option<u64> {
+        Schedule::parse(expression).unwrap().next_after(now)
+    }
+
+    #[test]
+    fn intervals_parse_and_reject_zero_or_overflow() {
+        assert!(matches!(Schedule::parse("@every 30m"), Ok(Schedule::Every(1800))));
+        assert!(matches!(Schedule::parse(" @every 2d "), Ok(Schedule::Every(172_800))));
+        for invalid in ["@every 0s", "@every 10", "@every m", "@every 5w", "@every 300000000000000000d"] {
+            assert!(Schedule::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(next("@every 90s", 100), Some(190));
+        assert_eq!(Schedule::Every(10).next_after(u64::MAX), None);
+    }
+
+    #[test]
+    fn cron_expressions_are_checked_field_by_field() {
+        for valid in ["@hourly", "@daily", "@weekly", "*/15 * * * *", "0 9-17/2 * * 1-5", "0,30 0 1,15 * *"] {
+            assert!(Schedule::parse(valid).is_ok(), "{}", valid);
+        }
+        for invalid in ["* * * *", "60 * * * *", "0 24 * * *", "0 0 0 * *", "*/0 * * * *", "5-1 * * * *", "a * * * *"] {
+            assert!(Schedule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cron_runs_strictly_after_now() {
+        assert_eq!(next("30 2 * * *", MONDAY), Some(MONDAY + 2 * HOUR + 1800));
+        assert_eq!(next("30 2 * * *", MONDAY + 2 * HOUR + 1800), Some(MONDAY + DAY + 2 * HOUR + 1800));
+        assert_eq!(next("@hourly", MONDAY + 59), Some(MONDAY + HOUR));
+        assert_eq!(next("0 0 1 3 *", MONDAY), Some(MONDAY + 60 * DAY), "2024 is a leap year");
+        assert_eq!(next("0 0 30 2 *", MONDAY), None);
+    }
+
+    #[test]
+    fn cron_weekdays_count_from_sunday_and_either_day_field_matches() {
+        assert_eq!(next("0 0 * * 7", MONDAY), Some(MONDAY + 6 * DAY));
+        assert_eq!(next("@weekly", MONDAY), Some(MONDAY + 6 * DAY));
+        // The 13th or any Friday, whichever comes first
+        assert_eq!(next("0 0 13 * 5", MONDAY), Some(MONDAY + 4 * DAY));
+        assert_eq!(next("0 0 3 * 5", MONDAY), Some(MONDAY + 2 * DAY));
+    }
+
+    #[test]
+    fn names_round_trip_through_timestamps() {
+        let taken_at = MONDAY + 13 * HOUR + 5 * 60 + 9;
+        assert_eq!(timestamp(taken_at), "20240101T130509Z");
+        assert_eq!(parse_name(&format!("{}{}.db", PREFIX, timestamp(taken_at))), Some(taken_at));
+        assert_eq!(parse_name(&format!("{}{}.db.gz", PREFIX, timestamp(taken_at))), Some(taken_at));
+        for other in ["manual.db", "scheduled-2024.db", "scheduled-20240101X130509Z.db", "scheduled-20240101T130509Z.txt"] {
+            assert_eq!(parse_name(other), None, "{}", other);
+        }
+    }
+
+    #[test]
+    fn weeks_start_on_monday_and_days_at_midnight() {
+        assert_ne!(week(MONDAY - 1), week(MONDAY));
+        assert_eq!(week(MONDAY), week(MONDAY + 7 * DAY - 1));
+        assert_ne!(day(MONDAY - 1), day(MONDAY));
+        assert_eq!(day(MONDAY), day(MONDAY + DAY - 1));
+    }
+
+    #[test]
+    fn prune_keeps_the_latest_daily_and_weekly_backups() {
+        let dir = env::temp_dir().join(format!("tinysql-prune-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Twice a day from Monday 2024-01-01 to Sunday 2024-01-21
+        for taken_at in (0..42).map(|i| MONDAY + i * 12 * HOUR) {
+            File::create(dir.join(format!("{}{}.db", PREFIX, timestamp(taken_at)))).unwrap();
+        }
+        File::create(dir.join("manual.db")).unwrap();
+
+        let config = ScheduleConfig { schedule: None, compress: false, keep_last: 2, keep_daily: 3, keep_weekly: 2 };
+        let pruned = prune(&config, &dir).unwrap();
+
+        let mut kept: Vec<String> =
+            fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        kept.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(pruned.len(), 37);
+        assert_eq!(
+            kept,
+            [
+                "manual.db",
+                "scheduled-20240114T120000Z.db",
+                "scheduled-20240119T120000Z.db",
+                "scheduled-20240120T120000Z.db",
+                "scheduled-20240121T000000Z.db",
+                "scheduled-20240121T120000Z.db",
+            ]
+        );
+    }
+}
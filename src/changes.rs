@@ -0,0 +1,453 @@
+use async_std::channel::{self, Receiver, TrySendError};
+use rusqlite::hooks::Action;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::backup;
+use crate::config::env_or;
+use crate::resources;
+use crate::schema::{self, quote_identifier};
+use crate::sql_policy::SqlPolicy;
+
+// A feed of committed inserts, updates and deletes. Every pooled connection
+// reports the rows it touches through SQLite's update hook; they are queued
+// per transaction and handed to the feed thread by the commit hook. That
+// hook runs before the commit is written, which can still fail and roll
+// back; the rollback hook then flags the batch and the feed thread drops it.
+// Surviving changes are read back and fanned out to subscribers, keeping the
+// most recent ones so clients can resume.
+//
+// The update hook does not fire for WITHOUT ROWID tables, so those never
+// appear in the feed, and neither do the shadow tables of virtual tables.
+#[derive(Debug, Clone)]
+pub struct ChangesConfig {
+    // Comma-separated TINYSQL_CHANGES_TABLES; empty watches every table
+    pub tables: Vec<String>,
+    // Changes kept for clients resuming with `Last-Event-ID`
+    pub history: usize,
+    pub keepalive: Duration,
+}
+
+impl ChangesConfig {
+    pub fn from_env() -> Self {
+        let tables = env_or("TINYSQL_CHANGES_TABLES", String::new());
+        ChangesConfig {
+            tables: tables.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect(),
+            history: env_or("TINYSQL_CHANGES_HISTORY", 1024),
+            keepalive: Duration::from_millis(env_or("TINYSQL_CHANGES_KEEPALIVE_MS", 15_000)),
+        }
+    }
+
+    fn watches(&self, table: &str) -> bool {
+        !table.starts_with("sqlite_")
+            && (self.tables.is_empty() || self.tables.iter().any(|t| t.eq_ignore_ascii_case(table)))
+    }
+}
+
+pub fn config() -> &'static ChangesConfig {
+    static CONFIG: OnceLock<ChangesConfig> = OnceLock::new();
+    CONFIG.get_or_init(ChangesConfig::from_env)
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Change {
+    // `<boot>-<sequence>`; the boot part changes when the server restarts
+    pub id: String,
+    // Empty for `restore`
+    pub table: String,
+    // `insert`, `update` or `delete`; or `restore` when the whole database
+    // was replaced and clients should reload
+    pub operation: String,
+    pub rowid: i64,
+    // The row as the feed read it after the commit, not as that commit left
+    // it: a later write to the same row shows through. `None` for deletes,
+    // or when the row was gone by then.
+    pub row: Option<Value>,
+}
+
+impl Change {
+    // Whether a subscriber to `tables` (every table when empty) that is bound
+    // by `policy` gets this change. Restores concern every table.
+    pub fn is_for(&self, tables: &[String], policy: &SqlPolicy) -> bool {
+        if self.operation == "restore" {
+            return true;
+        }
+        (tables.is_empty() || tables.iter().any(|t| t.eq_ignore_ascii_case(&self.table)))
+            && policy.check_read(&self.table).is_ok()
+    }
+}
+
+// What the hooks record before the row is read
+struct Touched {
+    table: String,
+    operation: &'static str,
+    rowid: i64,
+}
+
+// One connection's hook state
+#[derive(Default)]
+struct Hooks {
+    // Touched by the open transaction
+    pending: Vec<Touched>,
+    // Batches handed to the feed whose commit has not been confirmed yet; a
+    // rollback flags them all
+    committing: Vec<Arc<AtomicBool>>,
+}
+
+// A transaction's changes on their way to the feed thread
+struct Batch {
+    changes: Vec<Touched>,
+    rolled_back: Arc<AtomicBool>,
+    // Where the batch came from, so the feed can settle it; `None` for
+    // batches that need no confirmation
+    hooks: Option<Arc<Mutex<Hooks>>>,
+}
+
+impl Hooks {
+    // From the commit hook: the pending changes as a batch for the feed
+    // thread, unconfirmed until it has `landed`
+    fn commit(hooks: &Arc<Mutex<Hooks>>) -> Option<Batch> {
+        let mut state = hooks.lock().unwrap();
+        if state.pending.is_empty() {
+            return None;
+        }
+        let rolled_back = Arc::new(AtomicBool::new(false));
+        state.committing.push(rolled_back.clone());
+        let changes = std::mem::take(&mut state.pending);
+        Some(Batch { changes, rolled_back, hooks: Some(hooks.clone()) })
+    }
+
+    // From the rollback hook, which also fires when a commit fails after the
+    // commit hook ran
+    fn rollback(&mut self) {
+        self.pending.clear();
+        for flag in self.committing.drain(..) {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Batch {
+    // Whether the commit survived, as far as the hooks reported by the time
+    // the feed takes the batch. Rollbacks reported after this belong to later
+    // transactions.
+    fn landed(&self) -> bool {
+        if let Some(hooks) = &self.hooks {
+            hooks.lock().unwrap().committing.retain(|flag| !Arc::ptr_eq(flag, &self.rolled_back));
+        }
+        !self.rolled_back.load(Ordering::SeqCst)
+    }
+}
+
+fn operation(action: Action) -> Option<&'static str> {
+    match action {
+        Action::SQLITE_INSERT => Some("insert"),
+        Action::SQLITE_UPDATE => Some("update"),
+        Action::SQLITE_DELETE => Some("delete"),
+        _ => None,
+    }
+}
+
+fn committed() -> &'static Mutex<mpsc::Sender<Batch>> {
+    static COMMITTED: OnceLock<Mutex<mpsc::Sender<Batch>>> = OnceLock::new();
+    COMMITTED.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        *incoming().lock().unwrap() = Some(receiver);
+        Mutex::new(sender)
+    })
+}
+
+// Taken by the feed thread when it starts
+fn incoming() -> &'static Mutex<Option<mpsc::Receiver<Batch>>> {
+    static INCOMING: OnceLock<Mutex<Option<mpsc::Receiver<Batch>>>> = OnceLock::new();
+    INCOMING.get_or_init(|| Mutex::new(None))
+}
+
+// Registers the hooks on a connection, typically from a pool's init
+pub fn install(conn: &Connection) {
+    let hooks: Arc<Mutex<Hooks>> = Arc::default();
+
+    let touched = hooks.clone();
+    conn.update_hook(Some(move |action, database: &str, table: &str, rowid| {
+        if let Some(operation) = operation(action).filter(|_| database == "main" && config().watches(table)) {
+            touched.lock().unwrap().pending.push(Touched { table: table.to_string(), operation, rowid });
+        }
+    }));
+
+    let on_commit = hooks.clone();
+    conn.commit_hook(Some(move || {
+        if let Some(batch) = Hooks::commit(&on_commit) {
+            let _ = committed().lock().unwrap().send(batch);
+        }
+        // Returning true would turn the commit into a rollback
+        false
+    }));
+
+    conn.rollback_hook(Some(move || hooks.lock().unwrap().rollback()));
+}
+
+// Restores replace the database file underneath the hooks, so the rows they
+// bring back are announced as one `restore` change instead
+pub fn restored() {
+    let restore = Touched { table: String::new(), operation: "restore", rowid: 0 };
+    let batch = Batch { changes: vec![restore], rolled_back: Arc::default(), hooks: None };
+    let _ = committed().lock().unwrap().send(batch);
+}
+
+struct Feed {
+    boot: u64,
+    next_sequence: u64,
+    history: VecDeque<(u64, Arc<Change>)>,
+    subscribers: Vec<channel::Sender<Arc<Change>>>,
+}
+
+fn feed() -> &'static Mutex<Feed> {
+    static FEED: OnceLock<Mutex<Feed>> = OnceLock::new();
+    FEED.get_or_init(|| {
+        Mutex::new(Feed {
+            boot: backup::unix_now(),
+            next_sequence: 1,
+            history: VecDeque::new(),
+            subscribers: Vec::new(),
+        })
+    })
+}
+
+impl Feed {
+    fn id(&self, sequence: u64) -> String {
+        format!("{}-{}", self.boot, sequence)
+    }
+
+    fn publish(&mut self, touched: Touched, row: Option<Value>) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let change = Arc::new(Change {
+            id: self.id(sequence),
+            table: touched.table,
+            operation: touched.operation.to_string(),
+            rowid: touched.rowid,
+            row,
+        });
+
+        self.history.push_back((sequence, change.clone()));
+        while self.history.len() > config().history {
+            self.history.pop_front();
+        }
+        // A subscriber that falls a full history behind is dropped; closing
+        // its stream makes the client reconnect and resume from its last id
+        self.subscribers.retain(|subscriber| match subscriber.try_send(change.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => false,
+        });
+    }
+}
+
+// Starts the feed thread, which reads changed rows from its own connection to
+// `db_path` and writes nothing
+pub fn start(db_path: &'static str) {
+    committed();
+    let Some(receiver) = incoming().lock().unwrap().take() else {
+        return;
+    };
+    thread::spawn(move || {
+        let conn = match Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_URI) {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Change feed disabled: {}", e);
+                return;
+            }
+        };
+        let mut ordinary = OrdinaryTables::default();
+        for batch in receiver {
+            if !batch.landed() {
+                continue;
+            }
+            ordinary.refresh(&conn);
+            for touched in batch
+                .changes
+                .into_iter()
+                .filter(|touched| touched.operation == "restore" || ordinary.contains(&conn, &touched.table))
+            {
+                let row = match touched.operation {
+                    "delete" | "restore" => None,
+                    _ => read_row(&conn, &touched.table, touched.rowid).unwrap_or_else(|e| {
+                        eprintln!("Change feed failed to read {} row {}: {}", touched.table, touched.rowid, e);
+                        None
+                    }),
+                };
+                feed().lock().unwrap().publish(touched, row);
+            }
+        }
+    });
+}
+
+// Writes to virtual tables land in their shadow tables, e.g. a full-text
+// index's, which are internal and left out of the feed. Which tables are
+// ordinary is remembered until the schema changes.
+#[derive(Default)]
+struct OrdinaryTables {
+    version: Option<i64>,
+    tables: HashMap<String, bool>,
+}
+
+impl OrdinaryTables {
+    // Once per batch: forgets every table after a schema change
+    fn refresh(&mut self, conn: &Connection) {
+        let version = schema::version(conn).ok();
+        if version.is_none() || version != self.version {
+            self.tables.clear();
+            self.version = version;
+        }
+    }
+
+    fn contains(&mut self, conn: &Connection, table: &str) -> bool {
+        if let Some(&ordinary) = self.tables.get(table) {
+            return ordinary;
+        }
+        let ordinary = schema::schema(conn).map_or(true, |schema| schema.table(table).is_some());
+        self.tables.insert(table.to_string(), ordinary);
+        ordinary
+    }
+}
+
+fn read_row(conn: &Connection, table: &str, rowid: i64) -> rusqlite::Result<Option<Value>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT * FROM {} WHERE rowid = ?1", quote_identifier(table)))?;
+    let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    stmt.query_row([rowid], |row| resources::row_json(row, &names)).optional()
+}
+
+pub struct Subscription {
+    // Changes after the client's last event id
+    pub missed: Vec<Arc<Change>>,
+    // The last event id is unknown or too old to resume from: changes may
+    // have been lost and the client should reload
+    pub reset: bool,
+    // The id to resume from after a reset
+    pub latest_id: String,
+    pub receiver: Receiver<Arc<Change>>,
+}
+
+// Subscribes to changes after `last_event_id`. Catching up and registering
+// happen under one lock, so nothing falls in between.
+pub fn subscribe(last_event_id: Option<&str>) -> Subscription {
+    let mut feed = feed().lock().unwrap();
+    let latest = feed.next_sequence - 1;
+    let oldest_kept = feed.history.front().map_or(feed.next_sequence, |(sequence, _)| *sequence);
+
+    let resume_from = last_event_id.map(|id| {
+        id.split_once('-')
+            .and_then(|(boot, sequence)| Some((boot.parse::<u64>().ok()?, sequence.parse::<u64>().ok()?)))
+            .filter(|(boot, sequence)| *boot == feed.boot && *sequence <= latest && sequence + 1 >= oldest_kept)
+            .map(|(_, sequence)| sequence)
+    });
+    let (missed, reset) = match resume_from {
+        Some(Some(after)) => {
+            let missed = feed.history.iter().filter(|(sequence, _)| *sequence > after).map(|(_, c)| c.clone());
+            (missed.collect(), false)
+        }
+        Some(None) => (Vec::new(), true),
+        None => (Vec::new(), false),
+    };
+
+    let (sender, receiver) = channel::bounded(config().history.max(1));
+    feed.subscribers.push(sender);
+    Subscription { missed, reset, latest_id: feed.id(latest), receiver }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn change(table: &str, operation: &str) -> Change {
+        Change { id: "1-1".to_string(), table: table.to_string(), operation: operation.to_string(), rowid: 1, row: None }
+    }
+
+    #[test]
+    fn subscribers_only_get_changes_their_policy_can_read() {
+        let policy = SqlPolicy::parse("read:person");
+        assert!(change("person", "insert").is_for(&[], &policy));
+        assert!(!change("secrets", "insert").is_for(&[], &policy));
+        assert!(!change("person", "update").is_for(&["orders".to_string()], &policy));
+        assert!(change("PERSON", "delete").is_for(&["person".to_string()], &policy));
+        // Restores concern every table
+        assert!(change("", "restore").is_for(&["orders".to_string()], &SqlPolicy::parse("")));
+    }
+
+    fn touch(hooks: &Arc<Mutex<Hooks>>, rowid: i64) {
+        hooks.lock().unwrap().pending.push(Touched { table: "t".to_string(), operation: "insert", rowid });
+    }
+
+    #[test]
+    fn commits_rolled_back_after_the_commit_hook_never_land() {
+        let hooks: Arc<Mutex<Hooks>> = Arc::default();
+        assert!(Hooks::commit(&hooks).is_none());
+
+        touch(&hooks, 1);
+        let failed = Hooks::commit(&hooks).unwrap();
+        hooks.lock().unwrap().rollback();
+        assert!(!failed.landed());
+
+        touch(&hooks, 2);
+        let committed = Hooks::commit(&hooks).unwrap();
+        assert!(committed.landed());
+        // Rollbacks of later transactions leave settled batches alone
+        hooks.lock().unwrap().rollback();
+        assert!(committed.landed());
+        assert!(hooks.lock().unwrap().committing.is_empty());
+    }
+
+    #[test]
+    fn ordinary_tables_are_remembered_until_the_schema_changes() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE person (name); CREATE VIRTUAL TABLE person_search USING fts5(name)").unwrap();
+        let mut ordinary = OrdinaryTables::default();
+        ordinary.refresh(&conn);
+        assert!(ordinary.contains(&conn, "person"));
+        assert!(!ordinary.contains(&conn, "person_search_data"));
+        assert!(!ordinary.contains(&conn, "team"));
+
+        conn.execute_batch("CREATE TABLE team (name)").unwrap();
+        assert!(!ordinary.contains(&conn, "team"));
+        ordinary.refresh(&conn);
+        assert!(ordinary.contains(&conn, "team"));
+    }
+
+    #[test]
+    fn hooks_hand_over_committed_transactions_only() {
+        let path = env::temp_dir().join(format!("tinysql-changes-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        committed();
+        let receiver = incoming().lock().unwrap().take().unwrap();
+        let next = || receiver.recv_timeout(Duration::from_millis(200)).ok();
+
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch("CREATE TABLE t (x)").unwrap();
+        install(&conn);
+
+        conn.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        let batch = next().unwrap();
+        assert_eq!(batch.changes.len(), 1);
+        assert!(batch.landed());
+
+        conn.execute_batch("BEGIN; INSERT INTO t VALUES (2); INSERT INTO t VALUES (3); ROLLBACK").unwrap();
+        assert!(next().is_none());
+
+        conn.execute_batch("BEGIN; INSERT INTO t VALUES (4); DELETE FROM t WHERE x = 1; COMMIT").unwrap();
+        let batch = next().unwrap();
+        let operations: Vec<&str> = batch.changes.iter().map(|touched| touched.operation).collect();
+        assert_eq!(operations, ["insert", "delete"]);
+        assert!(batch.landed());
+
+        drop(conn);
+        let _ = fs::remove_file(&path);
+    }
+}
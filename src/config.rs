@@ -0,0 +1,23 @@
+use std::env;
+use std::str::FromStr;
+
+// Read a setting from the environment, falling back to `default` when the
+// variable is unset or cannot be parsed.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                eprintln!("Ignoring invalid value for {}: {}", name, value);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// The address a server listens on: TINYSQL_ADDR, or the variant's usual one.
+// Tests point it at a free local port to run several servers side by side.
+pub fn listen_addr(default: &str) -> String {
+    env_or("TINYSQL_ADDR", default.to_string())
+}
@@ -0,0 +1,130 @@
+use std::env;
+use std::sync::OnceLock;
+
+use crate::config::env_or;
+
+// CORS for browser clients, enabled by listing allowed origins in
+// TINYSQL_CORS_ORIGINS (comma separated, `*` for any). Methods, request
+// headers, credentials and the preflight max-age come from
+// TINYSQL_CORS_METHODS, TINYSQL_CORS_HEADERS, TINYSQL_CORS_CREDENTIALS and
+// TINYSQL_CORS_MAX_AGE. Credentials are only sent to origins listed by name,
+// never to one matched by `*`.
+#[derive(Debug)]
+pub struct CorsConfig {
+    origins: Vec<String>,
+    methods: String,
+    headers: String,
+    credentials: bool,
+    max_age: u64,
+}
+
+impl CorsConfig {
+    pub fn from_env() -> Option<Self> {
+        let origins: Vec<String> = env::var("TINYSQL_CORS_ORIGINS")
+            .ok()?
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        if origins.is_empty() {
+            return None;
+        }
+        Some(CorsConfig {
+            origins,
+            methods: env::var("TINYSQL_CORS_METHODS").unwrap_or_else(|_| "GET, POST, PUT, DELETE, OPTIONS".to_string()),
+            headers: env::var("TINYSQL_CORS_HEADERS").unwrap_or_else(|_| "Content-Type, Authorization, X-API-Key".to_string()),
+            credentials: env_or("TINYSQL_CORS_CREDENTIALS", false),
+            max_age: env_or("TINYSQL_CORS_MAX_AGE", 600),
+        })
+    }
+
+    // The Access-Control-Allow-Origin value for `origin`, if it is allowed,
+    // and whether the response may carry credentials. Only origins listed by
+    // name get credentials: a `*` match is answered with a literal `*`, since
+    // echoing every origin back with credentials would let any site make
+    // authenticated requests.
+    fn allow_origin(&self, origin: &str) -> Option<(String, bool)> {
+        if self.origins.iter().any(|allowed| allowed == origin) {
+            Some((origin.to_string(), self.credentials))
+        } else if self.origins.iter().any(|allowed| allowed == "*") {
+            Some(("*".to_string(), false))
+        } else {
+            None
+        }
+    }
+
+    // Headers for a response to a request from `origin`. Empty when the
+    // origin isn't allowed, which leaves the browser to block the response.
+    pub fn headers(&self, origin: Option<&str>, preflight: bool) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("Vary", "Origin".to_string())];
+        let Some((allow_origin, credentials)) = origin.and_then(|origin| self.allow_origin(origin)) else {
+            return headers;
+        };
+        headers.push(("Access-Control-Allow-Origin", allow_origin));
+        if credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+        }
+        if preflight {
+            headers.push(("Access-Control-Allow-Methods", self.methods.clone()));
+            headers.push(("Access-Control-Allow-Headers", self.headers.clone()));
+            headers.push(("Access-Control-Max-Age", self.max_age.to_string()));
+        } else {
+            headers.push((
+                "Access-Control-Expose-Headers",
+                "Retry-After, X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset".to_string(),
+            ));
+        }
+        headers
+    }
+}
+
+pub fn cors() -> Option<&'static CorsConfig> {
+    static CORS: OnceLock<Option<CorsConfig>> = OnceLock::new();
+    CORS.get_or_init(CorsConfig::from_env).as_ref()
+}
+
+// A preflight is an OPTIONS request that names the method it wants to use
+pub fn is_preflight(is_options: bool, request_method: Option<&str>) -> bool {
+    is_options && request_method.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(origins: &[&str], credentials: bool) -> CorsConfig {
+        CorsConfig {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: "GET".to_string(),
+            headers: "Content-Type".to_string(),
+            credentials,
+            max_age: 600,
+        }
+    }
+
+    fn header<'a>(headers: &'a [(&'static str, String)], name: &str) -> Option<&'a str> {
+        headers.iter().find(|(key, _)| *key == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn wildcard_never_reflects_the_origin_with_credentials() {
+        let headers = config(&["*"], true).headers(Some("https://evil.example"), false);
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(header(&headers, "Access-Control-Allow-Credentials"), None);
+    }
+
+    #[test]
+    fn listed_origins_are_echoed_with_credentials() {
+        let config = config(&["https://app.example", "*"], true);
+        let headers = config.headers(Some("https://app.example"), true);
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some("https://app.example"));
+        assert_eq!(header(&headers, "Access-Control-Allow-Credentials"), Some("true"));
+        assert_eq!(header(&headers, "Access-Control-Max-Age"), Some("600"));
+    }
+
+    #[test]
+    fn unlisted_origins_get_no_cors_headers() {
+        let headers = config(&["https://app.example"], true).headers(Some("https://evil.example"), false);
+        assert_eq!(headers, vec![("Vary", "Origin".to_string())]);
+    }
+}
@@ -0,0 +1,383 @@
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+use crate::config::env_or;
+use crate::errors::ErrorBody;
+use crate::repository::{configure_connection, create_table};
+use crate::retry;
+use crate::schema;
+use crate::search;
+
+// Named databases, one `<name>.db` file each in a data directory, next to the
+// server's default database. Every CRUD, row, schema, search and query route
+// is also served under `/db/<name>/`. Pools are opened on first use and
+// closed again when idle for too long, or when more than `max_open` are open,
+// least recently used first. Managing databases takes an admin principal.
+#[derive(Debug, Clone)]
+pub struct DatabasesConfig {
+    pub dir: PathBuf,
+    // Databases that may exist at once; creating more is refused
+    pub max_count: usize,
+    pub max_open: usize,
+    pub idle_timeout: Duration,
+    // Connections per named database's pool
+    pub pool_size: u32,
+}
+
+impl DatabasesConfig {
+    pub fn from_env() -> Self {
+        DatabasesConfig {
+            dir: PathBuf::from(env_or("TINYSQL_DATA_DIR", "data".to_string())),
+            max_count: env_or("TINYSQL_DB_MAX_COUNT", 100),
+            max_open: env_or("TINYSQL_DB_MAX_OPEN", 8).max(1),
+            idle_timeout: Duration::from_secs(env_or("TINYSQL_DB_IDLE_SECS", 300)),
+            pool_size: env_or("TINYSQL_DB_POOL_SIZE", 4).max(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    InvalidName(String),
+    NotFound(String),
+    Exists(String),
+    // Creating more than `max_count` databases
+    TooMany(usize),
+    // Deleting a database with requests in flight
+    InUse(String),
+    Io(io::Error),
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+}
+
+impl DatabaseError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            DatabaseError::InvalidName(_) => 400,
+            DatabaseError::NotFound(_) => 404,
+            DatabaseError::Exists(_) | DatabaseError::TooMany(_) | DatabaseError::InUse(_) => 409,
+            DatabaseError::Sqlite(e) => retry::status_code(e),
+            DatabaseError::Io(_) | DatabaseError::Pool(_) => 500,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            DatabaseError::InvalidName(name) => {
+                format!("Invalid database name {:?}: use letters, digits, `_` and `-`", name)
+            }
+            DatabaseError::NotFound(name) => format!("No such database: {}", name),
+            DatabaseError::Exists(name) => format!("Database {} already exists", name),
+            DatabaseError::TooMany(max) => format!("At most {} databases may exist", max),
+            DatabaseError::InUse(name) => format!("Database {} has requests in flight", name),
+            DatabaseError::Sqlite(e) if retry::is_busy(e) => "Database is busy, retry later".to_string(),
+            DatabaseError::Io(e) => format!("Database operation failed: {}", e),
+            DatabaseError::Sqlite(e) => format!("Database operation failed: {}", e),
+            DatabaseError::Pool(e) => format!("Failed to open database: {}", e),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        matches!(self, DatabaseError::Sqlite(e) if retry::is_busy(e))
+    }
+
+    pub fn body(&self) -> serde_json::Value {
+        json!(ErrorBody::new(self.message()))
+    }
+}
+
+impl From<io::Error> for DatabaseError {
+    fn from(err: io::Error) -> Self {
+        DatabaseError::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(err: rusqlite::Error) -> Self {
+        DatabaseError::Sqlite(err)
+    }
+}
+
+impl From<r2d2::Error> for DatabaseError {
+    fn from(err: r2d2::Error) -> Self {
+        DatabaseError::Pool(err)
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct NewDatabase {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct DatabaseInfo {
+    pub name: String,
+    pub size_bytes: u64,
+    // Whether a pool is currently open
+    pub open: bool,
+    // Only when inspecting one database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tables: Option<Vec<String>>,
+}
+
+struct OpenDatabase {
+    pool: Pool<SqliteConnectionManager>,
+    last_used: Instant,
+}
+
+// A named database's pool for one request. The database counts as in use
+// while any handle on it is alive, including handles on a pool the registry
+// has since evicted.
+#[derive(Clone)]
+pub struct Handle {
+    pub pool: Pool<SqliteConnectionManager>,
+    _live: Arc<()>,
+}
+
+#[derive(Default)]
+struct Registry {
+    open: HashMap<String, OpenDatabase>,
+    live: HashMap<String, Weak<()>>,
+}
+
+impl Registry {
+    fn in_use(&self, name: &str) -> bool {
+        self.live.get(name).is_some_and(|live| live.strong_count() > 0)
+    }
+}
+
+// The databases in one data directory, with the pools open on them
+pub struct Databases {
+    config: DatabasesConfig,
+    registry: Mutex<Registry>,
+}
+
+fn databases() -> &'static Databases {
+    static DATABASES: OnceLock<Databases> = OnceLock::new();
+    DATABASES.get_or_init(|| Databases::new(DatabasesConfig::from_env()))
+}
+
+// The server's databases, under TINYSQL_DATA_DIR
+pub fn pool(name: &str) -> Result<Handle, DatabaseError> {
+    databases().pool(name)
+}
+
+pub fn list() -> Result<Vec<DatabaseInfo>, DatabaseError> {
+    databases().list()
+}
+
+pub fn create(name: &str) -> Result<DatabaseInfo, DatabaseError> {
+    databases().create(name)
+}
+
+pub fn inspect(name: &str) -> Result<DatabaseInfo, DatabaseError> {
+    databases().inspect(name)
+}
+
+pub fn delete(name: &str) -> Result<(), DatabaseError> {
+    databases().delete(name)
+}
+
+impl Databases {
+    pub fn new(config: DatabasesConfig) -> Self {
+        Databases { config, registry: Mutex::default() }
+    }
+
+    fn database_file(&self, name: &str) -> Result<PathBuf, DatabaseError> {
+        let valid = !name.is_empty()
+            && name.len() <= 64
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+        if !valid {
+            return Err(DatabaseError::InvalidName(name.to_string()));
+        }
+        Ok(self.config.dir.join(format!("{}.db", name)))
+    }
+
+    fn existing(&self, name: &str) -> Result<PathBuf, DatabaseError> {
+        let path = self.database_file(name)?;
+        if !path.is_file() {
+            return Err(DatabaseError::NotFound(name.to_string()));
+        }
+        Ok(path)
+    }
+
+    // A handle on the pool of a named database, opened on first use. Handles
+    // outlive eviction, so evicting a pool never cuts a request off; its
+    // connections close when the last handle goes.
+    pub fn pool(&self, name: &str) -> Result<Handle, DatabaseError> {
+        let mut registry = self.registry.lock().unwrap();
+        // Checked under the lock so a concurrent delete can't slip in between
+        let path = self.existing(name)?;
+        let now = Instant::now();
+        registry.open.retain(|_, database| now.duration_since(database.last_used) < self.config.idle_timeout);
+        registry.live.retain(|_, live| live.strong_count() > 0);
+        let live = match registry.live.get(name).and_then(Weak::upgrade) {
+            Some(live) => live,
+            None => {
+                let live = Arc::new(());
+                registry.live.insert(name.to_string(), Arc::downgrade(&live));
+                live
+            }
+        };
+        if let Some(database) = registry.open.get_mut(name) {
+            database.last_used = now;
+            return Ok(Handle { pool: database.pool.clone(), _live: live });
+        }
+
+        while registry.open.len() >= self.config.max_open {
+            let Some(oldest) = registry.open.iter().min_by_key(|(_, database)| database.last_used).map(|(name, _)| name.clone())
+            else {
+                break;
+            };
+            registry.open.remove(&oldest);
+        }
+        // Without SQLITE_OPEN_CREATE, so a pool outliving its files can't bring
+        // back an empty database
+        let manager = SqliteConnectionManager::file(path)
+            .with_flags(OpenFlags::default().difference(OpenFlags::SQLITE_OPEN_CREATE))
+            .with_init(|conn| {
+                configure_connection(conn);
+                Ok(())
+            });
+        // Connections are opened as requests need them, not all up front
+        let pool = Pool::builder().max_size(self.config.pool_size).min_idle(Some(0)).build(manager)?;
+        registry.open.insert(name.to_string(), OpenDatabase { pool: pool.clone(), last_used: now });
+        Ok(Handle { pool, _live: live })
+    }
+
+    fn info(&self, name: &str, tables: Option<Vec<String>>) -> Result<DatabaseInfo, DatabaseError> {
+        let size_bytes = fs::metadata(self.existing(name)?)?.len();
+        let open = self.registry.lock().unwrap().open.contains_key(name);
+        Ok(DatabaseInfo { name: name.to_string(), size_bytes, open, tables })
+    }
+
+    fn names(&self) -> Result<Vec<String>, DatabaseError> {
+        let entries = match fs::read_dir(&self.config.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+            if let Some(name) = file_name.strip_suffix(".db").filter(|name| self.database_file(name).is_ok()) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn list(&self) -> Result<Vec<DatabaseInfo>, DatabaseError> {
+        self.names()?.iter().map(|name| self.info(name, None)).collect()
+    }
+
+    // Creates an empty database with the tables the CRUD routes expect, unless
+    // `max_count` already exist
+    pub fn create(&self, name: &str) -> Result<DatabaseInfo, DatabaseError> {
+        let path = self.database_file(name)?;
+        {
+            // Counting and claiming the file under the lock keeps concurrent
+            // creates from overshooting the limit or racing for one name
+            let _registry = self.registry.lock().unwrap();
+            if self.names()?.len() >= self.config.max_count {
+                return Err(DatabaseError::TooMany(self.config.max_count));
+            }
+            fs::create_dir_all(&self.config.dir)?;
+            if let Err(e) = OpenOptions::new().write(true).create_new(true).open(&path) {
+                return Err(match e.kind() {
+                    io::ErrorKind::AlreadyExists => DatabaseError::Exists(name.to_string()),
+                    _ => e.into(),
+                });
+            }
+        }
+        let initialized = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+            .and_then(|conn| create_table(&conn).and_then(|()| search::create_index(&conn)));
+        if let Err(e) = initialized {
+            let _ = fs::remove_file(&path);
+            return Err(e.into());
+        }
+        self.info(name, None)
+    }
+
+    pub fn inspect(&self, name: &str) -> Result<DatabaseInfo, DatabaseError> {
+        let conn = self.pool(name)?.pool.get()?;
+        let tables = schema::schema(&conn)?.tables.keys().cloned().collect();
+        self.info(name, Some(tables))
+    }
+
+    // Closes the database's pool and removes its files. Refused while any
+    // request holds a handle on it.
+    pub fn delete(&self, name: &str) -> Result<(), DatabaseError> {
+        let mut registry = self.registry.lock().unwrap();
+        let path = self.existing(name)?;
+        if registry.in_use(name) {
+            return Err(DatabaseError::InUse(name.to_string()));
+        }
+        registry.open.remove(name);
+        fs::remove_file(&path)?;
+        for suffix in ["-wal", "-shm", "-journal"] {
+            let mut sidecar = path.clone().into_os_string();
+            sidecar.push(suffix);
+            let _ = fs::remove_file(sidecar);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Databases in a fresh directory of their own, named after the test
+    fn databases_in(test: &str) -> Databases {
+        let dir = env::temp_dir().join(format!("tinysql-databases-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Databases::new(DatabasesConfig {
+            dir,
+            max_count: 100,
+            max_open: 8,
+            idle_timeout: Duration::from_secs(300),
+            pool_size: 4,
+        })
+    }
+
+    #[test]
+    fn handles_keep_evicted_databases_from_being_deleted() {
+        let databases = databases_in("held");
+        databases.create("held").unwrap();
+        let handle = databases.pool("held").unwrap();
+        // As if evicted for being idle
+        databases.registry.lock().unwrap().open.remove("held");
+        assert!(matches!(databases.delete("held"), Err(DatabaseError::InUse(_))));
+
+        drop(handle);
+        databases.delete("held").unwrap();
+        assert!(matches!(databases.pool("held"), Err(DatabaseError::NotFound(_))));
+        let _ = fs::remove_dir_all(&databases.config.dir);
+    }
+
+    #[test]
+    fn pools_never_recreate_removed_files() {
+        let databases = databases_in("removed");
+        let path = databases.config.dir.join("removed.db");
+        databases.create("removed").unwrap();
+        let handle = databases.pool("removed").unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(handle.pool.get_timeout(Duration::from_millis(100)).is_err());
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(&databases.config.dir);
+    }
+}
@@ -0,0 +1,311 @@
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use serde_json::json;
+use async_std::task;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::config::env_or;
+use crate::errors::ErrorBody;
+use crate::repository::{configure_connection, open_pool};
+use crate::retry;
+
+// How a server gets at SQLite, independent of its HTTP framework. Each
+// strategy runs a job against a connection; `with_connection` wraps that for
+// handlers that want a result back. Strategies block, so async frontends call
+// them from blocking tasks.
+pub type Job = Box<dyn FnOnce(&Connection) + Send>;
+
+pub trait DbStrategy: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self, job: Job) -> Result<(), StrategyError>;
+}
+
+pub const STRATEGIES: [&str; 4] = ["per_request", "mutex", "pool", "db_thread"];
+
+// The strategy called `name`, on the database at `path`
+pub fn open(name: &str, path: impl Into<String>) -> Result<Box<dyn DbStrategy>, StrategyError> {
+    open_with(name, path, |_| {})
+}
+
+// Like `open`, with `init` run on every connection the strategy opens
+pub fn open_with(name: &str, path: impl Into<String>, init: fn(&Connection)) -> Result<Box<dyn DbStrategy>, StrategyError> {
+    let path = path.into();
+    Ok(match name {
+        "per_request" => Box::new(PerRequest { path, init }),
+        "mutex" => Box::new(SharedMutex::open(&path, init)?),
+        "pool" => Box::new(Pooled::new(open_pool(&path, init)?)),
+        "db_thread" => Box::new(DbThread::spawn(&path, init, env_or("TINYSQL_DB_THREAD_QUEUE", 64))?),
+        _ => return Err(StrategyError::Unknown(name.to_string())),
+    })
+}
+
+#[derive(Debug)]
+pub enum StrategyError {
+    Unknown(String),
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+    // The database thread is gone
+    Closed,
+}
+
+impl StrategyError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            StrategyError::Sqlite(e) => retry::status_code(e),
+            _ => 500,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            StrategyError::Unknown(name) => {
+                format!("Unknown database strategy {:?}: use one of {}", name, STRATEGIES.join(", "))
+            }
+            StrategyError::Sqlite(e) if retry::is_busy(e) => "Database is busy, retry later".to_string(),
+            StrategyError::Sqlite(e) => format!("Database operation failed: {}", e),
+            StrategyError::Pool(e) => format!("Failed to get a connection: {}", e),
+            StrategyError::Closed => "The database thread has stopped".to_string(),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        matches!(self, StrategyError::Sqlite(e) if retry::is_busy(e))
+    }
+
+    pub fn body(&self) -> serde_json::Value {
+        json!(ErrorBody::new(self.message()))
+    }
+}
+
+impl From<rusqlite::Error> for StrategyError {
+    fn from(err: rusqlite::Error) -> Self {
+        StrategyError::Sqlite(err)
+    }
+}
+
+impl From<r2d2::Error> for StrategyError {
+    fn from(err: r2d2::Error) -> Self {
+        StrategyError::Pool(err)
+    }
+}
+
+// Runs `f` through `strategy` and hands back what it returns
+pub fn with_connection<T: Send + 'static>(
+    strategy: &dyn DbStrategy,
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, StrategyError> {
+    let (sender, receiver) = mpsc::channel();
+    strategy.run(Box::new(move |conn| {
+        let _ = sender.send(f(conn));
+    }))?;
+    // A job that panicked drops its sender unanswered
+    Ok(receiver.recv().map_err(|_| StrategyError::Closed)??)
+}
+
+// `with_connection` for async handlers, on a blocking task
+pub async fn spawn_with_connection<T: Send + 'static>(
+    strategy: Arc<dyn DbStrategy>,
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, StrategyError> {
+    task::spawn_blocking(move || with_connection(&*strategy, f)).await
+}
+
+fn connect(path: &str, init: fn(&Connection)) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    configure_connection(&conn);
+    init(&conn);
+    Ok(conn)
+}
+
+// A fresh connection for every job
+pub struct PerRequest {
+    path: String,
+    init: fn(&Connection),
+}
+
+impl DbStrategy for PerRequest {
+    fn name(&self) -> &'static str {
+        "per_request"
+    }
+
+    fn run(&self, job: Job) -> Result<(), StrategyError> {
+        job(&connect(&self.path, self.init)?);
+        Ok(())
+    }
+}
+
+// One connection, taken in turn
+pub struct SharedMutex {
+    conn: Mutex<Connection>,
+}
+
+impl SharedMutex {
+    fn open(path: &str, init: fn(&Connection)) -> rusqlite::Result<Self> {
+        Ok(SharedMutex { conn: Mutex::new(connect(path, init)?) })
+    }
+}
+
+impl DbStrategy for SharedMutex {
+    fn name(&self) -> &'static str {
+        "mutex"
+    }
+
+    fn run(&self, job: Job) -> Result<(), StrategyError> {
+        // A panicking job leaves the connection itself usable
+        let conn = self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        job(&conn);
+        Ok(())
+    }
+}
+
+// An r2d2 pool with its default size
+pub struct Pooled {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl Pooled {
+    // Shares a pool the server already has, such as a named database's
+    pub fn new(pool: Pool<SqliteConnectionManager>) -> Self {
+        Pooled { pool }
+    }
+}
+
+impl DbStrategy for Pooled {
+    fn name(&self) -> &'static str {
+        "pool"
+    }
+
+    fn run(&self, job: Job) -> Result<(), StrategyError> {
+        let conn = self.pool.get()?;
+        job(&conn);
+        Ok(())
+    }
+}
+
+// One thread owns the connection and runs jobs in arrival order. At most
+// `queue_len` jobs wait for it (TINYSQL_DB_THREAD_QUEUE); further callers
+// block until there is room, so a slow database holds requests back instead
+// of queueing them without bound.
+pub struct DbThread {
+    jobs: SyncSender<Job>,
+}
+
+impl DbThread {
+    fn spawn(path: &str, init: fn(&Connection), queue_len: usize) -> rusqlite::Result<Self> {
+        let conn = connect(path, init)?;
+        let (jobs, receiver) = mpsc::sync_channel::<Job>(queue_len);
+        thread::spawn(move || {
+            for job in receiver {
+                // A panicking job must not take the thread down with it
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| job(&conn)));
+            }
+        });
+        Ok(DbThread { jobs })
+    }
+}
+
+impl DbStrategy for DbThread {
+    fn name(&self) -> &'static str {
+        "db_thread"
+    }
+
+    fn run(&self, job: Job) -> Result<(), StrategyError> {
+        self.jobs.send(job).map_err(|_| StrategyError::Closed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    // A fresh database file, removed when dropped
+    struct TempDb(String);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("tinysql-strategy-{}-{}.db", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            TempDb(path.to_string_lossy().into_owned())
+        }
+
+        fn path(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn every_strategy_runs_jobs_on_its_database() {
+        for name in STRATEGIES {
+            let db = TempDb::new(name);
+            let strategy = open(name, db.path()).unwrap();
+            assert_eq!(strategy.name(), name);
+            with_connection(&*strategy, |conn| conn.execute("CREATE TABLE t (x)", [])).unwrap();
+            for x in 0..3 {
+                with_connection(&*strategy, move |conn| conn.execute("INSERT INTO t VALUES (?1)", [x])).unwrap();
+            }
+            let count: i64 = with_connection(&*strategy, |conn| conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))).unwrap();
+            assert_eq!(count, 3, "{}", name);
+        }
+        assert!(matches!(open("nope", TempDb::new("nope").path()), Err(StrategyError::Unknown(_))));
+    }
+
+    #[test]
+    fn init_runs_on_every_connection() {
+        fn init(conn: &Connection) {
+            conn.execute_batch("CREATE TEMP TABLE initialised (x)").unwrap();
+        }
+        for name in STRATEGIES {
+            let db = TempDb::new(&format!("init-{}", name));
+            let strategy = open_with(name, db.path(), init).unwrap();
+            let found = with_connection(&*strategy, |conn| conn.query_row("SELECT count(*) FROM initialised", [], |row| row.get::<_, i64>(0)));
+            assert!(found.is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn the_db_thread_outlives_panicking_jobs() {
+        let db = TempDb::new("panic");
+        let strategy = open("db_thread", db.path()).unwrap();
+        let panicked = with_connection(&*strategy, |_| -> rusqlite::Result<()> { panic!("job failed") });
+        assert!(matches!(panicked, Err(StrategyError::Closed)));
+        assert_eq!(with_connection(&*strategy, |conn| conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0))).unwrap(), 1);
+    }
+
+    #[test]
+    fn the_db_thread_queue_holds_callers_back_when_full() {
+        let db = TempDb::new("queue");
+        let strategy = Arc::new(DbThread::spawn(db.path(), |_| {}, 1).unwrap());
+        let (release, wait) = mpsc::channel::<()>();
+        // The first job holds the thread and the second fills the queue
+        strategy.run(Box::new(move |_| { let _ = wait.recv(); })).unwrap();
+        strategy.run(Box::new(|_| {})).unwrap();
+
+        let queued = Arc::new(AtomicBool::new(false));
+        let caller = {
+            let (strategy, queued) = (Arc::clone(&strategy), Arc::clone(&queued));
+            thread::spawn(move || {
+                strategy.run(Box::new(|_| {})).unwrap();
+                queued.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!queued.load(Ordering::SeqCst));
+
+        release.send(()).unwrap();
+        caller.join().unwrap();
+        assert!(queued.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,14 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// Every JSON error body, e.g. from the table and row routes
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+impl ErrorBody {
+    pub fn new(message: impl Into<String>) -> Self {
+        ErrorBody { error: message.into() }
+    }
+}
@@ -0,0 +1,400 @@
+use percent_encoding::percent_decode_str;
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, Deserializer, IntoDeserializer, Visitor};
+use serde::{forward_to_deserialize_any, Serialize};
+use serde_json::{Map, Value};
+
+// Wire formats for response and request bodies. Responses follow `Accept`,
+// request bodies follow `Content-Type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Ndjson,
+    Csv,
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    pub const ALL: [Format; 5] = [Format::Json, Format::Ndjson, Format::Csv, Format::MessagePack, Format::Cbor];
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Csv => "text/csv",
+            Format::MessagePack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.trim().to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => Some(Format::Ndjson),
+            "text/csv" | "text/*" => Some(Format::Csv),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::MessagePack),
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    // The preferred format in an `Accept` header, honouring q-values. A
+    // missing header means JSON; `None` means nothing acceptable (406).
+    pub fn from_accept(accept: Option<&str>) -> Option<Self> {
+        let Some(accept) = accept.filter(|a| !a.trim().is_empty()) else {
+            return Some(Format::Json);
+        };
+        let mut ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|range| {
+                let mut parts = range.split(';');
+                let media_type = parts.next().unwrap_or("").trim();
+                let q = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (media_type, q)
+            })
+            .filter(|(_, q)| *q > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.into_iter().find_map(|(media_type, _)| Format::from_media_type(media_type))
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Format::Ndjson => {
+                let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+                let rows = match value {
+                    Value::Array(rows) => rows,
+                    row => vec![row],
+                };
+                let mut body = Vec::new();
+                for row in rows {
+                    serde_json::to_writer(&mut body, &row).map_err(|e| e.to_string())?;
+                    body.push(b'\n');
+                }
+                Ok(body)
+            }
+            Format::Csv => encode_csv(serde_json::to_value(value).map_err(|e| e.to_string())?),
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Format::Cbor => {
+                let mut body = Vec::new();
+                ciborium::into_writer(value, &mut body).map_err(|e| e.to_string())?;
+                Ok(body)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            Format::Ndjson => {
+                let mut rows = Vec::new();
+                for line in body.split(|b| *b == b'\n').filter(|l| !l.iter().all(u8::is_ascii_whitespace)) {
+                    rows.push(serde_json::from_slice::<Value>(line).map_err(|e| e.to_string())?);
+                }
+                // A single row decodes as one record, several as a list
+                let value = if rows.len() == 1 { rows.remove(0) } else { Value::Array(rows) };
+                serde_json::from_value(value).map_err(|e| e.to_string())
+            }
+            Format::Csv => T::deserialize(decode_csv(body)?).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::from_reader(body).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// The format of a request body: any response format, or an HTML form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFormat {
+    Encoded(Format),
+    Form,
+}
+
+impl BodyFormat {
+    // Bodies without a type are read as JSON; `None` means an unsupported
+    // type (415), which includes plain text.
+    pub fn from_content_type(content_type: Option<&str>) -> Option<Self> {
+        let media_type = content_type.and_then(|ct| ct.split(';').next()).unwrap_or("").trim();
+        match media_type.to_ascii_lowercase().as_str() {
+            "" => Some(BodyFormat::Encoded(Format::Json)),
+            "application/x-www-form-urlencoded" => Some(BodyFormat::Form),
+            "application/*" | "*/*" | "text/*" | "text/plain" => None,
+            other => Format::from_media_type(other).map(BodyFormat::Encoded),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, String> {
+        match self {
+            BodyFormat::Encoded(format) => format.decode(body),
+            BodyFormat::Form => T::deserialize(decode_form(body)).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// One CSV row per array element (or one for a single value). Nested objects
+// become dotted columns, lists are written as JSON and null as an empty cell.
+fn encode_csv(value: Value) -> Result<Vec<u8>, String> {
+    let rows: Vec<Map<String, Value>> = match value {
+        Value::Array(rows) => rows.into_iter().map(flatten).collect(),
+        row => vec![flatten(row)],
+    };
+    let mut columns: Vec<String> = Vec::new();
+    for row in &rows {
+        for key in row.keys() {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&columns).map_err(|e| e.to_string())?;
+    for row in &rows {
+        let record = columns.iter().map(|column| match row.get(column) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(other) => other.to_string(),
+        });
+        writer.write_record(record).map_err(|e| e.to_string())?;
+    }
+    writer.into_inner().map_err(|e| e.to_string())
+}
+
+fn flatten(value: Value) -> Map<String, Value> {
+    fn walk(prefix: &str, value: Value, out: &mut Map<String, Value>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, field) in fields {
+                    let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                    walk(&key, field, out);
+                }
+            }
+            other => {
+                let key = if prefix.is_empty() { "value".to_string() } else { prefix.to_string() };
+                out.insert(key, other);
+            }
+        }
+    }
+    let mut out = Map::new();
+    walk("", value, &mut out);
+    out
+}
+
+// Each record becomes an object keyed by the header row
+fn decode_csv(body: &[u8]) -> Result<Rows, String> {
+    let mut reader = csv::Reader::from_reader(body);
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        let row = headers.iter().zip(record.iter()).map(|(header, cell)| (header.to_string(), Cell::new(cell)));
+        rows.push(Row(row.collect()));
+    }
+    Ok(Rows(rows))
+}
+
+// A form is a single row of `name=value` cells
+fn decode_form(body: &[u8]) -> Row {
+    let decode = |s: &str| percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned();
+    let body = String::from_utf8_lossy(body);
+    let fields = body
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (decode(key), Cell::new(&decode(value))));
+    Row(fields.collect())
+}
+
+// CSV and form cells are text, so each one is read as whatever type the
+// target asks for: "007" stays a string for a `String` field and becomes 7
+// for an integer one. Targets that don't say (`Value`) get the text, which
+// the generic row routes coerce against the column's affinity. Empty cells
+// are null, and lists or objects are written as JSON.
+struct Cell(Option<String>);
+
+impl Cell {
+    fn new(cell: &str) -> Self {
+        Cell(Some(cell.to_string()).filter(|cell| !cell.is_empty()))
+    }
+
+    fn parse<T: std::str::FromStr>(&self, expected: &str) -> Result<T, de::value::Error> {
+        let text = self.0.as_deref().unwrap_or("");
+        text.parse().map_err(|_| de::Error::invalid_value(de::Unexpected::Str(text), &expected))
+    }
+
+    fn json(self) -> Result<Value, de::value::Error> {
+        serde_json::from_str(self.0.as_deref().unwrap_or("null")).map_err(de::Error::custom)
+    }
+}
+
+macro_rules! parse_cell {
+    ($($method:ident => $visit:ident as $ty:ty),* $(,)?) => {
+        $(fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            visitor.$visit(self.parse::<$ty>(stringify!($ty))?)
+        })*
+    };
+}
+
+impl<'de> Deserializer<'de> for Cell {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Some(text) => visitor.visit_string(text),
+            None => visitor.visit_none(),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Some(_) => visitor.visit_some(self),
+            None => visitor.visit_none(),
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.json()?.deserialize_seq(visitor).map_err(de::Error::custom)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.json()?.deserialize_map(visitor).map_err(de::Error::custom)
+    }
+
+    parse_cell! {
+        deserialize_bool => visit_bool as bool,
+        deserialize_i8 => visit_i8 as i8,
+        deserialize_i16 => visit_i16 as i16,
+        deserialize_i32 => visit_i32 as i32,
+        deserialize_i64 => visit_i64 as i64,
+        deserialize_u8 => visit_u8 as u8,
+        deserialize_u16 => visit_u16 as u16,
+        deserialize_u32 => visit_u32 as u32,
+        deserialize_u64 => visit_u64 as u64,
+        deserialize_f32 => visit_f32 as f32,
+        deserialize_f64 => visit_f64 as f64,
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct newtype_struct
+        tuple tuple_struct struct enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, de::value::Error> for Cell {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+struct Row(Vec<(String, Cell)>);
+
+impl<'de> Deserializer<'de> for Row {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(MapDeserializer::new(self.0.into_iter()))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, de::value::Error> for Row {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+// A single row decodes as one record, several as a list
+struct Rows(Vec<Row>);
+
+impl<'de> Deserializer<'de> for Rows {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.0.len() == 1 {
+            self.0.remove(0).deserialize_any(visitor)
+        } else {
+            self.deserialize_seq(visitor)
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_seq(SeqDeserializer::new(self.0.into_iter()))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct tuple tuple_struct map
+        struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Record {
+        code: String,
+        flag: String,
+        count: i32,
+        ratio: Option<f64>,
+        active: bool,
+    }
+
+    #[test]
+    fn csv_cells_follow_the_target_types() {
+        let body = b"code,flag,count,ratio,active\n007,true,12,,false\n";
+        let record: Record = Format::Csv.decode(body).unwrap();
+        assert_eq!(
+            record,
+            Record { code: "007".into(), flag: "true".into(), count: 12, ratio: None, active: false }
+        );
+    }
+
+    #[test]
+    fn csv_rows_into_values_stay_text() {
+        let rows: Value = Format::Csv.decode(b"code,count\n007,1\n,2\n").unwrap();
+        assert_eq!(rows, json!([{ "code": "007", "count": "1" }, { "code": null, "count": "2" }]));
+    }
+
+    #[test]
+    fn csv_cells_that_do_not_parse_are_rejected() {
+        assert!(Format::Csv.decode::<Record>(b"code,flag,count,ratio,active\n1,x,many,,no\n").is_err());
+    }
+
+    #[test]
+    fn forms_decode_as_one_typed_row() {
+        let format = BodyFormat::from_content_type(Some("application/x-www-form-urlencoded; charset=utf-8")).unwrap();
+        let record: Record = format.decode(b"code=0%307&flag=a+b&count=3&ratio=0.5&active=true").unwrap();
+        assert_eq!(
+            record,
+            Record { code: "007".into(), flag: "a b".into(), count: 3, ratio: Some(0.5), active: true }
+        );
+    }
+
+    #[test]
+    fn body_types() {
+        assert_eq!(BodyFormat::from_content_type(None), Some(BodyFormat::Encoded(Format::Json)));
+        assert_eq!(BodyFormat::from_content_type(Some("text/csv")), Some(BodyFormat::Encoded(Format::Csv)));
+        assert_eq!(BodyFormat::from_content_type(Some("text/plain")), None);
+        assert_eq!(BodyFormat::from_content_type(Some("*/*")), None);
+    }
+}
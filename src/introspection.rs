@@ -0,0 +1,201 @@
+use rusqlite::{Connection, OptionalExtension};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// Read-only descriptions of the database, built on `sqlite_master` and the
+// PRAGMA table-valued functions. SQLite's own tables and the shadow tables
+// backing virtual tables are left out.
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Column {
+    pub name: String,
+    // The declared type, empty when untyped
+    #[serde(rename = "type")]
+    pub declared_type: String,
+    pub nullable: bool,
+    // The default as SQL text, e.g. `0` or `'n/a'`
+    pub default: Option<String>,
+    // Position in the primary key, 0 when not part of it
+    pub primary_key: u32,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Index {
+    pub name: String,
+    pub unique: bool,
+    // `c` for CREATE INDEX, `u` for a UNIQUE constraint, `pk` for the primary key
+    pub origin: String,
+    pub partial: bool,
+    // `None` entries are expressions
+    pub columns: Vec<Option<String>>,
+    // `None` for indexes SQLite creates for constraints
+    pub sql: Option<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ForeignKey {
+    pub columns: Vec<String>,
+    pub table: String,
+    // `None` entries refer to the parent's primary key
+    pub references: Vec<Option<String>>,
+    pub on_update: String,
+    pub on_delete: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Trigger {
+    pub name: String,
+    pub sql: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Table {
+    pub name: String,
+    pub sql: String,
+    pub columns: Vec<Column>,
+    pub indexes: Vec<Index>,
+    pub foreign_keys: Vec<ForeignKey>,
+    pub triggers: Vec<Trigger>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct View {
+    pub name: String,
+    pub sql: String,
+    pub columns: Vec<Column>,
+    pub triggers: Vec<Trigger>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Database {
+    pub tables: Vec<Table>,
+    pub views: Vec<View>,
+}
+
+// Names and CREATE SQL of the user objects of one type, optionally only the
+// one called `name`
+fn objects(conn: &Connection, kind: &str, name: Option<&str>) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        r"SELECT name, sql FROM sqlite_master
+          WHERE type = ?1 AND name NOT LIKE 'sqlite\_%' ESCAPE '\'
+            AND name NOT IN (SELECT name FROM pragma_table_list WHERE type = 'shadow')
+            AND (?2 IS NULL OR name = ?2 COLLATE NOCASE)
+          ORDER BY name",
+    )?;
+    let rows = stmt.query_map((kind, name), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+fn columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<Column>> {
+    let mut stmt = conn.prepare(r#"SELECT name, type, "notnull", dflt_value, pk FROM pragma_table_info(?1)"#)?;
+    let rows = stmt.query_map([table], |row| {
+        Ok(Column {
+            name: row.get(0)?,
+            declared_type: row.get(1)?,
+            nullable: !row.get::<_, bool>(2)?,
+            default: row.get(3)?,
+            primary_key: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn indexes(conn: &Connection, table: &str) -> rusqlite::Result<Vec<Index>> {
+    let mut list = conn.prepare(r#"SELECT name, "unique", origin, partial FROM pragma_index_list(?1) ORDER BY seq DESC"#)?;
+    let mut info = conn.prepare("SELECT name FROM pragma_index_info(?1) ORDER BY seqno")?;
+    let mut sql = conn.prepare("SELECT sql FROM sqlite_master WHERE type = 'index' AND name = ?1")?;
+
+    let listed = list
+        .query_map([table], |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut indexes = Vec::with_capacity(listed.len());
+    for (name, unique, origin, partial) in listed {
+        let columns = info.query_map([&name], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        let sql = sql.query_row([&name], |row| row.get(0)).optional()?.flatten();
+        indexes.push(Index { name, unique, origin, partial, columns, sql });
+    }
+    Ok(indexes)
+}
+
+// One entry per constraint; multi-column keys come as several rows sharing an id
+fn foreign_keys(conn: &Connection, table: &str) -> rusqlite::Result<Vec<ForeignKey>> {
+    let mut stmt = conn.prepare(
+        r#"SELECT id, "table", "from", "to", on_update, on_delete
+           FROM pragma_foreign_key_list(?1) ORDER BY id, seq"#,
+    )?;
+    let mut rows = stmt.query([table])?;
+    let mut keys: Vec<(i64, ForeignKey)> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        if keys.last().map(|(last, _)| *last) != Some(id) {
+            keys.push((
+                id,
+                ForeignKey {
+                    columns: Vec::new(),
+                    table: row.get(1)?,
+                    references: Vec::new(),
+                    on_update: row.get(4)?,
+                    on_delete: row.get(5)?,
+                },
+            ));
+        }
+        let (_, key) = keys.last_mut().unwrap();
+        key.columns.push(row.get(2)?);
+        key.references.push(row.get(3)?);
+    }
+    Ok(keys.into_iter().map(|(_, key)| key).collect())
+}
+
+fn triggers(conn: &Connection, table: &str) -> rusqlite::Result<Vec<Trigger>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, sql FROM sqlite_master WHERE type = 'trigger' AND tbl_name = ?1 COLLATE NOCASE ORDER BY name",
+    )?;
+    let rows = stmt.query_map([table], |row| Ok(Trigger { name: row.get(0)?, sql: row.get(1)? }))?;
+    rows.collect()
+}
+
+fn describe_table(conn: &Connection, name: String, sql: String) -> rusqlite::Result<Table> {
+    Ok(Table {
+        columns: columns(conn, &name)?,
+        indexes: indexes(conn, &name)?,
+        foreign_keys: foreign_keys(conn, &name)?,
+        triggers: triggers(conn, &name)?,
+        name,
+        sql,
+    })
+}
+
+fn describe_view(conn: &Connection, name: String, sql: String) -> rusqlite::Result<View> {
+    Ok(View {
+        columns: columns(conn, &name)?,
+        triggers: triggers(conn, &name)?,
+        name,
+        sql,
+    })
+}
+
+pub fn database(conn: &Connection) -> rusqlite::Result<Database> {
+    let tables = objects(conn, "table", None)?
+        .into_iter()
+        .map(|(name, sql)| describe_table(conn, name, sql))
+        .collect::<rusqlite::Result<_>>()?;
+    let views = objects(conn, "view", None)?
+        .into_iter()
+        .map(|(name, sql)| describe_view(conn, name, sql))
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(Database { tables, views })
+}
+
+pub fn table(conn: &Connection, name: &str) -> rusqlite::Result<Option<Table>> {
+    match objects(conn, "table", Some(name))?.pop() {
+        Some((name, sql)) => describe_table(conn, name, sql).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn view(conn: &Connection, name: &str) -> rusqlite::Result<Option<View>> {
+    match objects(conn, "view", Some(name))?.pop() {
+        Some((name, sql)) => describe_view(conn, name, sql).map(Some),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,136 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::config::env_or;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // Largest request body a handler will read before answering 413
+    pub max_body_bytes: u64,
+    // Longest a single socket read may stall, which bounds slow headers (tide)
+    pub read_timeout: Duration,
+    // Total time allowed to receive a request body
+    pub body_timeout: Duration,
+    // Longest a keep-alive connection may sit silent between requests (tide)
+    pub idle_timeout: Duration,
+    // Streamed CSV imports get their own, larger budget
+    pub max_import_bytes: u64,
+    pub import_timeout: Duration,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        Limits {
+            max_body_bytes: env_or("TINYSQL_MAX_BODY_BYTES", 1024 * 1024),
+            read_timeout: Duration::from_millis(env_or("TINYSQL_READ_TIMEOUT_MS", 5_000)),
+            body_timeout: Duration::from_millis(env_or("TINYSQL_BODY_TIMEOUT_MS", 10_000)),
+            idle_timeout: Duration::from_millis(env_or("TINYSQL_IDLE_TIMEOUT_MS", 60_000)),
+            max_import_bytes: env_or("TINYSQL_MAX_IMPORT_BYTES", 64 * 1024 * 1024),
+            import_timeout: Duration::from_millis(env_or("TINYSQL_IMPORT_TIMEOUT_MS", 300_000)),
+        }
+    }
+}
+
+pub fn limits() -> &'static Limits {
+    static LIMITS: OnceLock<Limits> = OnceLock::new();
+    LIMITS.get_or_init(Limits::from_env)
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge,
+    TimedOut,
+    Invalid,
+    UnsupportedMediaType,
+    Io(io::Error),
+}
+
+impl BodyError {
+    pub fn from_io(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => BodyError::TimedOut,
+            _ => BodyError::Io(err),
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self {
+            BodyError::TooLarge => 413,
+            BodyError::TimedOut => 408,
+            BodyError::Invalid => 400,
+            BodyError::UnsupportedMediaType => 415,
+            BodyError::Io(_) => 400,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            BodyError::TooLarge => "Request body too large",
+            BodyError::TimedOut => "Timed out reading request body",
+            BodyError::Invalid => "Invalid request body",
+            BodyError::UnsupportedMediaType => "Unsupported Content-Type",
+            BodyError::Io(_) => "Failed to read request body",
+        }
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::Io(e) => write!(f, "{}: {}", self.message(), e),
+            _ => f.write_str(self.message()),
+        }
+    }
+}
+
+// A body stream that fails once it passes `max_bytes` or its deadline, for
+// handlers that consume the body incrementally. After a failed read,
+// `take_error` tells which limit tripped.
+pub struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    deadline: Instant,
+    error: Option<BodyError>,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, max_bytes: u64, timeout: Duration) -> Self {
+        LimitedReader {
+            inner,
+            remaining: max_bytes,
+            deadline: Instant::now() + timeout,
+            error: None,
+        }
+    }
+
+    pub fn take_error(&mut self) -> Option<BodyError> {
+        self.error.take()
+    }
+
+    fn fail(&mut self, error: BodyError) -> io::Error {
+        let err = io::Error::other(error.message());
+        self.error = Some(error);
+        err
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if Instant::now() > self.deadline {
+            return Err(self.fail(BodyError::TimedOut));
+        }
+        // Read one byte past the limit so an exact fit still succeeds
+        let max = buf.len().min(usize::try_from(self.remaining.saturating_add(1)).unwrap_or(usize::MAX));
+        let read = match self.inner.read(&mut buf[..max]) {
+            Ok(read) => read,
+            Err(e) => return Err(self.fail(BodyError::from_io(e))),
+        };
+        if read as u64 > self.remaining {
+            return Err(self.fail(BodyError::TooLarge));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
@@ -5,8 +5,67 @@ mod tinyhttp_routes_crud;
 mod tide_routes_crud;
 mod tinyhttp_db_hosted;
 mod tinyhttp_rayon_db_pooled_r2d2;
+mod attributes;
+mod auth;
+mod backup;
+mod backup_schedule;
+mod changes;
+mod config;
+mod cors;
+mod databases;
+mod db_strategy;
+mod errors;
+mod format;
+mod introspection;
+mod limits;
+mod matrix;
+mod memory;
+mod metrics;
+mod openapi;
+mod query;
+mod rate_limit;
+mod repository;
+mod resources;
+mod retry;
+mod schema;
+mod search;
+mod sql_policy;
+mod stream_io;
+mod table_csv;
+mod tide_admin;
+mod tide_auth;
+mod tide_changes;
+mod tide_cors;
+mod tide_databases;
+mod tide_format;
+mod tide_introspection;
+mod tide_limits;
+mod tide_matrix;
+mod tide_openapi;
+mod tide_rate_limit;
+mod tide_resources;
+mod tide_search;
+mod tide_table_csv;
+mod tide_url;
+mod tide_websocket;
+mod tinyhttp_admin;
+mod tinyhttp_auth;
+mod tinyhttp_bounded_pool;
+mod tinyhttp_cors;
+mod tinyhttp_databases;
+mod tinyhttp_format;
+mod tinyhttp_introspection;
+mod tinyhttp_limits;
+mod tinyhttp_matrix;
+mod tinyhttp_openapi;
+mod tinyhttp_rate_limit;
+mod tinyhttp_resources;
+mod tinyhttp_search;
+mod tinyhttp_table_csv;
+mod tinyhttp_url;
+mod tls;
 
-use std::{io::{self, Write}};
+use std::{env, io::{self, Write}, process};
 
 use async_std::task;
 use tinyhttp_db_hosted::tiny_db_hosted;
@@ -17,7 +76,11 @@ use tinyhttp_routes_crud::tinyhttp_crud;
 use tide_routes_crud::tide_crud;
 use tinyhttp_rayon_db_pooled_r2d2::server_db_pooled;
 
-fn call_function(name: &str) {
+// The function name, then any arguments, e.g. `serve tide pool`
+fn call_function(input: &str) {
+    let mut words = input.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
     match name {
         "tiny_db_hosted" => tiny_db_hosted(),
         "tiny_pooled" => tiny_pooled(),
@@ -26,10 +89,29 @@ fn call_function(name: &str) {
         "tinyhttp_crud" => tinyhttp_crud(),
         "tide_crud" => task::block_on(tide_crud()).unwrap(),
         "server_db_pooled" => server_db_pooled(),
+        "serve" => matrix::serve(&args),
+        "issue_token" => auth::issue_token(),
         _ => println!("Function not found"),
     }
 }
 fn main() {
+    // Bad TLS settings stop the process before any server starts
+    if let Err(e) = tls::init() {
+        eprintln!("{}", e.message());
+        process::exit(1);
+    }
+    if let Err(e) = search::check_sqlite() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    // Arguments on the command line skip the prompt
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        call_function(&args.join(" "));
+        memory::snapshot_all();
+        return;
+    }
 
     let mut input = String::new();
 
@@ -47,6 +129,8 @@ fn main() {
        
     // Call the corresponding function
     call_function(input);
+    // A server that returns still leaves its in-memory database behind
+    memory::snapshot_all();
 
 }
 
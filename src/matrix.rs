@@ -0,0 +1,153 @@
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::config;
+use crate::db_strategy::{self, with_connection, DbStrategy, StrategyError, STRATEGIES};
+use crate::errors::ErrorBody;
+use crate::memory;
+use crate::openapi::{ApiSpec, Operation, RouteGroup, SpecCache};
+use crate::repository::{create_table, delete_person, insert_person, select_person, Person};
+use crate::tide_matrix;
+use crate::tinyhttp_matrix;
+
+// Every HTTP frontend crossed with every database strategy, serving the same
+// people routes through the same handlers so combinations benchmark fairly:
+//
+//   serve <frontend> <strategy>
+//
+//   POST   /people          insert, `{"name": ..., "age": ...}`
+//   GET    /people/<name>   select
+//   DELETE /people/<name>   delete
+const DB_FILE: &str = "matrix.db";
+
+// An HTTP framework and the way it schedules requests. Frontends decode
+// requests into `Action`s and encode the `Reply` from `handle`.
+pub trait Frontend {
+    fn name(&self) -> &'static str;
+    // Serves on `addr` until the process ends
+    fn serve(&self, addr: &str, strategy: Arc<dyn DbStrategy>);
+}
+
+pub fn frontends() -> Vec<Box<dyn Frontend>> {
+    vec![
+        Box::new(tide_matrix::Tide),
+        Box::new(tinyhttp_matrix::Serial),
+        Box::new(tinyhttp_matrix::Rayon),
+        Box::new(tinyhttp_matrix::AsyncTasks),
+    ]
+}
+
+pub enum Action {
+    Create(Person),
+    Read(String),
+    Delete(String),
+}
+
+// A framework-neutral response; `busy` asks for a Retry-After header
+pub struct Reply {
+    pub status: u16,
+    pub body: Option<Value>,
+    pub busy: bool,
+}
+
+impl Reply {
+    fn json(status: u16, body: impl serde::Serialize) -> Self {
+        Reply { status, body: Some(serde_json::json!(body)), busy: false }
+    }
+
+    fn not_found(name: &str) -> Self {
+        Reply::json(404, ErrorBody::new(format!("No person named {}", name)))
+    }
+
+    fn error(err: &StrategyError) -> Self {
+        eprintln!("Request failed: {}", err.message());
+        Reply { status: err.status_code(), body: Some(err.body()), busy: err.is_busy() }
+    }
+}
+
+// The handlers every combination shares. Blocks until the strategy is done.
+pub fn handle(strategy: &dyn DbStrategy, action: Action) -> Reply {
+    let result = match action {
+        Action::Create(person) => with_connection(strategy, move |conn| {
+            insert_person(conn, &person.name, person.age, person.attributes.as_ref())?;
+            Ok(person)
+        })
+        .map(|person| Reply::json(201, person)),
+        Action::Read(name) => {
+            let lookup = name.clone();
+            with_connection(strategy, move |conn| select_person(conn, &lookup)).map(|person| match person {
+                Some(person) => Reply::json(200, person),
+                None => Reply::not_found(&name),
+            })
+        }
+        Action::Delete(name) => {
+            let lookup = name.clone();
+            with_connection(strategy, move |conn| delete_person(conn, &lookup)).map(|deleted| match deleted {
+                0 => Reply::not_found(&name),
+                _ => Reply { status: 204, body: None, busy: false },
+            })
+        }
+    };
+    result.unwrap_or_else(|e| Reply::error(&e))
+}
+
+pub fn serve(args: &[&str]) {
+    let frontends = frontends();
+    let [frontend, strategy] = args else {
+        let names: Vec<&str> = frontends.iter().map(|f| f.name()).collect();
+        println!("Usage: serve <frontend> <strategy>");
+        println!("  frontends:  {}", names.join(", "));
+        println!("  strategies: {}", STRATEGIES.join(", "));
+        return;
+    };
+    let Some(frontend) = frontends.into_iter().find(|f| f.name() == *frontend) else {
+        println!("Unknown frontend {:?}; run `serve` alone to list them", frontend);
+        return;
+    };
+    let strategy: Arc<dyn DbStrategy> = match db_strategy::open(strategy, memory::database("serve", DB_FILE)) {
+        Ok(strategy) => Arc::from(strategy),
+        Err(e) => {
+            eprintln!("{}", e.message());
+            return;
+        }
+    };
+    if let Err(e) = with_connection(&*strategy, create_table) {
+        eprintln!("Failed to create table: {}", e.message());
+        return;
+    }
+
+    let addr = config::listen_addr("0.0.0.0:8000");
+    println!("Serving {} with {}", frontend.name(), strategy.name());
+    frontend.serve(&addr, strategy);
+}
+
+// Documents the routes every combination answers
+pub fn api_spec() -> Arc<Value> {
+    static SPEC: SpecCache = SpecCache::new();
+    SPEC.get(0, build_spec)
+}
+
+fn build_spec() -> Value {
+    let mut spec = ApiSpec::new("tinysql frontend × strategy matrix");
+    spec.add(
+        Operation::new("post", "/people", "Insert a person")
+            .body(spec.schema::<Person>())
+            .response(201, "The inserted person", spec.schema::<Person>())
+            .response(400, "The body is not a person", spec.schema::<ErrorBody>())
+            .response(503, "The database is busy", spec.schema::<ErrorBody>()),
+    );
+    spec.add(
+        Operation::new("get", "/people/{name}", "Look a person up by name")
+            .response(200, "The person", spec.schema::<Person>())
+            .response(404, "No such person", spec.schema::<ErrorBody>())
+            .response(503, "The database is busy", spec.schema::<ErrorBody>()),
+    );
+    spec.add(
+        Operation::new("delete", "/people/{name}", "Delete a person by name")
+            .response_empty(204, "Deleted")
+            .response(404, "No such person", spec.schema::<ErrorBody>())
+            .response(503, "The database is busy", spec.schema::<ErrorBody>()),
+    );
+    RouteGroup::Docs.document(&mut spec, None);
+    spec.document()
+}
@@ -0,0 +1,214 @@
+use rusqlite::{Connection, DatabaseName};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::env_or;
+
+// In-memory databases for tests and benchmarks, so runs skip fsync and leave
+// no files behind. Each variant selected by TINYSQL_MEMORY gets its own named
+// shared-cache database instead of its file; every connection opened on the
+// returned URI, pooled or not, sees the same data. One connection is held for
+// the life of the process so the database outlives idle pools.
+//
+// Shared-cache connections lock per table rather than per file, and a
+// conflicting lock fails at once with SQLITE_LOCKED instead of waiting, so
+// busy_timeout has no effect on them. Writers behind another connection's
+// open transaction rely on `retry`, which treats SQLITE_LOCKED as busy.
+#[derive(Debug, Clone)]
+pub struct MemoryConfig {
+    // Comma-separated TINYSQL_MEMORY: variant names, or `*` for all
+    pub variants: Vec<String>,
+    // TINYSQL_MEMORY_SEED: a database file loaded at startup
+    pub seed: Option<PathBuf>,
+    // TINYSQL_MEMORY_SNAPSHOT: where the database is written on SIGINT or
+    // SIGTERM, when the server returns and every `snapshot_interval`.
+    // `{variant}` in the path is replaced by the variant name.
+    pub snapshot: Option<PathBuf>,
+    // TINYSQL_MEMORY_SNAPSHOT_SECS, 0 for no periodic snapshots
+    pub snapshot_interval: Option<Duration>,
+}
+
+impl MemoryConfig {
+    pub fn from_env() -> Self {
+        let variants = env_or("TINYSQL_MEMORY", String::new());
+        let path = |name| Some(env_or(name, String::new())).filter(|p| !p.is_empty()).map(PathBuf::from);
+        MemoryConfig {
+            variants: variants.split(',').map(str::trim).filter(|v| !v.is_empty()).map(String::from).collect(),
+            seed: path("TINYSQL_MEMORY_SEED"),
+            snapshot: path("TINYSQL_MEMORY_SNAPSHOT"),
+            snapshot_interval: Some(Duration::from_secs(env_or("TINYSQL_MEMORY_SNAPSHOT_SECS", 0)))
+                .filter(|interval| !interval.is_zero()),
+        }
+    }
+
+    pub fn selects(&self, variant: &str) -> bool {
+        self.variants.iter().any(|v| v == "*" || v == variant)
+    }
+}
+
+pub fn config() -> &'static MemoryConfig {
+    static CONFIG: OnceLock<MemoryConfig> = OnceLock::new();
+    CONFIG.get_or_init(MemoryConfig::from_env)
+}
+
+struct MemoryDatabase {
+    uri: String,
+    keeper: Mutex<Connection>,
+}
+
+// By variant, leaked so their URIs can be handed out for the process's life
+fn databases() -> &'static Mutex<HashMap<String, &'static MemoryDatabase>> {
+    static DATABASES: OnceLock<Mutex<HashMap<String, &'static MemoryDatabase>>> = OnceLock::new();
+    DATABASES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Where `variant` keeps its database: `file`, or the URI of its in-memory
+// database when TINYSQL_MEMORY selects it. Either opens with
+// `Connection::open` and `SqliteConnectionManager::file`; callers passing
+// their own flags must include `SQLITE_OPEN_URI`.
+pub fn database(variant: &str, file: &'static str) -> &'static str {
+    database_with(config(), variant, file)
+}
+
+// Like `database`, under `config` rather than the environment's
+fn database_with(config: &MemoryConfig, variant: &str, file: &'static str) -> &'static str {
+    if !config.selects(variant) {
+        return file;
+    }
+    let mut databases = databases().lock().unwrap();
+    if let Some(database) = databases.get(variant) {
+        return &database.uri;
+    }
+
+    let uri = format!("file:tinysql-{}?mode=memory&cache=shared", variant);
+    let mut keeper = Connection::open(&uri).expect("Failed to open in-memory database");
+    if let Some(seed) = &config.seed {
+        match keeper.restore(DatabaseName::Main, seed, None::<fn(rusqlite::backup::Progress)>) {
+            Ok(()) => println!("Seeded in-memory database from {}", seed.display()),
+            Err(e) => eprintln!("Failed to seed in-memory database from {}: {}", seed.display(), e),
+        }
+    }
+    if databases.is_empty() && config.snapshot.is_some() {
+        snapshot_on_shutdown();
+        if let Some(interval) = config.snapshot_interval {
+            snapshot_every(interval);
+        }
+    }
+    println!("Using in-memory database {}", uri);
+    let database: &'static MemoryDatabase = Box::leak(Box::new(MemoryDatabase { uri, keeper: Mutex::new(keeper) }));
+    databases.insert(variant.to_string(), database);
+    &database.uri
+}
+
+// Writes every in-memory database to its snapshot path, if one is set. Runs
+// when a server returns, on SIGINT or SIGTERM, and periodically when
+// TINYSQL_MEMORY_SNAPSHOT_SECS is set.
+pub fn snapshot_all() {
+    let Some(path) = &config().snapshot else {
+        return;
+    };
+    let databases: Vec<(String, &'static MemoryDatabase)> =
+        databases().lock().unwrap().iter().map(|(variant, database)| (variant.clone(), *database)).collect();
+    let several = databases.len() > 1;
+    for (variant, database) in databases {
+        let path = snapshot_path(path, &variant, several);
+        match snapshot(database, &path) {
+            Ok(()) => println!("Wrote in-memory database to {}", path.display()),
+            Err(e) => eprintln!("Failed to write in-memory database to {}: {}", path.display(), e),
+        }
+    }
+}
+
+// `{variant}` in `path` names the variant. Without it, several databases
+// would overwrite one another, so each gets its variant appended instead.
+fn snapshot_path(path: &Path, variant: &str, several: bool) -> PathBuf {
+    let template = path.to_string_lossy();
+    if template.contains("{variant}") {
+        return PathBuf::from(template.replace("{variant}", variant));
+    }
+    if !several {
+        return path.to_path_buf();
+    }
+    let mut path = path.as_os_str().to_os_string();
+    path.push(format!(".{}", variant));
+    PathBuf::from(path)
+}
+
+// Snapshots when the process is asked to stop, then exits: the servers have
+// no graceful shutdown to return through
+fn snapshot_on_shutdown() {
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Snapshots on shutdown disabled: {}", e);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        let Some(signal) = signals.forever().next() else {
+            return;
+        };
+        snapshot_all();
+        process::exit(128 + signal);
+    });
+}
+
+// Bounds what a crash or SIGKILL can lose
+fn snapshot_every(interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        snapshot_all();
+    });
+}
+
+// The copy goes to a temporary file first so a crash midway never leaves a
+// torn snapshot
+fn snapshot(database: &MemoryDatabase, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".partial");
+    let _ = fs::remove_file(&partial);
+    database.keeper.lock().unwrap().backup(DatabaseName::Main, &partial, None)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_variant_gets_its_own_database() {
+        let config = MemoryConfig { variants: vec!["*".to_string()], seed: None, snapshot: None, snapshot_interval: None };
+        let first = database_with(&config, "memory_first", "first.db");
+        let second = database_with(&config, "memory_second", "second.db");
+        assert_ne!(first, second);
+        assert_eq!(database_with(&config, "memory_first", "first.db"), first);
+        let unselected = MemoryConfig { variants: vec!["memory_first".to_string()], ..config };
+        assert_eq!(database_with(&unselected, "memory_second_file", "second.db"), "second.db");
+
+        Connection::open(first).unwrap().execute_batch("CREATE TABLE only_first (x)").unwrap();
+        let tables = |uri| {
+            Connection::open(uri)
+                .unwrap()
+                .query_row("SELECT count(*) FROM sqlite_schema WHERE name = 'only_first'", [], |row| row.get::<_, i64>(0))
+                .unwrap()
+        };
+        assert_eq!((tables(first), tables(second)), (1, 0));
+    }
+
+    #[test]
+    fn snapshot_paths_name_the_variant_when_needed() {
+        let path = Path::new("snap.db");
+        assert_eq!(snapshot_path(path, "tide_crud", false), PathBuf::from("snap.db"));
+        assert_eq!(snapshot_path(path, "tide_crud", true), PathBuf::from("snap.db.tide_crud"));
+        let template = Path::new("snap-{variant}.db");
+        assert_eq!(snapshot_path(template, "tide_crud", false), PathBuf::from("snap-tide_crud.db"));
+    }
+}
@@ -0,0 +1,105 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::tinyhttp_auth;
+use crate::tinyhttp_cors;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Writes that hit SQLITE_BUSY/SQLITE_LOCKED and were attempted again
+pub static WRITE_RETRIES: Counter = Counter::new();
+// Writes that were still busy after the last retry
+pub static WRITE_RETRIES_EXHAUSTED: Counter = Counter::new();
+
+// Requests accepted by a bounded worker pool but not yet picked up
+pub static REQUESTS_QUEUED: Gauge = Gauge::new();
+// Requests currently being handled by a bounded worker pool
+pub static REQUESTS_RUNNING: Gauge = Gauge::new();
+// Requests rejected with 503 because the bounded worker pool was full
+pub static REQUESTS_SHED: Counter = Counter::new();
+// Requests rejected with 429 by the per-client rate limiter
+pub static REQUESTS_RATE_LIMITED: Counter = Counter::new();
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Snapshot {
+    pub write_retries: u64,
+    pub write_retries_exhausted: u64,
+    pub requests_queued: i64,
+    pub requests_running: i64,
+    pub requests_shed: u64,
+    pub requests_rate_limited: u64,
+}
+
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        write_retries: WRITE_RETRIES.get(),
+        write_retries_exhausted: WRITE_RETRIES_EXHAUSTED.get(),
+        requests_queued: REQUESTS_QUEUED.get(),
+        requests_running: REQUESTS_RUNNING.get(),
+        requests_shed: REQUESTS_SHED.get(),
+        requests_rate_limited: REQUESTS_RATE_LIMITED.get(),
+    }
+}
+
+pub fn is_metrics_request(request: &Request) -> bool {
+    request.method() == &Method::Get && request.url() == "/metrics"
+}
+
+pub fn tinyhttp_response() -> Response<Cursor<Vec<u8>>> {
+    Response::from_string(serde_json::to_string(&snapshot()).unwrap())
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+// Answers a metrics request on the spot and hands any other back. Accept
+// loops filter their requests through this so the counters stay visible
+// while the workers are overloaded.
+pub fn answer_tinyhttp(request: Request) -> Option<Request> {
+    if !is_metrics_request(&request) {
+        return Some(request);
+    }
+    let response = match tinyhttp_auth::authenticate(&request) {
+        Ok(_) => tinyhttp_response(),
+        Err(response) => response,
+    };
+    if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+    None
+}
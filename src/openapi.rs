@@ -0,0 +1,568 @@
+use rusqlite::Connection;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+
+use crate::attributes;
+use crate::backup::{BackupReport, BackupStatus, RestoreReport};
+use crate::changes::Change;
+use crate::databases::{DatabaseInfo, NewDatabase};
+use crate::errors::ErrorBody;
+use crate::format::Format;
+use crate::introspection::{Database, Table, View};
+use crate::resources;
+use crate::schema::{self, Affinity, RowKey, TableInfo};
+use crate::search::SearchResults;
+use crate::table_csv::ImportReport;
+
+// An OpenAPI 3 document built from the route list each server declares next
+// to its routes. Schemas come from the Rust types via `JsonSchema`, so they
+// follow the structs the handlers actually (de)serialize.
+pub struct ApiSpec {
+    title: String,
+    generator: RefCell<SchemaGenerator>,
+    components: Map<String, Value>,
+    paths: Map<String, Value>,
+}
+
+pub struct Operation {
+    method: &'static str,
+    path: String,
+    value: Map<String, Value>,
+    responses: Map<String, Value>,
+    has_body: bool,
+}
+
+// Responses are negotiated, so typed content is listed in every format
+fn content(schema: Value) -> Value {
+    let content: Map<String, Value> = Format::ALL
+        .iter()
+        .map(|f| (f.content_type().to_string(), json!({ "schema": schema })))
+        .collect();
+    Value::Object(content)
+}
+
+fn text_content() -> Value {
+    json!({ "text/plain": { "schema": { "type": "string" } } })
+}
+
+impl Operation {
+    // Path parameters written as `{name}` are declared as strings
+    pub fn new(method: &'static str, path: impl Into<String>, summary: &str) -> Self {
+        let path = path.into();
+        let parameters: Vec<Value> = path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')))
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+            .collect();
+        let mut value = Map::new();
+        value.insert("summary".to_string(), json!(summary));
+        if !parameters.is_empty() {
+            value.insert("parameters".to_string(), Value::Array(parameters));
+        }
+        Operation {
+            method,
+            path,
+            value,
+            responses: Map::new(),
+            has_body: false,
+        }
+    }
+
+    pub fn query(mut self, name: &str, description: &str, schema: Value) -> Self {
+        let parameter = json!({ "name": name, "in": "query", "description": description, "schema": schema });
+        match self.value.get_mut("parameters") {
+            Some(Value::Array(parameters)) => parameters.push(parameter),
+            _ => {
+                self.value.insert("parameters".to_string(), json!([parameter]));
+            }
+        }
+        self
+    }
+
+    // A body in any of the request formats, or as a form
+    pub fn body(self, schema: Value) -> Self {
+        let mut content = content(schema.clone());
+        content["application/x-www-form-urlencoded"] = json!({ "schema": schema });
+        self.body_content(content)
+    }
+
+    pub fn body_as(self, content_type: &str, schema: Value) -> Self {
+        self.body_content(json!({ content_type: { "schema": schema } }))
+    }
+
+    fn body_content(mut self, content: Value) -> Self {
+        self.value
+            .insert("requestBody".to_string(), json!({ "required": true, "content": content }));
+        self.has_body = true;
+        self
+    }
+
+    pub fn response(self, status: u16, description: &str, schema: Value) -> Self {
+        self.response_content(status, description, Some(content(schema)))
+    }
+
+    pub fn response_as(self, status: u16, description: &str, content_type: &str, schema: Value) -> Self {
+        self.response_content(status, description, Some(json!({ content_type: { "schema": schema } })))
+    }
+
+    pub fn response_text(self, status: u16, description: &str) -> Self {
+        self.response_content(status, description, Some(text_content()))
+    }
+
+    pub fn response_empty(self, status: u16, description: &str) -> Self {
+        self.response_content(status, description, None)
+    }
+
+    fn response_content(mut self, status: u16, description: &str, content: Option<Value>) -> Self {
+        let mut response = json!({ "description": description });
+        if let Some(content) = content {
+            response["content"] = content;
+        }
+        self.responses.insert(status.to_string(), response);
+        self
+    }
+}
+
+impl ApiSpec {
+    pub fn new(title: &str) -> Self {
+        ApiSpec {
+            title: title.to_string(),
+            generator: RefCell::new(SchemaGenerator::new(SchemaSettings::openapi3())),
+            components: Map::new(),
+            paths: Map::new(),
+        }
+    }
+
+    // A reference to `T`'s schema, registered under `components`
+    pub fn schema<T: JsonSchema>(&self) -> Value {
+        serde_json::to_value(self.generator.borrow_mut().subschema_for::<T>()).unwrap()
+    }
+
+    // A reference to a schema built at runtime, e.g. for a table's rows
+    pub fn component(&mut self, name: &str, schema: Value) -> Value {
+        self.components.insert(name.to_string(), schema);
+        json!({ "$ref": format!("#/components/schemas/{}", name) })
+    }
+
+    // Adds an operation along with the errors any route can answer with:
+    // authentication, rate limiting, negotiation and, for routes with a
+    // body, the body limits. Body errors come in the negotiated format.
+    pub fn add(&mut self, operation: Operation) {
+        let Operation { method, path, mut value, mut responses, has_body } = operation;
+        let common = [
+            (401, "Missing, invalid or expired credentials"),
+            (403, "The principal has read-only access"),
+            (406, "No acceptable response format"),
+            (429, "Rate limit exceeded; see Retry-After"),
+        ];
+        for (status, description) in common {
+            responses
+                .entry(status.to_string())
+                .or_insert_with(|| json!({ "description": description, "content": text_content() }));
+        }
+        if has_body {
+            let error = content(self.schema::<ErrorBody>());
+            for (status, description) in [
+                (400, "Unreadable request body"),
+                (408, "Request body timed out"),
+                (413, "Request body too large"),
+                (415, "Unsupported Content-Type"),
+            ] {
+                responses
+                    .entry(status.to_string())
+                    .or_insert_with(|| json!({ "description": description, "content": error }));
+            }
+        }
+        value.insert("responses".to_string(), Value::Object(responses));
+        let item = self.paths.entry(path).or_insert_with(|| json!({}));
+        item[method] = Value::Object(value);
+    }
+
+    pub fn document(self) -> Value {
+        let mut schemas: Map<String, Value> = self
+            .generator
+            .into_inner()
+            .take_definitions()
+            .into_iter()
+            .map(|(name, schema)| (name, serde_json::to_value(schema).unwrap()))
+            .collect();
+        schemas.extend(self.components);
+        json!({
+            "openapi": "3.0.3",
+            "info": { "title": self.title, "version": env!("CARGO_PKG_VERSION") },
+            "paths": self.paths,
+            "components": {
+                "schemas": schemas,
+                "securitySchemes": {
+                    "apiKey": { "type": "apiKey", "in": "header", "name": "X-API-Key" },
+                    "bearer": { "type": "http", "scheme": "bearer" },
+                },
+            },
+            // Credentials are only required when TINYSQL_API_KEYS or
+            // TINYSQL_TOKEN_SECRET is set
+            "security": [{ "apiKey": [] }, { "bearer": [] }, {}],
+        })
+    }
+}
+
+fn column_schema(affinity: Affinity, nullable: bool) -> Value {
+    let mut schema = match affinity {
+        Affinity::Integer => json!({ "type": "integer", "format": "int64" }),
+        Affinity::Real | Affinity::Numeric => json!({ "type": "number" }),
+        Affinity::Text => json!({ "type": "string" }),
+        Affinity::Blob => json!({ "description": "String, number or boolean; blobs are read back as base64" }),
+    };
+    if nullable {
+        schema["nullable"] = json!(true);
+    }
+    schema
+}
+
+// Row, new-row and update schemas for a table, following the same column
+// rules `resources` validates against
+fn row_schemas(spec: &mut ApiSpec, table: &TableInfo) -> (Value, Value, Value) {
+    let stem: String = table
+        .name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+
+    let mut row = Map::new();
+    if table.key == Some(RowKey::Rowid) {
+        row.insert("rowid".to_string(), column_schema(Affinity::Integer, false));
+    }
+    let mut input = Map::new();
+    let mut required = Vec::new();
+    for column in &table.columns {
+        let nullable = !column.not_null || table.is_rowid_alias(column);
+        row.insert(column.name.clone(), column_schema(column.affinity, !column.not_null));
+        input.insert(column.name.clone(), column_schema(column.affinity, nullable));
+        if column.name == attributes::COLUMN {
+            let attributes = "A JSON object, read back as JSON text";
+            row[&column.name]["description"] = json!(attributes);
+            input[&column.name] = json!({ "description": attributes, "nullable": nullable });
+        }
+        if column.not_null && column.default.is_none() && !table.is_rowid_alias(column) {
+            required.push(column.name.clone());
+        }
+    }
+
+    let mut new_row = json!({ "type": "object", "properties": input, "additionalProperties": false });
+    if !required.is_empty() {
+        new_row["required"] = json!(required);
+    }
+    let update = json!({ "type": "object", "properties": input, "additionalProperties": false, "minProperties": 1 });
+    (
+        spec.component(&format!("Row_{}", stem), json!({ "type": "object", "properties": row })),
+        spec.component(&format!("NewRow_{}", stem), new_row),
+        spec.component(&format!("RowUpdate_{}", stem), update),
+    )
+}
+
+// The CSV and row routes shared by the CRUD servers. Row routes are listed
+// per table with schemas from the current database schema.
+fn table_routes(spec: &mut ApiSpec, conn: &Connection) {
+    let schema = match schema::schema(conn) {
+        Ok(schema) => schema,
+        Err(e) => {
+            eprintln!("Failed to read schema for the API spec: {}", e);
+            return;
+        }
+    };
+    let error = spec.schema::<ErrorBody>();
+    let report = spec.schema::<ImportReport>();
+    let delimiter = json!({ "type": "string", "default": "," });
+
+    spec.add(
+        Operation::new("post", "/tables/{name}/import", "Import CSV rows into a table in one transaction")
+            .query("delimiter", "`,` by default, `tab` or any single ASCII character", delimiter.clone())
+            .body_as("text/csv", json!({ "type": "string" }))
+            .response(200, "Every row was imported", report.clone())
+            .response(400, "Invalid header row or delimiter", error.clone())
+            .response(404, "No such table", error.clone())
+            .response(422, "Some rows failed; nothing was imported", report)
+            .response(503, "Database is busy; see Retry-After", error.clone()),
+    );
+    spec.add(
+        Operation::new("get", "/tables/{name}/export.csv", "Stream a table out as CSV")
+            .query("delimiter", "`,` by default, `tab` or any single ASCII character", delimiter)
+            .response_as(200, "The table with a header row", "text/csv", json!({ "type": "string" }))
+            .response(400, "Invalid delimiter", error.clone())
+            .response(404, "No such table", error.clone()),
+    );
+
+    for table in schema.tables.values() {
+        let (row, new_row, update) = row_schemas(spec, table);
+        let rows = format!("/tables/{}/rows", table.name);
+        let page = |name: &str| json!({ "type": "integer", "minimum": 0, "description": name });
+        let mut list = Operation::new("get", rows.clone(), &format!("List rows of {}", table.name))
+            .query("limit", "Page size", page(&format!("At most {}", resources::MAX_PAGE_SIZE)))
+            .query("offset", "Rows to skip", page("Defaults to 0"));
+        if table.column(attributes::COLUMN).is_some() {
+            list = list.query(
+                "attr.{path}",
+                "Only rows whose attribute at the dotted path, e.g. `attr.team` or `attr.address.city`, equals \
+                 this value; repeat for several",
+                json!({ "type": "string" }),
+            );
+        }
+        spec.add(
+            list.response(200, "Rows ordered by key", json!({ "type": "array", "items": row }))
+                .response(400, "Invalid limit, offset or attribute path", error.clone()),
+        );
+        spec.add(
+            Operation::new("post", rows.clone(), &format!("Create a row in {}", table.name))
+                .body(new_row)
+                .response(201, "The row as stored", row.clone())
+                .response(400, "Unknown column, wrong type or missing value", error.clone())
+                .response(409, "A constraint rejected the row", error.clone()),
+        );
+        if table.key.is_none() {
+            continue;
+        }
+        let one = format!("{}/{{key}}", rows);
+        spec.add(
+            Operation::new("get", one.clone(), &format!("Read a row of {}", table.name))
+                .response(200, "The row", row.clone())
+                .response(404, "No such row", error.clone()),
+        );
+        spec.add(
+            Operation::new("put", one.clone(), &format!("Update columns of a row of {}", table.name))
+                .body(update)
+                .response(200, "The updated row", row)
+                .response(400, "Unknown column, wrong type or no columns", error.clone())
+                .response(404, "No such row", error.clone())
+                .response(409, "A constraint rejected the update", error.clone()),
+        );
+        spec.add(
+            Operation::new("delete", one, &format!("Delete a row of {}", table.name))
+                .response_empty(204, "Deleted")
+                .response(404, "No such row", error.clone()),
+        );
+    }
+}
+
+// Full-text search over people, shared by the CRUD servers
+fn search_routes(spec: &mut ApiSpec) {
+    let error = spec.schema::<ErrorBody>();
+    let page = |name: &str| json!({ "type": "integer", "minimum": 0, "description": name });
+    spec.add(
+        Operation::new("get", "/people/search", "Search people by name, best matches first")
+            .query(
+                "q",
+                "FTS5 query: terms must all match, `ali*` matches a prefix, `\"ada lovelace\"` a phrase; \
+                 OR, NOT and parentheses combine them",
+                json!({ "type": "string" }),
+            )
+            .query("limit", "Page size", page(&format!("At most {}", resources::MAX_PAGE_SIZE)))
+            .query("offset", "Matches to skip", page("Defaults to 0"))
+            .response(200, "Matches ranked by bm25 with HTML-escaped, `<mark>`ed snippets", spec.schema::<SearchResults>())
+            .response(400, "Missing or invalid query, limit or offset", error.clone())
+            .response(503, "Database is busy; see Retry-After", error),
+    );
+}
+
+// The read-only schema routes shared by the CRUD servers
+fn schema_routes(spec: &mut ApiSpec) {
+    let error = spec.schema::<ErrorBody>();
+    spec.add(
+        Operation::new("get", "/schema", "Every table and view with columns, indexes, foreign keys and triggers")
+            .response(200, "The database schema", spec.schema::<Database>()),
+    );
+    spec.add(
+        Operation::new("get", "/schema/tables/{name}", "One table")
+            .response(200, "The table", spec.schema::<Table>())
+            .response(404, "No such table", error.clone()),
+    );
+    spec.add(
+        Operation::new("get", "/schema/views/{name}", "One view")
+            .response(200, "The view", spec.schema::<View>())
+            .response(404, "No such view", error),
+    );
+}
+
+// The change feed and WebSocket endpoint of the tide CRUD server
+fn realtime_routes(spec: &mut ApiSpec) {
+    spec.add(
+        Operation::new("get", "/changes", "Stream committed inserts, updates and deletes as Server-Sent Events")
+            .query("tables", "Comma-separated tables to follow; all watched tables by default", json!({ "type": "string" }))
+            .query(
+                "last_event_id",
+                "Resume after this event id, as the Last-Event-ID header does",
+                json!({ "type": "string" }),
+            )
+            .response_as(
+                200,
+                "`message` events carrying a change, `reset` when changes may have been missed, and `ping`",
+                "text/event-stream",
+                spec.schema::<Change>(),
+            ),
+    );
+    spec.add(
+        Operation::new("get", "/ws", "Run queries and follow changes over one WebSocket")
+            .response_empty(
+                101,
+                "Switched to a WebSocket exchanging JSON text frames: `query` (`id`, `sql`, `params` as an array \
+                 or object), `subscribe` (`id`, `tables`, `last_event_id`) and `unsubscribe` (`id`, \
+                 `subscription`). Replies carry the request `id`.",
+            )
+            .response_text(426, "Not a WebSocket upgrade request"),
+    );
+}
+
+// The admin routes shared by the CRUD servers
+fn admin_routes(spec: &mut ApiSpec) {
+    let error = spec.schema::<ErrorBody>();
+    let not_admin = "The caller is not an admin principal";
+    spec.add(
+        Operation::new("get", "/admin/backup", "Progress of a running backup and the last outcome")
+            .response(200, "Backup status", spec.schema::<BackupStatus>())
+            .response_text(403, not_admin),
+    );
+    spec.add(
+        Operation::new("post", "/admin/backup", "Take an online backup")
+            .query(
+                "path",
+                "File name to keep the backup under in TINYSQL_BACKUP_DIR; without it the snapshot is streamed",
+                json!({ "type": "string" }),
+            )
+            .response(200, "The backup was kept; or, when streamed, the database file", spec.schema::<BackupReport>())
+            .response(400, "Invalid file name", error.clone())
+            .response_text(403, not_admin)
+            .response(409, "A backup is running or the file exists", error.clone())
+            .response(500, "The backup failed", error.clone())
+            .response(503, "Writes kept restarting the backup; see Retry-After", error.clone()),
+    );
+    spec.add(
+        Operation::new("post", "/admin/restore", "Replace the database with a validated snapshot")
+            .query(
+                "path",
+                "File name of a backup in TINYSQL_BACKUP_DIR, `.gz` ones included; without it the body is the snapshot",
+                json!({ "type": "string" }),
+            )
+            .query("schema_version", "Reject snapshots whose `PRAGMA user_version` differs", json!({ "type": "integer" }))
+            .body_as("application/vnd.sqlite3", json!({ "type": "string", "format": "binary" }))
+            .response(200, "The database was swapped", spec.schema::<RestoreReport>())
+            .response(400, "Invalid file name or query", error.clone())
+            .response_text(403, not_admin)
+            .response(404, "No such backup", error.clone())
+            .response(422, "The snapshot failed validation", error.clone())
+            .response(503, "In-flight work did not drain; see Retry-After", error),
+    );
+}
+
+// The named database routes shared by the CRUD servers
+fn database_routes(spec: &mut ApiSpec) {
+    let error = spec.schema::<ErrorBody>();
+    let info = spec.schema::<DatabaseInfo>();
+    let not_admin = "The caller is not an admin principal";
+    spec.add(
+        Operation::new(
+            "get",
+            "/databases",
+            "Named databases in TINYSQL_DATA_DIR; every data route is also served under `/db/{name}/`",
+        )
+        .response(200, "Databases by name", json!({ "type": "array", "items": info.clone() }))
+        .response_text(403, not_admin),
+    );
+    spec.add(
+        Operation::new("post", "/databases", "Create an empty named database with the `person` table")
+            .body(spec.schema::<NewDatabase>())
+            .response(201, "The new database", info.clone())
+            .response(400, "Unreadable body", error.clone())
+            .response_text(403, not_admin)
+            .response(409, "A database of that name exists, or TINYSQL_DB_MAX_COUNT do", error.clone()),
+    );
+    spec.add(
+        Operation::new("get", "/databases/{name}", "One database with its tables")
+            .response(200, "The database", info)
+            .response(400, "Invalid name", error.clone())
+            .response_text(403, not_admin)
+            .response(404, "No such database", error.clone()),
+    );
+    spec.add(
+        Operation::new("delete", "/databases/{name}", "Close a database and remove its files")
+            .response_empty(204, "Deleted")
+            .response_text(403, not_admin)
+            .response(404, "No such database", error.clone())
+            .response(409, "Requests are in flight on it", error),
+    );
+}
+
+// The routes serving the document itself
+fn docs_routes(spec: &mut ApiSpec) {
+    spec.add(
+        Operation::new("get", "/openapi.json", "This document")
+            .response_as(200, "OpenAPI 3 document", "application/json", json!({ "type": "object" })),
+    );
+    spec.add(
+        Operation::new("get", "/docs", "Browsable docs for this document")
+            .response_as(200, "Self-contained HTML page", "text/html", json!({ "type": "string" })),
+    );
+}
+
+// The route groups the CRUD servers share. Each server lists the groups it
+// serves once and builds both its router and its document from that list,
+// so the document can't drift from what is served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteGroup {
+    Tables,
+    Search,
+    Schema,
+    Realtime,
+    Admin,
+    Databases,
+    Docs,
+}
+
+impl RouteGroup {
+    // Adds the group's operations; table routes need `conn` for its schema
+    // and are left out without one
+    pub fn document(self, spec: &mut ApiSpec, conn: Option<&Connection>) {
+        match self {
+            RouteGroup::Tables => {
+                if let Some(conn) = conn {
+                    table_routes(spec, conn);
+                }
+            }
+            RouteGroup::Search => search_routes(spec),
+            RouteGroup::Schema => schema_routes(spec),
+            RouteGroup::Realtime => realtime_routes(spec),
+            RouteGroup::Admin => admin_routes(spec),
+            RouteGroup::Databases => database_routes(spec),
+            RouteGroup::Docs => docs_routes(spec),
+        }
+    }
+}
+
+// The last document built, kept until the version it was built for changes:
+// `schema::version` for servers documenting a route per table, 0 otherwise
+pub struct SpecCache(Mutex<Option<(i64, Arc<Value>)>>);
+
+impl SpecCache {
+    pub const fn new() -> Self {
+        SpecCache(Mutex::new(None))
+    }
+
+    pub fn get(&self, version: i64, build: impl FnOnce() -> Value) -> Arc<Value> {
+        let mut cached = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match cached.as_ref() {
+            Some((built_for, document)) if *built_for == version => document.clone(),
+            _ => {
+                let document = Arc::new(build());
+                *cached = Some((version, document.clone()));
+                document
+            }
+        }
+    }
+}
+
+// A self-contained page with the document inlined, so it works offline and
+// with the same credentials as `/openapi.json`
+pub fn docs_html(document: &Value) -> String {
+    let spec = document.to_string().replace("</", "<\\/");
+    include_str!("openapi_docs.html").replace("/*SPEC*/null", &spec)
+}
@@ -0,0 +1,184 @@
+use rusqlite::types::Value as SqlValue;
+use rusqlite::Connection;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+
+use crate::auth::{Access, Principal};
+use crate::config::env_or;
+use crate::errors::ErrorBody;
+use crate::resources;
+use crate::sql_policy::{self, PolicyError};
+
+// One parameterized statement run on behalf of a client, under its SQL
+// policy. Read-only principals may only run statements that don't write.
+
+// Rows one statement may return, from TINYSQL_QUERY_MAX_ROWS
+pub fn max_rows() -> usize {
+    static MAX_ROWS: OnceLock<usize> = OnceLock::new();
+    *MAX_ROWS.get_or_init(|| env_or("TINYSQL_QUERY_MAX_ROWS", 10_000))
+}
+
+// `?1`-style parameters come as an array, `:name`-style ones as an object
+#[derive(Debug, Default, Deserialize)]
+#[serde(untagged)]
+pub enum Params {
+    #[default]
+    None,
+    Positional(Vec<Value>),
+    Named(serde_json::Map<String, Value>),
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    Invalid(String),
+    ReadOnly,
+    Denied { action: String, table: Option<String> },
+    // The statement returned more than `max_rows`
+    TooManyRows(usize),
+    Sqlite(rusqlite::Error),
+}
+
+impl QueryError {
+    pub fn body(&self) -> Value {
+        let message = match self {
+            QueryError::Denied { action, table } => {
+                return json!({ "error": "Operation not permitted", "action": action, "table": table });
+            }
+            QueryError::Invalid(message) => message.clone(),
+            QueryError::ReadOnly => "Read-only access cannot run statements that write".to_string(),
+            QueryError::TooManyRows(max) => format!("Statement returned more than {} rows; add a LIMIT", max),
+            QueryError::Sqlite(e) => e.to_string(),
+        };
+        json!(ErrorBody::new(message))
+    }
+}
+
+impl From<rusqlite::Error> for QueryError {
+    fn from(err: rusqlite::Error) -> Self {
+        QueryError::Sqlite(err)
+    }
+}
+
+fn to_sql(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => SqlValue::Integer(n),
+            None => SqlValue::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => SqlValue::Text(s.clone()),
+        // Arrays and objects are stored as JSON text
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+// Runs `sql` and returns `{ "rows": [...] }` for statements that return
+// rows, at most `max_rows` of them, or `{ "changes": n, "last_insert_rowid":
+// id or null }` for the rest
+pub fn execute(
+    conn: &Connection,
+    principal: Option<&Principal>,
+    sql: &str,
+    params: &Params,
+    max_rows: usize,
+) -> Result<Value, QueryError> {
+    let policy = sql_policy::policies().for_principal(principal);
+    let read_only = principal.is_some_and(|p| p.access == Access::ReadOnly);
+    let result = sql_policy::run(conn, policy, |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+        if read_only && !stmt.readonly() {
+            return Ok(Err(QueryError::ReadOnly));
+        }
+
+        match params {
+            Params::None => {}
+            Params::Positional(values) => {
+                if values.len() != stmt.parameter_count() {
+                    return Ok(Err(QueryError::Invalid(format!(
+                        "Expected {} parameters, got {}",
+                        stmt.parameter_count(),
+                        values.len()
+                    ))));
+                }
+                for (i, value) in values.iter().enumerate() {
+                    stmt.raw_bind_parameter(i + 1, to_sql(value))?;
+                }
+            }
+            Params::Named(values) => {
+                for (name, value) in values {
+                    let Some(index) = [":", "@", "$"].iter().find_map(|prefix| {
+                        stmt.parameter_index(&format!("{}{}", prefix, name)).ok().flatten()
+                    }) else {
+                        return Ok(Err(QueryError::Invalid(format!("Unknown parameter {:?}", name))));
+                    };
+                    stmt.raw_bind_parameter(index, to_sql(value))?;
+                }
+            }
+        }
+
+        if stmt.column_count() > 0 {
+            let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+            let mut rows = stmt.raw_query();
+            let mut out = Vec::new();
+            while let Some(row) = rows.next()? {
+                if out.len() == max_rows {
+                    return Ok(Err(QueryError::TooManyRows(max_rows)));
+                }
+                out.push(resources::row_json(row, &names)?);
+            }
+            Ok(Ok(json!({ "rows": out })))
+        } else {
+            // Pooled connections remember the last insert of whoever used
+            // them before, so only report one this statement made
+            let before = conn.last_insert_rowid();
+            let changes = stmt.raw_execute()?;
+            let rowid = Some(conn.last_insert_rowid()).filter(|rowid| *rowid != before);
+            Ok(Ok(json!({ "changes": changes, "last_insert_rowid": rowid })))
+        }
+    });
+
+    match result {
+        Ok(outcome) => outcome,
+        Err(PolicyError::Denied(denial)) => Err(QueryError::Denied { action: denial.action, table: denial.table }),
+        Err(PolicyError::Sqlite(e)) => Err(QueryError::Sqlite(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn people() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE person (name TEXT); INSERT INTO person VALUES ('a'), ('b'), ('c');").unwrap();
+        conn
+    }
+
+    #[test]
+    fn results_past_the_row_cap_are_refused() {
+        let conn = people();
+        let all = execute(&conn, None, "SELECT name FROM person", &Params::None, 3).unwrap();
+        assert_eq!(all["rows"].as_array().map(Vec::len), Some(3));
+
+        let err = execute(&conn, None, "SELECT name FROM person", &Params::None, 2).unwrap_err();
+        assert!(matches!(err, QueryError::TooManyRows(2)));
+        assert_eq!(err.body(), json!({ "error": "Statement returned more than 2 rows; add a LIMIT" }));
+    }
+
+    #[test]
+    fn parameters_bind_by_position_or_name() {
+        let conn = people();
+        let positional = Params::Positional(vec![json!("b")]);
+        let rows = execute(&conn, None, "SELECT name FROM person WHERE name = ?1", &positional, 10).unwrap();
+        assert_eq!(rows, json!({ "rows": [{ "name": "b" }] }));
+
+        let named = Params::Named(serde_json::Map::from_iter([("name".to_string(), json!("c"))]));
+        let rows = execute(&conn, None, "SELECT name FROM person WHERE name = :name", &named, 10).unwrap();
+        assert_eq!(rows, json!({ "rows": [{ "name": "c" }] }));
+
+        let err = execute(&conn, None, "SELECT ?1, ?2", &positional, 10).unwrap_err();
+        assert_eq!(err.body(), json!({ "error": "Expected 2 parameters, got 1" }));
+    }
+}
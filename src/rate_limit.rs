@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use crate::auth::Principal;
+use crate::metrics;
+
+// Stale buckets are pruned once the table grows past this many keys
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Read,
+    Write,
+    Sql,
+}
+
+impl RouteClass {
+    pub fn for_method(is_write: bool) -> Self {
+        if is_write {
+            RouteClass::Write
+        } else {
+            RouteClass::Read
+        }
+    }
+}
+
+// `<requests>/<seconds>`, e.g. `120/60` allows bursts of 120 and refills
+// two tokens per second
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    capacity: f64,
+    per_sec: f64,
+}
+
+impl Rate {
+    fn parse(value: &str) -> Option<Self> {
+        let (requests, seconds) = value.split_once('/')?;
+        let capacity: f64 = requests.trim().parse().ok()?;
+        let seconds: f64 = seconds.trim().parse().ok()?;
+        if capacity < 1.0 || seconds <= 0.0 {
+            return None;
+        }
+        Some(Rate { capacity, per_sec: capacity / seconds })
+    }
+
+    fn from_env(name: &str) -> Option<Self> {
+        let value = env::var(name).ok()?;
+        let rate = Rate::parse(&value);
+        if rate.is_none() {
+            eprintln!("Ignoring invalid rate limit for {}: {}", name, value);
+        }
+        rate
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Outcome of a rate limit check, carried into the X-RateLimit-* headers
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    // Seconds until the bucket is full again
+    pub reset_secs: u64,
+    // Seconds until the next request would be allowed
+    pub retry_after_secs: u64,
+}
+
+impl Decision {
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("X-RateLimit-Limit", self.limit.to_string()),
+            ("X-RateLimit-Remaining", self.remaining.to_string()),
+            ("X-RateLimit-Reset", self.reset_secs.to_string()),
+        ];
+        if !self.allowed {
+            headers.push(("Retry-After", self.retry_after_secs.to_string()));
+        }
+        headers
+    }
+}
+
+// Token buckets per route class and client. Limits come from
+// TINYSQL_RATE_READ, TINYSQL_RATE_WRITE and TINYSQL_RATE_SQL; a class without
+// a limit is not tracked at all.
+pub struct RateLimiter {
+    rates: HashMap<RouteClass, Rate>,
+    buckets: Mutex<HashMap<(RouteClass, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> Self {
+        let mut rates = HashMap::new();
+        for (class, name) in [
+            (RouteClass::Read, "TINYSQL_RATE_READ"),
+            (RouteClass::Write, "TINYSQL_RATE_WRITE"),
+            (RouteClass::Sql, "TINYSQL_RATE_SQL"),
+        ] {
+            if let Some(rate) = Rate::from_env(name) {
+                rates.insert(class, rate);
+            }
+        }
+        RateLimiter {
+            rates,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, class: RouteClass, key: String) -> Option<Decision> {
+        let rate = *self.rates.get(&class)?;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|(class, _), bucket| match self.rates.get(class) {
+                Some(rate) => bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate.per_sec < rate.capacity,
+                None => false,
+            });
+        }
+
+        let bucket = buckets.entry((class, key)).or_insert(Bucket {
+            tokens: rate.capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate.per_sec).min(rate.capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        } else {
+            metrics::REQUESTS_RATE_LIMITED.inc();
+        }
+        Some(Decision {
+            allowed,
+            limit: rate.capacity as u64,
+            remaining: bucket.tokens.floor() as u64,
+            reset_secs: ((rate.capacity - bucket.tokens) / rate.per_sec).ceil() as u64,
+            retry_after_secs: ((1.0 - bucket.tokens).max(0.0) / rate.per_sec).ceil().max(1.0) as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+impl RateLimiter {
+    // Limits just `class`, to `rate`
+    pub fn with_rate(class: RouteClass, rate: &str) -> Self {
+        RateLimiter {
+            rates: HashMap::from([(class, Rate::parse(rate).unwrap())]),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+pub fn limiter() -> &'static RateLimiter {
+    static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+    LIMITER.get_or_init(RateLimiter::from_env)
+}
+
+// Authenticated callers are limited per principal, everyone else per IP
+pub fn client_key(principal: Option<&Principal>, ip: Option<IpAddr>) -> String {
+    match (principal, ip) {
+        (Some(principal), _) => format!("principal:{}", principal.name),
+        (None, Some(ip)) => format!("ip:{}", ip),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn limiter(class: RouteClass, rate: &str) -> RateLimiter {
+        RateLimiter::with_rate(class, rate)
+    }
+
+    #[test]
+    fn rates_parse_requests_per_seconds() {
+        let rate = Rate::parse("120/60").unwrap();
+        assert_eq!((rate.capacity, rate.per_sec), (120.0, 2.0));
+        for invalid in ["", "120", "0/60", "10/0", "x/1", "1/-1"] {
+            assert!(Rate::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn bursts_up_to_capacity_then_refuses() {
+        let limiter = limiter(RouteClass::Read, "3/60");
+        let remaining: Vec<u64> = (0..3).map(|_| limiter.check(RouteClass::Read, "a".into()).unwrap().remaining).collect();
+        assert_eq!(remaining, [2, 1, 0]);
+
+        let refused = limiter.check(RouteClass::Read, "a".into()).unwrap();
+        assert!(!refused.allowed);
+        assert_eq!(refused.limit, 3);
+        assert_eq!(refused.retry_after_secs, 20);
+        assert!(refused.headers().iter().any(|(name, value)| *name == "Retry-After" && value == "20"));
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = limiter(RouteClass::Write, "1/0.05");
+        assert!(limiter.check(RouteClass::Write, "a".into()).unwrap().allowed);
+        assert!(!limiter.check(RouteClass::Write, "a".into()).unwrap().allowed);
+        thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check(RouteClass::Write, "a".into()).unwrap().allowed);
+    }
+
+    #[test]
+    fn clients_and_classes_have_their_own_buckets() {
+        let limiter = limiter(RouteClass::Read, "1/60");
+        assert!(limiter.check(RouteClass::Read, "a".into()).unwrap().allowed);
+        assert!(limiter.check(RouteClass::Read, "b".into()).unwrap().allowed);
+        // Classes without a limit are not tracked
+        assert!(limiter.check(RouteClass::Write, "a".into()).is_none());
+    }
+
+    #[test]
+    fn allowed_decisions_carry_no_retry_after() {
+        let decision = limiter(RouteClass::Read, "5/5").check(RouteClass::Read, "a".into()).unwrap();
+        let names: Vec<&str> = decision.headers().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["X-RateLimit-Limit", "X-RateLimit-Remaining", "X-RateLimit-Reset"]);
+    }
+
+    #[test]
+    fn principals_share_a_bucket_across_addresses() {
+        let principal = Principal { name: "ci".to_string(), access: crate::auth::Access::ReadWrite, expiry: None };
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(client_key(Some(&principal), Some(ip)), "principal:ci");
+        assert_eq!(client_key(None, Some(ip)), "ip:10.0.0.1");
+        assert_eq!(client_key(None, None), "unknown");
+    }
+}
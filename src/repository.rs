@@ -0,0 +1,164 @@
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::attributes;
+use crate::config::env_or;
+use crate::retry;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Person {
+    pub name: String,
+    pub age: i32,
+    // Arbitrary nested fields, stored as a JSON object
+    #[serde(default)]
+    pub attributes: Option<Map<String, Value>>,
+}
+
+// Capacity of rusqlite's per-connection prepared statement cache. Setting
+// TINYSQL_STMT_CACHE=false drops it to zero so every call re-prepares its SQL,
+// which lets benchmarks compare both modes with identical handlers.
+pub fn statement_cache_capacity() -> usize {
+    if env_or("TINYSQL_STMT_CACHE", true) {
+        env_or("TINYSQL_STMT_CACHE_CAPACITY", 16)
+    } else {
+        0
+    }
+}
+
+pub fn configure_connection(conn: &Connection) {
+    conn.set_prepared_statement_cache_capacity(statement_cache_capacity());
+}
+
+// `init` runs on every new connection after the common configuration
+pub fn open_pool(
+    path: &str,
+    init: fn(&Connection),
+) -> std::result::Result<Pool<SqliteConnectionManager>, r2d2::Error> {
+    let manager = SqliteConnectionManager::file(path).with_init(move |conn| {
+        configure_connection(conn);
+        init(conn);
+        Ok(())
+    });
+    Pool::new(manager)
+}
+
+pub fn create_table(conn: &Connection) -> Result<()> {
+    retry::policy().run(|| {
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS person (
+                    name TEXT NOT NULL,
+                    age INTEGER,
+                    {}
+                )",
+                attributes::COLUMN_DEFINITION
+            ),
+            [],
+        )
+    })?;
+
+    // Tables created before attributes existed get the column added
+    let has_attributes = conn
+        .query_row("SELECT 1 FROM pragma_table_info('person') WHERE name = ?1", [attributes::COLUMN], |_| Ok(()))
+        .optional()?
+        .is_some();
+    if !has_attributes {
+        let sql = format!("ALTER TABLE person ADD COLUMN {}", attributes::COLUMN_DEFINITION);
+        retry::policy().run(|| conn.execute(&sql, []))?;
+    }
+    attributes::create_indexes(conn, "person")
+}
+
+// For `Option<Option<T>>` fields with `#[serde(default)]`: a field left out
+// stays None while an explicit `null` becomes Some(None)
+pub fn explicit_null<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> std::result::Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+// Attributes travel as JSON text
+fn attributes_json(attributes: Option<&Map<String, Value>>) -> Option<String> {
+    attributes.map(|attributes| Value::Object(attributes.clone()).to_string())
+}
+
+pub fn select_person(conn: &Connection, name: &str) -> Result<Option<Person>> {
+    let mut stmt = conn.prepare_cached("SELECT name, age, attributes FROM person WHERE name = ?1")?;
+    stmt.query_row(params![name], |row| {
+        let attributes: Option<String> = row.get(2)?;
+        Ok(Person {
+            name: row.get(0)?,
+            age: row.get(1)?,
+            // The column only admits JSON objects
+            attributes: attributes.and_then(|json| serde_json::from_str(&json).ok()),
+        })
+    })
+    .optional()
+}
+
+pub fn insert_person(conn: &Connection, name: &str, age: i32, attributes: Option<&Map<String, Value>>) -> Result<usize> {
+    retry::policy().run(|| insert_person_once(conn, name, age, attributes))
+}
+
+// Attributes, when given, replace the stored ones, and `Some(None)` clears
+// them
+pub fn update_person(conn: &Connection, name: &str, new_age: i32, attributes: Option<Option<&Map<String, Value>>>) -> Result<usize> {
+    retry::policy().run(|| update_person_once(conn, name, new_age, attributes))
+}
+
+pub fn delete_person(conn: &Connection, name: &str) -> Result<usize> {
+    retry::policy().run(|| delete_person_once(conn, name))
+}
+
+// Single attempts of the writes above
+fn insert_person_once(conn: &Connection, name: &str, age: i32, attributes: Option<&Map<String, Value>>) -> Result<usize> {
+    conn.prepare_cached("INSERT INTO person (name, age, attributes) VALUES (?1, ?2, ?3)")?
+        .execute(params![name, age, attributes_json(attributes)])
+}
+
+fn update_person_once(conn: &Connection, name: &str, new_age: i32, attributes: Option<Option<&Map<String, Value>>>) -> Result<usize> {
+    conn.prepare_cached("UPDATE person SET age = ?1, attributes = CASE WHEN ?2 THEN ?3 ELSE attributes END WHERE name = ?4")?
+        .execute(params![new_age, attributes.is_some(), attributes_json(attributes.flatten()), name])
+}
+
+fn delete_person_once(conn: &Connection, name: &str) -> Result<usize> {
+    conn.prepare_cached("DELETE FROM person WHERE name = ?1")?
+        .execute(params![name])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Deserialize)]
+    struct Update {
+        #[serde(default, deserialize_with = "explicit_null")]
+        attributes: Option<Option<Map<String, Value>>>,
+    }
+
+    fn attributes(conn: &Connection) -> Option<Map<String, Value>> {
+        select_person(conn, "ada").unwrap().unwrap().attributes
+    }
+
+    #[test]
+    fn updates_keep_replace_or_clear_attributes() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        let team = json!({ "team": "infra" }).as_object().cloned().unwrap();
+        insert_person(&conn, "ada", 36, Some(&team)).unwrap();
+
+        let update = |body: Value| {
+            let update: Update = serde_json::from_value(body).unwrap();
+            update_person(&conn, "ada", 37, update.attributes.as_ref().map(Option::as_ref)).unwrap();
+        };
+        update(json!({}));
+        assert_eq!(attributes(&conn), Some(team));
+        update(json!({ "attributes": { "team": "search" } }));
+        assert_eq!(attributes(&conn).unwrap()["team"], "search");
+        update(json!({ "attributes": null }));
+        assert_eq!(attributes(&conn), None);
+    }
+}
@@ -0,0 +1,351 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rusqlite::types::{Value as SqlValue, ValueRef};
+use rusqlite::{params_from_iter, Connection, ErrorCode, OptionalExtension, Row};
+use serde_json::{json, Map, Value};
+
+use crate::attributes;
+use crate::errors::ErrorBody;
+use crate::retry;
+use crate::schema::{self, quote_identifier, Affinity, ColumnInfo, RowKey, TableInfo};
+use crate::sql_policy::{Denial, SqlPolicy};
+
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+#[derive(Debug)]
+pub enum ResourceError {
+    TableNotFound(String),
+    RowNotFound,
+    // WITHOUT ROWID tables without a single-column key can only be listed
+    NoKey(String),
+    Invalid(String),
+    // A constraint such as UNIQUE or NOT NULL rejected the write
+    Conflict(String),
+    // The caller's SQL policy doesn't cover the table
+    Denied(Denial),
+    Sqlite(rusqlite::Error),
+}
+
+impl ResourceError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ResourceError::TableNotFound(_) | ResourceError::RowNotFound => 404,
+            ResourceError::NoKey(_) => 405,
+            ResourceError::Invalid(_) => 400,
+            ResourceError::Conflict(_) => 409,
+            ResourceError::Denied(_) => 403,
+            ResourceError::Sqlite(e) => retry::status_code(e),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        matches!(self, ResourceError::Sqlite(e) if retry::is_busy(e))
+    }
+
+    pub fn body(&self) -> Value {
+        let message = match self {
+            ResourceError::Denied(denial) => {
+                return json!({ "error": "Operation not permitted", "action": denial.action, "table": denial.table });
+            }
+            ResourceError::TableNotFound(table) => format!("No such table: {}", table),
+            ResourceError::RowNotFound => "No such row".to_string(),
+            ResourceError::NoKey(table) => format!("Table {} has no key to address rows by", table),
+            ResourceError::Invalid(message) | ResourceError::Conflict(message) => message.clone(),
+            ResourceError::Sqlite(e) if retry::is_busy(e) => "Database is busy, retry later".to_string(),
+            ResourceError::Sqlite(e) => {
+                eprintln!("Resource operation failed: {}", e);
+                "Resource operation failed".to_string()
+            }
+        };
+        json!(ErrorBody::new(message))
+    }
+}
+
+impl From<Denial> for ResourceError {
+    fn from(denial: Denial) -> Self {
+        ResourceError::Denied(denial)
+    }
+}
+
+impl From<rusqlite::Error> for ResourceError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err.sqlite_error_code() {
+            Some(ErrorCode::ConstraintViolation) => ResourceError::Conflict(err.to_string()),
+            _ => ResourceError::Sqlite(err),
+        }
+    }
+}
+
+fn table(conn: &Connection, name: &str) -> Result<TableInfo, ResourceError> {
+    schema::schema(conn)?
+        .table(name)
+        .cloned()
+        .ok_or_else(|| ResourceError::TableNotFound(name.to_string()))
+}
+
+// The key column expression and its affinity
+fn key_of(table: &TableInfo) -> Result<(String, Affinity), ResourceError> {
+    match &table.key {
+        Some(RowKey::Column(name)) => {
+            let affinity = table.column(name).map_or(Affinity::Blob, |c| c.affinity);
+            Ok((quote_identifier(name), affinity))
+        }
+        Some(RowKey::Rowid) => Ok(("rowid".to_string(), Affinity::Integer)),
+        None => Err(ResourceError::NoKey(table.name.clone())),
+    }
+}
+
+// Keys arrive as path segments, so they are typed by the key's affinity
+fn parse_key(key: &str, affinity: Affinity) -> Result<SqlValue, ResourceError> {
+    match affinity {
+        Affinity::Integer => key
+            .parse()
+            .map(SqlValue::Integer)
+            .map_err(|_| ResourceError::Invalid(format!("Invalid key {:?}", key))),
+        Affinity::Real | Affinity::Numeric => Ok(key
+            .parse()
+            .map(SqlValue::Integer)
+            .or_else(|_| key.parse().map(SqlValue::Real))
+            .unwrap_or_else(|_| SqlValue::Text(key.to_string()))),
+        Affinity::Text | Affinity::Blob => Ok(SqlValue::Text(key.to_string())),
+    }
+}
+
+// Rowid tables keyed by rowid return it alongside the columns so rows can be
+// addressed
+fn select_list(table: &TableInfo) -> String {
+    let columns = table.columns.iter().map(|c| quote_identifier(&c.name));
+    if table.key == Some(RowKey::Rowid) {
+        std::iter::once("rowid".to_string()).chain(columns).collect::<Vec<_>>().join(", ")
+    } else {
+        columns.collect::<Vec<_>>().join(", ")
+    }
+}
+
+pub fn row_json(row: &Row, names: &[String]) -> rusqlite::Result<Value> {
+    let mut object = Map::new();
+    for (i, name) in names.iter().enumerate() {
+        let value = match row.get_ref(i)? {
+            ValueRef::Null => Value::Null,
+            ValueRef::Integer(n) => Value::from(n),
+            ValueRef::Real(n) => Value::from(n),
+            ValueRef::Text(text) => Value::String(String::from_utf8_lossy(text).into_owned()),
+            ValueRef::Blob(blob) => Value::String(BASE64.encode(blob)),
+        };
+        object.insert(name.clone(), value);
+    }
+    Ok(Value::Object(object))
+}
+
+fn select_one(conn: &Connection, table: &TableInfo, key_column: &str, key: SqlValue) -> Result<Value, ResourceError> {
+    let sql = format!(
+        "SELECT {} FROM {} WHERE {} = ?1",
+        select_list(table),
+        quote_identifier(&table.name),
+        key_column,
+    );
+    let mut stmt = conn.prepare_cached(&sql)?;
+    let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    stmt.query_row([key], |row| row_json(row, &names))
+        .optional()?
+        .ok_or(ResourceError::RowNotFound)
+}
+
+// Checks a JSON value against the column's type. Strings in numeric columns
+// are parsed the way SQLite's affinity would, which is how CSV and form cells
+// (always text) reach their column's type.
+fn to_sql(table: &TableInfo, column: &ColumnInfo, value: &Value) -> Result<SqlValue, ResourceError> {
+    let converted = match (column.affinity, value) {
+        (_, Value::Null) if column.not_null && !table.is_rowid_alias(column) => None,
+        (_, Value::Null) => Some(SqlValue::Null),
+        (Affinity::Integer, Value::Number(n)) => n.as_i64().map(SqlValue::Integer),
+        (Affinity::Real, Value::Number(n)) => n.as_f64().map(SqlValue::Real),
+        (Affinity::Numeric | Affinity::Blob, Value::Number(n)) => {
+            n.as_i64().map(SqlValue::Integer).or_else(|| n.as_f64().map(SqlValue::Real))
+        }
+        (Affinity::Integer | Affinity::Numeric | Affinity::Blob, Value::Bool(b)) => Some(SqlValue::Integer(*b as i64)),
+        (Affinity::Text | Affinity::Blob, Value::String(s)) => Some(SqlValue::Text(s.clone())),
+        (Affinity::Integer, Value::String(s)) => s.parse().ok().or_else(|| parse_bool(s)).map(SqlValue::Integer),
+        (Affinity::Real, Value::String(s)) => s.parse().ok().filter(|n: &f64| n.is_finite()).map(SqlValue::Real),
+        (Affinity::Numeric, Value::String(s)) => s
+            .parse()
+            .ok()
+            .or_else(|| parse_bool(s))
+            .map(SqlValue::Integer)
+            .or_else(|| s.parse().ok().filter(|n: &f64| n.is_finite()).map(SqlValue::Real)),
+        // Nested values such as `attributes` are stored as JSON text
+        (Affinity::Text | Affinity::Blob, Value::Object(_) | Value::Array(_)) => Some(SqlValue::Text(value.to_string())),
+        _ => None,
+    };
+    converted.ok_or_else(|| {
+        let expected = match column.affinity {
+            _ if value.is_null() => "a value",
+            Affinity::Integer => "an integer",
+            Affinity::Real | Affinity::Numeric => "a number",
+            Affinity::Text => "a string",
+            Affinity::Blob => "a string, number or boolean",
+        };
+        ResourceError::Invalid(format!("Expected {} for column {}", expected, column.name))
+    })
+}
+
+fn parse_bool(text: &str) -> Option<i64> {
+    text.parse::<bool>().ok().map(i64::from)
+}
+
+// Validates a request body into (column, value) pairs
+fn assignments<'a>(table: &'a TableInfo, body: &Value) -> Result<Vec<(&'a ColumnInfo, SqlValue)>, ResourceError> {
+    let Value::Object(fields) = body else {
+        return Err(ResourceError::Invalid("Expected a JSON object".to_string()));
+    };
+    let mut values = Vec::with_capacity(fields.len());
+    for (name, value) in fields {
+        let column = table
+            .column(name)
+            .ok_or_else(|| ResourceError::Invalid(format!("Unknown column {}", name)))?;
+        values.push((column, to_sql(table, column, value)?));
+    }
+    Ok(values)
+}
+
+// `filters` are `attr.` query pairs with the prefix stripped; see `attributes`
+pub fn list(
+    conn: &Connection,
+    policy: &SqlPolicy,
+    table: &str,
+    filters: &[(String, String)],
+    limit: Option<u32>,
+    offset: Option<u32>,
+) -> Result<Value, ResourceError> {
+    policy.check_read(table)?;
+    let table = self::table(conn, table)?;
+    let mut conditions = Vec::with_capacity(filters.len());
+    let mut params = Vec::new();
+    for (path, value) in filters {
+        let (condition, values) = attributes::condition(&table, path, value).map_err(ResourceError::Invalid)?;
+        conditions.push(condition);
+        params.extend(values);
+    }
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    };
+    let order = match key_of(&table) {
+        Ok((key_column, _)) => format!(" ORDER BY {}", key_column),
+        Err(_) => String::new(),
+    };
+    let sql = format!(
+        "SELECT {} FROM {}{}{} LIMIT ? OFFSET ?",
+        select_list(&table),
+        quote_identifier(&table.name),
+        filter,
+        order,
+    );
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    params.push(SqlValue::Integer(limit.into()));
+    params.push(SqlValue::Integer(offset.unwrap_or(0).into()));
+    let mut stmt = conn.prepare_cached(&sql)?;
+    let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let rows = stmt
+        .query_map(params_from_iter(params.iter()), |row| row_json(row, &names))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(Value::Array(rows))
+}
+
+pub fn get(conn: &Connection, policy: &SqlPolicy, table: &str, key: &str) -> Result<Value, ResourceError> {
+    policy.check_read(table)?;
+    let table = self::table(conn, table)?;
+    let (key_column, affinity) = key_of(&table)?;
+    select_one(conn, &table, &key_column, parse_key(key, affinity)?)
+}
+
+// Inserts a row and returns it as stored, defaults and assigned key included
+pub fn create(conn: &Connection, policy: &SqlPolicy, table: &str, body: &Value) -> Result<Value, ResourceError> {
+    policy.check_write(table)?;
+    let table = self::table(conn, table)?;
+    let values = assignments(&table, body)?;
+    for column in &table.columns {
+        let required = column.not_null && column.default.is_none() && !table.is_rowid_alias(column);
+        if required && !values.iter().any(|(c, _)| c.name == column.name) {
+            return Err(ResourceError::Invalid(format!("Missing column {}", column.name)));
+        }
+    }
+
+    let sql = if values.is_empty() {
+        format!("INSERT INTO {} DEFAULT VALUES", quote_identifier(&table.name))
+    } else {
+        format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote_identifier(&table.name),
+            values.iter().map(|(c, _)| quote_identifier(&c.name)).collect::<Vec<_>>().join(", "),
+            vec!["?"; values.len()].join(", "),
+        )
+    };
+    retry::policy().run(|| {
+        conn.prepare_cached(&sql)?
+            .execute(params_from_iter(values.iter().map(|(_, v)| v)))
+    })?;
+
+    if table.has_rowid {
+        return select_one(conn, &table, "rowid", SqlValue::Integer(conn.last_insert_rowid()));
+    }
+    let (key_column, _) = key_of(&table)?;
+    let key = values
+        .into_iter()
+        .find(|(c, _)| table.key == Some(RowKey::Column(c.name.clone())))
+        .map(|(_, v)| v)
+        .ok_or(ResourceError::RowNotFound)?;
+    select_one(conn, &table, &key_column, key)
+}
+
+// Sets the given columns only and returns the updated row
+pub fn update(conn: &Connection, policy: &SqlPolicy, table: &str, key: &str, body: &Value) -> Result<Value, ResourceError> {
+    policy.check_write(table)?;
+    let table = self::table(conn, table)?;
+    let (key_column, affinity) = key_of(&table)?;
+    let key = parse_key(key, affinity)?;
+    let values = assignments(&table, body)?;
+    if values.is_empty() {
+        return Err(ResourceError::Invalid("No columns to update".to_string()));
+    }
+
+    let sql = format!(
+        "UPDATE {} SET {} WHERE {} = ?",
+        quote_identifier(&table.name),
+        values.iter().map(|(c, _)| format!("{} = ?", quote_identifier(&c.name))).collect::<Vec<_>>().join(", "),
+        key_column,
+    );
+    let params: Vec<&SqlValue> = values.iter().map(|(_, v)| v).chain(std::iter::once(&key)).collect();
+    let changed = retry::policy().run(|| conn.prepare_cached(&sql)?.execute(params_from_iter(params.iter())))?;
+    if changed == 0 {
+        return Err(ResourceError::RowNotFound);
+    }
+
+    // The key itself may have been updated
+    let key = values
+        .into_iter()
+        .find(|(c, _)| table.key == Some(RowKey::Column(c.name.clone())))
+        .map_or(key, |(_, v)| v);
+    select_one(conn, &table, &key_column, key)
+}
+
+pub fn delete(conn: &Connection, policy: &SqlPolicy, table: &str, key: &str) -> Result<(), ResourceError> {
+    policy.check_write(table)?;
+    let table = self::table(conn, table)?;
+    let (key_column, affinity) = key_of(&table)?;
+    let key = parse_key(key, affinity)?;
+    let sql = format!("DELETE FROM {} WHERE {} = ?1", quote_identifier(&table.name), key_column);
+    let changed = retry::policy().run(|| conn.prepare_cached(&sql)?.execute([&key]))?;
+    if changed == 0 {
+        return Err(ResourceError::RowNotFound);
+    }
+    Ok(())
+}
+
+// Logged at startup so operators can see what the resource routes expose
+pub fn describe(conn: &Connection) -> rusqlite::Result<String> {
+    let schema = schema::schema(conn)?;
+    Ok(schema.tables.keys().cloned().collect::<Vec<_>>().join(", "))
+}
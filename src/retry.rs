@@ -0,0 +1,205 @@
+use rand::Rng;
+use rusqlite::{Error, ErrorCode, Result};
+use serde::Serialize;
+use std::io::Cursor;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::env_or;
+use crate::format::Format;
+use crate::metrics;
+use crate::tide_format;
+use crate::tinyhttp_format;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        RetryPolicy {
+            max_retries: env_or("TINYSQL_RETRY_MAX", 5),
+            base_delay: Duration::from_millis(env_or("TINYSQL_RETRY_BASE_MS", 10)),
+            max_delay: Duration::from_millis(env_or("TINYSQL_RETRY_MAX_DELAY_MS", 1000)),
+        }
+    }
+
+    // Run `op`, retrying it with exponential backoff while SQLite reports the
+    // database as busy or locked. Any other error is returned immediately.
+    pub fn run<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if is_busy(&e) => match self.next_delay(&mut attempt) {
+                    Some(delay) => thread::sleep(delay),
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    // How long to wait before the next attempt, or None once retries are
+    // exhausted
+    fn next_delay(&self, attempt: &mut u32) -> Option<Duration> {
+        if *attempt >= self.max_retries {
+            metrics::WRITE_RETRIES_EXHAUSTED.inc();
+            return None;
+        }
+        *attempt += 1;
+        metrics::WRITE_RETRIES.inc();
+        Some(self.backoff(*attempt))
+    }
+
+    // Full jitter: a random delay between zero and the capped exponential step
+    fn backoff(&self, attempt: u32) -> Duration {
+        let step = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        let millis = step.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    // Seconds a client should wait before trying again, for `Retry-After`
+    pub fn retry_after_secs(&self) -> u64 {
+        self.max_delay.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+pub fn policy() -> &'static RetryPolicy {
+    static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+    POLICY.get_or_init(RetryPolicy::from_env)
+}
+
+pub fn is_busy(err: &Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked)
+    )
+}
+
+// The status for a failed SQLite call: 503 while the database stays busy so
+// clients back off, 500 otherwise
+pub fn status_code(err: &Error) -> u16 {
+    if is_busy(err) {
+        503
+    } else {
+        500
+    }
+}
+
+// A response with `status`; busy ones tell the client when to come back
+// with Retry-After
+pub fn tide_response<T: Serialize>(format: Format, status: u16, body: &T, busy: bool) -> tide::Result {
+    let mut response = tide_format::response(format, body)?;
+    response.set_status(tide::StatusCode::try_from(status).unwrap());
+    if busy {
+        response.insert_header("Retry-After", policy().retry_after_secs().to_string());
+    }
+    Ok(response)
+}
+
+pub fn tinyhttp_response<T: Serialize>(
+    format: Format,
+    status: u16,
+    body: &T,
+    busy: bool,
+) -> tiny_http::Response<Cursor<Vec<u8>>> {
+    let response = tinyhttp_format::response(format, body).with_status_code(status);
+    if !busy {
+        return response;
+    }
+    let retry_after = policy().retry_after_secs().to_string();
+    response.with_header(tiny_http::Header::from_bytes(&b"Retry-After"[..], retry_after.as_bytes()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::ffi;
+    use std::cell::Cell;
+
+    fn sqlite_error(code: i32) -> Error {
+        Error::SqliteFailure(ffi::Error::new(code), None)
+    }
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+        }
+    }
+
+    #[test]
+    fn busy_and_locked_are_retryable() {
+        assert!(is_busy(&sqlite_error(ffi::SQLITE_BUSY)));
+        assert!(is_busy(&sqlite_error(ffi::SQLITE_LOCKED)));
+        assert!(!is_busy(&sqlite_error(ffi::SQLITE_CONSTRAINT)));
+        assert!(!is_busy(&Error::QueryReturnedNoRows));
+    }
+
+    #[test]
+    fn backoff_stays_under_the_capped_step() {
+        let policy = policy(10);
+        for attempt in 1..=10 {
+            let cap = Duration::from_millis(1 << (attempt - 1)).min(policy.max_delay);
+            assert!(policy.backoff(attempt) <= cap);
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let calls = Cell::new(0);
+        let result: Result<()> = policy(3).run(|| {
+            calls.set(calls.get() + 1);
+            Err(sqlite_error(ffi::SQLITE_BUSY))
+        });
+        assert!(result.is_err_and(|e| is_busy(&e)));
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn succeeds_once_the_lock_clears() {
+        let calls = Cell::new(0);
+        let result = policy(3).run(|| {
+            calls.set(calls.get() + 1);
+            match calls.get() {
+                1 | 2 => Err(sqlite_error(ffi::SQLITE_LOCKED)),
+                n => Ok(n),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let calls = Cell::new(0);
+        let result: Result<()> = policy(3).run(|| {
+            calls.set(calls.get() + 1);
+            Err(sqlite_error(ffi::SQLITE_CONSTRAINT))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn busy_errors_map_to_503_with_retry_after() {
+        assert_eq!(status_code(&sqlite_error(ffi::SQLITE_BUSY)), 503);
+        assert_eq!(status_code(&sqlite_error(ffi::SQLITE_CONSTRAINT)), 500);
+
+        let retry_after = |response: &tiny_http::Response<Cursor<Vec<u8>>>| {
+            response.headers().iter().any(|h| h.field.equiv("Retry-After"))
+        };
+        let body = serde_json::json!({ "error": "busy" });
+        let busy = tinyhttp_response(Format::Json, 503, &body, true);
+        assert_eq!((busy.status_code().0, retry_after(&busy)), (503, true));
+        let failed = tinyhttp_response(Format::Json, 500, &body, false);
+        assert_eq!((failed.status_code().0, retry_after(&failed)), (500, false));
+    }
+}
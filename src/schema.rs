@@ -0,0 +1,234 @@
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// SQLite's type affinities. `Blob` is the affinity of untyped columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    Integer,
+    Real,
+    Numeric,
+    Text,
+    Blob,
+}
+
+// SQLite's type affinity rules for a declared column type
+pub fn affinity(declared: &str) -> Affinity {
+    let declared = declared.to_ascii_uppercase();
+    if declared.contains("INT") {
+        Affinity::Integer
+    } else if declared.contains("CHAR") || declared.contains("CLOB") || declared.contains("TEXT") {
+        Affinity::Text
+    } else if declared.contains("BLOB") || declared.is_empty() {
+        Affinity::Blob
+    } else if declared.contains("REAL") || declared.contains("FLOA") || declared.contains("DOUB") {
+        Affinity::Real
+    } else {
+        Affinity::Numeric
+    }
+}
+
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnInfo {
+    pub name: String,
+    pub declared_type: String,
+    pub affinity: Affinity,
+    pub not_null: bool,
+    pub default: Option<String>,
+    // Position in the primary key, 0 when not part of it
+    pub primary_key: u32,
+}
+
+// How a single row is addressed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowKey {
+    Column(String),
+    Rowid,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableInfo {
+    pub name: String,
+    pub columns: Vec<ColumnInfo>,
+    // Generated columns, which can be read and indexed but not written
+    pub generated: Vec<String>,
+    pub has_rowid: bool,
+    // `None` for WITHOUT ROWID tables with no single-column key
+    pub key: Option<RowKey>,
+}
+
+impl TableInfo {
+    pub fn column(&self, name: &str) -> Option<&ColumnInfo> {
+        self.columns.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    // An `INTEGER PRIMARY KEY`, in any case, aliases the rowid and is
+    // assigned on insert
+    pub fn is_rowid_alias(&self, column: &ColumnInfo) -> bool {
+        self.has_rowid
+            && column.primary_key > 0
+            && column.declared_type.trim().eq_ignore_ascii_case("INTEGER")
+            && self.key == Some(RowKey::Column(column.name.clone()))
+    }
+}
+
+#[derive(Debug)]
+pub struct Schema {
+    pub version: i64,
+    pub tables: BTreeMap<String, TableInfo>,
+}
+
+impl Schema {
+    // Table names are case-insensitive in SQLite
+    pub fn table(&self, name: &str) -> Option<&TableInfo> {
+        self.tables
+            .get(name)
+            .or_else(|| self.tables.values().find(|t| t.name.eq_ignore_ascii_case(name)))
+    }
+}
+
+// Moves on every schema change, from this connection or any other
+pub fn version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("PRAGMA schema_version", [], |row| row.get(0))
+}
+
+fn load_table(conn: &Connection, name: String) -> rusqlite::Result<TableInfo> {
+    let mut stmt = conn.prepare(r#"SELECT name, type, "notnull", dflt_value, pk FROM pragma_table_info(?1)"#)?;
+    let columns = stmt
+        .query_map([&name], |row| {
+            let declared_type: String = row.get(1)?;
+            Ok(ColumnInfo {
+                name: row.get(0)?,
+                affinity: affinity(&declared_type),
+                declared_type,
+                not_null: row.get(2)?,
+                default: row.get(3)?,
+                primary_key: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_xinfo(?1) WHERE hidden IN (2, 3)")?;
+    let generated = stmt
+        .query_map([&name], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    // WITHOUT ROWID tables fail to prepare a rowid select
+    let has_rowid = conn
+        .prepare(&format!("SELECT rowid FROM {} LIMIT 0", quote_identifier(&name)))
+        .is_ok();
+
+    let pk: Vec<&ColumnInfo> = columns.iter().filter(|c| c.primary_key > 0).collect();
+    let key = if pk.len() == 1 {
+        Some(RowKey::Column(pk[0].name.clone()))
+    } else if has_rowid {
+        Some(RowKey::Rowid)
+    } else {
+        unique_column(conn, &name)?.map(RowKey::Column)
+    };
+
+    Ok(TableInfo { name, columns, generated, has_rowid, key })
+}
+
+// The first single-column unique index of a table, if any
+fn unique_column(conn: &Connection, table: &str) -> rusqlite::Result<Option<String>> {
+    let mut indexes = conn.prepare(r#"SELECT name FROM pragma_index_list(?1) WHERE "unique" = 1"#)?;
+    let names = indexes
+        .query_map([table], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for index in names {
+        let mut info = conn.prepare("SELECT name FROM pragma_index_info(?1)")?;
+        let columns = info
+            .query_map([&index], |row| row.get::<_, Option<String>>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if let [Some(column)] = columns.as_slice() {
+            return Ok(Some(column.clone()));
+        }
+    }
+    Ok(None)
+}
+
+// Every ordinary user table. SQLite's own tables are never exposed, nor are
+// virtual tables such as full-text indexes and the shadow tables backing them.
+fn load(conn: &Connection, version: i64) -> rusqlite::Result<Schema> {
+    let mut stmt = conn.prepare(
+        r"SELECT name FROM pragma_table_list
+          WHERE schema = 'main' AND type = 'table' AND name NOT LIKE 'sqlite\_%' ESCAPE '\'
+          ORDER BY name",
+    )?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut tables = BTreeMap::new();
+    for name in names {
+        tables.insert(name.clone(), load_table(conn, name)?);
+    }
+    Ok(Schema { version, tables })
+}
+
+fn cache() -> &'static Mutex<HashMap<String, Arc<Schema>>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, Arc<Schema>>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// The schema of `conn`'s database, cached per database file and reloaded
+// whenever `PRAGMA schema_version` moves, i.e. after any DDL from any
+// connection. In-memory and temporary databases have no file name to tell
+// them apart and are cheap to read, so they are loaded every time.
+pub fn schema(conn: &Connection) -> rusqlite::Result<Arc<Schema>> {
+    let version = version(conn)?;
+    let Some(path) = conn.path().filter(|path| !path.is_empty()).map(str::to_string) else {
+        return Ok(Arc::new(load(conn, version)?));
+    };
+    if let Some(schema) = cache().lock().unwrap().get(&path) {
+        if schema.version == version {
+            return Ok(schema.clone());
+        }
+    }
+    let schema = Arc::new(load(conn, version)?);
+    cache().lock().unwrap().insert(path, schema.clone());
+    Ok(schema)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_memory_databases_do_not_share_a_cached_schema() {
+        let first = Connection::open_in_memory().unwrap();
+        first.execute_batch("CREATE TABLE a (id INTEGER PRIMARY KEY)").unwrap();
+        let second = Connection::open_in_memory().unwrap();
+        second.execute_batch("CREATE TABLE b (id INTEGER PRIMARY KEY)").unwrap();
+
+        assert!(schema(&first).unwrap().table("a").is_some());
+        let other = schema(&second).unwrap();
+        assert!(other.table("a").is_none());
+        assert!(other.table("b").is_some());
+    }
+
+    #[test]
+    fn integer_primary_keys_alias_the_rowid_in_any_case() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE lower (id integer primary key, name TEXT);
+             CREATE TABLE mixed (id Integer PRIMARY KEY);
+             CREATE TABLE wide (id BIGINT PRIMARY KEY);
+             CREATE TABLE keyed (id INTEGER PRIMARY KEY, v TEXT) WITHOUT ROWID;",
+        )
+        .unwrap();
+        let schema = schema(&conn).unwrap();
+        let alias = |table: &str| {
+            let table = schema.table(table).unwrap();
+            table.is_rowid_alias(table.column("id").unwrap())
+        };
+        assert!(alias("lower"));
+        assert!(alias("mixed"));
+        assert!(!alias("wide"));
+        assert!(!alias("keyed"));
+    }
+}
@@ -0,0 +1,223 @@
+use rusqlite::{Connection, ErrorCode};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::errors::ErrorBody;
+use crate::resources::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::retry;
+
+// Full-text search over `person` names. `person_search` is an external
+// content FTS5 table: it stores only the index and reads names back from
+// `person`, and triggers keep it in step with every write, whichever route
+// or connection makes it.
+const INDEX_SQL: &str = "
+    CREATE VIRTUAL TABLE IF NOT EXISTS person_search USING fts5(
+        name,
+        content = 'person',
+        content_rowid = 'rowid',
+        tokenize = 'unicode61 remove_diacritics 2',
+        prefix = '2 3'
+    );
+    CREATE TRIGGER IF NOT EXISTS person_search_insert AFTER INSERT ON person BEGIN
+        INSERT INTO person_search (rowid, name) VALUES (new.rowid, new.name);
+    END;
+    CREATE TRIGGER IF NOT EXISTS person_search_delete AFTER DELETE ON person BEGIN
+        INSERT INTO person_search (person_search, rowid, name) VALUES ('delete', old.rowid, old.name);
+    END;
+    CREATE TRIGGER IF NOT EXISTS person_search_update AFTER UPDATE OF name ON person BEGIN
+        INSERT INTO person_search (person_search, rowid, name) VALUES ('delete', old.rowid, old.name);
+        INSERT INTO person_search (rowid, name) VALUES (new.rowid, new.name);
+    END;
+";
+
+// FTS5 puts these control characters around matched terms, which become
+// `<mark>` tags once the rest of the snippet is HTML-escaped, and shows this
+// many tokens around them
+const MATCH_OPEN: char = '\u{2}';
+const MATCH_CLOSE: char = '\u{3}';
+const SNIPPET_TOKENS: u32 = 16;
+
+// FTS5 and `pragma_table_list`, which the schema is listed from, need SQLite
+// 3.37 or later
+const MIN_SQLITE_VERSION: i32 = 3_037_000;
+
+// Checked at startup, so a system SQLite that is too old or built without
+// FTS5 fails fast rather than on the first search
+pub fn check_sqlite() -> Result<(), String> {
+    if rusqlite::version_number() < MIN_SQLITE_VERSION {
+        return Err(format!("SQLite {} is too old, 3.37 or later is needed", rusqlite::version()));
+    }
+    let conn = Connection::open_in_memory().map_err(|e| e.to_string())?;
+    conn.execute_batch("CREATE VIRTUAL TABLE probe USING fts5(text)")
+        .map_err(|e| format!("SQLite was built without FTS5: {}", e))
+}
+
+#[derive(Debug)]
+pub enum SearchError {
+    Invalid(String),
+    Sqlite(rusqlite::Error),
+}
+
+impl SearchError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            SearchError::Invalid(_) => 400,
+            SearchError::Sqlite(e) => retry::status_code(e),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        matches!(self, SearchError::Sqlite(e) if retry::is_busy(e))
+    }
+
+    pub fn body(&self) -> Value {
+        let message = match self {
+            SearchError::Invalid(message) => message.clone(),
+            SearchError::Sqlite(e) if retry::is_busy(e) => "Database is busy, retry later".to_string(),
+            SearchError::Sqlite(e) => {
+                eprintln!("Search failed: {}", e);
+                "Search failed".to_string()
+            }
+        };
+        json!(ErrorBody::new(message))
+    }
+}
+
+impl From<rusqlite::Error> for SearchError {
+    fn from(err: rusqlite::Error) -> Self {
+        SearchError::Sqlite(err)
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SearchHit {
+    pub name: String,
+    pub age: Option<i32>,
+    // bm25 score; lower is a better match
+    pub rank: f64,
+    // The name, HTML-escaped, with matched terms wrapped in `<mark>`
+    pub snippet: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SearchResults {
+    pub query: String,
+    // Best matches first
+    pub hits: Vec<SearchHit>,
+}
+
+// Creates the index and its triggers, and rebuilds the index when it does
+// not match `person`: when it was just created over existing rows, or after
+// a VACUUM renumbered them, as `person` has no INTEGER PRIMARY KEY to keep
+// rowids stable
+pub fn create_index(conn: &Connection) -> rusqlite::Result<()> {
+    retry::policy().run(|| conn.execute_batch(INDEX_SQL))?;
+    let check = "INSERT INTO person_search (person_search, rank) VALUES ('integrity-check', 1)";
+    if let Err(e) = conn.execute(check, []) {
+        if retry::is_busy(&e) {
+            return Err(e);
+        }
+        println!("Rebuilding the person search index");
+        retry::policy().run(|| conn.execute("INSERT INTO person_search (person_search) VALUES ('rebuild')", []))?;
+    }
+    Ok(())
+}
+
+fn snippet_html(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            MATCH_OPEN => html.push_str("<mark>"),
+            MATCH_CLOSE => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+// Runs an FTS5 query: bare terms must all match, `ali*` matches prefixes,
+// `"ada lovelace"` matches a phrase, and OR, NOT and parentheses combine them
+pub fn search(conn: &Connection, query: &str, limit: Option<u32>, offset: Option<u32>) -> Result<SearchResults, SearchError> {
+    if query.trim().is_empty() {
+        return Err(SearchError::Invalid("Missing search query `q`".to_string()));
+    }
+    let sql = format!(
+        "SELECT person.name, person.age, person_search.rank,
+                snippet(person_search, 0, char({}), char({}), '…', {})
+         FROM person_search JOIN person ON person.rowid = person_search.rowid
+         WHERE person_search MATCH ?1
+         ORDER BY person_search.rank
+         LIMIT ?2 OFFSET ?3",
+        MATCH_OPEN as u32, MATCH_CLOSE as u32, SNIPPET_TOKENS,
+    );
+    let mut stmt = match conn.prepare_cached(&sql) {
+        Ok(stmt) => stmt,
+        // A restored snapshot may predate the index
+        Err(e) if e.to_string().contains("no such table") => {
+            create_index(conn)?;
+            conn.prepare_cached(&sql)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let rows = stmt.query_map((query, limit, offset.unwrap_or(0)), |row| {
+        Ok(SearchHit {
+            name: row.get(0)?,
+            age: row.get(1)?,
+            rank: row.get(2)?,
+            snippet: snippet_html(&row.get::<_, String>(3)?),
+        })
+    });
+    // The query is only parsed once the statement runs, so errors from it
+    // other than busy ones are the client's
+    let hits = rows.and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>()).map_err(|e| {
+        match e.sqlite_error_code() {
+            Some(ErrorCode::Unknown) => SearchError::Invalid(format!("Invalid search query: {}", e)),
+            _ => SearchError::Sqlite(e),
+        }
+    })?;
+    Ok(SearchResults { query: query.to_string(), hits })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn people(names: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE person (name TEXT, age INTEGER)").unwrap();
+        create_index(&conn).unwrap();
+        for name in names {
+            conn.execute("INSERT INTO person (name) VALUES (?1)", [name]).unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn snippets_escape_names_around_the_marks() {
+        let conn = people(&["<b>Ada</b> & \"Lovelace\"", "Grace Hopper"]);
+        let results = search(&conn, "ada", None, None).unwrap();
+        assert_eq!(results.hits.len(), 1);
+        assert_eq!(results.hits[0].snippet, "&lt;b&gt;<mark>Ada</mark>&lt;/b&gt; &amp; &quot;Lovelace&quot;");
+    }
+
+    #[test]
+    fn invalid_queries_are_the_clients_fault() {
+        let conn = people(&["Ada"]);
+        let err = search(&conn, "\"unbalanced", None, None).unwrap_err();
+        assert_eq!(err.status_code(), 400);
+        assert_eq!(search(&conn, " ", None, None).unwrap_err().status_code(), 400);
+    }
+
+    #[test]
+    fn the_linked_sqlite_is_new_enough() {
+        assert_eq!(check_sqlite(), Ok(()));
+    }
+}
@@ -0,0 +1,417 @@
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::auth::Principal;
+
+// What a principal may do through the raw SQL endpoint, and which tables the
+// generic row routes and CSV transfers may read or write. Rules are comma
+// separated: `read:<table>`, `write:<table>` (either may use `*`), `ddl`,
+// `attach`, `pragma` (allows PRAGMA assignments and the pragmas missing from
+// `READ_ONLY_PRAGMAS`), `functions` (allows the functions in
+// `UNSAFE_FUNCTIONS`) or `all`.
+#[derive(Debug, Clone, Default)]
+pub struct SqlPolicy {
+    read: Vec<String>,
+    write: Vec<String>,
+    ddl: bool,
+    attach: bool,
+    pragma_writes: bool,
+    unsafe_functions: bool,
+}
+
+// Functions that reach past the tables a policy lists: loading native code,
+// swapping FTS tokenizers by pointer, or the shell's file access helpers
+const UNSAFE_FUNCTIONS: [&str; 5] = ["load_extension", "fts3_tokenizer", "readfile", "writefile", "edit"];
+
+// Pragmas that only report state when given no value. Anything else, such as
+// `wal_checkpoint`, `optimize` or `shrink_memory`, acts on the database even
+// without one.
+const READ_ONLY_PRAGMAS: [&str; 24] = [
+    "application_id",
+    "auto_vacuum",
+    "cache_size",
+    "collation_list",
+    "compile_options",
+    "data_version",
+    "database_list",
+    "encoding",
+    "foreign_key_check",
+    "foreign_keys",
+    "freelist_count",
+    "function_list",
+    "integrity_check",
+    "journal_mode",
+    "module_list",
+    "page_count",
+    "page_size",
+    "pragma_list",
+    "quick_check",
+    "schema_version",
+    "synchronous",
+    "table_list",
+    "temp_store",
+    "user_version",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Denial {
+    pub action: String,
+    pub table: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Denied(Denial),
+    Sqlite(rusqlite::Error),
+}
+
+impl SqlPolicy {
+    pub fn parse(rules: &str) -> Self {
+        let mut policy = SqlPolicy::default();
+        for rule in rules.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            match rule.split_once(':') {
+                Some(("read", table)) => policy.read.push(table.to_string()),
+                Some(("write", table)) => policy.write.push(table.to_string()),
+                None if rule == "ddl" => policy.ddl = true,
+                None if rule == "attach" => policy.attach = true,
+                None if rule == "pragma" => policy.pragma_writes = true,
+                None if rule == "functions" => policy.unsafe_functions = true,
+                None if rule == "all" => {
+                    policy.read.push("*".to_string());
+                    policy.write.push("*".to_string());
+                    policy.ddl = true;
+                    policy.attach = true;
+                    policy.pragma_writes = true;
+                    policy.unsafe_functions = true;
+                }
+                _ => eprintln!("Ignoring unknown SQL policy rule: {}", rule),
+            }
+        }
+        policy
+    }
+
+    // Table-level checks for routes that build their own statements, such as
+    // the generic row routes and CSV transfers
+    pub fn check_read(&self, table: &str) -> Result<(), Denial> {
+        match self.can_read(table) {
+            true => Ok(()),
+            false => Err(Denial { action: "read".to_string(), table: Some(table.to_string()) }),
+        }
+    }
+
+    pub fn check_write(&self, table: &str) -> Result<(), Denial> {
+        match self.can_write(table) {
+            true => Ok(()),
+            false => Err(Denial { action: "write".to_string(), table: Some(table.to_string()) }),
+        }
+    }
+
+    fn can_write(&self, table: &str) -> bool {
+        // DDL writes the schema table before SQLite checks the DDL action
+        // itself, so schema writes stand or fall with the `ddl` rule. Other
+        // internal tables, such as `sqlite_sequence` and `sqlite_stat1`, are
+        // written like any table.
+        if is_schema_table(table) {
+            return self.ddl;
+        }
+        matches_table(&self.write, table)
+    }
+
+    fn can_read(&self, table: &str) -> bool {
+        if is_schema_table(table) && self.ddl {
+            return true;
+        }
+        matches_table(&self.read, table) || matches_table(&self.write, table)
+    }
+
+    fn check(&self, action: &AuthAction) -> Result<(), Denial> {
+        let deny = |action: &str, table: Option<&str>| {
+            Err(Denial {
+                action: action.to_string(),
+                table: table.map(str::to_string),
+            })
+        };
+
+        match *action {
+            AuthAction::Function { function_name }
+                if !self.unsafe_functions && UNSAFE_FUNCTIONS.iter().any(|f| f.eq_ignore_ascii_case(function_name)) =>
+            {
+                deny(&format!("function {}", function_name), None)
+            }
+            AuthAction::Select
+            | AuthAction::Transaction { .. }
+            | AuthAction::Savepoint { .. }
+            | AuthAction::Function { .. }
+            | AuthAction::Recursive => Ok(()),
+            AuthAction::Read { table_name, .. } if !self.can_read(table_name) => deny("read", Some(table_name)),
+            AuthAction::Read { .. } => Ok(()),
+            // Without `ddl`, name the missing rule rather than the schema
+            // table a CREATE statement writes first
+            AuthAction::Insert { table_name } | AuthAction::Update { table_name, .. } | AuthAction::Delete { table_name }
+                if is_schema_table(table_name) && !self.ddl =>
+            {
+                deny("ddl", Some(table_name))
+            }
+            AuthAction::Insert { table_name } if !self.can_write(table_name) => deny("insert", Some(table_name)),
+            AuthAction::Update { table_name, .. } if !self.can_write(table_name) => deny("update", Some(table_name)),
+            AuthAction::Delete { table_name } if !self.can_write(table_name) => deny("delete", Some(table_name)),
+            AuthAction::Insert { .. } | AuthAction::Update { .. } | AuthAction::Delete { .. } => Ok(()),
+            AuthAction::Pragma { pragma_name, pragma_value } if !self.pragma_writes => {
+                let read_only = READ_ONLY_PRAGMAS.iter().any(|p| p.eq_ignore_ascii_case(pragma_name));
+                match pragma_value {
+                    None if read_only => Ok(()),
+                    _ => deny(&format!("pragma {}", pragma_name), None),
+                }
+            }
+            AuthAction::Pragma { .. } => Ok(()),
+            AuthAction::Attach { .. } if !self.attach => deny("attach", None),
+            AuthAction::Detach { .. } if !self.attach => deny("detach", None),
+            AuthAction::Attach { .. } | AuthAction::Detach { .. } => Ok(()),
+            AuthAction::Unknown { .. } => deny("unknown", None),
+            _ if self.ddl => Ok(()),
+            AuthAction::CreateTable { table_name } | AuthAction::CreateTempTable { table_name } => {
+                deny("create table", Some(table_name))
+            }
+            AuthAction::DropTable { table_name } | AuthAction::DropTempTable { table_name } => {
+                deny("drop table", Some(table_name))
+            }
+            AuthAction::AlterTable { table_name, .. } => deny("alter table", Some(table_name)),
+            AuthAction::CreateIndex { table_name, .. } | AuthAction::CreateTempIndex { table_name, .. } => {
+                deny("create index", Some(table_name))
+            }
+            AuthAction::DropIndex { table_name, .. } | AuthAction::DropTempIndex { table_name, .. } => {
+                deny("drop index", Some(table_name))
+            }
+            AuthAction::CreateTrigger { table_name, .. } | AuthAction::CreateTempTrigger { table_name, .. } => {
+                deny("create trigger", Some(table_name))
+            }
+            AuthAction::DropTrigger { table_name, .. } | AuthAction::DropTempTrigger { table_name, .. } => {
+                deny("drop trigger", Some(table_name))
+            }
+            AuthAction::CreateView { view_name } | AuthAction::CreateTempView { view_name } => {
+                deny("create view", Some(view_name))
+            }
+            AuthAction::DropView { view_name } | AuthAction::DropTempView { view_name } => {
+                deny("drop view", Some(view_name))
+            }
+            AuthAction::CreateVtable { table_name, .. } => deny("create virtual table", Some(table_name)),
+            AuthAction::DropVtable { table_name, .. } => deny("drop virtual table", Some(table_name)),
+            AuthAction::Reindex { .. } => deny("reindex", None),
+            AuthAction::Analyze { table_name } => deny("analyze", Some(table_name)),
+            _ => deny("unknown", None),
+        }
+    }
+}
+
+// The schema table under its current and legacy names, main and temp
+fn is_schema_table(table: &str) -> bool {
+    ["sqlite_master", "sqlite_schema", "sqlite_temp_master", "sqlite_temp_schema"]
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(table))
+}
+
+fn matches_table(rules: &[String], table: &str) -> bool {
+    rules.iter().any(|rule| rule == "*" || rule.eq_ignore_ascii_case(table))
+}
+
+// Policies per principal from TINYSQL_SQL_POLICIES (`name=rules;name=rules`).
+// Other authenticated principals get TINYSQL_SQL_DEFAULT_POLICY, which
+// defaults to reads and writes on every table without DDL, ATTACH or PRAGMA
+// assignments. Unauthenticated callers get TINYSQL_SQL_ANONYMOUS_POLICY,
+// which defaults to reads only.
+pub struct SqlPolicies {
+    by_principal: HashMap<String, SqlPolicy>,
+    default: SqlPolicy,
+    anonymous: SqlPolicy,
+}
+
+impl SqlPolicies {
+    pub fn from_env() -> Self {
+        let mut by_principal = HashMap::new();
+        if let Ok(config) = env::var("TINYSQL_SQL_POLICIES") {
+            for entry in config.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+                match entry.split_once('=') {
+                    Some((name, rules)) => {
+                        by_principal.insert(name.trim().to_string(), SqlPolicy::parse(rules));
+                    }
+                    None => eprintln!("Ignoring malformed SQL policy entry: {}", entry),
+                }
+            }
+        }
+        let default = env::var("TINYSQL_SQL_DEFAULT_POLICY").unwrap_or_else(|_| "read:*,write:*".to_string());
+        let anonymous = env::var("TINYSQL_SQL_ANONYMOUS_POLICY").unwrap_or_else(|_| "read:*".to_string());
+        SqlPolicies {
+            by_principal,
+            default: SqlPolicy::parse(&default),
+            anonymous: SqlPolicy::parse(&anonymous),
+        }
+    }
+
+    pub fn for_principal(&self, principal: Option<&Principal>) -> &SqlPolicy {
+        match principal {
+            Some(principal) => self.by_principal.get(&principal.name).unwrap_or(&self.default),
+            None => &self.anonymous,
+        }
+    }
+}
+
+pub fn policies() -> &'static SqlPolicies {
+    static POLICIES: OnceLock<SqlPolicies> = OnceLock::new();
+    POLICIES.get_or_init(SqlPolicies::from_env)
+}
+
+// Run `op` with `policy` installed as the connection's authorizer. The hook
+// is removed again before returning so the connection can be shared.
+pub fn run<T>(
+    conn: &Connection,
+    policy: &SqlPolicy,
+    op: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+) -> Result<T, PolicyError> {
+    let denial: Arc<Mutex<Option<Denial>>> = Arc::new(Mutex::new(None));
+    let hook_policy = policy.clone();
+    let hook_denial = Arc::clone(&denial);
+    conn.authorizer(Some(move |ctx: AuthContext<'_>| match hook_policy.check(&ctx.action) {
+        Ok(()) => Authorization::Allow,
+        Err(d) => {
+            hook_denial.lock().unwrap().get_or_insert(d);
+            Authorization::Deny
+        }
+    }));
+
+    let result = op(conn);
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+
+    let denied = denial.lock().unwrap().take();
+    match (result, denied) {
+        (Err(_), Some(denial)) => Err(PolicyError::Denied(denial)),
+        (Err(e), None) => Err(PolicyError::Sqlite(e)),
+        (Ok(value), _) => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Access;
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE person (name TEXT, age INTEGER); CREATE TABLE secret (value TEXT);")
+            .unwrap();
+        conn
+    }
+
+    fn execute(conn: &Connection, rules: &str, sql: &str) -> Result<(), PolicyError> {
+        run(conn, &SqlPolicy::parse(rules), |conn| conn.execute_batch(sql))
+    }
+
+    fn denied(result: Result<(), PolicyError>) -> Denial {
+        match result {
+            Err(PolicyError::Denied(denial)) => denial,
+            other => panic!("expected a denial, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_need_a_read_or_write_rule() {
+        let conn = database();
+        assert!(execute(&conn, "read:person", "SELECT name FROM person").is_ok());
+        assert!(execute(&conn, "write:person", "SELECT name FROM person").is_ok());
+        let denial = denied(execute(&conn, "read:person", "SELECT value FROM secret"));
+        assert_eq!((denial.action.as_str(), denial.table.as_deref()), ("read", Some("secret")));
+    }
+
+    #[test]
+    fn table_checks_follow_the_same_rules() {
+        let policy = SqlPolicy::parse("read:person,write:log");
+        assert!(policy.check_read("person").is_ok());
+        assert!(policy.check_read("LOG").is_ok());
+        assert!(policy.check_write("log").is_ok());
+        let denial = policy.check_write("person").unwrap_err();
+        assert_eq!((denial.action.as_str(), denial.table.as_deref()), ("write", Some("person")));
+        assert_eq!(policy.check_read("secret").unwrap_err().action, "read");
+    }
+
+    #[test]
+    fn writes_need_a_write_rule() {
+        let conn = database();
+        assert!(execute(&conn, "write:*", "INSERT INTO person VALUES ('ada', 36)").is_ok());
+        assert_eq!(denied(execute(&conn, "read:*", "INSERT INTO person VALUES ('ada', 36)")).action, "insert");
+        assert_eq!(denied(execute(&conn, "read:*", "UPDATE person SET age = 1")).action, "update");
+        assert_eq!(denied(execute(&conn, "read:*", "DELETE FROM person")).action, "delete");
+    }
+
+    #[test]
+    fn ddl_attach_and_pragma_writes_need_their_rules() {
+        let conn = database();
+        let denial = denied(execute(&conn, "read:*,write:*", "CREATE TABLE t (x)"));
+        assert_eq!((denial.action.as_str(), denial.table.as_deref()), ("ddl", Some("sqlite_master")));
+        assert!(execute(&conn, "ddl", "CREATE TABLE t (x)").is_ok());
+        assert_eq!(denied(execute(&conn, "read:*,write:*", "ATTACH ':memory:' AS other")).action, "attach");
+        assert_eq!(denied(execute(&conn, "read:*,write:*", "PRAGMA user_version = 3")).action, "pragma user_version");
+        assert!(execute(&conn, "read:*", "PRAGMA user_version").is_ok());
+    }
+
+    #[test]
+    fn internal_tables_need_a_write_rule() {
+        let conn = database();
+        conn.execute_batch(
+            "CREATE TABLE counter (id INTEGER PRIMARY KEY AUTOINCREMENT); INSERT INTO counter DEFAULT VALUES;
+             CREATE INDEX person_name ON person (name); ANALYZE;",
+        )
+        .unwrap();
+        for sql in ["UPDATE sqlite_sequence SET seq = 0", "DELETE FROM sqlite_sequence", "DELETE FROM sqlite_stat1"] {
+            assert!(matches!(denied(execute(&conn, "read:*", sql)).action.as_str(), "update" | "delete"), "{}", sql);
+            assert!(execute(&conn, "read:*,write:person,ddl", sql).is_err(), "{}", sql);
+            assert!(execute(&conn, "write:*", sql).is_ok(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn only_read_only_pragmas_run_without_the_pragma_rule() {
+        let conn = database();
+        for pragma in ["user_version", "table_list", "PAGE_COUNT", "integrity_check"] {
+            assert!(execute(&conn, "read:*", &format!("PRAGMA {}", pragma)).is_ok(), "{}", pragma);
+        }
+        for pragma in ["wal_checkpoint", "optimize", "incremental_vacuum", "shrink_memory"] {
+            let denial = denied(execute(&conn, "read:*,write:*", &format!("PRAGMA {}", pragma)));
+            assert_eq!(denial.action, format!("pragma {}", pragma));
+            assert!(execute(&conn, "pragma", &format!("PRAGMA {}", pragma)).is_ok(), "{}", pragma);
+        }
+    }
+
+    #[test]
+    fn unsafe_functions_need_their_rule() {
+        let conn = database();
+        let denial = denied(execute(&conn, "read:*,write:*", "SELECT load_extension('x')"));
+        assert_eq!(denial.action, "function load_extension");
+        assert!(execute(&conn, "read:*", "SELECT upper('x'), count(*) FROM person").is_ok());
+    }
+
+    #[test]
+    fn the_authorizer_is_removed_afterwards() {
+        let conn = database();
+        let _ = execute(&conn, "read:person", "SELECT value FROM secret");
+        assert!(conn.execute_batch("SELECT value FROM secret").is_ok());
+    }
+
+    #[test]
+    fn anonymous_callers_default_to_read_only() {
+        let policies = SqlPolicies {
+            by_principal: HashMap::from([("ops".to_string(), SqlPolicy::parse("all"))]),
+            default: SqlPolicy::parse("read:*,write:*"),
+            anonymous: SqlPolicy::parse("read:*"),
+        };
+        let principal = |name: &str| Principal { name: name.to_string(), access: Access::ReadWrite, expiry: None };
+        let conn = database();
+        let insert = "INSERT INTO person VALUES ('ada', 36)";
+        let anonymous = policies.for_principal(None);
+        assert!(matches!(run(&conn, anonymous, |c| c.execute_batch(insert)), Err(PolicyError::Denied(_))));
+        assert!(run(&conn, policies.for_principal(Some(&principal("ci"))), |c| c.execute_batch(insert)).is_ok());
+        assert!(policies.for_principal(Some(&principal("ops"))).ddl);
+    }
+}
@@ -0,0 +1,131 @@
+use async_std::channel::{bounded, Receiver, Sender};
+use async_std::io::{Read as AsyncRead, ReadExt};
+use async_std::stream::Stream;
+use async_std::task;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Chunks in flight between a blocking producer and the response body. Small,
+// so a slow client pauses the producer instead of buffering the whole body.
+const CHANNEL_CHUNKS: usize = 16;
+const CHUNK_BYTES: usize = 16 * 1024;
+
+// A byte pipe from a blocking writer (e.g. a SQLite export on its own thread)
+// to a reader that is either blocking (tiny_http) or async (tide). An error
+// sent by the writer surfaces on the reader, so a failed stream is cut off
+// rather than ending as if it were complete.
+pub fn pipe() -> (ChannelWriter, ChannelReader) {
+    let (tx, rx) = bounded(CHANNEL_CHUNKS);
+    (
+        ChannelWriter { tx, buf: Vec::with_capacity(CHUNK_BYTES) },
+        ChannelReader { rx, chunk: Vec::new(), pos: 0 },
+    )
+}
+
+pub struct ChannelWriter {
+    tx: Sender<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+impl ChannelWriter {
+    pub fn fail(mut self, err: io::Error) {
+        self.buf.clear();
+        let _ = self.tx.send_blocking(Err(err));
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_BYTES));
+        self.tx
+            .send_blocking(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "reader went away"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_BYTES {
+            self.send()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        let _ = self.send();
+    }
+}
+
+pub struct ChannelReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    fn copy_out(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        n
+    }
+
+    fn refill(&mut self, next: Option<io::Result<Vec<u8>>>) -> io::Result<bool> {
+        match next {
+            Some(Ok(chunk)) => {
+                self.chunk = chunk;
+                self.pos = 0;
+                Ok(true)
+            }
+            Some(Err(e)) => Err(e),
+            None => Ok(false),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if !self.refill(self.rx.recv_blocking().ok())? {
+                return Ok(0);
+            }
+        }
+        Ok(self.copy_out(buf))
+    }
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.pos == this.chunk.len() {
+            let next = match Pin::new(&mut this.rx).poll_next(cx) {
+                Poll::Ready(next) => next,
+                Poll::Pending => return Poll::Pending,
+            };
+            match this.refill(next) {
+                Ok(true) => {}
+                Ok(false) => return Poll::Ready(Ok(0)),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(this.copy_out(buf)))
+    }
+}
+
+// Blocking view of an async body, for consuming it from `spawn_blocking`
+pub struct BlockingReader<R>(pub R);
+
+impl<R: AsyncRead + Unpin> Read for BlockingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        task::block_on(self.0.read(buf))
+    }
+}
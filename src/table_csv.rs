@@ -0,0 +1,291 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{params_from_iter, Connection, Transaction, TransactionBehavior};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::json;
+use std::io::{Read, Write};
+
+use crate::errors::ErrorBody;
+use crate::retry;
+use crate::schema::{self, quote_identifier, Affinity, ColumnInfo};
+use crate::sql_policy::{Denial, SqlPolicy};
+
+// Imports stop collecting errors once this many lines have failed
+const MAX_REPORTED_ERRORS: usize = 100;
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct LineError {
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ImportReport {
+    pub table: String,
+    pub imported: u64,
+    pub errors: Vec<LineError>,
+}
+
+#[derive(Debug)]
+pub enum TableError {
+    NotFound(String),
+    // The header row doesn't match the table
+    BadHeader(String),
+    // Some rows failed; nothing was written
+    Rejected(ImportReport),
+    // The input or output stream failed, e.g. a body limit tripped
+    Stream(csv::Error),
+    // The caller's SQL policy doesn't cover the table
+    Denied(Denial),
+    Sqlite(rusqlite::Error),
+}
+
+impl TableError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            TableError::NotFound(_) => 404,
+            TableError::BadHeader(_) | TableError::Stream(_) => 400,
+            TableError::Rejected(_) => 422,
+            TableError::Denied(_) => 403,
+            TableError::Sqlite(e) => retry::status_code(e),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        matches!(self, TableError::Sqlite(e) if retry::is_busy(e))
+    }
+
+    // Response body: the report for rejected imports, an error otherwise
+    pub fn body(&self) -> serde_json::Value {
+        match self {
+            TableError::Rejected(report) => json!(report),
+            TableError::NotFound(table) => json!(ErrorBody::new(format!("No such table: {}", table))),
+            TableError::BadHeader(message) => json!(ErrorBody::new(format!("Invalid header row: {}", message))),
+            TableError::Stream(e) => json!(ErrorBody::new(e.to_string())),
+            TableError::Denied(denial) => {
+                json!({ "error": "Operation not permitted", "action": denial.action, "table": denial.table })
+            }
+            TableError::Sqlite(e) if retry::is_busy(e) => json!(ErrorBody::new("Database is busy, retry later")),
+            TableError::Sqlite(e) => {
+                eprintln!("Table operation failed: {}", e);
+                json!(ErrorBody::new("Table operation failed"))
+            }
+        }
+    }
+}
+
+impl From<Denial> for TableError {
+    fn from(denial: Denial) -> Self {
+        TableError::Denied(denial)
+    }
+}
+
+impl From<rusqlite::Error> for TableError {
+    fn from(err: rusqlite::Error) -> Self {
+        TableError::Sqlite(err)
+    }
+}
+
+// `,` by default; `tab`, `\t` or any single ASCII character otherwise
+pub fn parse_delimiter(value: Option<&str>) -> Option<u8> {
+    match value {
+        None | Some("") => Some(b','),
+        Some("tab") | Some("\\t") | Some("\t") => Some(b'\t'),
+        Some(d) if d.len() == 1 && d.is_ascii() => Some(d.as_bytes()[0]),
+        _ => None,
+    }
+}
+
+// `Content-Disposition` file name for an export, safe to put in quotes
+pub fn export_filename(table: &str) -> String {
+    let stem: String = table
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect();
+    format!("{}.csv", stem)
+}
+
+// Imports take raw CSV. Bodies without a type, or sent as forms or plain
+// text by tools like `curl --data-binary`, are read as CSV too.
+pub fn is_csv_content_type(content_type: Option<&str>) -> bool {
+    let media_type = content_type.and_then(|ct| ct.split(';').next()).unwrap_or("").trim();
+    matches!(
+        media_type.to_ascii_lowercase().as_str(),
+        "" | "text/csv"
+            | "text/plain"
+            | "text/tab-separated-values"
+            | "application/octet-stream"
+            | "application/x-www-form-urlencoded"
+    )
+}
+
+// Columns of a user table
+fn columns(conn: &Connection, table: &str) -> Result<Vec<ColumnInfo>, TableError> {
+    match schema::schema(conn)?.table(table) {
+        Some(info) => Ok(info.columns.clone()),
+        None => Err(TableError::NotFound(table.to_string())),
+    }
+}
+
+// Checks an import or export up front, before any of the body is read or
+// the response is started
+pub fn check_import(conn: &Connection, policy: &SqlPolicy, table: &str) -> Result<(), TableError> {
+    policy.check_write(table)?;
+    columns(conn, table).map(|_| ())
+}
+
+pub fn check_export(conn: &Connection, policy: &SqlPolicy, table: &str) -> Result<(), TableError> {
+    policy.check_read(table)?;
+    columns(conn, table).map(|_| ())
+}
+
+fn coerce(cell: &str, column: &ColumnInfo) -> Result<Value, String> {
+    if cell.is_empty() {
+        return Ok(Value::Null);
+    }
+    let invalid = |kind: &str| format!("expected {} for column {}, got {:?}", kind, column.name, cell);
+    match column.affinity {
+        Affinity::Integer => cell.trim().parse().map(Value::Integer).map_err(|_| invalid("an integer")),
+        Affinity::Real => cell.trim().parse().map(Value::Real).map_err(|_| invalid("a number")),
+        Affinity::Numeric => {
+            let cell = cell.trim();
+            cell.parse()
+                .map(Value::Integer)
+                .or_else(|_| cell.parse().map(Value::Real))
+                .map_err(|_| invalid("a number"))
+        }
+        // Untyped columns store cells as given
+        Affinity::Text | Affinity::Blob => Ok(Value::Text(cell.to_string())),
+    }
+}
+
+// Streams CSV rows from `input` into `table` in one transaction. The header
+// row names the columns to fill. Any bad row rolls the whole import back and
+// the report lists the failing lines.
+pub fn import<R: Read>(
+    conn: &Connection,
+    policy: &SqlPolicy,
+    table: &str,
+    input: R,
+    delimiter: u8,
+) -> Result<ImportReport, TableError> {
+    policy.check_write(table)?;
+    let table_columns = columns(conn, table)?;
+    let mut reader = csv::ReaderBuilder::new().delimiter(delimiter).from_reader(input);
+
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) if e.is_io_error() => return Err(TableError::Stream(e)),
+        Err(e) => return Err(TableError::BadHeader(e.to_string())),
+    };
+    let mut targets: Vec<&ColumnInfo> = Vec::new();
+    for header in headers.iter() {
+        let column = table_columns
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(header.trim()))
+            .ok_or_else(|| TableError::BadHeader(format!("unknown column {:?}", header)))?;
+        if targets.iter().any(|t| t.name == column.name) {
+            return Err(TableError::BadHeader(format!("duplicate column {:?}", header)));
+        }
+        targets.push(column);
+    }
+    if targets.is_empty() {
+        return Err(TableError::BadHeader("empty header row".to_string()));
+    }
+
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        quote_identifier(table),
+        targets.iter().map(|c| quote_identifier(&c.name)).collect::<Vec<_>>().join(", "),
+        vec!["?"; targets.len()].join(", "),
+    );
+    // Take the write lock up front rather than failing halfway through
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let mut report = ImportReport {
+        table: table.to_string(),
+        imported: 0,
+        errors: Vec::new(),
+    };
+    {
+        let mut insert = tx.prepare(&sql)?;
+        for record in reader.records() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) if e.is_io_error() => return Err(TableError::Stream(e)),
+                Err(e) => {
+                    let line = e.position().map_or(0, |p| p.line());
+                    report.errors.push(LineError { line, message: e.to_string() });
+                    if report.errors.len() >= MAX_REPORTED_ERRORS {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let line = record.position().map_or(0, |p| p.line());
+            let values: Result<Vec<Value>, String> =
+                record.iter().zip(&targets).map(|(cell, column)| coerce(cell, column)).collect();
+            let result = values.and_then(|values| insert.execute(params_from_iter(values)).map_err(|e| e.to_string()));
+            match result {
+                Ok(_) => report.imported += 1,
+                Err(message) => {
+                    report.errors.push(LineError { line, message });
+                    if report.errors.len() >= MAX_REPORTED_ERRORS {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    if report.errors.is_empty() {
+        tx.commit()?;
+        Ok(report)
+    } else {
+        report.imported = 0;
+        Err(TableError::Rejected(report))
+    }
+}
+
+// Streams every row of `table` to `output` as CSV with a header row. NULL is
+// written as an empty cell and blobs as base64.
+pub fn export<W: Write>(
+    conn: &Connection,
+    policy: &SqlPolicy,
+    table: &str,
+    output: W,
+    delimiter: u8,
+) -> Result<u64, TableError> {
+    policy.check_read(table)?;
+    let table_columns = columns(conn, table)?;
+    let mut writer = csv::WriterBuilder::new().delimiter(delimiter).from_writer(output);
+    writer
+        .write_record(table_columns.iter().map(|c| c.name.as_str()))
+        .map_err(TableError::Stream)?;
+
+    let sql = format!(
+        "SELECT {} FROM {}",
+        table_columns.iter().map(|c| quote_identifier(&c.name)).collect::<Vec<_>>().join(", "),
+        quote_identifier(table),
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query([])?;
+    let mut exported = 0;
+    while let Some(row) = rows.next()? {
+        let mut record = Vec::with_capacity(table_columns.len());
+        for i in 0..table_columns.len() {
+            record.push(match row.get_ref(i)? {
+                ValueRef::Null => String::new(),
+                ValueRef::Integer(n) => n.to_string(),
+                ValueRef::Real(n) => n.to_string(),
+                ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+                ValueRef::Blob(blob) => BASE64.encode(blob),
+            });
+        }
+        writer.write_record(&record).map_err(TableError::Stream)?;
+        exported += 1;
+    }
+    writer.flush().map_err(|e| TableError::Stream(e.into()))?;
+    Ok(exported)
+}
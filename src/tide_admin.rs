@@ -0,0 +1,153 @@
+use async_std::io::BufReader;
+use async_std::task;
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use serde::Deserialize;
+use std::fs;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::backup::{self, BackupError};
+use crate::errors::ErrorBody;
+use crate::limits::{limits, BodyError, LimitedReader};
+use crate::retry;
+use crate::stream_io::BlockingReader;
+use crate::tide_format;
+use crate::tide_limits;
+
+#[derive(Deserialize)]
+struct BackupQuery {
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RestoreQuery {
+    path: Option<String>,
+    schema_version: Option<i64>,
+}
+
+// `POST /admin/backup[?path=<file name>]`. With a path the backup is kept in
+// the backup directory and a report is returned; without one the snapshot
+// itself is the response body.
+pub async fn backup<State>(req: Request<State>, db_path: &'static str) -> tide::Result {
+    let format = tide_format::format(&req);
+    let Ok(query) = req.query::<BackupQuery>() else {
+        return error_response(&req, &BackupError::InvalidPath(String::new()));
+    };
+
+    let keep = query.path.is_some();
+    let dest = match query.path {
+        Some(name) => match backup::destination(&name) {
+            Ok(dest) => dest,
+            Err(e) => return error_response(&req, &e),
+        },
+        None => backup::scratch_path(),
+    };
+    let result = task::spawn_blocking({
+        let dest = dest.clone();
+        move || backup::run_from_path(db_path, &dest, keep)
+    })
+    .await;
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => return error_response(&req, &e),
+    };
+    if keep {
+        return tide_format::response(format, &report);
+    }
+
+    // Unlinked once open, so the scratch file goes away with the response
+    let file = async_std::fs::File::open(&dest).await?;
+    let _ = fs::remove_file(&dest);
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_body(Body::from_reader(BufReader::new(file), Some(report.bytes as usize)));
+    response.set_content_type("application/vnd.sqlite3");
+    response.insert_header(
+        "Content-Disposition",
+        format!("attachment; filename=\"backup-{}.db\"", report.started_at),
+    );
+    response.insert_header("X-Backup-Pages", report.pages.to_string());
+    response.insert_header("X-Backup-Steps", report.steps.to_string());
+    response.insert_header("X-Backup-Duration-Ms", report.duration_ms.to_string());
+    Ok(response)
+}
+
+// `GET /admin/backup`: progress of a running backup and the last outcome
+pub async fn backup_status<State>(req: Request<State>) -> tide::Result {
+    tide_format::response(tide_format::format(&req), &backup::current_status())
+}
+
+// `POST /admin/restore[?path=<file name>][&schema_version=<n>]`. Restores
+// from a kept backup, or from the snapshot uploaded as the body. Every pooled
+// connection is checked out while the database is swapped, so in-flight
+// requests finish first and new ones wait until it is done.
+pub async fn restore<State>(
+    mut req: Request<State>,
+    pool: Pool<SqliteConnectionManager>,
+    db_path: &'static str,
+) -> tide::Result {
+    let format = tide_format::format(&req);
+    let Ok(query) = req.query::<RestoreQuery>() else {
+        let mut response = tide_format::response(format, &ErrorBody::new("Invalid query string"))?;
+        response.set_status(StatusCode::BadRequest);
+        return Ok(response);
+    };
+
+    let kept = match query.path.as_deref().map(backup::existing) {
+        Some(Ok(path)) => Some(path),
+        Some(Err(e)) => return error_response(&req, &e),
+        None => None,
+    };
+    if kept.is_none() {
+        if !backup::is_snapshot_content_type(req.header("Content-Type").map(|h| h.as_str())) {
+            return tide_limits::body_error_response(format, &BodyError::UnsupportedMediaType);
+        }
+        if req.len().is_some_and(|len| len as u64 > limits().max_import_bytes) {
+            return tide_limits::body_error_response(format, &BodyError::TooLarge);
+        }
+    }
+
+    let body = req.take_body();
+    let (result, body_error) = task::spawn_blocking(move || {
+        let keep_path = kept.is_some();
+        let snapshot = match kept {
+            Some(path) => path,
+            None => {
+                let limits = limits();
+                let mut input = LimitedReader::new(BlockingReader(body), limits.max_import_bytes, limits.import_timeout);
+                match backup::save_upload(&mut input) {
+                    Ok(path) => path,
+                    Err(e) => return (Err(e), input.take_error()),
+                }
+            }
+        };
+        let result = quiesce(&pool).and_then(|_held| backup::restore(db_path, &snapshot, query.schema_version));
+        if !keep_path {
+            let _ = fs::remove_file(&snapshot);
+        }
+        (result, None)
+    })
+    .await;
+
+    if let Some(e) = body_error {
+        return tide_limits::body_error_response(format, &e);
+    }
+    match result {
+        Ok(report) => tide_format::response(format, &report),
+        Err(e) => error_response(&req, &e),
+    }
+}
+
+// Checks out every connection in the pool, waiting for in-flight requests to
+// return theirs. Serving resumes when the returned guards are dropped.
+fn quiesce(
+    pool: &Pool<SqliteConnectionManager>,
+) -> Result<Vec<PooledConnection<SqliteConnectionManager>>, BackupError> {
+    let timeout = backup::config().restore_timeout;
+    (0..pool.max_size())
+        .map(|_| pool.get_timeout(timeout).map_err(|_| BackupError::Busy))
+        .collect()
+}
+
+fn error_response<State>(req: &Request<State>, err: &BackupError) -> tide::Result {
+    retry::tide_response(tide_format::format(req), err.status_code(), &err.body(), err.is_busy())
+}
@@ -0,0 +1,56 @@
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::auth::{self, auth, AuthError, Credentials, Principal};
+
+// Rejects unauthenticated requests and stores the `Principal` as a request
+// extension, so handlers can read it with `req.ext::<Principal>()`.
+pub struct AuthMiddleware;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AuthMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let api_key = req.header("X-API-Key").map(|h| h.as_str().to_string());
+        let authorization = req.header("Authorization").map(|h| h.as_str().to_string());
+        let credentials = Credentials {
+            api_key: api_key.as_deref(),
+            authorization: authorization.as_deref(),
+        };
+        let is_write = auth::is_write(req.method().as_ref());
+
+        match auth().authenticate(&credentials, is_write) {
+            Ok(Some(principal)) => {
+                println!("{} {} by {}", req.method(), req.url().path(), principal);
+                req.set_ext::<Principal>(principal);
+                Ok(next.run(req).await)
+            }
+            Ok(None) => Ok(next.run(req).await),
+            Err(e) => Ok(rejection(&req, &e)),
+        }
+    }
+}
+
+// Put on the admin routes after `AuthMiddleware` has run: only admin
+// principals get through, everyone else gets a 403
+pub struct AdminMiddleware;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AdminMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        match auth().authorize_admin(req.ext::<Principal>()) {
+            Ok(()) => Ok(next.run(req).await),
+            Err(e) => Ok(rejection(&req, &e)),
+        }
+    }
+}
+
+fn rejection<State>(req: &Request<State>, e: &AuthError) -> Response {
+    eprintln!("Rejected {} {}: {}", req.method(), req.url().path(), e.message());
+    let mut response = Response::new(StatusCode::try_from(e.status_code()).unwrap());
+    if e.status_code() == 401 {
+        response.insert_header("WWW-Authenticate", "Bearer");
+    }
+    response.set_body(e.message());
+    response.set_content_type("text/plain");
+    response
+}
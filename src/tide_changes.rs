@@ -0,0 +1,57 @@
+use async_std::future;
+use serde::Deserialize;
+use tide::sse::Sender;
+use tide::Request;
+
+use crate::auth::Principal;
+use crate::changes::{self, Change};
+use crate::sql_policy;
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    // Comma-separated table names; every watched table when absent
+    tables: Option<String>,
+    // For clients that cannot set `Last-Event-ID`
+    last_event_id: Option<String>,
+}
+
+// `GET /changes`, an SSE stream of committed changes. Each change is a
+// `message` event carrying its id; a client reconnecting with
+// `Last-Event-ID` first gets what it missed. When that is no longer known a
+// `reset` event tells it to reload. Idle streams get a `ping` event every
+// keep-alive interval. Changes to tables the caller's SQL policy does not
+// let it read are left out.
+pub async fn stream<State>(req: Request<State>, sender: Sender) -> tide::Result<()> {
+    let query: ChangesQuery = req.query()?;
+    let tables: Vec<String> = query
+        .tables
+        .map(|tables| tables.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect())
+        .unwrap_or_default();
+    let policy = sql_policy::policies().for_principal(req.ext::<Principal>());
+    let wanted = |change: &Change| change.is_for(&tables, policy);
+
+    let last_event_id = req.header("Last-Event-ID").map(|h| h.as_str().to_string()).or(query.last_event_id);
+    let subscription = changes::subscribe(last_event_id.as_deref());
+    if subscription.reset {
+        sender.send("reset", "{}", Some(&subscription.latest_id)).await?;
+    }
+    for change in subscription.missed.iter().filter(|change| wanted(change)) {
+        send(&sender, change).await?;
+    }
+
+    let keepalive = changes::config().keepalive;
+    loop {
+        match future::timeout(keepalive, subscription.receiver.recv()).await {
+            Ok(Ok(change)) if wanted(&change) => send(&sender, &change).await?,
+            Ok(Ok(_)) => {}
+            // Dropped for falling behind; the client resumes on reconnect
+            Ok(Err(_)) => return Ok(()),
+            Err(_) => sender.send("ping", "{}", None).await?,
+        }
+    }
+}
+
+async fn send(sender: &Sender, change: &Change) -> tide::Result<()> {
+    sender.send("message", serde_json::to_string(change)?, Some(&change.id)).await?;
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use tide::http::Method;
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::cors::{cors, is_preflight};
+
+// Must be registered before `AuthMiddleware`: browsers send preflights
+// without credentials, and rejections still need CORS headers to be readable.
+pub struct CorsMiddleware;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CorsMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let Some(config) = cors() else {
+            return Ok(next.run(req).await);
+        };
+        let origin = req.header("Origin").map(|h| h.as_str().to_string());
+        let preflight = is_preflight(
+            req.method() == Method::Options,
+            req.header("Access-Control-Request-Method").map(|h| h.as_str()),
+        );
+
+        let mut response = if preflight {
+            Response::new(StatusCode::NoContent)
+        } else {
+            next.run(req).await
+        };
+        for (name, value) in config.headers(origin.as_deref(), preflight) {
+            response.insert_header(name, value);
+        }
+        Ok(response)
+    }
+}
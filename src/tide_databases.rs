@@ -0,0 +1,98 @@
+use async_std::task;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::databases::{self, DatabaseError, NewDatabase};
+use crate::retry;
+use crate::tide_format;
+use crate::tide_limits;
+use crate::tide_url;
+
+// Database management routes, for admin principals only:
+//   GET    /databases          list
+//   POST   /databases          create, `{"name": ...}`
+//   GET    /databases/:name    inspect
+//   DELETE /databases/:name    delete
+
+// The database named by a route's `:db` parameter
+#[derive(Clone)]
+struct NamedDatabase(databases::Handle);
+
+// Resolves `/db/:db/...` routes to their database before the handler runs,
+// answering 404 for unknown ones. Handlers pick it up with `pool`.
+pub struct DatabaseMiddleware;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for DatabaseMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if req.param("db").is_err() {
+            return Ok(next.run(req).await);
+        }
+        let name = tide_url::param(&req, "db")?;
+        match databases::pool(&name) {
+            Ok(handle) => {
+                req.set_ext(NamedDatabase(handle));
+                Ok(next.run(req).await)
+            }
+            Err(e) => error_response(&req, &e),
+        }
+    }
+}
+
+// The named database's pool under `/db/:db`, otherwise `default`
+pub fn pool<State>(req: &Request<State>, default: &Pool<SqliteConnectionManager>) -> Pool<SqliteConnectionManager> {
+    req.ext::<NamedDatabase>().map_or_else(|| default.clone(), |named| named.0.pool.clone())
+}
+
+pub fn is_named<State>(req: &Request<State>) -> bool {
+    req.ext::<NamedDatabase>().is_some()
+}
+
+// The handle on a named database, for work that outlives the request
+pub fn handle<State>(req: &Request<State>) -> Option<databases::Handle> {
+    req.ext::<NamedDatabase>().map(|named| named.0.clone())
+}
+
+pub async fn list<State>(req: Request<State>) -> tide::Result {
+    match task::spawn_blocking(databases::list).await {
+        Ok(databases) => tide_format::response(tide_format::format(&req), &databases),
+        Err(e) => error_response(&req, &e),
+    }
+}
+
+pub async fn create<State>(mut req: Request<State>) -> tide::Result {
+    let new: NewDatabase = match tide_format::read(&mut req).await {
+        Ok(new) => new,
+        Err(e) => return tide_limits::body_error_response(tide_format::format(&req), &e),
+    };
+    match task::spawn_blocking(move || databases::create(&new.name)).await {
+        Ok(info) => {
+            let mut response = tide_format::response(tide_format::format(&req), &info)?;
+            response.set_status(StatusCode::Created);
+            Ok(response)
+        }
+        Err(e) => error_response(&req, &e),
+    }
+}
+
+pub async fn inspect<State>(req: Request<State>) -> tide::Result {
+    let name = tide_url::param(&req, "name")?;
+    match task::spawn_blocking(move || databases::inspect(&name)).await {
+        Ok(info) => tide_format::response(tide_format::format(&req), &info),
+        Err(e) => error_response(&req, &e),
+    }
+}
+
+pub async fn delete<State>(req: Request<State>) -> tide::Result {
+    let name = tide_url::param(&req, "name")?;
+    match task::spawn_blocking(move || databases::delete(&name)).await {
+        Ok(()) => Ok(Response::new(StatusCode::NoContent)),
+        Err(e) => error_response(&req, &e),
+    }
+}
+
+fn error_response<State>(req: &Request<State>, err: &DatabaseError) -> tide::Result {
+    retry::tide_response(tide_format::format(req), err.status_code(), &err.body(), err.is_busy())
+}
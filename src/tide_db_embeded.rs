@@ -1,15 +1,29 @@
-use rusqlite::Connection;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
 use std::time::Instant;
-use tide::{Request, Response, StatusCode};
+use tide::Request;
 
-#[derive(Debug, Deserialize, Serialize)]  // Add Serialize here
+use crate::config;
+use crate::db_strategy::{self, spawn_with_connection, DbStrategy};
+use crate::limits::BodyError;
+use crate::memory;
+use crate::openapi::{ApiSpec, Operation, RouteGroup, SpecCache};
+use crate::tide_auth::AuthMiddleware;
+use crate::tide_cors::CorsMiddleware;
+use crate::tide_format::{self, FormatMiddleware};
+use crate::tide_rate_limit::RateLimitMiddleware;
+use crate::tide_limits;
+use crate::tide_openapi;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]  // Add Serialize here
 struct RequestData {
     key: String,
     value: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 struct ResponseData {
     message: String,
     status: String,
@@ -17,35 +31,42 @@ struct ResponseData {
     received_data: Option<RequestData>,
 }
 
+type State = Arc<dyn DbStrategy>;
+
+// tide, opening a connection per request
 pub async fn tide_embedded() {
-    let mut app = tide::new();
+    let strategy = db_strategy::open("per_request", memory::database("tide_embedded", "db/my_database.sqlite")).unwrap();
+
+    let mut app = tide::with_state(State::from(strategy));
+    app.with(CorsMiddleware);
+    app.with(AuthMiddleware);
+    app.with(RateLimitMiddleware);
+    app.with(FormatMiddleware);
     app.at("/").post(handle_request);
+    tide_openapi::serve(&mut app, api_spec);
 
-    println!("Listening on http://0.0.0.0:8000/");
+    let addr = config::listen_addr("0.0.0.0:8000");
 
-    app.listen("0.0.0.0:8000").await.unwrap();
+    tide_limits::listen(app, &addr).await.unwrap();
 }
 
-async fn handle_request(mut req: Request<()>) -> tide::Result {
+async fn handle_request(mut req: Request<State>) -> tide::Result {
     let start = Instant::now();
 
-    // Parse JSON from request body
-    let request_data: Result<RequestData, tide::Error> = req.body_json().await;
-
-    let received_data = match request_data {
+    // Parse the request body in its Content-Type
+    let received_data = match tide_format::read::<RequestData, _>(&mut req).await {
         Ok(data) => Some(data),
+        Err(e @ (BodyError::TooLarge | BodyError::TimedOut | BodyError::UnsupportedMediaType)) => {
+            return tide_limits::body_error_response(tide_format::format(&req), &e);
+        }
         Err(_) => None,
     };
 
-    // Open a connection to SQLite
-    let sqlite_status = match Connection::open("db/my_database.sqlite") {
-        Ok(conn) => {
-            // Close the connection by letting it go out of scope
-            drop(conn);
-            "Connection opened and closed successfully"
-        }
+    // Open a connection to SQLite; it closes when the job returns
+    let sqlite_status = match spawn_with_connection(Arc::clone(req.state()), |_| Ok(())).await {
+        Ok(()) => "Connection opened and closed successfully",
         Err(e) => {
-            eprintln!("Failed to open SQLite connection: {}", e);
+            eprintln!("Failed to open SQLite connection: {}", e.message());
             "Failed to open SQLite connection"
         }
     };
@@ -53,7 +74,7 @@ async fn handle_request(mut req: Request<()>) -> tide::Result {
     let duration = start.elapsed();
     println!("Time taken to open and close SQLite connection: {:?}", duration);
 
-    // Create a JSON response
+    // Create a response in the negotiated format
     let response_data = ResponseData {
         message: String::from("SQLite connection open/close measured"),
         status: sqlite_status.to_string(),
@@ -61,10 +82,22 @@ async fn handle_request(mut req: Request<()>) -> tide::Result {
         received_data,
     };
 
-    let response_body = serde_json::to_string(&response_data)?;
-    let mut response = Response::new(StatusCode::Ok);
-    response.set_body(response_body);
-    response.set_content_type("application/json");
+    tide_format::response(tide_format::format(&req), &response_data)
+}
+
+// Documents the routes registered in `tide_embedded`
+fn api_spec(_req: &Request<State>) -> Arc<Value> {
+    static SPEC: SpecCache = SpecCache::new();
+    SPEC.get(0, build_spec)
+}
 
-    Ok(response)
+fn build_spec() -> Value {
+    let mut spec = ApiSpec::new("tinysql embedded (tide)");
+    spec.add(
+        Operation::new("post", "/", "Measure opening and closing an SQLite connection")
+            .body(spec.schema::<RequestData>())
+            .response(200, "Timing; `received_data` is null when the body doesn't parse", spec.schema::<ResponseData>()),
+    );
+    RouteGroup::Docs.document(&mut spec, None);
+    spec.document()
 }
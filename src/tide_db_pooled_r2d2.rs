@@ -1,16 +1,29 @@
-use tide::{Request, Response, Result};
+use tide::{Request, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use r2d2::{Pool};
-use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::Value;
+use std::sync::Arc;
 use std::time::Instant;
 
-#[derive(Serialize, Deserialize)]
+use crate::config;
+use crate::db_strategy::{self, spawn_with_connection, DbStrategy, StrategyError};
+use crate::limits::BodyError;
+use crate::memory;
+use crate::openapi::{ApiSpec, Operation, RouteGroup, SpecCache};
+use crate::tide_auth::AuthMiddleware;
+use crate::tide_cors::CorsMiddleware;
+use crate::tide_format::{self, FormatMiddleware};
+use crate::tide_rate_limit::RateLimitMiddleware;
+use crate::tide_limits;
+use crate::tide_openapi;
+
+#[derive(Serialize, Deserialize, JsonSchema)]
 struct RequestData {
     key: String,
     value: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 struct ResponseData {
     message: String,
     status: String,
@@ -18,47 +31,43 @@ struct ResponseData {
     received_data: Option<RequestData>,
 }
 
-#[derive(Clone)]
-struct State {
-    pool: Pool<SqliteConnectionManager>,
-}
+type State = Arc<dyn DbStrategy>;
 
 async fn handle_request(mut req: Request<State>) -> Result {
     let start = Instant::now();
 
-    // Parse JSON from the request body
-    let request_data: Result<RequestData> = req.body_json().await;
+    // Parse the request body in its Content-Type
+    let request_data: std::result::Result<RequestData, BodyError> = tide_format::read(&mut req).await;
 
     let received_data = match request_data {
         Ok(data) => Some(data),
+        Err(e @ (BodyError::TooLarge | BodyError::TimedOut | BodyError::UnsupportedMediaType)) => {
+            return tide_limits::body_error_response(tide_format::format(&req), &e);
+        }
         Err(e) => {
-            eprintln!("Failed to parse JSON request: {}", e);
+            eprintln!("Failed to parse request body: {}", e);
             None
         }
     };
 
-    // Get the connection pool from the state
-    let pool = req.state().pool.clone();
-    let sqlite_status = match pool.get() {
-        Ok(conn) => {
-            match conn.execute("SELECT 1", []) {
-                Ok(_) => "Connection opened and query executed successfully".to_string(),
-                Err(e) => {
-                    eprintln!("Failed to execute SQLite query: {}", e);
-                    "Failed to execute SQLite query".to_string()
-                }
-            }
+    // Run the query on a pooled connection
+    let query = |conn: &rusqlite::Connection| conn.query_row("SELECT 1", [], |_| Ok(()));
+    let sqlite_status = match spawn_with_connection(Arc::clone(req.state()), query).await {
+        Ok(()) => "Connection opened and query executed successfully".to_string(),
+        Err(e @ StrategyError::Pool(_)) => {
+            eprintln!("Failed to get connection from pool: {}", e.message());
+            "Failed to get connection from pool".to_string()
         }
         Err(e) => {
-            eprintln!("Failed to get connection from pool: {}", e);
-            "Failed to get connection from pool".to_string()
+            eprintln!("Failed to execute SQLite query: {}", e.message());
+            "Failed to execute SQLite query".to_string()
         }
     };
 
     let duration = start.elapsed();
     println!("Time taken to execute SQLite query: {:?}", duration);
 
-    // Create a JSON response
+    // Create a response in the negotiated format
     let response_data = ResponseData {
         message: "SQLite connection open/close measured".to_string(),
         status: sqlite_status,
@@ -66,26 +75,42 @@ async fn handle_request(mut req: Request<State>) -> Result {
         received_data,
     };
 
-    let response_body = serde_json::to_string(&response_data)?;
-    let mut response = Response::new(200);
-    response.set_body(response_body);
-    response.insert_header("Content-Type", "application/json");
-
-    Ok(response)
+    tide_format::response(tide_format::format(&req), &response_data)
 }
 
+// tide, with connections from an r2d2 pool
 pub async fn tide_pooled_db() {
-    // Set up the SQLite connection manager and pool
-    let manager = SqliteConnectionManager::file("my_database.db");
-    let pool = Pool::new(manager).expect("Failed to create pool.");
+    let strategy = db_strategy::open("pool", memory::database("tide_pooled_db", "my_database.db")).expect("Failed to create pool.");
 
-    let mut app = tide::with_state(State { pool });
+    let mut app = tide::with_state(State::from(strategy));
+    app.with(CorsMiddleware);
+    app.with(AuthMiddleware);
+    app.with(RateLimitMiddleware);
+    app.with(FormatMiddleware);
 
     // Define a route that handles all incoming requests
     app.at("/").all(|req: Request<State>| async move {
         handle_request(req).await
     });
+    tide_openapi::serve(&mut app, api_spec);
+
+    let addr = config::listen_addr("0.0.0.0:8081");
+    tide_limits::listen(app, &addr).await.unwrap();
+}
+
+// Documents the routes registered in `tide_pooled_db`
+fn api_spec(_req: &Request<State>) -> Arc<Value> {
+    static SPEC: SpecCache = SpecCache::new();
+    SPEC.get(0, build_spec)
+}
 
-    println!("Listening on http://0.0.0.0:8081/");
-    app.listen("0.0.0.0:8081").await.unwrap();
+fn build_spec() -> Value {
+    let mut spec = ApiSpec::new("tinysql pooled (tide)");
+    spec.add(
+        Operation::new("post", "/", "Run `SELECT 1` on a pooled connection; any method is accepted")
+            .body(spec.schema::<RequestData>())
+            .response(200, "Timing; `received_data` is null when the body doesn't parse", spec.schema::<ResponseData>()),
+    );
+    RouteGroup::Docs.document(&mut spec, None);
+    spec.document()
 }
@@ -0,0 +1,57 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::format::{BodyFormat, Format};
+use crate::limits::BodyError;
+use crate::tide_limits;
+
+// Answers 406 when `Accept` names no supported format, before any handler
+// runs, and stores the negotiated `Format` as a request extension.
+pub struct FormatMiddleware;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for FormatMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let accept = req.header("Accept").map(|h| h.as_str());
+        // Event streams are always sent as such
+        if accept.is_some_and(|accept| accept.contains("text/event-stream")) {
+            return Ok(next.run(req).await);
+        }
+        match Format::from_accept(accept) {
+            Some(format) => {
+                req.set_ext(format);
+                Ok(next.run(req).await)
+            }
+            None => {
+                let mut response = Response::new(StatusCode::NotAcceptable);
+                response.set_body("Not Acceptable");
+                response.set_content_type("text/plain");
+                Ok(response)
+            }
+        }
+    }
+}
+
+pub fn format<State>(req: &Request<State>) -> Format {
+    req.ext::<Format>().copied().unwrap_or(Format::Json)
+}
+
+// Reads the body within the limits and decodes it per `Content-Type`
+pub async fn read<T: DeserializeOwned, State>(req: &mut Request<State>) -> Result<T, BodyError> {
+    let format = BodyFormat::from_content_type(req.header("Content-Type").map(|h| h.as_str()))
+        .ok_or(BodyError::UnsupportedMediaType)?;
+    let body = tide_limits::read_body(req).await?;
+    format.decode(&body).map_err(|_| BodyError::Invalid)
+}
+
+pub fn response<T: Serialize>(format: Format, value: &T) -> tide::Result<Response> {
+    let body = format
+        .encode(value)
+        .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e))?;
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_body(body);
+    response.set_content_type(format.content_type());
+    Ok(response)
+}
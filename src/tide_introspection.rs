@@ -0,0 +1,49 @@
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde::Serialize;
+use tide::{Request, StatusCode};
+
+use crate::errors::ErrorBody;
+use crate::format::Format;
+use crate::introspection;
+use crate::tide_format;
+use crate::tide_url;
+
+// Read-only schema routes:
+//   GET /schema                   every table and view
+//   GET /schema/tables/:name      one table
+//   GET /schema/views/:name       one view
+
+pub async fn database<State>(req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let conn = pool.get()?;
+    respond(format, introspection::database(&conn).map(Some), "")
+}
+
+pub async fn table<State>(req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let name = tide_url::param(&req, "name")?;
+    let conn = pool.get()?;
+    respond(format, introspection::table(&conn, &name), &format!("No such table: {}", name))
+}
+
+pub async fn view<State>(req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let name = tide_url::param(&req, "name")?;
+    let conn = pool.get()?;
+    respond(format, introspection::view(&conn, &name), &format!("No such view: {}", name))
+}
+
+fn respond<T: Serialize>(format: Format, result: rusqlite::Result<Option<T>>, not_found: &str) -> tide::Result {
+    let (status, mut response) = match result {
+        Ok(Some(value)) => (StatusCode::Ok, tide_format::response(format, &value)?),
+        Ok(None) => (StatusCode::NotFound, tide_format::response(format, &ErrorBody::new(not_found))?),
+        Err(e) => {
+            eprintln!("Failed to read schema: {}", e);
+            let body = ErrorBody::new("Failed to read schema");
+            (StatusCode::InternalServerError, tide_format::response(format, &body)?)
+        }
+    };
+    response.set_status(status);
+    Ok(response)
+}
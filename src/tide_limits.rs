@@ -0,0 +1,263 @@
+use async_h1::server::{ConnectionStatus, Server as H1Server};
+use async_io::Timer;
+use async_std::io::{timeout, Read, ReadExt, Write};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::stream::StreamExt;
+use async_std::task;
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use futures_util::lock::{Mutex as AsyncMutex, OwnedMutexLockFuture};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tide::StatusCode;
+
+use crate::errors::ErrorBody;
+use crate::format::Format;
+use crate::limits::{limits, BodyError};
+use crate::tide_format;
+use crate::tls;
+
+// Replacement for `app.listen(addr)`. tide's own listener hard-codes
+// async-h1's options, so this accept loop closes connections that stay idle
+// between requests, wraps each socket so a stalled read times out and
+// terminates TLS when it is configured. Announces the bound address.
+pub async fn listen<State>(app: tide::Server<State>, addr: &str) -> io::Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let acceptor = tls::tide_acceptor();
+    let listener = TcpListener::bind(addr).await?;
+    tls::announce(listener.local_addr()?);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                task::spawn(serve(app.clone(), stream, acceptor.clone()));
+            }
+            Err(e) => eprintln!("Failed to accept connection: {}", e),
+        }
+    }
+    Ok(())
+}
+
+async fn serve<State>(app: tide::Server<State>, stream: TcpStream, acceptor: Option<TlsAcceptor>)
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let limits = limits();
+    let local_addr = stream.local_addr().ok();
+    let peer_addr = stream.peer_addr().ok();
+    let io = TimedStream::new(stream, limits.read_timeout, limits.idle_timeout);
+    let awaiting_request = Arc::clone(&io.awaiting_request);
+    let upgraded = Arc::clone(&io.upgraded);
+
+    let endpoint = |mut req: tide::http::Request| async {
+        req.set_local_addr(local_addr);
+        req.set_peer_addr(peer_addr);
+        let response: tide::http::Result<tide::http::Response> = app.respond(req).await;
+        if matches!(&response, Ok(response) if response.status() == StatusCode::SwitchingProtocols) {
+            upgraded.store(true, Ordering::Relaxed);
+        }
+        response
+    };
+    match acceptor {
+        None => accept_requests(H1Server::new(io, endpoint), &awaiting_request).await,
+        Some(acceptor) => match acceptor.accept(io).await {
+            Ok(tls_stream) => {
+                let io = TlsIo::new(tls_stream);
+                accept_requests(H1Server::new(io, endpoint), &awaiting_request).await
+            }
+            Err(e) => eprintln!("TLS handshake failed: {}", e),
+        },
+    }
+}
+
+async fn accept_requests<RW, F, Fut>(mut server: H1Server<RW, F, Fut>, awaiting_request: &AtomicBool)
+where
+    RW: Read + Write + Clone + Send + Sync + Unpin + 'static,
+    F: Fn(tide::http::Request) -> Fut,
+    Fut: Future<Output = tide::http::Result<tide::http::Response>>,
+{
+    loop {
+        // Reads wait up to the idle timeout until the next request starts
+        awaiting_request.store(true, Ordering::Relaxed);
+        match server.accept_one().await {
+            Ok(ConnectionStatus::KeepAlive) => continue,
+            Ok(ConnectionStatus::Close) => break,
+            // Nothing arrived within the idle timeout
+            Err(_) if awaiting_request.load(Ordering::Relaxed) => break,
+            Err(e) => {
+                eprintln!("Failed to serve connection: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+pub async fn read_body<State>(req: &mut tide::Request<State>) -> Result<Vec<u8>, BodyError> {
+    let limits = limits();
+    if let Some(length) = req.len() {
+        if length as u64 > limits.max_body_bytes {
+            return Err(BodyError::TooLarge);
+        }
+    }
+
+    let mut reader = req.take_body().take(limits.max_body_bytes + 1);
+    let mut body = Vec::new();
+    timeout(limits.body_timeout, reader.read_to_end(&mut body))
+        .await
+        .map_err(BodyError::from_io)?;
+    if body.len() as u64 > limits.max_body_bytes {
+        return Err(BodyError::TooLarge);
+    }
+    Ok(body)
+}
+
+pub fn body_error_response(format: Format, err: &BodyError) -> tide::Result {
+    let mut response = tide_format::response(format, &ErrorBody::new(err.message()))?;
+    response.set_status(StatusCode::try_from(err.status_code()).unwrap());
+    Ok(response)
+}
+
+// A socket whose reads fail with `TimedOut` once they have been pending for
+// longer than `timeout`, or `idle_timeout` while no byte of the next request
+// has arrived yet. Clones share the socket but keep their own timer. Once
+// the connection is upgraded, e.g. to a WebSocket, reads no longer time out.
+struct TimedStream {
+    inner: TcpStream,
+    timeout: Duration,
+    idle_timeout: Duration,
+    awaiting_request: Arc<AtomicBool>,
+    upgraded: Arc<AtomicBool>,
+    // The pending read's timer and whether it runs for the idle timeout
+    timer: Option<(Timer, bool)>,
+}
+
+impl TimedStream {
+    fn new(inner: TcpStream, timeout: Duration, idle_timeout: Duration) -> Self {
+        TimedStream {
+            inner,
+            timeout,
+            idle_timeout,
+            awaiting_request: Arc::new(AtomicBool::new(false)),
+            upgraded: Arc::new(AtomicBool::new(false)),
+            timer: None,
+        }
+    }
+}
+
+impl Clone for TimedStream {
+    fn clone(&self) -> Self {
+        TimedStream {
+            inner: self.inner.clone(),
+            timeout: self.timeout,
+            idle_timeout: self.idle_timeout,
+            awaiting_request: Arc::clone(&self.awaiting_request),
+            upgraded: Arc::clone(&self.upgraded),
+            timer: None,
+        }
+    }
+}
+
+impl Read for TimedStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.timer = None;
+                if matches!(result, Ok(n) if n > 0) {
+                    this.awaiting_request.store(false, Ordering::Relaxed);
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending if this.upgraded.load(Ordering::Relaxed) => Poll::Pending,
+            Poll::Pending => {
+                let idle = this.awaiting_request.load(Ordering::Relaxed);
+                if matches!(this.timer, Some((_, timer_idle)) if timer_idle != idle) {
+                    this.timer = None;
+                }
+                let timeout = if idle { this.idle_timeout } else { this.timeout };
+                let (timer, _) = this.timer.get_or_insert_with(|| (Timer::after(timeout), idle));
+                match Pin::new(timer).poll(cx) {
+                    Poll::Ready(_) => {
+                        this.timer = None;
+                        Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+                    }
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+}
+
+impl Write for TimedStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+// async-h1 reads and writes through separate clones of the connection, which
+// a TLS session can't provide, so clones share it behind an async lock held
+// only for the duration of each poll. A clone finding it taken is woken once
+// it is free instead of blocking the executor thread.
+struct TlsIo {
+    stream: Arc<AsyncMutex<TlsStream<TimedStream>>>,
+    locking: Option<OwnedMutexLockFuture<TlsStream<TimedStream>>>,
+}
+
+impl TlsIo {
+    fn new(stream: TlsStream<TimedStream>) -> Self {
+        TlsIo { stream: Arc::new(AsyncMutex::new(stream)), locking: None }
+    }
+
+    fn poll_locked<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(Pin<&mut TlsStream<TimedStream>>, &mut Context<'_>) -> Poll<T>,
+    ) -> Poll<T> {
+        let stream = &self.stream;
+        let locking = self.locking.get_or_insert_with(|| Arc::clone(stream).lock_owned());
+        let mut guard = ready!(Pin::new(locking).poll(cx));
+        self.locking = None;
+        op(Pin::new(&mut *guard), cx)
+    }
+}
+
+impl Clone for TlsIo {
+    fn clone(&self) -> Self {
+        TlsIo { stream: Arc::clone(&self.stream), locking: None }
+    }
+}
+
+impl Read for TlsIo {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_locked(cx, |stream, cx| stream.poll_read(cx, buf))
+    }
+}
+
+impl Write for TlsIo {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_locked(cx, |stream, cx| stream.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_locked(cx, |stream, cx| stream.poll_flush(cx))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_locked(cx, |stream, cx| stream.poll_close(cx))
+    }
+}
@@ -0,0 +1,67 @@
+use async_std::task;
+use std::sync::Arc;
+use tide::{Request, Response, StatusCode};
+
+use crate::db_strategy::DbStrategy;
+use crate::matrix::{self, Action, Frontend};
+use crate::repository::Person;
+use crate::retry;
+use crate::tide_auth::AuthMiddleware;
+use crate::tide_cors::CorsMiddleware;
+use crate::tide_format::{self, FormatMiddleware};
+use crate::tide_limits;
+use crate::tide_openapi;
+use crate::tide_url;
+
+type State = Arc<dyn DbStrategy>;
+
+// tide on async-std; handlers run on blocking tasks since every strategy
+// blocks
+pub struct Tide;
+
+impl Frontend for Tide {
+    fn name(&self) -> &'static str {
+        "tide"
+    }
+
+    fn serve(&self, addr: &str, strategy: Arc<dyn DbStrategy>) {
+        let mut app = tide::with_state(strategy);
+        app.with(CorsMiddleware);
+        app.with(AuthMiddleware);
+        app.with(FormatMiddleware);
+        app.at("/people").post(create);
+        app.at("/people/:name").get(read).delete(delete);
+        tide_openapi::serve(&mut app, |_| matrix::api_spec());
+
+        if let Err(e) = task::block_on(tide_limits::listen(app, addr)) {
+            eprintln!("Server failed: {}", e);
+        }
+    }
+}
+
+async fn create(mut req: Request<State>) -> tide::Result {
+    match tide_format::read::<Person, _>(&mut req).await {
+        Ok(person) => respond(req, Action::Create(person)).await,
+        Err(e) => tide_limits::body_error_response(tide_format::format(&req), &e),
+    }
+}
+
+async fn read(req: Request<State>) -> tide::Result {
+    let name = tide_url::param(&req, "name")?;
+    respond(req, Action::Read(name)).await
+}
+
+async fn delete(req: Request<State>) -> tide::Result {
+    let name = tide_url::param(&req, "name")?;
+    respond(req, Action::Delete(name)).await
+}
+
+async fn respond(req: Request<State>, action: Action) -> tide::Result {
+    let strategy = Arc::clone(req.state());
+    let reply = task::spawn_blocking(move || matrix::handle(&*strategy, action)).await;
+
+    match &reply.body {
+        Some(body) => retry::tide_response(tide_format::format(&req), reply.status, body, reply.busy),
+        None => Ok(Response::new(StatusCode::NoContent)),
+    }
+}
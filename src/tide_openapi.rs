@@ -0,0 +1,21 @@
+use serde_json::Value;
+use std::sync::Arc;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::openapi;
+
+// Serves `/openapi.json` and `/docs` from the spec `spec` returns, usually
+// from an `openapi::SpecCache` so it is only rebuilt when the schema changes
+pub fn serve<State: Clone + Send + Sync + 'static>(app: &mut tide::Server<State>, spec: fn(&Request<State>) -> Arc<Value>) {
+    app.at("/openapi.json").get(move |req: Request<State>| async move {
+        let mut response = Response::new(StatusCode::Ok);
+        response.set_body(Body::from_json(&*spec(&req))?);
+        Ok(response)
+    });
+    app.at("/docs").get(move |req: Request<State>| async move {
+        let mut response = Response::new(StatusCode::Ok);
+        response.set_body(openapi::docs_html(&spec(&req)));
+        response.set_content_type("text/html; charset=utf-8");
+        Ok(response)
+    });
+}
@@ -0,0 +1,39 @@
+use std::net::SocketAddr;
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::auth::{self, Principal};
+use crate::rate_limit::{client_key, limiter, RouteClass};
+
+// Must be registered after `AuthMiddleware` so the principal is known.
+// Requests are classed as reads or writes by method.
+pub struct RateLimitMiddleware;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RateLimitMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let is_write = auth::is_write(req.method().as_ref());
+        let ip = req
+            .peer_addr()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .map(|addr| addr.ip());
+        let key = client_key(req.ext::<Principal>(), ip);
+
+        let Some(decision) = limiter().check(RouteClass::for_method(is_write), key) else {
+            return Ok(next.run(req).await);
+        };
+
+        let mut response = if decision.allowed {
+            next.run(req).await
+        } else {
+            let mut response = Response::new(StatusCode::TooManyRequests);
+            response.set_body("Too Many Requests");
+            response.set_content_type("text/plain");
+            response
+        };
+        for (name, value) in decision.headers() {
+            response.insert_header(name, value);
+        }
+        Ok(response)
+    }
+}
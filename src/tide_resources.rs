@@ -0,0 +1,112 @@
+use async_std::task;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use serde::Deserialize;
+use serde_json::Value;
+use tide::{Request, Response, StatusCode};
+
+use crate::attributes;
+use crate::auth::Principal;
+use crate::format::Format;
+use crate::resources::{self, ResourceError};
+use crate::retry;
+use crate::sql_policy;
+use crate::tide_format;
+use crate::tide_limits;
+use crate::tide_url;
+
+// Generic row routes for every table:
+//   GET    /tables/:name/rows         list, paged by `limit` and `offset` and
+//                                     filtered by `attr.<path>`
+//   POST   /tables/:name/rows         create
+//   GET    /tables/:name/rows/:key    read
+//   PUT    /tables/:name/rows/:key    update the given columns
+//   DELETE /tables/:name/rows/:key    delete
+
+#[derive(Deserialize)]
+struct PageQuery {
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+pub async fn list<State>(req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let table = tide_url::param(&req, "name")?;
+    let policy = sql_policy::policies().for_principal(req.ext::<Principal>());
+    let Ok(page) = req.query::<PageQuery>() else {
+        return error_response(format, &ResourceError::Invalid("Invalid limit or offset".to_string()));
+    };
+    let filters = attributes::filters(req.url().query_pairs());
+    let result = blocking(pool, move |conn| resources::list(conn, policy, &table, &filters, page.limit, page.offset)).await?;
+    respond(format, StatusCode::Ok, result)
+}
+
+pub async fn get<State>(req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let table = tide_url::param(&req, "name")?;
+    let policy = sql_policy::policies().for_principal(req.ext::<Principal>());
+    let key = tide_url::param(&req, "key")?;
+    let result = blocking(pool, move |conn| resources::get(conn, policy, &table, &key)).await?;
+    respond(format, StatusCode::Ok, result)
+}
+
+pub async fn create<State>(mut req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let table = tide_url::param(&req, "name")?;
+    let policy = sql_policy::policies().for_principal(req.ext::<Principal>());
+    let body: Value = match tide_format::read(&mut req).await {
+        Ok(body) => body,
+        Err(e) => return tide_limits::body_error_response(format, &e),
+    };
+    let result = blocking(pool, move |conn| resources::create(conn, policy, &table, &body)).await?;
+    respond(format, StatusCode::Created, result)
+}
+
+pub async fn update<State>(mut req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let table = tide_url::param(&req, "name")?;
+    let policy = sql_policy::policies().for_principal(req.ext::<Principal>());
+    let key = tide_url::param(&req, "key")?;
+    let body: Value = match tide_format::read(&mut req).await {
+        Ok(body) => body,
+        Err(e) => return tide_limits::body_error_response(format, &e),
+    };
+    let result = blocking(pool, move |conn| resources::update(conn, policy, &table, &key, &body)).await?;
+    respond(format, StatusCode::Ok, result)
+}
+
+pub async fn delete<State>(req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let table = tide_url::param(&req, "name")?;
+    let policy = sql_policy::policies().for_principal(req.ext::<Principal>());
+    let key = tide_url::param(&req, "key")?;
+    match blocking(pool, move |conn| resources::delete(conn, policy, &table, &key)).await? {
+        Ok(()) => Ok(Response::new(StatusCode::NoContent)),
+        Err(e) => error_response(format, &e),
+    }
+}
+
+// Runs `f` on a pooled connection off the executor: queries block, and
+// writes may back off while the database is busy
+async fn blocking<T: Send + 'static>(
+    pool: Pool<SqliteConnectionManager>,
+    f: impl FnOnce(&Connection) -> Result<T, ResourceError> + Send + 'static,
+) -> tide::Result<Result<T, ResourceError>> {
+    Ok(task::spawn_blocking(move || pool.get().map(|conn| f(&conn))).await?)
+}
+
+fn respond(format: Format, status: StatusCode, result: Result<Value, ResourceError>) -> tide::Result {
+    match result {
+        Ok(value) => {
+            let mut response = tide_format::response(format, &value)?;
+            response.set_status(status);
+            Ok(response)
+        }
+        Err(e) => error_response(format, &e),
+    }
+}
+
+fn error_response(format: Format, err: &ResourceError) -> tide::Result {
+    retry::tide_response(format, err.status_code(), &err.body(), err.is_busy())
+}
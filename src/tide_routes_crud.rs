@@ -1,16 +1,50 @@
-use rusqlite::{Connection, params, Result};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::time::Instant;
-use tide::{Request, Response, StatusCode};
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Person {
-    name: String,
-    age: i32,
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tide::{Request, StatusCode};
+
+use crate::backup_schedule;
+use crate::changes;
+use crate::config;
+use crate::db_strategy::{self, spawn_with_connection, DbStrategy, Pooled};
+use crate::format::Format;
+use crate::limits::BodyError;
+use crate::memory;
+use crate::metrics::{self, Snapshot};
+use crate::openapi::{ApiSpec, Operation, RouteGroup, SpecCache};
+use crate::repository::{self, create_table, delete_person, insert_person, select_person, update_person, Person};
+use crate::resources;
+use crate::retry;
+use crate::schema;
+use crate::search;
+use crate::tide_admin;
+use crate::tide_auth::{AdminMiddleware, AuthMiddleware};
+use crate::tide_changes;
+use crate::tide_cors::CorsMiddleware;
+use crate::tide_databases::{self, DatabaseMiddleware};
+use crate::tide_format::{self, FormatMiddleware};
+use crate::tide_introspection;
+use crate::tide_rate_limit::RateLimitMiddleware;
+use crate::tide_resources;
+use crate::tide_search;
+use crate::tide_limits;
+use crate::tide_openapi;
+use crate::tide_table_csv;
+use crate::tide_websocket;
+
+const DB_FILE: &str = "my_database.db";
+
+// The database file, or its in-memory stand-in under TINYSQL_MEMORY
+fn db_path() -> &'static str {
+    memory::database("tide_crud", DB_FILE)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct ApiResponse<T> {
     status: String,
     data: Option<T>,
@@ -18,62 +52,162 @@ struct ApiResponse<T> {
     time_taken: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct UpdatePersonRequest {
     age: i32,
+    // Replaces the stored attributes when given, and clears them when null
+    #[serde(default, deserialize_with = "repository::explicit_null")]
+    attributes: Option<Option<Map<String, Value>>>,
 }
 
+#[derive(Clone)]
+struct State {
+    pool: Pool<SqliteConnectionManager>,
+    // Person routes open a connection per request unless TINYSQL_TIDE_CRUD_POOL
+    // serves them from the pool, where the prepared statement cache carries
+    // over between requests
+    people: Arc<dyn DbStrategy>,
+}
 
 pub async fn tide_crud() -> tide::Result<()> {
-    // Open a connection to SQLite and create the table once
-    let conn = Connection::open("my_database.db").expect("Failed to open SQLite connection");
+    // Pooled connections keep their prepared statements across requests and
+    // report what they change to the change feed
+    let pool = repository::open_pool(db_path(), changes::install).expect("Failed to create pool.");
+    changes::start(db_path());
+    let conn = pool.get().expect("Failed to get connection from pool");
     create_table(&conn).expect("Failed to create table");
+    search::create_index(&conn).expect("Failed to create search index");
+    println!("Row routes for tables: {}", resources::describe(&conn).expect("Failed to read schema"));
+    drop(conn);
+
+    println!("Prepared statement cache capacity: {}", repository::statement_cache_capacity());
+    backup_schedule::start(db_path());
+
+    let people: Arc<dyn DbStrategy> = if config::env_or("TINYSQL_TIDE_CRUD_POOL", false) {
+        Arc::new(Pooled::new(pool.clone()))
+    } else {
+        Arc::from(db_strategy::open_with("per_request", db_path(), changes::install).expect("Failed to open database"))
+    };
+    println!("Person routes use the {} strategy", people.name());
+
+    let mut app = tide::with_state(State { pool, people });
+    app.with(CorsMiddleware);
+    app.with(AuthMiddleware);
+    app.with(RateLimitMiddleware);
+    app.with(FormatMiddleware);
+    app.with(DatabaseMiddleware);
+    app.at("/metrics").get(handle_metrics_request);
+    for group in ROUTES {
+        serve_group(&mut app, group);
+    }
 
-    let mut app = tide::new();
-    app.at("/").post(handle_post_request);
-    app.at("/:name").get(handle_get_request);
-    app.at("/:name").put(handle_put_request);
-    app.at("/:name").delete(handle_delete_request);
+    // Person routes, on the default database and on named ones
+    for prefix in DATA_PREFIXES {
+        let at = |path: &str| format!("{}{}", prefix, path);
+        app.at(&at("/")).post(handle_post_request);
+        app.at(&at("/:name")).get(handle_get_request).put(handle_put_request).delete(handle_delete_request);
+    }
 
-    println!("Listening on http://0.0.0.0:8000/");
+    let addr = config::listen_addr("0.0.0.0:8000");
     
-    app.listen("0.0.0.0:8000").await?;
+    tide_limits::listen(app, &addr).await?;
     Ok(())
 }
 
-async fn handle_post_request(mut req: Request<()>) -> tide::Result {
+// The route groups served besides the person routes; `api_spec` documents
+// the same list
+const ROUTES: [RouteGroup; 7] = [
+    RouteGroup::Tables,
+    RouteGroup::Search,
+    RouteGroup::Schema,
+    RouteGroup::Realtime,
+    RouteGroup::Admin,
+    RouteGroup::Databases,
+    RouteGroup::Docs,
+];
+
+// Data routes are served on the default database and on named ones under
+// `/db/:db`
+const DATA_PREFIXES: [&str; 2] = ["", "/db/:db"];
+
+fn serve_group(app: &mut tide::Server<State>, group: RouteGroup) {
+    match group {
+        RouteGroup::Tables => {
+            for prefix in DATA_PREFIXES {
+                let at = |path: &str| format!("{}{}", prefix, path);
+                app.at(&at("/tables/:name/import")).post(handle_import_request);
+                app.at(&at("/tables/:name/export.csv")).get(handle_export_request);
+                app.at(&at("/tables/:name/rows")).get(handle_list_rows_request).post(handle_create_row_request);
+                app.at(&at("/tables/:name/rows/:key"))
+                    .get(handle_get_row_request)
+                    .put(handle_update_row_request)
+                    .delete(handle_delete_row_request);
+            }
+        }
+        RouteGroup::Search => {
+            for prefix in DATA_PREFIXES {
+                app.at(&format!("{}/people/search", prefix)).get(handle_search_request);
+            }
+        }
+        RouteGroup::Schema => {
+            for prefix in DATA_PREFIXES {
+                let at = |path: &str| format!("{}{}", prefix, path);
+                app.at(&at("/schema")).get(handle_schema_request);
+                app.at(&at("/schema/tables/:name")).get(handle_schema_table_request);
+                app.at(&at("/schema/views/:name")).get(handle_schema_view_request);
+            }
+        }
+        RouteGroup::Realtime => {
+            app.at("/changes").get(tide::sse::endpoint(tide_changes::stream));
+            for prefix in DATA_PREFIXES {
+                app.at(&format!("{}/ws", prefix)).get(handle_websocket_request);
+            }
+        }
+        RouteGroup::Admin => {
+            app.at("/admin/backup").with(AdminMiddleware).get(tide_admin::backup_status).post(handle_backup_request);
+            app.at("/admin/restore").with(AdminMiddleware).post(handle_restore_request);
+        }
+        RouteGroup::Databases => {
+            app.at("/databases").with(AdminMiddleware).get(tide_databases::list).post(tide_databases::create);
+            app.at("/databases/:name").with(AdminMiddleware).get(tide_databases::inspect).delete(tide_databases::delete);
+        }
+        RouteGroup::Docs => tide_openapi::serve(app, api_spec),
+    }
+}
+
+async fn handle_post_request(mut req: Request<State>) -> tide::Result {
     let start = Instant::now();
 
-    // Parse the JSON body of the request
-    let person: Person = match req.body_json().await {
+    let format = tide_format::format(&req);
+
+    // Parse the request body in its Content-Type
+    let person: Person = match tide_format::read(&mut req).await {
         Ok(person) => person,
-        Err(_) => {
-            let response = ApiResponse::<()>::error("Invalid JSON data", start.elapsed());
-            return Ok(response.into());
+        Err(e @ BodyError::Invalid) => {
+            return ApiResponse::<()>::error(e.message(), start.elapsed()).into_response(format);
         }
+        Err(e) => return body_error_response(format, &e, start.elapsed()),
     };
 
-    // Open a connection to SQLite
-    let sqlite_status = match Connection::open("my_database.db") {
-        Ok(conn) => {
-            match insert_person(&conn, &person.name, person.age) {
-                Ok(_) => "Person inserted successfully".to_string(),
-                Err(e) => {
-                    eprintln!("Failed to insert person: {}", e);
-                    "Failed to insert person".to_string()
-                }
-            }
+    // Insert through the person routes' strategy
+    let (name, attributes) = (person.name.clone(), person.attributes.clone());
+    let insert = move |conn: &Connection| insert_person(conn, &name, person.age, attributes.as_ref());
+    let sqlite_status = match spawn_with_connection(people(&req), insert).await {
+        Ok(_) => "Person inserted successfully".to_string(),
+        Err(e) if e.is_busy() => {
+            eprintln!("Database busy, giving up on insert: {}", e.message());
+            return busy_response(format, start.elapsed());
         }
         Err(e) => {
-            eprintln!("Failed to open SQLite connection: {}", e);
-            "Failed to open SQLite connection".to_string()
+            eprintln!("Failed to insert person: {}", e.message());
+            "Failed to insert person".to_string()
         }
     };
 
     let duration = start.elapsed();
     println!("Time taken to handle request: {:?}", duration);
 
-    // Create a JSON response
+    // Create a response in the negotiated format
     let response = ApiResponse {
         status: sqlite_status,
         data: Some(person),
@@ -81,34 +215,32 @@ async fn handle_post_request(mut req: Request<()>) -> tide::Result {
         time_taken: format!("{:?}", duration),
     };
     
-    Ok(response.into())
+    response.into_response(format)
 }
 
-async fn handle_get_request(req: Request<()>) -> tide::Result {
+async fn handle_get_request(req: Request<State>) -> tide::Result {
     let start = Instant::now();
+    let format = tide_format::format(&req);
     let name = req.param("name")?;
 
-    // Open a connection to SQLite
-    let (sqlite_status, person) = match Connection::open("my_database.db") {
-        Ok(conn) => {
-            match select_person(&conn, name) {
-                Ok(person) => ("Person retrieved successfully".to_string(), Some(person)),
-                Err(e) => {
-                    eprintln!("Failed to retrieve person: {}", e);
-                    ("Failed to retrieve person".to_string(), None)
-                }
-            }
+    // Look the person up through the person routes' strategy
+    let lookup = name.to_string();
+    let (sqlite_status, person) = match spawn_with_connection(people(&req), move |conn| select_person(conn, &lookup)).await {
+        Ok(Some(person)) => ("Person retrieved successfully".to_string(), Some(person)),
+        Ok(None) => {
+            eprintln!("Failed to retrieve person: no person named {}", name);
+            ("Failed to retrieve person".to_string(), None)
         }
         Err(e) => {
-            eprintln!("Failed to open SQLite connection: {}", e);
-            ("Failed to open SQLite connection".to_string(), None)
+            eprintln!("Failed to retrieve person: {}", e.message());
+            ("Failed to retrieve person".to_string(), None)
         }
     };
 
     let duration = start.elapsed();
     println!("Time taken to handle request: {:?}", duration);
     
-    // Create a JSON response
+    // Create a response in the negotiated format
     let response = ApiResponse {
         status: sqlite_status,
         data: person,
@@ -116,42 +248,41 @@ async fn handle_get_request(req: Request<()>) -> tide::Result {
         time_taken: format!("{:?}", duration),
     };
     
-    Ok(response.into())
+    response.into_response(format)
 }
 
-async fn handle_put_request(mut req: Request<()>) -> tide::Result {
+async fn handle_put_request(mut req: Request<State>) -> tide::Result {
     let start = Instant::now();
     let name = req.param("name")?.to_string(); // Convert to String to own the data
 
-    let update_request: UpdatePersonRequest = match req.body_json().await {
+    let format = tide_format::format(&req);
+    let update_request: UpdatePersonRequest = match tide_format::read(&mut req).await {
         Ok(data) => data,
-        Err(_) => {
-            let response = ApiResponse::<()>::error("Invalid JSON data", start.elapsed());
-            return Ok(response.into());
+        Err(e @ BodyError::Invalid) => {
+            return ApiResponse::<()>::error(e.message(), start.elapsed()).into_response(format);
         }
+        Err(e) => return body_error_response(format, &e, start.elapsed()),
     };
 
-    // Open a connection to SQLite
-    let sqlite_status = match Connection::open("my_database.db") {
-        Ok(conn) => {
-            match update_person_age(&conn, &name, update_request.age) {
-                Ok(_) => "Person updated successfully".to_string(),
-                Err(e) => {
-                    eprintln!("Failed to update person: {}", e);
-                    "Failed to update person".to_string()
-                }
-            }
+    // Update through the person routes' strategy
+    let (age, attributes) = (update_request.age, update_request.attributes.clone());
+    let update = move |conn: &Connection| update_person(conn, &name, age, attributes.as_ref().map(Option::as_ref));
+    let sqlite_status = match spawn_with_connection(people(&req), update).await {
+        Ok(_) => "Person updated successfully".to_string(),
+        Err(e) if e.is_busy() => {
+            eprintln!("Database busy, giving up on update: {}", e.message());
+            return busy_response(format, start.elapsed());
         }
         Err(e) => {
-            eprintln!("Failed to open SQLite connection: {}", e);
-            "Failed to open SQLite connection".to_string()
+            eprintln!("Failed to update person: {}", e.message());
+            "Failed to update person".to_string()
         }
     };
 
     let duration = start.elapsed();
     println!("Time taken to handle request: {:?}", duration);
 
-    // Create a JSON response
+    // Create a response in the negotiated format
     let response = ApiResponse {
         status: sqlite_status,
         data: Some(update_request),
@@ -159,34 +290,32 @@ async fn handle_put_request(mut req: Request<()>) -> tide::Result {
         time_taken: format!("{:?}", duration),
     };
 
-    Ok(response.into())
+    response.into_response(format)
 }
 
-async fn handle_delete_request(req: Request<()>) -> tide::Result {
+async fn handle_delete_request(req: Request<State>) -> tide::Result {
     let start = Instant::now();
+    let format = tide_format::format(&req);
     let name = req.param("name")?;
 
-    // Open a connection to SQLite
-    let sqlite_status = match Connection::open("my_database.db") {
-        Ok(conn) => {
-            match delete_person(&conn, name) {
-                Ok(_) => "Person deleted successfully".to_string(),
-                Err(e) => {
-                    eprintln!("Failed to delete person: {}", e);
-                    "Failed to delete person".to_string()
-                }
-            }
+    // Delete through the person routes' strategy
+    let name = name.to_string();
+    let sqlite_status = match spawn_with_connection(people(&req), move |conn| delete_person(conn, &name)).await {
+        Ok(_) => "Person deleted successfully".to_string(),
+        Err(e) if e.is_busy() => {
+            eprintln!("Database busy, giving up on delete: {}", e.message());
+            return busy_response(format, start.elapsed());
         }
         Err(e) => {
-            eprintln!("Failed to open SQLite connection: {}", e);
-            "Failed to open SQLite connection".to_string()
+            eprintln!("Failed to delete person: {}", e.message());
+            "Failed to delete person".to_string()
         }
     };
 
     let duration = start.elapsed();
     println!("Time taken to handle request: {:?}", duration);
     
-    // Create a JSON response
+    // Create a response in the negotiated format
     let response = ApiResponse::<()> {
         status: sqlite_status,
         data: None,
@@ -194,72 +323,182 @@ async fn handle_delete_request(req: Request<()>) -> tide::Result {
         time_taken: format!("{:?}", duration),
     };
 
-    Ok(response.into())
+    response.into_response(format)
 }
 
-impl<T> ApiResponse<T> {
-    fn error(message: &str, duration: std::time::Duration) -> Self {
-        Self {
-            status: "Error".to_string(),
-            data: None,
-            error: Some(message.to_string()),
-            time_taken: format!("{:?}", duration),
-        }
-    }
+async fn handle_metrics_request(req: Request<State>) -> tide::Result {
+    tide_format::response(tide_format::format(&req), &metrics::snapshot())
 }
 
-impl<T: Serialize> Into<Response> for ApiResponse<T> {
-    fn into(self) -> Response {
-        let mut response = Response::new(StatusCode::Ok);
-        response.set_body(serde_json::to_string(&self).unwrap());
-        response.set_content_type("application/json");
-        response
-    }
+async fn handle_import_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_table_csv::import(req, pool).await
 }
 
-fn create_table(conn: &Connection) -> Result<()> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS person (
-            name TEXT NOT NULL,
-            age INTEGER
-        )",
-        [],
-    )?;
-    Ok(())
+async fn handle_export_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_table_csv::export(req, pool).await
+}
+
+async fn handle_list_rows_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_resources::list(req, pool).await
+}
+
+async fn handle_create_row_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_resources::create(req, pool).await
 }
 
-fn select_person(conn: &Connection, name: &str) -> Result<Person> {
-    let mut stmt = conn.prepare("SELECT name, age FROM person WHERE name = ?1")?;
-    let person_iter = stmt.query_map(params![name], |row| {
-        Ok(Person {
-            name: row.get(0)?,
-            age: row.get(1)?,
-        })
-    })?;
+async fn handle_get_row_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_resources::get(req, pool).await
+}
+
+async fn handle_update_row_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_resources::update(req, pool).await
+}
+
+async fn handle_delete_row_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_resources::delete(req, pool).await
+}
+
+async fn handle_websocket_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    let named = tide_databases::handle(&req);
+    tide_websocket::upgrade(req, pool, named).await
+}
+
+async fn handle_backup_request(req: Request<State>) -> tide::Result {
+    tide_admin::backup(req, db_path()).await
+}
+
+async fn handle_restore_request(req: Request<State>) -> tide::Result {
+    let pool = req.state().pool.clone();
+    tide_admin::restore(req, pool, db_path()).await
+}
+
+async fn handle_search_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_search::people(req, pool).await
+}
+
+async fn handle_schema_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_introspection::database(req, pool).await
+}
 
-    for person in person_iter {
-        return Ok(person?);
+async fn handle_schema_table_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_introspection::table(req, pool).await
+}
+
+async fn handle_schema_view_request(req: Request<State>) -> tide::Result {
+    let pool = pool(&req);
+    tide_introspection::view(req, pool).await
+}
+
+// The database a request addresses: a named one under `/db/:db`, otherwise
+// the default one
+fn pool(req: &Request<State>) -> Pool<SqliteConnectionManager> {
+    tide_databases::pool(req, &req.state().pool)
+}
+
+// How person routes reach their database; named databases always use their
+// pool
+fn people(req: &Request<State>) -> Arc<dyn DbStrategy> {
+    if tide_databases::is_named(req) {
+        return Arc::new(Pooled::new(pool(req)));
+    }
+    Arc::clone(&req.state().people)
+}
+
+// Documents the routes registered in `tide_crud`
+fn api_spec(req: &Request<State>) -> Arc<Value> {
+    static SPEC: SpecCache = SpecCache::new();
+    let conn = match req.state().pool.get() {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            eprintln!("Failed to get connection from pool: {}", e);
+            None
+        }
+    };
+    // Row routes are listed per table, so the document follows the schema
+    let version = conn.as_deref().and_then(|conn| schema::version(conn).ok()).unwrap_or_default();
+    SPEC.get(version, || build_spec(conn.as_deref()))
+}
+
+fn build_spec(conn: Option<&Connection>) -> Value {
+    let mut spec = ApiSpec::new("tinysql CRUD (tide)");
+    let outcome = "Outcome; database failures are reported in `status` with a 200";
+    let error = spec.schema::<ApiResponse<()>>();
+    // Body errors keep their status but use the usual envelope
+    let body_errors = |operation: Operation| {
+        operation
+            .response(400, "Unreadable request body", error.clone())
+            .response(408, "Request body timed out", error.clone())
+            .response(413, "Request body too large", error.clone())
+            .response(415, "Unsupported Content-Type", error.clone())
+    };
+    let busy = "Database is busy; see Retry-After";
+
+    spec.add(body_errors(
+        Operation::new("post", "/", "Insert a person")
+            .body(spec.schema::<Person>())
+            .response(200, outcome, spec.schema::<ApiResponse<Person>>())
+            .response(503, busy, error.clone()),
+    ));
+    spec.add(
+        Operation::new("get", "/metrics", "Retry, pool and rate limit counters")
+            .response(200, "Current counters", spec.schema::<Snapshot>()),
+    );
+    spec.add(
+        Operation::new("get", "/{name}", "Read a person by name")
+            .response(200, outcome, spec.schema::<ApiResponse<Person>>()),
+    );
+    spec.add(body_errors(
+        Operation::new("put", "/{name}", "Update a person's age")
+            .body(spec.schema::<UpdatePersonRequest>())
+            .response(200, outcome, spec.schema::<ApiResponse<UpdatePersonRequest>>())
+            .response(503, busy, error.clone()),
+    ));
+    spec.add(
+        Operation::new("delete", "/{name}", "Delete a person by name")
+            .response(200, outcome, error.clone())
+            .response(503, busy, error.clone()),
+    );
+
+    for group in ROUTES {
+        group.document(&mut spec, conn);
     }
-    Err(rusqlite::Error::QueryReturnedNoRows)
+    spec.document()
 }
 
-fn insert_person(conn: &Connection, name: &str, age: i32) -> Result<usize> {
-    conn.execute(
-        "INSERT INTO person (name, age) VALUES (?1, ?2)",
-        params![name, age],
-    )
+// Oversized, slow or unreadable bodies keep their HTTP status (413, 408, 400)
+fn body_error_response(format: Format, err: &BodyError, duration: Duration) -> tide::Result {
+    let mut response = ApiResponse::<()>::error(err.message(), duration).into_response(format)?;
+    response.set_status(StatusCode::try_from(err.status_code()).unwrap());
+    Ok(response)
 }
 
-fn update_person_age(conn: &Connection, name: &str, new_age: i32) -> Result<usize> {
-    conn.execute(
-        "UPDATE person SET age = ?1 WHERE name = ?2",
-        params![new_age, name],
-    )
+// Writes that are still busy after every retry get a 503 so clients back off
+fn busy_response(format: Format, duration: Duration) -> tide::Result {
+    retry::tide_response(format, 503, &ApiResponse::<()>::error("Database is busy, retry later", duration), true)
 }
 
-fn delete_person(conn: &Connection, name: &str) -> Result<usize> {
-    conn.execute(
-        "DELETE FROM person WHERE name = ?1",
-        params![name],
-    )
+impl<T: Serialize> ApiResponse<T> {
+    fn into_response(self, format: Format) -> tide::Result {
+        tide_format::response(format, &self)
+    }
+
+    fn error(message: &str, duration: Duration) -> Self {
+        Self {
+            status: "Error".to_string(),
+            data: None,
+            error: Some(message.to_string()),
+            time_taken: format!("{:?}", duration),
+        }
+    }
 }
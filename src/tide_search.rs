@@ -0,0 +1,39 @@
+use async_std::task;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde::Deserialize;
+use tide::Request;
+
+use crate::format::Format;
+use crate::retry;
+use crate::search::{self, SearchError};
+use crate::tide_format;
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+// `GET /people/search?q=`, ranked matches with highlighted snippets
+pub async fn people<State>(req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let Ok(query) = req.query::<SearchQuery>() else {
+        return error_response(format, &SearchError::Invalid("Invalid limit or offset".to_string()));
+    };
+    // Searches block, so they run off the executor
+    let results = task::spawn_blocking(move || {
+        pool.get().map(|conn| search::search(&conn, &query.q, query.limit, query.offset))
+    })
+    .await?;
+    match results {
+        Ok(results) => tide_format::response(format, &results),
+        Err(e) => error_response(format, &e),
+    }
+}
+
+fn error_response(format: Format, err: &SearchError) -> tide::Result {
+    retry::tide_response(format, err.status_code(), &err.body(), err.is_busy())
+}
@@ -0,0 +1,107 @@
+use async_std::io::BufReader;
+use async_std::task;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde::Deserialize;
+use std::io;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::auth::Principal;
+use crate::errors::ErrorBody;
+use crate::format::Format;
+use crate::limits::{limits, BodyError, LimitedReader};
+use crate::retry;
+use crate::sql_policy;
+use crate::stream_io::{self, BlockingReader};
+use crate::table_csv::{self, TableError};
+use crate::tide_format;
+use crate::tide_limits;
+use crate::tide_url;
+
+#[derive(Deserialize)]
+struct TableQuery {
+    delimiter: Option<String>,
+}
+
+fn delimiter<State>(req: &Request<State>) -> Option<u8> {
+    let query: TableQuery = req.query().ok()?;
+    table_csv::parse_delimiter(query.delimiter.as_deref())
+}
+
+// `POST /tables/:name/import`. The body is streamed into the table from a
+// blocking task, so its size is bounded by the import limits rather than the
+// request body limit.
+pub async fn import<State>(mut req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let table = tide_url::param(&req, "name")?;
+    let policy = sql_policy::policies().for_principal(req.ext::<Principal>());
+    let Some(delimiter) = delimiter(&req) else {
+        return invalid_delimiter(format);
+    };
+    if !table_csv::is_csv_content_type(req.header("Content-Type").map(|h| h.as_str())) {
+        return tide_limits::body_error_response(format, &BodyError::UnsupportedMediaType);
+    }
+    let limits = limits();
+    if req.len().is_some_and(|len| len as u64 > limits.max_import_bytes) {
+        return tide_limits::body_error_response(format, &BodyError::TooLarge);
+    }
+
+    let conn = pool.get()?;
+    let body = req.take_body();
+    let (max_bytes, timeout) = (limits.max_import_bytes, limits.import_timeout);
+    let (result, body_error) = task::spawn_blocking(move || {
+        let mut input = LimitedReader::new(BlockingReader(body), max_bytes, timeout);
+        let result = table_csv::import(&conn, policy, &table, &mut input, delimiter);
+        (result, input.take_error())
+    })
+    .await;
+
+    if let Some(e) = body_error {
+        return tide_limits::body_error_response(format, &e);
+    }
+    match result {
+        Ok(report) => tide_format::response(format, &report),
+        Err(e) => error_response(format, &e),
+    }
+}
+
+// `GET /tables/:name/export.csv`. Rows are written from a blocking task into
+// a bounded pipe, so the table is never held in memory.
+pub async fn export<State>(req: Request<State>, pool: Pool<SqliteConnectionManager>) -> tide::Result {
+    let format = tide_format::format(&req);
+    let table = tide_url::param(&req, "name")?;
+    let policy = sql_policy::policies().for_principal(req.ext::<Principal>());
+    let Some(delimiter) = delimiter(&req) else {
+        return invalid_delimiter(format);
+    };
+
+    let conn = pool.get()?;
+    if let Err(e) = table_csv::check_export(&conn, policy, &table) {
+        return error_response(format, &e);
+    }
+
+    let filename = table_csv::export_filename(&table);
+    let (mut writer, reader) = stream_io::pipe();
+    task::spawn_blocking(move || {
+        if let Err(e) = table_csv::export(&conn, policy, &table, &mut writer, delimiter) {
+            eprintln!("Failed to export table {}: {:?}", table, e);
+            writer.fail(io::Error::other("export failed"));
+        }
+    });
+
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_body(Body::from_reader(BufReader::new(reader), None));
+    response.set_content_type("text/csv");
+    response.insert_header("Content-Disposition", format!("attachment; filename=\"{}\"", filename));
+    Ok(response)
+}
+
+fn invalid_delimiter(format: Format) -> tide::Result {
+    let mut response = tide_format::response(format, &ErrorBody::new("Invalid delimiter"))?;
+    response.set_status(StatusCode::BadRequest);
+    Ok(response)
+}
+
+fn error_response(format: Format, err: &TableError) -> tide::Result {
+    retry::tide_response(format, err.status_code(), &err.body(), err.is_busy())
+}
@@ -0,0 +1,7 @@
+use percent_encoding::percent_decode_str;
+use tide::Request;
+
+// tide leaves route parameters percent-encoded
+pub fn param<State>(req: &Request<State>, name: &str) -> tide::Result<String> {
+    Ok(percent_decode_str(req.param(name)?).decode_utf8_lossy().into_owned())
+}
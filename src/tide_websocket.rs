@@ -0,0 +1,394 @@
+use async_std::channel::{self, Sender};
+use async_std::future;
+use async_std::task::{self, JoinHandle};
+use async_tungstenite::tungstenite::handshake::derive_accept_key;
+use async_tungstenite::tungstenite::protocol::Role;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures_util::{SinkExt, StreamExt};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tide::http::upgrade::Connection;
+use tide::{Request, Response, StatusCode};
+
+use crate::auth::{AuthError, Principal};
+use crate::changes::{self, Change};
+use crate::config::env_or;
+use crate::databases;
+use crate::errors::ErrorBody;
+use crate::query::{self, Params};
+use crate::rate_limit::{self, RateLimiter, RouteClass};
+use crate::sql_policy::{self, SqlPolicy};
+
+// `GET /ws`. Clients exchange JSON text frames over one connection:
+//
+//   {"type": "query", "id": 1, "sql": "SELECT * FROM person WHERE age > ?1", "params": [30]}
+//   {"type": "subscribe", "id": 2, "tables": ["person"], "last_event_id": "..."}
+//   {"type": "unsubscribe", "id": 3, "subscription": 2}
+//
+// Replies carry the request's `id`, so queries may be answered out of order:
+// `result` with `rows` or `changes`, `error`, `subscribed` and
+// `unsubscribed`. Subscriptions push `change` messages, and `reset` when
+// changes may have been missed, tagged with the subscribing request's id.
+//
+// Queries count against the client's TINYSQL_RATE_SQL bucket, and at most
+// TINYSQL_WS_MAX_IN_FLIGHT of them run at once per connection; others are
+// answered with an `error`. The connection is closed once the bearer token
+// it was opened with expires.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Query {
+        id: Value,
+        sql: String,
+        #[serde(default)]
+        params: Params,
+    },
+    Subscribe {
+        id: Value,
+        // Every watched table when empty
+        #[serde(default)]
+        tables: Vec<String>,
+        last_event_id: Option<String>,
+    },
+    Unsubscribe {
+        id: Value,
+        subscription: Value,
+    },
+}
+
+// What one connection may do, settled at the upgrade
+struct Quota {
+    limiter: &'static RateLimiter,
+    // The bucket queries are charged to, shared with the client's HTTP calls
+    rate_key: String,
+    max_in_flight: usize,
+    max_rows: usize,
+}
+
+fn max_in_flight() -> usize {
+    static MAX_IN_FLIGHT: OnceLock<usize> = OnceLock::new();
+    *MAX_IN_FLIGHT.get_or_init(|| env_or("TINYSQL_WS_MAX_IN_FLIGHT", 8).max(1))
+}
+
+// Counts a running query until dropped
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn acquire(running: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < max).then_some(n + 1))
+            .ok()
+            .map(|_| InFlight(running.clone()))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn error(message: impl Into<String>) -> Value {
+    json!(ErrorBody::new(message))
+}
+
+// Completes the upgrade handshake and serves the socket from its own task.
+// Sockets on a `named` database hold its handle while open, and refuse
+// subscriptions: the change feed only follows the default database.
+pub async fn upgrade<State>(
+    req: Request<State>,
+    pool: Pool<SqliteConnectionManager>,
+    named: Option<databases::Handle>,
+) -> tide::Result {
+    let is_upgrade = req.header("Upgrade").is_some_and(|h| h.as_str().eq_ignore_ascii_case("websocket"))
+        && req
+            .header("Connection")
+            .is_some_and(|h| h.as_str().split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")));
+    let key = req.header("Sec-WebSocket-Key").map(|h| h.as_str().to_string());
+    let (true, Some(key)) = (is_upgrade, key) else {
+        let mut response = Response::new(StatusCode::UpgradeRequired);
+        response.insert_header("Upgrade", "websocket");
+        response.set_body("Expected a WebSocket upgrade");
+        return Ok(response);
+    };
+
+    let principal = req.ext::<Principal>().cloned();
+    let ip = req.peer_addr().and_then(|addr| addr.parse::<SocketAddr>().ok()).map(|addr| addr.ip());
+    let quota = Quota {
+        limiter: rate_limit::limiter(),
+        rate_key: rate_limit::client_key(principal.as_ref(), ip),
+        max_in_flight: max_in_flight(),
+        max_rows: query::max_rows(),
+    };
+    let mut response = Response::new(StatusCode::SwitchingProtocols);
+    response.insert_header("Upgrade", "websocket");
+    response.insert_header("Connection", "Upgrade");
+    response.insert_header("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()));
+
+    let http_response: &mut tide::http::Response = response.as_mut();
+    let upgrade = http_response.recv_upgrade().await;
+    task::spawn(async move {
+        if let Some(connection) = upgrade.await {
+            let socket = WebSocketStream::from_raw_socket(connection, Role::Server, None).await;
+            serve(socket, pool, principal, quota, named.is_none()).await;
+        }
+        drop(named);
+    });
+    Ok(response)
+}
+
+async fn serve(
+    socket: WebSocketStream<Connection>,
+    pool: Pool<SqliteConnectionManager>,
+    principal: Option<Principal>,
+    quota: Quota,
+    follows_changes: bool,
+) {
+    let (mut sink, mut stream) = socket.split();
+    // Replies and changes funnel through one writer
+    let (outgoing, queued) = channel::bounded::<Value>(changes::config().history.max(1));
+    let writer = task::spawn(async move {
+        let keepalive = changes::config().keepalive;
+        loop {
+            let message = match future::timeout(keepalive, queued.recv()).await {
+                Ok(Ok(reply)) => Message::Text(reply.to_string()),
+                Ok(Err(_)) => break,
+                Err(_) => Message::Ping(Vec::new()),
+            };
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let expires = principal.as_ref().and_then(Principal::expires_in).map(|left| Instant::now() + left);
+    let running = Arc::new(AtomicUsize::new(0));
+    let mut subscriptions: HashMap<String, JoinHandle<()>> = HashMap::new();
+    loop {
+        let next = match expires {
+            Some(expires) => future::timeout(expires.saturating_duration_since(Instant::now()), stream.next()).await,
+            None => Ok(stream.next().await),
+        };
+        let message = match next {
+            Ok(Some(Ok(message))) => message,
+            Ok(_) => break,
+            Err(_) => {
+                let expired = error(AuthError::Expired.message());
+                let _ = outgoing.send(tagged("error", &Value::Null, expired)).await;
+                break;
+            }
+        };
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            // tungstenite answers pings itself
+            _ => continue,
+        };
+        let message = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(message) => message,
+            Err(e) => {
+                let _ = outgoing.send(tagged("error", &Value::Null, error(e.to_string()))).await;
+                continue;
+            }
+        };
+
+        match message {
+            ClientMessage::Query { id, sql, params } => {
+                let decision = quota.limiter.check(RouteClass::Sql, quota.rate_key.clone());
+                if let Some(decision) = decision.filter(|decision| !decision.allowed) {
+                    let mut refused = error("Too Many Requests");
+                    refused["retry_after"] = json!(decision.retry_after_secs);
+                    let _ = outgoing.send(tagged("error", &id, refused)).await;
+                    continue;
+                }
+                let Some(in_flight) = InFlight::acquire(&running, quota.max_in_flight) else {
+                    let refused = error(format!("Too many queries in flight, at most {} run at once", quota.max_in_flight));
+                    let _ = outgoing.send(tagged("error", &id, refused)).await;
+                    continue;
+                };
+                let (pool, principal, outgoing) = (pool.clone(), principal.clone(), outgoing.clone());
+                let max_rows = quota.max_rows;
+                task::spawn_blocking(move || {
+                    let outcome = pool
+                        .get()
+                        .map_err(|e| error(e.to_string()))
+                        .and_then(|conn| query::execute(&conn, principal.as_ref(), &sql, &params, max_rows).map_err(|e| e.body()));
+                    let reply = match outcome {
+                        Ok(result) => tagged("result", &id, result),
+                        Err(error) => tagged("error", &id, error),
+                    };
+                    // Released before replying so the client may follow up at once
+                    drop(in_flight);
+                    let _ = outgoing.send_blocking(reply);
+                });
+            }
+            ClientMessage::Subscribe { id, tables, last_event_id } => {
+                let key = id.to_string();
+                if !follows_changes {
+                    let refused = error("Changes are only followed on the default database");
+                    let _ = outgoing.send(tagged("error", &id, refused)).await;
+                    continue;
+                }
+                if subscriptions.contains_key(&key) {
+                    let _ = outgoing.send(tagged("error", &id, error("Subscription id already in use"))).await;
+                    continue;
+                }
+                let _ = outgoing.send(tagged("subscribed", &id, json!({}))).await;
+                let policy = sql_policy::policies().for_principal(principal.as_ref());
+                let forward = task::spawn(forward_changes(id, tables, policy, last_event_id, outgoing.clone()));
+                subscriptions.insert(key, forward);
+            }
+            ClientMessage::Unsubscribe { id, subscription } => match subscriptions.remove(&subscription.to_string()) {
+                Some(forward) => {
+                    forward.cancel().await;
+                    let _ = outgoing.send(tagged("unsubscribed", &id, json!({ "subscription": subscription }))).await;
+                }
+                None => {
+                    let _ = outgoing.send(tagged("error", &id, error("No such subscription"))).await;
+                }
+            },
+        }
+    }
+
+    for (_, forward) in subscriptions {
+        forward.cancel().await;
+    }
+    drop(outgoing);
+    writer.await;
+}
+
+fn tagged(kind: &str, id: &Value, mut body: Value) -> Value {
+    if let Value::Object(fields) = &mut body {
+        fields.insert("type".to_string(), json!(kind));
+        fields.insert("id".to_string(), id.clone());
+    }
+    body
+}
+
+// Forwards the changes to `tables` that `policy` lets the client read
+async fn forward_changes(
+    id: Value,
+    tables: Vec<String>,
+    policy: &'static SqlPolicy,
+    last_event_id: Option<String>,
+    outgoing: Sender<Value>,
+) {
+    let wanted = |change: &Change| change.is_for(&tables, policy);
+    let subscription = changes::subscribe(last_event_id.as_deref());
+    if subscription.reset {
+        let reset = json!({ "latest_id": subscription.latest_id });
+        if outgoing.send(tagged("reset", &id, reset)).await.is_err() {
+            return;
+        }
+    }
+    let missed = subscription.missed.into_iter();
+    let live = futures_util::stream::unfold(subscription.receiver, |receiver| async {
+        receiver.recv().await.ok().map(|change| (change, receiver))
+    });
+    let mut changes = futures_util::stream::iter(missed).chain(live).boxed();
+    while let Some(change) = changes.next().await {
+        if wanted(&change) && outgoing.send(tagged("change", &id, json!({ "change": *change }))).await.is_err() {
+            return;
+        }
+    }
+    // Dropped by the feed for falling behind; resubscribing with the last
+    // change's id catches up
+    let _ = outgoing.send(tagged("reset", &id, json!({ "latest_id": null }))).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    const SLOW: &str = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 2000000) SELECT count(*) FROM c";
+
+    fn quota(limiter: RateLimiter, max_in_flight: usize, max_rows: usize) -> Quota {
+        Quota { limiter: Box::leak(Box::new(limiter)), rate_key: "test".to_string(), max_in_flight, max_rows }
+    }
+
+    async fn connect(principal: Option<Principal>, quota: Quota) -> WebSocketStream<UnixStream> {
+        let (server, client) = UnixStream::pair().unwrap();
+        let pool = Pool::builder().max_size(2).build(SqliteConnectionManager::memory()).unwrap();
+        task::spawn(async move {
+            let socket = WebSocketStream::from_raw_socket(Connection::new(server), Role::Server, None).await;
+            serve(socket, pool, principal, quota, false).await;
+        });
+        WebSocketStream::from_raw_socket(client, Role::Client, None).await
+    }
+
+    async fn query(socket: &mut WebSocketStream<UnixStream>, id: u32, sql: &str) {
+        let message = json!({ "type": "query", "id": id, "sql": sql });
+        socket.send(Message::Text(message.to_string())).await.unwrap();
+    }
+
+    async fn next(socket: &mut WebSocketStream<UnixStream>) -> Option<Value> {
+        match future::timeout(Duration::from_secs(5), socket.next()).await.unwrap() {
+            Some(Ok(Message::Text(text))) => Some(serde_json::from_str(&text).unwrap()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn queries_past_the_in_flight_limit_are_refused() {
+        task::block_on(async {
+            let mut socket = connect(None, quota(RateLimiter::with_rate(RouteClass::Read, "1/1"), 1, 10)).await;
+            query(&mut socket, 1, SLOW).await;
+            query(&mut socket, 2, "SELECT 1").await;
+            let refused = next(&mut socket).await.unwrap();
+            assert_eq!(refused["id"], 2);
+            assert_eq!(refused["error"], "Too many queries in flight, at most 1 run at once");
+            assert_eq!(next(&mut socket).await.unwrap()["type"], "result");
+
+            // The finished query frees its slot
+            query(&mut socket, 3, "SELECT 1").await;
+            assert_eq!(next(&mut socket).await.unwrap()["rows"], json!([{ "1": 1 }]));
+        });
+    }
+
+    #[test]
+    fn queries_count_against_the_sql_rate_limit() {
+        task::block_on(async {
+            let mut socket = connect(None, quota(RateLimiter::with_rate(RouteClass::Sql, "1/60"), 8, 10)).await;
+            query(&mut socket, 1, "SELECT 1").await;
+            assert_eq!(next(&mut socket).await.unwrap()["type"], "result");
+            query(&mut socket, 2, "SELECT 1").await;
+            let refused = next(&mut socket).await.unwrap();
+            assert_eq!(refused["error"], "Too Many Requests");
+            assert_eq!(refused["retry_after"], 60);
+        });
+    }
+
+    #[test]
+    fn results_are_capped() {
+        task::block_on(async {
+            let mut socket = connect(None, quota(RateLimiter::with_rate(RouteClass::Read, "1/1"), 8, 2)).await;
+            query(&mut socket, 1, "VALUES (1), (2), (3)").await;
+            let refused = next(&mut socket).await.unwrap();
+            assert_eq!(refused["type"], "error");
+            assert_eq!(refused["error"], "Statement returned more than 2 rows; add a LIMIT");
+        });
+    }
+
+    #[test]
+    fn connections_close_when_the_token_expires() {
+        task::block_on(async {
+            let expiry = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 1;
+            let principal = Principal { name: "ci".to_string(), access: crate::auth::Access::ReadOnly, expiry: Some(expiry) };
+            let mut socket = connect(Some(principal), quota(RateLimiter::with_rate(RouteClass::Read, "1/1"), 8, 10)).await;
+            let expired = next(&mut socket).await.unwrap();
+            assert_eq!(expired, json!({ "type": "error", "id": null, "error": "Bearer token has expired" }));
+            assert!(next(&mut socket).await.is_none());
+        });
+    }
+}
@@ -0,0 +1,149 @@
+use std::fs::{self, File};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::backup::{self, BackupError};
+use crate::errors::ErrorBody;
+use crate::format::Format;
+use crate::limits::{limits, BodyError};
+use crate::retry;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_url;
+
+// Admin routes, as on the tide stack; only admin principals reach them:
+//   GET  /admin/backup                    backup progress and last outcome
+//   POST /admin/backup[?path=<file>]      take a backup, kept or streamed
+//   POST /admin/restore[?path=<file>]     restore a kept backup or the uploaded body
+pub enum AdminRoute {
+    Backup,
+    Restore,
+}
+
+pub fn route(request: &Request) -> Option<AdminRoute> {
+    match tinyhttp_url::path(request) {
+        "/admin/backup" => Some(AdminRoute::Backup),
+        "/admin/restore" => Some(AdminRoute::Restore),
+        _ => None,
+    }
+}
+
+// `rate_limit` holds the headers every response carries, from
+// `tinyhttp_rate_limit::check`
+pub fn handle(request: Request, db_path: &'static str, format: Format, route: AdminRoute, rate_limit: Vec<Header>) {
+    match (route, request.method()) {
+        (AdminRoute::Backup, Method::Get) => {
+            respond(request, tinyhttp_format::response(format, &backup::current_status()), &rate_limit)
+        }
+        (AdminRoute::Backup, Method::Post) => run_backup(request, db_path, format, rate_limit),
+        (AdminRoute::Restore, Method::Post) => restore(request, db_path, format, &rate_limit),
+        _ => respond(request, tinyhttp_format::error(format, "Method not allowed", 405), &rate_limit),
+    }
+}
+
+fn respond<R: std::io::Read>(request: Request, response: Response<R>, rate_limit: &[Header]) {
+    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+}
+
+fn error(request: Request, format: Format, err: &BackupError, rate_limit: &[Header]) {
+    respond(request, retry::tinyhttp_response(format, err.status_code(), &err.body(), err.is_busy()), rate_limit)
+}
+
+// Runs on its own thread with its own connection, so the server loop keeps
+// answering while pages are copied
+fn run_backup(request: Request, db_path: &'static str, format: Format, rate_limit: Vec<Header>) {
+    let name = tinyhttp_url::query_param(&request, "path");
+    let keep = name.is_some();
+    let dest = match name {
+        Some(name) => match backup::destination(&name) {
+            Ok(dest) => dest,
+            Err(e) => return error(request, format, &e, &rate_limit),
+        },
+        None => backup::scratch_path(),
+    };
+
+    thread::spawn(move || {
+        let report = match backup::run_from_path(db_path, &dest, keep) {
+            Ok(report) => report,
+            Err(e) => return error(request, format, &e, &rate_limit),
+        };
+        if keep {
+            return respond(request, tinyhttp_format::response(format, &report), &rate_limit);
+        }
+
+        // Unlinked once open, so the scratch file goes away with the response
+        let file = match File::open(&dest) {
+            Ok(file) => file,
+            Err(e) => return error(request, format, &BackupError::Io(e), &rate_limit),
+        };
+        let _ = fs::remove_file(&dest);
+        let disposition = format!("attachment; filename=\"backup-{}.db\"", report.started_at);
+        let headers = [
+            ("Content-Type", "application/vnd.sqlite3".to_string()),
+            ("Content-Disposition", disposition),
+            ("X-Backup-Pages", report.pages.to_string()),
+            ("X-Backup-Steps", report.steps.to_string()),
+            ("X-Backup-Duration-Ms", report.duration_ms.to_string()),
+        ];
+        let mut response = Response::from_file(file);
+        for (name, value) in headers {
+            response.add_header(Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+        }
+        respond(request, response, &rate_limit);
+    });
+}
+
+// Runs on the server loop itself, which has nothing else in flight, so the
+// swap only waits for backup and export threads to release their locks
+fn restore(mut request: Request, db_path: &'static str, format: Format, rate_limit: &[Header]) {
+    let expected_schema = match tinyhttp_url::query_param(&request, "schema_version").map(|v| v.parse::<i64>()) {
+        Some(Ok(version)) => Some(version),
+        Some(Err(_)) => {
+            let response = tinyhttp_format::response(format, &ErrorBody::new("Invalid schema_version"));
+            return respond(request, response.with_status_code(400), rate_limit);
+        }
+        None => None,
+    };
+
+    let snapshot = match tinyhttp_url::query_param(&request, "path") {
+        Some(name) => match backup::existing(&name) {
+            Ok(path) => Some(path),
+            Err(e) => return error(request, format, &e, rate_limit),
+        },
+        None => None,
+    };
+    let keep_path = snapshot.is_some();
+    let snapshot = match snapshot {
+        Some(path) => path,
+        None => {
+            if !backup::is_snapshot_content_type(tinyhttp_cors::header(&request, "Content-Type")) {
+                return respond(request, tinyhttp_limits::body_error_response(format, &BodyError::UnsupportedMediaType), rate_limit);
+            }
+            let limits = limits();
+            if request.body_length().is_some_and(|len| len as u64 > limits.max_import_bytes) {
+                return respond(request, tinyhttp_limits::body_error_response(format, &BodyError::TooLarge), rate_limit);
+            }
+            let mut input = tinyhttp_limits::body_reader(&mut request, limits.max_import_bytes, limits.import_timeout);
+            let saved = backup::save_upload(&mut input);
+            if let Some(e) = input.take_error() {
+                return respond(request, tinyhttp_limits::body_error_response(format, &e), rate_limit);
+            }
+            match saved {
+                Ok(path) => path,
+                Err(e) => return error(request, format, &e, rate_limit),
+            }
+        }
+    };
+
+    let result = backup::restore(db_path, &snapshot, expected_schema);
+    if !keep_path {
+        let _ = fs::remove_file(&snapshot);
+    }
+    match result {
+        Ok(report) => respond(request, tinyhttp_format::response(format, &report), rate_limit),
+        Err(e) => error(request, format, &e, rate_limit),
+    }
+}
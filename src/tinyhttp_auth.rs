@@ -0,0 +1,47 @@
+use std::io::Cursor;
+use tiny_http::{Header, Request, Response};
+
+use crate::auth::{self, auth, AuthError, Credentials, Principal};
+
+// Pre-handler check for tiny_http servers. On failure the caller responds
+// with the returned 401/403 and skips the handler.
+pub fn authenticate(request: &Request) -> Result<Option<Principal>, Response<Cursor<Vec<u8>>>> {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str())
+    };
+    let credentials = Credentials {
+        api_key: header("X-API-Key"),
+        authorization: header("Authorization"),
+    };
+    let is_write = auth::is_write(request.method().as_str());
+
+    match auth().authenticate(&credentials, is_write) {
+        Ok(Some(principal)) => {
+            println!("{} {} by {}", request.method(), request.url(), principal);
+            Ok(Some(principal))
+        }
+        Ok(None) => Ok(None),
+        Err(e) => Err(rejection(request, &e)),
+    }
+}
+
+// Checked before the admin routes: only admin principals get through,
+// everyone else gets the returned 403
+pub fn authorize_admin(request: &Request, principal: Option<&Principal>) -> Result<(), Response<Cursor<Vec<u8>>>> {
+    auth().authorize_admin(principal).map_err(|e| rejection(request, &e))
+}
+
+fn rejection(request: &Request, e: &AuthError) -> Response<Cursor<Vec<u8>>> {
+    eprintln!("Rejected {} {}: {}", request.method(), request.url(), e.message());
+    let mut response = Response::from_string(e.message())
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap())
+        .with_status_code(e.status_code());
+    if e.status_code() == 401 {
+        response.add_header(Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..]).unwrap());
+    }
+    response
+}
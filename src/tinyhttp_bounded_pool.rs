@@ -0,0 +1,144 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Request, Response};
+
+use crate::config::env_or;
+use crate::metrics;
+use crate::tinyhttp_cors;
+
+// A rayon pool that admits at most `num_threads + queue_len` requests at a
+// time. Anything beyond that is answered with an immediate 503 instead of
+// growing rayon's unbounded queue.
+pub struct BoundedPool {
+    pool: ThreadPool,
+    limit: usize,
+    in_flight: Arc<AtomicUsize>,
+    shedder: SyncSender<Request>,
+    retry_after_secs: u64,
+}
+
+// Releases the in-flight slot when the handler (or its helper) returns or
+// unwinds; the pool's panic handler keeps a panicking handler from aborting
+// the process
+struct Slot {
+    in_flight: Arc<AtomicUsize>,
+    request: bool,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if self.request {
+            metrics::REQUESTS_RUNNING.dec();
+        }
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl BoundedPool {
+    pub fn new(num_threads: usize) -> Self {
+        BoundedPool::with_queue(num_threads, env_or("TINYSQL_QUEUE_LEN", 64))
+    }
+
+    // With no queue every admitted job starts on a thread of its own
+    pub fn with_queue(num_threads: usize, queue_len: usize) -> Self {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .panic_handler(|_| eprintln!("Request handler panicked"))
+            .build()
+            .unwrap();
+        let retry_after_secs = env_or("TINYSQL_SHED_RETRY_AFTER", 1);
+        BoundedPool {
+            pool,
+            limit: num_threads + queue_len,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            shedder: spawn_shedder(queue_len, retry_after_secs),
+            retry_after_secs,
+        }
+    }
+
+    pub fn spawn(&self, request: Request, handle: impl FnOnce(Request) + Send + 'static) {
+        if !self.admit(1) {
+            self.shed(request);
+            return;
+        }
+        self.run(request, handle);
+    }
+
+    // Runs `handle` along with a `helper` it waits on, such as the producer
+    // of a streamed body. The two are admitted together, so in a pool with
+    // no queue the helper never sits queued behind the handler waiting for it.
+    pub fn spawn_with_helper(
+        &self,
+        request: Request,
+        helper: impl FnOnce() + Send + 'static,
+        handle: impl FnOnce(Request) + Send + 'static,
+    ) {
+        if !self.admit(2) {
+            self.shed(request);
+            return;
+        }
+        let in_flight = Arc::clone(&self.in_flight);
+        self.pool.spawn(move || {
+            let _slot = Slot { in_flight, request: false };
+            helper();
+        });
+        self.run(request, handle);
+    }
+
+    fn admit(&self, slots: usize) -> bool {
+        if self.in_flight.fetch_add(slots, Ordering::SeqCst) + slots > self.limit {
+            self.in_flight.fetch_sub(slots, Ordering::SeqCst);
+            metrics::REQUESTS_SHED.inc();
+            return false;
+        }
+        true
+    }
+
+    fn run(&self, request: Request, handle: impl FnOnce(Request) + Send + 'static) {
+        metrics::REQUESTS_QUEUED.inc();
+        let in_flight = Arc::clone(&self.in_flight);
+        self.pool.spawn(move || {
+            metrics::REQUESTS_QUEUED.dec();
+            metrics::REQUESTS_RUNNING.inc();
+            let _slot = Slot { in_flight, request: true };
+            handle(request);
+        });
+    }
+
+    // tiny_http drains any unread body when a request is dropped, so the 503
+    // goes out from the shedding thread to keep a slow upload off the accept
+    // loop. Once that thread is backed up too, the accept loop answers itself.
+    fn shed(&self, request: Request) {
+        match self.shedder.try_send(request) {
+            Ok(()) => {}
+            Err(TrySendError::Full(request) | TrySendError::Disconnected(request)) => {
+                unavailable(request, self.retry_after_secs)
+            }
+        }
+    }
+}
+
+// One thread answers every shed request in turn, however many arrive
+fn spawn_shedder(queue_len: usize, retry_after_secs: u64) -> SyncSender<Request> {
+    let (shedder, requests) = mpsc::sync_channel(queue_len);
+    thread::spawn(move || {
+        for request in requests {
+            unavailable(request, retry_after_secs);
+        }
+    });
+    shedder
+}
+
+fn unavailable(request: Request, retry_after_secs: u64) {
+    let retry_after = retry_after_secs.to_string();
+    let response = Response::from_string("Service Unavailable")
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap())
+        .with_header(Header::from_bytes(&b"Retry-After"[..], retry_after.as_bytes()).unwrap())
+        .with_status_code(503);
+    if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+}
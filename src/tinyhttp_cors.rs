@@ -0,0 +1,46 @@
+use std::io::{self, Read};
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+use crate::cors::{cors, is_preflight};
+
+pub fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+fn preflight(request: &Request) -> bool {
+    is_preflight(
+        request.method() == &Method::Options,
+        header(request, "Access-Control-Request-Method"),
+    )
+}
+
+// Drop-in for `request.respond(response)` that adds the CORS headers for the
+// request's origin, and `headers`: the rate limit headers
+// `tinyhttp_rate_limit::check` returned for it, or none before the check
+pub fn respond<R: Read>(request: Request, mut response: Response<R>, headers: &[Header]) -> io::Result<()> {
+    if let Some(config) = cors() {
+        for (name, value) in config.headers(header(&request, "Origin"), preflight(&request)) {
+            response.add_header(Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+        }
+    }
+    for header in headers {
+        response.add_header(header.clone());
+    }
+    request.respond(response)
+}
+
+// Answers a preflight with 204 before authentication, since browsers send
+// them without credentials. Returns the request when it isn't one.
+pub fn handle_preflight(request: Request) -> Option<Request> {
+    if cors().is_none() || !preflight(&request) {
+        return Some(request);
+    }
+    if let Err(e) = respond(request, Response::empty(StatusCode(204)), &[]) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+    None
+}
@@ -0,0 +1,81 @@
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use std::io::Cursor;
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+use crate::databases::{self, DatabaseError, NewDatabase};
+use crate::format::Format;
+use crate::retry;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_url;
+
+// Database management routes, as on the tide stack, for admin principals
+// only:
+//   /databases           GET list, POST create (`{"name": ...}`)
+//   /databases/<name>    GET inspect, DELETE
+pub struct DatabaseRoute {
+    name: Option<String>,
+}
+
+pub fn route(request: &Request) -> Option<DatabaseRoute> {
+    if tinyhttp_url::database(request).is_some() {
+        return None;
+    }
+    let segments = tinyhttp_url::segments(tinyhttp_url::path(request), "/databases")?;
+    match segments.as_slice() {
+        [] => Some(DatabaseRoute { name: None }),
+        [name] => Some(DatabaseRoute { name: Some(name.clone()) }),
+        _ => None,
+    }
+}
+
+pub fn handle(mut request: Request, format: Format, route: DatabaseRoute, rate_limit: &[Header]) {
+    let response = match (request.method(), route.name) {
+        (Method::Get, None) => respond(format, 200, databases::list()),
+        (Method::Post, None) => match read_new_database(&mut request, format) {
+            Ok(new) => respond(format, 201, databases::create(&new.name)),
+            Err(response) => response,
+        },
+        (Method::Get, Some(name)) => respond(format, 200, databases::inspect(&name)),
+        (Method::Delete, Some(name)) => match databases::delete(&name) {
+            Ok(()) => Response::from_data(Vec::new()).with_status_code(StatusCode(204)),
+            Err(e) => error_response(format, &e),
+        },
+        _ => tinyhttp_format::error(format, "Method not allowed", 405),
+    };
+
+    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+}
+
+// A connection to the named database a `/db/<name>/...` request addresses,
+// with the handle that marks the database in use while the request runs
+pub fn connection(name: &str) -> Result<(databases::Handle, PooledConnection<SqliteConnectionManager>), DatabaseError> {
+    let handle = databases::pool(name)?;
+    let conn = handle.pool.get()?;
+    Ok((handle, conn))
+}
+
+fn read_new_database(request: &mut Request, format: Format) -> Result<NewDatabase, Response<Cursor<Vec<u8>>>> {
+    let body = tinyhttp_limits::read_body(request).map_err(|e| tinyhttp_limits::body_error_response(format, &e))?;
+    let value = tinyhttp_format::decode(request, &body).map_err(|e| tinyhttp_limits::body_error_response(format, &e))?;
+    serde_json::from_value(value).map_err(|_| tinyhttp_format::error(format, "Expected {\"name\": ...}", 400))
+}
+
+fn respond<T: serde::Serialize>(
+    format: Format,
+    status_code: u16,
+    result: Result<T, DatabaseError>,
+) -> Response<Cursor<Vec<u8>>> {
+    match result {
+        Ok(value) => tinyhttp_format::response(format, &value).with_status_code(status_code),
+        Err(e) => error_response(format, &e),
+    }
+}
+
+pub fn error_response(format: Format, err: &DatabaseError) -> Response<Cursor<Vec<u8>>> {
+    retry::tinyhttp_response(format, err.status_code(), &err.body(), err.is_busy())
+}
@@ -1,92 +1,182 @@
-use async_std::task;
-use tiny_http::{Server, Response, Header, Request};
+use tiny_http::Request;
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 use std::time::Instant;
-use serde_json::json;
-use std::sync::{Arc, Mutex};
-use rusqlite::{Connection, params};
+use serde_json::Value;
+use std::sync::Arc;
+use rusqlite::params;
 
-#[derive(Serialize)]
+use crate::config;
+use crate::db_strategy::{self, with_connection, DbStrategy};
+use crate::memory;
+use crate::openapi::{ApiSpec, Operation, RouteGroup, SpecCache};
+use crate::rate_limit::RouteClass;
+use crate::sql_policy::{self, PolicyError};
+use crate::tinyhttp_auth;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_matrix::AsyncTasks;
+use crate::tinyhttp_openapi;
+use crate::tinyhttp_rate_limit;
+use crate::tls;
+
+#[derive(Serialize, JsonSchema)]
 struct ResponseData {
     message: String,
     status: String,
     time_taken: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct RequestData {
     query: String,
 }
 
-async fn handle_request(mut request: Request, conn: Arc<Mutex<Connection>>) {
+// Sent with a 403 when the caller's SQL policy rejects the statement
+#[derive(Serialize, JsonSchema)]
+struct DeniedResponse {
+    error: String,
+    action: String,
+    table: Option<String>,
+}
+
+fn handle_request(request: Request, strategy: &dyn DbStrategy) {
     let start = Instant::now();
 
-    // Read and parse the JSON body
-    let mut request_body = Vec::new();
-    if let Err(e) = request.as_reader().read_to_end(&mut request_body) {
-        eprintln!("Failed to read request body: {}", e);
-        let response = Response::from_string("Failed to read request body")
-            .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap());
-        if let Err(e) = request.respond(response) {
+    let Some(mut request) = tinyhttp_cors::handle_preflight(request) else {
+        return;
+    };
+
+    let principal = match tinyhttp_auth::authenticate(&request) {
+        Ok(principal) => principal,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    let rate_limit = match tinyhttp_rate_limit::check(&request, principal.as_ref(), RouteClass::Sql) {
+        Ok(headers) => headers,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    if let Some(response) = tinyhttp_openapi::response(&request, api_spec) {
+        if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
             eprintln!("Failed to respond to request: {}", e);
         }
         return;
     }
 
-    let request_data: RequestData = match serde_json::from_slice(&request_body) {
-        Ok(data) => data,
+    let format = match tinyhttp_format::negotiate(&request) {
+        Ok(format) => format,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    // Read the body and parse it in its Content-Type
+    let request_body = match tinyhttp_limits::read_body(&mut request) {
+        Ok(body) => body,
         Err(e) => {
-            eprintln!("Failed to parse request body as JSON: {}", e);
-            let response = Response::from_string("Failed to parse request body as JSON")
-                .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap());
-            if let Err(e) = request.respond(response) {
+            eprintln!("Failed to read request body: {}", e);
+            if let Err(e) = tinyhttp_cors::respond(request, tinyhttp_limits::body_error_response(format, &e), &rate_limit) {
                 eprintln!("Failed to respond to request: {}", e);
             }
             return;
         }
     };
 
-    // Execute the query in SQLite
-    let sqlite_status = match conn.lock().unwrap().execute(&request_data.query, params![]) {
-        Ok(_) => "Query executed successfully".to_string(),
+    let request_data: RequestData = match tinyhttp_format::decode(&request, &request_body) {
+        Ok(data) => data,
         Err(e) => {
+            eprintln!("Failed to parse request body: {}", e);
+            if let Err(e) = tinyhttp_cors::respond(request, tinyhttp_limits::body_error_response(format, &e), &rate_limit) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    // Execute the query in SQLite under the caller's policy
+    let policy = sql_policy::policies().for_principal(principal.as_ref());
+    let query = request_data.query;
+    let result = with_connection(strategy, move |conn| Ok(sql_policy::run(conn, policy, |conn| conn.execute(&query, params![]))));
+    let sqlite_status = match result {
+        Ok(Ok(_)) => "Query executed successfully".to_string(),
+        Ok(Err(PolicyError::Denied(denial))) => {
+            eprintln!("Denied {} on {:?}", denial.action, denial.table);
+            let response_body = DeniedResponse {
+                error: "Operation not permitted".to_string(),
+                action: denial.action,
+                table: denial.table,
+            };
+            let response = tinyhttp_format::response(format, &response_body).with_status_code(403);
+            if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+        Ok(Err(PolicyError::Sqlite(e))) => {
             eprintln!("Failed to execute query: {}", e);
             "Failed to execute query".to_string()
         }
+        Err(e) => {
+            eprintln!("Failed to execute query: {}", e.message());
+            "Failed to execute query".to_string()
+        }
     };
 
     let duration = start.elapsed();
     println!("Time taken to execute query: {:?}", duration);
 
-    // Create a JSON response
+    // Create a response in the negotiated format
     let response_data = ResponseData {
         message: "SQLite query execution measured".to_string(),
         status: sqlite_status,
         time_taken: format!("{:?}", duration),
     };
 
-    let response_body = json!(response_data).to_string();
-    let response = Response::from_string(response_body)
-        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+    let response = tinyhttp_format::response(format, &response_data);
 
-    if let Err(e) = request.respond(response) {
+    if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
         eprintln!("Failed to respond to request: {}", e);
     }
 }
 
+// Documents the routes answered by `tiny_db_hosted`
+fn api_spec() -> Arc<Value> {
+    static SPEC: SpecCache = SpecCache::new();
+    SPEC.get(0, build_spec)
+}
+
+fn build_spec() -> Value {
+    let mut spec = ApiSpec::new("tinysql hosted SQL (tiny_http)");
+    spec.add(
+        Operation::new("post", "/", "Execute one SQL statement under the caller's policy; any method is accepted")
+            .body(spec.schema::<RequestData>())
+            .response(200, "Timing; SQLite failures are reported in `status`", spec.schema::<ResponseData>())
+            .response(403, "The statement touches something the policy denies", spec.schema::<DeniedResponse>()),
+    );
+    RouteGroup::Docs.document(&mut spec, None);
+    spec.document()
+}
+
+// tiny_http handing requests to async-std tasks that share one connection
 pub fn tiny_db_hosted() {
-    task::block_on(async {
-        let conn = Connection::open("my_database.db").unwrap();
-        let conn = Arc::new(Mutex::new(conn));
-
-        let server = Server::http("0.0.0.0:8081").unwrap();
-        println!("Listening on http://0.0.0.0:8081/");
-
-        for request in server.incoming_requests() {
-            let conn = Arc::clone(&conn);
-            task::spawn(async move {
-                handle_request(request, conn).await;
-            });
-        }
-    });
-}
\ No newline at end of file
+    let strategy = db_strategy::open("mutex", memory::database("tiny_db_hosted", "my_database.db")).unwrap();
+
+    let addr = config::listen_addr("0.0.0.0:8081");
+    let server = tls::tinyhttp_server(&addr);
+    AsyncTasks::dispatch(server.incoming_requests(), move |request| handle_request(request, &*strategy));
+}
@@ -1,17 +1,34 @@
-use rayon::ThreadPoolBuilder;
-use rusqlite::Connection;
-use tiny_http::{Server, Response, Request, Header};
+use tiny_http::Request;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
 use std::time::Instant;
 
-#[derive(Deserialize,Serialize)]
+use crate::auth;
+use crate::config;
+use crate::db_strategy::{self, with_connection, DbStrategy};
+use crate::memory;
+use crate::metrics::{self, Snapshot};
+use crate::openapi::{ApiSpec, Operation, RouteGroup, SpecCache};
+use crate::rate_limit::RouteClass;
+use crate::tinyhttp_auth;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_matrix::Rayon;
+use crate::tinyhttp_openapi;
+use crate::tinyhttp_rate_limit;
+use crate::tls;
+
+#[derive(Deserialize,Serialize,JsonSchema)]
 struct RequestData {
     // Add the fields expected in the JSON request
     field1: String,
     field2: i32,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 struct ResponseData {
     message: String,
     status: String,
@@ -19,62 +36,99 @@ struct ResponseData {
     received_data: Option<RequestData>,
 }
 
+// tiny_http on a bounded rayon pool, opening a connection per request
 pub fn tiny_pooled() {
-    // Create an HTTP server that listens on port 8000
-    let server = Server::http("0.0.0.0:8000").unwrap();
-
-    // Create a thread pool with a custom number of threads
-    let pool = ThreadPoolBuilder::new().num_threads(16).build().unwrap();
+    let strategy = match db_strategy::open("per_request", memory::database("tiny_pooled", "my_db.sqlite")) {
+        Ok(strategy) => strategy,
+        Err(e) => {
+            eprintln!("{}", e.message());
+            return;
+        }
+    };
 
-    println!("Listening on http://0.0.0.0:8000/");
+    // Create an HTTP server that listens on port 8000 unless TINYSQL_ADDR says otherwise
+    let addr = config::listen_addr("0.0.0.0:8000");
+    let server = tls::tinyhttp_server(&addr);
 
-    for request in server.incoming_requests() {
-        // Use the thread pool to handle the request concurrently
-        pool.spawn(move || {
-            handle_request(request);
-        });
-    }
+    // Metrics are answered from the accept loop so they stay visible under overload
+    let requests = server.incoming_requests().filter_map(metrics::answer_tinyhttp);
+    Rayon::dispatch(requests, move |request| handle_request(request, &*strategy));
 }
 
-pub fn handle_request(mut request: Request) {
+pub fn handle_request(request: Request, strategy: &dyn DbStrategy) {
     let start = Instant::now();
 
-    // Read the request body
-    let mut body = String::new();
-    if let Err(e) = request.as_reader().read_to_string(&mut body) {
-        eprintln!("Failed to read request body: {}", e);
-        let response = Response::from_string("Failed to read request body")
-            .with_status_code(400);
-        if let Err(e) = request.respond(response) {
+    let Some(mut request) = tinyhttp_cors::handle_preflight(request) else {
+        return;
+    };
+
+    let principal = match tinyhttp_auth::authenticate(&request) {
+        Ok(principal) => principal,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    let is_write = auth::is_write(request.method().as_str());
+    let rate_limit = match tinyhttp_rate_limit::check(&request, principal.as_ref(), RouteClass::for_method(is_write)) {
+        Ok(headers) => headers,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    if let Some(response) = tinyhttp_openapi::response(&request, api_spec) {
+        if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
             eprintln!("Failed to respond to request: {}", e);
         }
         return;
     }
 
-    // Parse JSON from the request body
-    let request_data: Result<RequestData, serde_json::Error> = serde_json::from_str(&body);
-    let received_data = match request_data {
-        Ok(data) => Some(data),
+    let format = match tinyhttp_format::negotiate(&request) {
+        Ok(format) => format,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    // Read the request body
+    let body = match tinyhttp_limits::read_body(&mut request) {
+        Ok(body) => body,
         Err(e) => {
-            eprintln!("Failed to parse JSON request: {}", e);
-            let response = Response::from_string("Failed to parse JSON request")
-                .with_status_code(400);
-            if let Err(e) = request.respond(response) {
+            eprintln!("Failed to read request body: {}", e);
+            if let Err(e) = tinyhttp_cors::respond(request, tinyhttp_limits::body_error_response(format, &e), &rate_limit) {
                 eprintln!("Failed to respond to request: {}", e);
             }
             return;
         }
     };
 
-    // Open a connection to SQLite
-    let sqlite_status = match Connection::open("my_db.sqlite") {
-        Ok(conn) => {
-            // Close the connection by letting it go out of scope
-            drop(conn);
-            "Connection opened and closed successfully".to_string()
+    // Parse the request body in its Content-Type
+    let received_data = match tinyhttp_format::decode::<RequestData>(&request, &body) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            eprintln!("Failed to parse request body: {}", e);
+            if let Err(e) = tinyhttp_cors::respond(request, tinyhttp_limits::body_error_response(format, &e), &rate_limit) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
         }
+    };
+
+    // Open a connection to SQLite; it closes when the job returns
+    let sqlite_status = match with_connection(strategy, |_| Ok(())) {
+        Ok(()) => "Connection opened and closed successfully".to_string(),
         Err(e) => {
-            eprintln!("Failed to open SQLite connection: {}", e);
+            eprintln!("Failed to open SQLite connection: {}", e.message());
             "Failed to open SQLite connection".to_string()
         }
     };
@@ -82,7 +136,7 @@ pub fn handle_request(mut request: Request) {
     let duration = start.elapsed();
     println!("Time taken to open and close SQLite connection: {:?}", duration);
 
-    // Create a JSON response
+    // Create a response in the negotiated format
     let response_data = ResponseData {
         message: "SQLite connection open/close measured".to_string(),
         status: sqlite_status,
@@ -90,11 +144,30 @@ pub fn handle_request(mut request: Request) {
         received_data,
     };
 
-    let response_body = serde_json::to_string(&response_data).unwrap();
-    let response = Response::from_string(response_body)
-        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+    let response = tinyhttp_format::response(format, &response_data);
 
-    if let Err(e) = request.respond(response) {
+    if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
         eprintln!("Failed to respond to request: {}", e);
     }
 }
+
+// Documents the routes answered by `tiny_pooled`
+fn api_spec() -> Arc<Value> {
+    static SPEC: SpecCache = SpecCache::new();
+    SPEC.get(0, build_spec)
+}
+
+fn build_spec() -> Value {
+    let mut spec = ApiSpec::new("tinysql pooled (tiny_http)");
+    spec.add(
+        Operation::new("post", "/", "Measure opening and closing an SQLite connection; any method is accepted")
+            .body(spec.schema::<RequestData>())
+            .response(200, "Timing", spec.schema::<ResponseData>()),
+    );
+    spec.add(
+        Operation::new("get", "/metrics", "Retry, pool and rate limit counters")
+            .response_as(200, "Current counters", "application/json", spec.schema::<Snapshot>()),
+    );
+    RouteGroup::Docs.document(&mut spec, None);
+    spec.document()
+}
@@ -0,0 +1,40 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Cursor;
+use tiny_http::{Header, Request, Response};
+
+use crate::errors::ErrorBody;
+use crate::format::{BodyFormat, Format};
+use crate::limits::BodyError;
+use crate::tinyhttp_cors::header;
+
+// The response format for `request`, or a 406 to send instead
+pub fn negotiate(request: &Request) -> Result<Format, Response<Cursor<Vec<u8>>>> {
+    Format::from_accept(header(request, "Accept")).ok_or_else(|| {
+        Response::from_string("Not Acceptable")
+            .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap())
+            .with_status_code(406)
+    })
+}
+
+// Decodes a body read with `tinyhttp_limits::read_body` per `Content-Type`
+pub fn decode<T: DeserializeOwned>(request: &Request, body: &[u8]) -> Result<T, BodyError> {
+    let format = BodyFormat::from_content_type(header(request, "Content-Type")).ok_or(BodyError::UnsupportedMediaType)?;
+    format.decode(body).map_err(|_| BodyError::Invalid)
+}
+
+pub fn response<T: Serialize>(format: Format, value: &T) -> Response<Cursor<Vec<u8>>> {
+    match format.encode(value) {
+        Ok(body) => Response::from_data(body)
+            .with_header(Header::from_bytes(&b"Content-Type"[..], format.content_type().as_bytes()).unwrap()),
+        Err(e) => {
+            eprintln!("Failed to encode response as {}: {}", format.content_type(), e);
+            Response::from_string("Failed to encode response").with_status_code(500)
+        }
+    }
+}
+
+// An `ErrorBody` in the negotiated format
+pub fn error(format: Format, message: &str, status_code: u16) -> Response<Cursor<Vec<u8>>> {
+    response(format, &ErrorBody::new(message)).with_status_code(status_code)
+}
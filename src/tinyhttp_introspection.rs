@@ -0,0 +1,58 @@
+use rusqlite::Connection;
+use serde::Serialize;
+use std::io::Cursor;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::errors::ErrorBody;
+use crate::format::Format;
+use crate::introspection;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_url;
+
+// Read-only schema routes, as on the tide stack:
+//   /schema, /schema/tables/<name>, /schema/views/<name>
+pub enum SchemaRoute {
+    Database,
+    Table(String),
+    View(String),
+}
+
+pub fn route(request: &Request) -> Option<SchemaRoute> {
+    let segments = tinyhttp_url::segments(tinyhttp_url::path(request), "/schema")?;
+    match segments.as_slice() {
+        [] => Some(SchemaRoute::Database),
+        [kind, name] if kind == "tables" => Some(SchemaRoute::Table(name.clone())),
+        [kind, name] if kind == "views" => Some(SchemaRoute::View(name.clone())),
+        _ => None,
+    }
+}
+
+pub fn handle(request: Request, conn: &Connection, format: Format, route: SchemaRoute, rate_limit: &[Header]) {
+    let response = if request.method() != &Method::Get {
+        tinyhttp_format::error(format, "Method not allowed", 405)
+    } else {
+        match route {
+            SchemaRoute::Database => respond(format, introspection::database(conn).map(Some), ""),
+            SchemaRoute::Table(name) => {
+                respond(format, introspection::table(conn, &name), &format!("No such table: {}", name))
+            }
+            SchemaRoute::View(name) => respond(format, introspection::view(conn, &name), &format!("No such view: {}", name)),
+        }
+    };
+
+    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+}
+
+fn respond<T: Serialize>(format: Format, result: rusqlite::Result<Option<T>>, not_found: &str) -> Response<Cursor<Vec<u8>>> {
+    match result {
+        Ok(Some(value)) => tinyhttp_format::response(format, &value),
+        Ok(None) => tinyhttp_format::response(format, &ErrorBody::new(not_found)).with_status_code(404),
+        Err(e) => {
+            eprintln!("Failed to read schema: {}", e);
+            tinyhttp_format::response(format, &ErrorBody::new("Failed to read schema")).with_status_code(500)
+        }
+    }
+}
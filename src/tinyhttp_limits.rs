@@ -0,0 +1,137 @@
+use std::io::{Cursor, Read};
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Request, Response};
+
+use crate::format::Format;
+use crate::limits::{limits, BodyError, LimitedReader};
+use crate::tinyhttp_format;
+
+// tiny_http 0.12 never exposes the accepted socket, and a read timeout set on
+// the listener also times out its accept loop. Headers, and bodies up to 1 KiB,
+// are read on tiny_http's own connection threads, so a slow client there never
+// holds a worker, but header and idle timeouts are only enforced on the tide
+// stack. Before a handler reads a body, `set_socket_timeouts` finds the
+// request's socket and bounds every read by `read_timeout`; the total body
+// deadline is checked between reads.
+pub fn read_body(request: &mut Request) -> Result<Vec<u8>, BodyError> {
+    let limits = limits();
+    if let Some(length) = request.body_length() {
+        if length as u64 > limits.max_body_bytes {
+            return Err(BodyError::TooLarge);
+        }
+    }
+    set_socket_timeouts(request, limits.read_timeout);
+
+    let deadline = Instant::now() + limits.body_timeout;
+    let mut reader = request.as_reader().take(limits.max_body_bytes + 1);
+    let mut body = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        let read = reader.read(&mut chunk).map_err(BodyError::from_io)?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+        if body.len() as u64 > limits.max_body_bytes {
+            return Err(BodyError::TooLarge);
+        }
+        if Instant::now() > deadline {
+            return Err(BodyError::TimedOut);
+        }
+    }
+    Ok(body)
+}
+
+// A streamed body for handlers that consume it incrementally, with the same
+// per-read socket timeout as `read_body`
+pub fn body_reader(request: &mut Request, max_bytes: u64, timeout: Duration) -> LimitedReader<&mut dyn Read> {
+    set_socket_timeouts(request, limits().read_timeout);
+    LimitedReader::new(request.as_reader(), max_bytes, timeout)
+}
+
+// Sets SO_RCVTIMEO and SO_SNDTIMEO on the connection `request` arrived on.
+// Without access to tiny_http's sockets, the descriptor is the one whose peer
+// is the request's remote address. Returns false when none was found.
+#[cfg(target_os = "linux")]
+pub fn set_socket_timeouts(request: &Request, timeout: Duration) -> bool {
+    use std::fs;
+    use std::os::fd::RawFd;
+
+    let Some(peer) = request.remote_addr().copied() else {
+        return false;
+    };
+    let Ok(fds) = fs::read_dir("/proc/self/fd") else {
+        return false;
+    };
+    let fd = fds
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+        .find(|&fd| socket::peer_addr(fd) == Some(peer));
+    match fd {
+        Some(fd) => socket::set_timeouts(fd, timeout),
+        None => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_socket_timeouts(_request: &Request, _timeout: Duration) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod socket {
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::fd::RawFd;
+    use std::time::Duration;
+
+    // The remote address of socket `fd`, or None for anything else
+    pub fn peer_addr(fd: RawFd) -> Option<SocketAddr> {
+        // SAFETY: sockaddr_storage is plain data, large enough for any
+        // address, and getpeername writes at most `len` bytes into it
+        unsafe {
+            let mut storage: libc::sockaddr_storage = mem::zeroed();
+            let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            if libc::getpeername(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) != 0 {
+                return None;
+            }
+            match storage.ss_family as libc::c_int {
+                libc::AF_INET => {
+                    let addr = &*(&storage as *const _ as *const libc::sockaddr_in);
+                    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                    Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(&storage as *const _ as *const libc::sockaddr_in6);
+                    let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                    let port = u16::from_be(addr.sin6_port);
+                    Some(SocketAddr::V6(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id)))
+                }
+                _ => None,
+            }
+        }
+    }
+
+    pub fn set_timeouts(fd: RawFd, timeout: Duration) -> bool {
+        let timeval = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        [libc::SO_RCVTIMEO, libc::SO_SNDTIMEO].into_iter().all(|option| {
+            // SAFETY: the option value is a timeval of the size passed
+            unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    option,
+                    &timeval as *const _ as *const libc::c_void,
+                    mem::size_of::<libc::timeval>() as libc::socklen_t,
+                ) == 0
+            }
+        })
+    }
+}
+
+pub fn body_error_response(format: Format, err: &BodyError) -> Response<Cursor<Vec<u8>>> {
+    tinyhttp_format::error(format, err.message(), err.status_code())
+        .with_header(Header::from_bytes(&b"Connection"[..], &b"close"[..]).unwrap())
+}
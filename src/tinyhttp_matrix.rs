@@ -0,0 +1,149 @@
+use async_std::task;
+use std::io::Cursor;
+use std::sync::Arc;
+use tiny_http::{Method, Request, Response, StatusCode};
+
+use crate::db_strategy::DbStrategy;
+use crate::format::Format;
+use crate::matrix::{self, Action, Frontend, Reply};
+use crate::repository::Person;
+use crate::retry;
+use crate::tinyhttp_auth;
+use crate::tinyhttp_bounded_pool::BoundedPool;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_openapi;
+use crate::tinyhttp_url;
+use crate::tls;
+
+// tiny_http's accept loop, handling one request at a time
+pub struct Serial;
+
+// tiny_http handing requests to a bounded rayon pool
+pub struct Rayon;
+
+// tiny_http spawning an async-std blocking task per request
+pub struct AsyncTasks;
+
+// Each frontend's scheduling is also used on its own by the single-variant
+// tiny_http servers, which pass their accept loop's requests and a handler
+impl Serial {
+    pub fn dispatch(requests: impl IntoIterator<Item = Request>, handler: impl Fn(Request)) {
+        for request in requests {
+            handler(request);
+        }
+    }
+}
+
+impl Rayon {
+    pub fn dispatch(requests: impl IntoIterator<Item = Request>, handler: impl Fn(Request) + Send + Sync + 'static) {
+        let pool = BoundedPool::new(16);
+        let handler = Arc::new(handler);
+        for request in requests {
+            let handler = Arc::clone(&handler);
+            pool.spawn(request, move |request| handler(request));
+        }
+    }
+}
+
+impl AsyncTasks {
+    // Handlers block, so each runs on async-std's blocking pool rather than
+    // tying up an executor thread
+    pub fn dispatch(requests: impl IntoIterator<Item = Request>, handler: impl Fn(Request) + Send + Sync + 'static) {
+        let handler = Arc::new(handler);
+        for request in requests {
+            let handler = Arc::clone(&handler);
+            task::spawn_blocking(move || handler(request));
+        }
+    }
+}
+
+impl Frontend for Serial {
+    fn name(&self) -> &'static str {
+        "tiny_serial"
+    }
+
+    fn serve(&self, addr: &str, strategy: Arc<dyn DbStrategy>) {
+        Serial::dispatch(tls::tinyhttp_server(addr).incoming_requests(), |request| handle(request, &*strategy));
+    }
+}
+
+impl Frontend for Rayon {
+    fn name(&self) -> &'static str {
+        "tiny_rayon"
+    }
+
+    fn serve(&self, addr: &str, strategy: Arc<dyn DbStrategy>) {
+        Rayon::dispatch(tls::tinyhttp_server(addr).incoming_requests(), move |request| handle(request, &*strategy));
+    }
+}
+
+impl Frontend for AsyncTasks {
+    fn name(&self) -> &'static str {
+        "tiny_async"
+    }
+
+    fn serve(&self, addr: &str, strategy: Arc<dyn DbStrategy>) {
+        AsyncTasks::dispatch(tls::tinyhttp_server(addr).incoming_requests(), move |request| handle(request, &*strategy));
+    }
+}
+
+enum Route {
+    Create,
+    Person(String),
+}
+
+fn route(request: &Request) -> Option<Route> {
+    let segments = tinyhttp_url::segments(tinyhttp_url::path(request), "/people")?;
+    match segments.as_slice() {
+        [] => Some(Route::Create),
+        [name] => Some(Route::Person(name.clone())),
+        _ => None,
+    }
+}
+
+fn handle(request: Request, strategy: &dyn DbStrategy) {
+    let Some(mut request) = tinyhttp_cors::handle_preflight(request) else {
+        return;
+    };
+    let response = match decode(&mut request) {
+        Ok((action, format)) => reply_response(format, matrix::handle(strategy, action)),
+        Err(response) => response,
+    };
+    if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+}
+
+// The action a request asks for and the format to answer in, or the
+// response refusing it
+fn decode(request: &mut Request) -> Result<(Action, Format), Response<Cursor<Vec<u8>>>> {
+    tinyhttp_auth::authenticate(request)?;
+    if let Some(response) = tinyhttp_openapi::response(request, matrix::api_spec) {
+        return Err(response);
+    }
+    let format = tinyhttp_format::negotiate(request)?;
+    let Some(route) = route(request) else {
+        return Err(tinyhttp_format::error(format, "Not found", 404));
+    };
+    let action = match (request.method(), route) {
+        (Method::Post, Route::Create) => {
+            let body = tinyhttp_limits::read_body(request).map_err(|e| tinyhttp_limits::body_error_response(format, &e))?;
+            let person: Person =
+                tinyhttp_format::decode(request, &body).map_err(|e| tinyhttp_limits::body_error_response(format, &e))?;
+            Action::Create(person)
+        }
+        (Method::Get, Route::Person(name)) => Action::Read(name),
+        (Method::Delete, Route::Person(name)) => Action::Delete(name),
+        _ => return Err(tinyhttp_format::error(format, "Method not allowed", 405)),
+    };
+    Ok((action, format))
+}
+
+fn reply_response(format: Format, reply: Reply) -> Response<Cursor<Vec<u8>>> {
+    match reply.body {
+        Some(body) => retry::tinyhttp_response(format, reply.status, &body, reply.busy),
+        None => Response::from_data(Vec::new()).with_status_code(StatusCode(reply.status)),
+    }
+}
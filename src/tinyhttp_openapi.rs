@@ -0,0 +1,21 @@
+use serde_json::Value;
+use std::io::Cursor;
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::openapi;
+use crate::tinyhttp_url;
+
+// Answers `/openapi.json` and `/docs`, or returns `None` for other routes.
+// The spec is only fetched when one of them is asked for.
+pub fn response(request: &Request, spec: impl FnOnce() -> Arc<Value>) -> Option<Response<Cursor<Vec<u8>>>> {
+    if request.method() != &Method::Get {
+        return None;
+    }
+    let (body, content_type) = match tinyhttp_url::path(request) {
+        "/openapi.json" => (spec().to_string(), "application/json"),
+        "/docs" => (openapi::docs_html(&spec()), "text/html; charset=utf-8"),
+        _ => return None,
+    };
+    Some(Response::from_string(body).with_header(Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap()))
+}
@@ -0,0 +1,33 @@
+use std::io::Cursor;
+use tiny_http::{Header, Request, Response};
+
+use crate::auth::Principal;
+use crate::rate_limit::{client_key, limiter, RouteClass};
+
+// Pre-handler check for tiny_http servers, run after authentication. On
+// failure the caller responds with the returned 429 and skips the handler.
+// Allowed requests get the X-RateLimit-* headers to pass to
+// `tinyhttp_cors::respond` with every response, as tide adds them to its own.
+pub fn check(request: &Request, principal: Option<&Principal>, class: RouteClass) -> Result<Vec<Header>, Response<Cursor<Vec<u8>>>> {
+    let key = client_key(principal, request.remote_addr().map(|addr| addr.ip()));
+    let Some(decision) = limiter().check(class, key) else {
+        return Ok(Vec::new());
+    };
+    let headers: Vec<Header> = decision
+        .headers()
+        .into_iter()
+        .map(|(name, value)| Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap())
+        .collect();
+    if decision.allowed {
+        return Ok(headers);
+    }
+
+    eprintln!("Rate limited {} {}", request.method(), request.url());
+    let mut response = Response::from_string("Too Many Requests")
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap())
+        .with_status_code(429);
+    for header in headers {
+        response.add_header(header);
+    }
+    Err(response)
+}
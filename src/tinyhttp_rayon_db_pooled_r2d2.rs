@@ -1,71 +1,124 @@
-use rayon::ThreadPoolBuilder;
-use std::sync::Arc;
-use tiny_http::{Server, Request as TinyRequest, Response as TinyResponse, Header, Method};
+use tiny_http::{Request as TinyRequest, Response as TinyResponse, Method};
 use std::time::Instant;
-use r2d2::Pool;
-use r2d2_sqlite::SqliteConnectionManager;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::auth;
+use crate::config;
+use crate::db_strategy::{self, with_connection, DbStrategy};
+use crate::memory;
+use crate::metrics::{self, Snapshot};
+use crate::openapi::{ApiSpec, Operation, RouteGroup, SpecCache};
+use crate::rate_limit::RouteClass;
+use crate::tinyhttp_auth;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_matrix::Rayon;
+use crate::tinyhttp_openapi;
+use crate::tinyhttp_rate_limit;
+use crate::tls;
 
-#[derive(Debug,Deserialize)]
+#[derive(Debug,Serialize,Deserialize,JsonSchema)]
 struct MyRequest {
     // Define your request fields here
     name: String,
     age: u32,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 struct MyResponse {
     message: String,
     status: String,
     time_taken: String,
+    received_data: MyRequest,
 }
 
+// tiny_http on a bounded rayon pool, with connections from an r2d2 pool
 pub fn server_db_pooled() {
-    // Create an HTTP server that listens on port 8000
-    let server = Server::http("0.0.0.0:8000").unwrap();
-    println!("Listening on http://0.0.0.0:8000/");
-
-    // Create a connection manager and pool for SQLite
-    let manager = SqliteConnectionManager::file("my_db.sqlite");
-    let pooldb = Pool::new(manager).unwrap();
-
-    // Use a rayon thread pool for handling requests
-    let thread_pool = ThreadPoolBuilder::new().num_threads(16).build().unwrap();
-    let thread_pool = Arc::new(thread_pool);
-
-    for request in server.incoming_requests() {
-        let pool_clone = Arc::clone(&thread_pool);
-        let pool_sqlite = pooldb.clone();
-        pool_clone.spawn(move || {
-            handle_request(request, pool_sqlite);
-        });
-    }
+    let strategy = match db_strategy::open("pool", memory::database("server_db_pooled", "my_db.sqlite")) {
+        Ok(strategy) => strategy,
+        Err(e) => {
+            eprintln!("{}", e.message());
+            return;
+        }
+    };
+
+    // Create an HTTP server that listens on port 8000 unless TINYSQL_ADDR says otherwise
+    let addr = config::listen_addr("0.0.0.0:8000");
+    let server = tls::tinyhttp_server(&addr);
+
+    // Metrics are answered from the accept loop so they stay visible under overload
+    let requests = server.incoming_requests().filter_map(metrics::answer_tinyhttp);
+    Rayon::dispatch(requests, move |request| handle_request(request, &*strategy));
 }
 
-pub fn handle_request(mut request: TinyRequest, pool_sqlite: Pool<SqliteConnectionManager>) {
+pub fn handle_request(request: TinyRequest, strategy: &dyn DbStrategy) {
     let start = Instant::now();
 
-    // Only handle POST requests
-    if request.method() == &Method::Post {
-        let mut content = String::new();
-        if let Err(e) = request.as_reader().read_to_string(&mut content) {
-            eprintln!("Failed to read request body: {}", e);
-            let response = TinyResponse::from_string("Failed to read request body")
-                .with_status_code(400);
-            if let Err(e) = request.respond(response) {
+    let Some(mut request) = tinyhttp_cors::handle_preflight(request) else {
+        return;
+    };
+
+    let principal = match tinyhttp_auth::authenticate(&request) {
+        Ok(principal) => principal,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    let is_write = auth::is_write(request.method().as_str());
+    let rate_limit = match tinyhttp_rate_limit::check(&request, principal.as_ref(), RouteClass::for_method(is_write)) {
+        Ok(headers) => headers,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
                 eprintln!("Failed to respond to request: {}", e);
             }
             return;
         }
+    };
+
+    if let Some(response) = tinyhttp_openapi::response(&request, api_spec) {
+        if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+            eprintln!("Failed to respond to request: {}", e);
+        }
+        return;
+    }
 
-        // Parse the request body as JSON into MyRequest struct
-        let json_data: MyRequest = match serde_json::from_str(&content) {
+    // Only handle POST requests
+    if request.method() == &Method::Post {
+        let format = match tinyhttp_format::negotiate(&request) {
+            Ok(format) => format,
+            Err(response) => {
+                if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+                    eprintln!("Failed to respond to request: {}", e);
+                }
+                return;
+            }
+        };
+
+        let content = match tinyhttp_limits::read_body(&mut request) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Failed to read request body: {}", e);
+                if let Err(e) = tinyhttp_cors::respond(request, tinyhttp_limits::body_error_response(format, &e), &rate_limit) {
+                    eprintln!("Failed to respond to request: {}", e);
+                }
+                return;
+            }
+        };
+
+        // Parse the request body in its Content-Type into MyRequest struct
+        let json_data: MyRequest = match tinyhttp_format::decode(&request, &content) {
             Ok(data) => data,
             Err(e) => {
-                eprintln!("Failed to parse JSON: {}", e);
-                let response = TinyResponse::from_string("Invalid JSON")
-                    .with_status_code(400);
-                if let Err(e) = request.respond(response) {
+                eprintln!("Failed to parse request body: {}", e);
+                if let Err(e) = tinyhttp_cors::respond(request, tinyhttp_limits::body_error_response(format, &e), &rate_limit) {
                     eprintln!("Failed to respond to request: {}", e);
                 }
                 return;
@@ -75,15 +128,11 @@ pub fn handle_request(mut request: TinyRequest, pool_sqlite: Pool<SqliteConnecti
         // Example of how you might use the parsed JSON data
         println!("Received JSON data: {:?}", json_data);
 
-        // Get a connection from the pool
-        let sqlite_status = match pool_sqlite.get() {
-            Ok(conn) => {
-                // Close the connection by letting it go out of scope
-                drop(conn);
-                "Connection opened and closed successfully".to_string()
-            }
+        // Get a connection from the pool; it goes back when the job returns
+        let sqlite_status = match with_connection(strategy, |_| Ok(())) {
+            Ok(()) => "Connection opened and closed successfully".to_string(),
             Err(e) => {
-                eprintln!("Failed to get SQLite connection: {}", e);
+                eprintln!("Failed to get SQLite connection: {}", e.message());
                 "Failed to open SQLite connection".to_string()
             }
         };
@@ -91,26 +140,47 @@ pub fn handle_request(mut request: TinyRequest, pool_sqlite: Pool<SqliteConnecti
         let duration = start.elapsed();
         println!("Time taken to open and close SQLite connection: {:?}", duration);
 
-        // Create a JSON response using MyResponse struct
+        // Create a response in the negotiated format using MyResponse struct
         let response_data = MyResponse {
             message: "SQLite connection open/close measured".to_string(),
             status: sqlite_status,
             time_taken: format!("{:?}", duration),
+            received_data: json_data,
         };
 
-        let response_body = serde_json::to_string(&response_data).unwrap();
-        let response = TinyResponse::from_string(response_body)
-            .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+        let response = tinyhttp_format::response(format, &response_data);
 
-        if let Err(e) = request.respond(response) {
+        if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
             eprintln!("Failed to respond to request: {}", e);
         }
     } else {
         // Respond with 405 Method Not Allowed for non-POST requests
         let response = TinyResponse::from_string("Method Not Allowed")
             .with_status_code(405);
-        if let Err(e) = request.respond(response) {
+        if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
             eprintln!("Failed to respond to request: {}", e);
         }
     }
 }
+
+// Documents the routes answered by `server_db_pooled`
+fn api_spec() -> Arc<Value> {
+    static SPEC: SpecCache = SpecCache::new();
+    SPEC.get(0, build_spec)
+}
+
+fn build_spec() -> Value {
+    let mut spec = ApiSpec::new("tinysql pooled r2d2 (tiny_http)");
+    spec.add(
+        Operation::new("post", "/", "Measure checking out a pooled SQLite connection")
+            .body(spec.schema::<MyRequest>())
+            .response(200, "Timing", spec.schema::<MyResponse>())
+            .response_text(405, "Only POST is accepted"),
+    );
+    spec.add(
+        Operation::new("get", "/metrics", "Retry, pool and rate limit counters")
+            .response_as(200, "Current counters", "application/json", spec.schema::<Snapshot>()),
+    );
+    RouteGroup::Docs.document(&mut spec, None);
+    spec.document()
+}
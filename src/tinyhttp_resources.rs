@@ -0,0 +1,98 @@
+use rusqlite::Connection;
+use serde_json::Value;
+use std::io::Cursor;
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+use crate::attributes;
+use crate::format::Format;
+use crate::resources::{self, ResourceError};
+use crate::retry;
+use crate::sql_policy::SqlPolicy;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_url;
+
+// Generic row routes for every table, as on the tide stack:
+//   /tables/<name>/rows          GET list (`limit`, `offset`, `attr.<path>`), POST create
+//   /tables/<name>/rows/<key>    GET, PUT (given columns), DELETE
+pub struct RowRoute {
+    table: String,
+    key: Option<String>,
+}
+
+pub fn route(request: &Request) -> Option<RowRoute> {
+    let segments = tinyhttp_url::segments(tinyhttp_url::path(request), "/tables/")?;
+    match segments.as_slice() {
+        [table, rows] if rows == "rows" => Some(RowRoute { table: table.clone(), key: None }),
+        [table, rows, key] if rows == "rows" => Some(RowRoute {
+            table: table.clone(),
+            key: Some(key.clone()),
+        }),
+        _ => None,
+    }
+}
+
+pub fn handle(
+    mut request: Request,
+    conn: &Connection,
+    policy: &SqlPolicy,
+    format: Format,
+    route: RowRoute,
+    rate_limit: &[Header],
+) {
+    let response = match (request.method(), route.key) {
+        (Method::Get, None) => match page(&request) {
+            Ok((limit, offset)) => {
+                let filters = attributes::filters(tinyhttp_url::query_pairs(&request));
+                respond(format, 200, resources::list(conn, policy, &route.table, &filters, limit, offset))
+            }
+            Err(e) => error_response(format, &e),
+        },
+        (Method::Post, None) => match read_json(&mut request, format) {
+            Ok(body) => respond(format, 201, resources::create(conn, policy, &route.table, &body)),
+            Err(response) => response,
+        },
+        (Method::Get, Some(key)) => respond(format, 200, resources::get(conn, policy, &route.table, &key)),
+        (Method::Put, Some(key)) => match read_json(&mut request, format) {
+            Ok(body) => respond(format, 200, resources::update(conn, policy, &route.table, &key, &body)),
+            Err(response) => response,
+        },
+        (Method::Delete, Some(key)) => match resources::delete(conn, policy, &route.table, &key) {
+            Ok(()) => Response::from_data(Vec::new()).with_status_code(StatusCode(204)),
+            Err(e) => error_response(format, &e),
+        },
+        _ => tinyhttp_format::error(format, "Method not allowed", 405),
+    };
+
+    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+}
+
+fn page(request: &Request) -> Result<(Option<u32>, Option<u32>), ResourceError> {
+    let parse = |name| match tinyhttp_url::query_param(request, name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ResourceError::Invalid("Invalid limit or offset".to_string())),
+        None => Ok(None),
+    };
+    Ok((parse("limit")?, parse("offset")?))
+}
+
+fn read_json(request: &mut Request, format: Format) -> Result<Value, Response<Cursor<Vec<u8>>>> {
+    let body = tinyhttp_limits::read_body(request).map_err(|e| tinyhttp_limits::body_error_response(format, &e))?;
+    tinyhttp_format::decode(request, &body).map_err(|e| tinyhttp_limits::body_error_response(format, &e))
+}
+
+fn respond(format: Format, status_code: u16, result: Result<Value, ResourceError>) -> Response<Cursor<Vec<u8>>> {
+    match result {
+        Ok(value) => tinyhttp_format::response(format, &value).with_status_code(status_code),
+        Err(e) => error_response(format, &e),
+    }
+}
+
+fn error_response(format: Format, err: &ResourceError) -> Response<Cursor<Vec<u8>>> {
+    retry::tinyhttp_response(format, err.status_code(), &err.body(), err.is_busy())
+}
@@ -1,79 +1,208 @@
-use rusqlite::{params, Connection, Result};
-use tiny_http::{Server, Response, Request, Header, Method};
+use rusqlite::Connection;
+use tiny_http::{Header, Response, Request, Method};
 use std::io::Cursor;
+use std::sync::Arc;
 use std::time::Instant;
-use serde_json::{json, Value};
-use serde::Deserialize;
+use serde_json::{Map, Value};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
-struct Person {
-    name: String,
-    age: i32,
+use crate::auth::{self, Principal};
+use crate::backup_schedule;
+use crate::config;
+use crate::db_strategy::{self, with_connection, DbStrategy};
+use crate::errors::ErrorBody;
+use crate::format::Format;
+use crate::memory;
+use crate::metrics::{self, Snapshot};
+use crate::openapi::{ApiSpec, Operation, RouteGroup, SpecCache};
+use crate::rate_limit::RouteClass;
+use crate::repository::{self, create_table, statement_cache_capacity, delete_person, insert_person, select_person, update_person};
+use crate::resources;
+use crate::retry;
+use crate::schema;
+use crate::search;
+use crate::sql_policy;
+use crate::tinyhttp_admin;
+use crate::tinyhttp_auth;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_databases;
+use crate::tinyhttp_format;
+use crate::tinyhttp_introspection;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_matrix::Serial;
+use crate::tinyhttp_openapi;
+use crate::tinyhttp_rate_limit;
+use crate::tinyhttp_resources;
+use crate::tinyhttp_search;
+use crate::tinyhttp_table_csv;
+use crate::tinyhttp_url;
+use crate::tls;
+
+const DB_FILE: &str = "db/my_database.db";
+
+// The database file, or its in-memory stand-in under TINYSQL_MEMORY
+fn db_path() -> &'static str {
+    memory::database("tinyhttp_crud", DB_FILE)
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct PersonRequest {
     name: String,
     age: Option<i32>,
+    // Stored on insert; on update, replaces the stored attributes, or clears
+    // them when null
+    #[serde(default, deserialize_with = "repository::explicit_null")]
+    attributes: Option<Option<Map<String, Value>>>,
 }
 
-pub fn tinyhttp_crud() {
-    // Create an HTTP server that listens on port 8000
-    let server = Server::http("0.0.0.0:8000").unwrap();
+#[derive(Serialize, JsonSchema)]
+struct Message {
+    message: String,
+}
 
-    println!("Listening on http://0.0.0.0:8000/");
+// tiny_http's accept loop on one shared connection
+pub fn tinyhttp_crud() {
+    // Create an HTTP server that listens on port 8000 unless TINYSQL_ADDR says otherwise
+    let addr = config::listen_addr("0.0.0.0:8000");
+    let server = tls::tinyhttp_server(&addr);
 
     // Open a connection to SQLite
-    match Connection::open("db/my_database.db") {
-        Ok(conn) => {
-            // Create table if it doesn't exist
-            if let Err(e) = create_table(&conn) {
-                eprintln!("Failed to create table: {}", e);
-                return;
-            }
-
-            // Handle incoming requests
-            for request in server.incoming_requests() {
-                handle_request(request, &conn);
-            }
-        }
+    let strategy = match db_strategy::open("mutex", db_path()) {
+        Ok(strategy) => strategy,
         Err(e) => {
-            eprintln!("Failed to open SQLite connection: {}", e);
+            eprintln!("Failed to open SQLite connection: {}", e.message());
+            return;
         }
+    };
+    println!("Prepared statement cache capacity: {}", statement_cache_capacity());
+
+    // Create table if it doesn't exist
+    if let Err(e) = with_connection(&*strategy, |conn| create_table(conn).and_then(|_| search::create_index(conn))) {
+        eprintln!("Failed to create tables: {}", e.message());
+        return;
     }
+    match with_connection(&*strategy, resources::describe) {
+        Ok(tables) => println!("Row routes for tables: {}", tables),
+        Err(e) => eprintln!("Failed to read schema: {}", e.message()),
+    }
+    backup_schedule::start(db_path());
+
+    // Handle incoming requests, each with the connection to itself
+    Serial::dispatch(server.incoming_requests(), |request| serve(request, &*strategy));
 }
 
-pub fn handle_request(mut request: Request, conn: &Connection) {
+fn serve(request: Request, strategy: &dyn DbStrategy) {
+    // A request whose job never runs is dropped, which answers it with a 500
+    if let Err(e) = strategy.run(Box::new(move |conn| handle_request(request, conn))) {
+        eprintln!("Failed to handle request: {}", e.message());
+    }
+}
+
+pub fn handle_request(request: Request, conn: &Connection) {
     let start = Instant::now();
 
-    // Read the request body
-    let mut body = String::new();
-    if let Err(e) = request.as_reader().read_to_string(&mut body) {
-        eprintln!("Failed to read request body: {}", e);
-        respond_with_error(request, "Failed to read request body", 400);
+    let Some(mut request) = tinyhttp_cors::handle_preflight(request) else {
+        return;
+    };
+
+    let principal = match tinyhttp_auth::authenticate(&request) {
+        Ok(principal) => principal,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    if metrics::is_metrics_request(&request) {
+        if let Err(e) = tinyhttp_cors::respond(request, metrics::tinyhttp_response(), &[]) {
+            eprintln!("Failed to respond to request: {}", e);
+        }
         return;
     }
 
-    // Parse the JSON body
-    let json_data: Value = match serde_json::from_str(&body) {
+    let is_write = auth::is_write(request.method().as_str());
+    let rate_limit = match tinyhttp_rate_limit::check(&request, principal.as_ref(), RouteClass::for_method(is_write)) {
+        Ok(headers) => headers,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &[]) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    let format = match tinyhttp_format::negotiate(&request) {
+        Ok(format) => format,
+        Err(response) => {
+            if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
+            return;
+        }
+    };
+
+    // Requests under `/db/<name>/` address a named database; the routes
+    // below see the path without that prefix
+    let database = tinyhttp_url::database(&request);
+    let named;
+    let (conn, db_path): (&Connection, &str) = match &database {
+        Some(name) => match tinyhttp_databases::connection(name) {
+            Ok(connection) => {
+                named = connection;
+                (&named.1, named.1.path().unwrap_or_default())
+            }
+            Err(e) => {
+                let response = tinyhttp_databases::error_response(format, &e);
+                if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+                    eprintln!("Failed to respond to request: {}", e);
+                }
+                return;
+            }
+        },
+        None => (conn, db_path()),
+    };
+
+    let addressed = Addressed { conn, path: db_path, named: database.is_some() };
+    for group in ROUTES {
+        request = match serve_group(group, request, &addressed, principal.as_ref(), format, &rate_limit) {
+            Some(request) => request,
+            None => return,
+        };
+    }
+
+    // Read the request body
+    let body = match tinyhttp_limits::read_body(&mut request) {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("Failed to read request body: {}", e);
+            respond_with_error(request, format, e.message(), e.status_code(), &rate_limit);
+            return;
+        }
+    };
+
+    // Parse the body in its Content-Type
+    let person_request: PersonRequest = match tinyhttp_format::decode(&request, &body) {
         Ok(data) => data,
         Err(e) => {
-            eprintln!("Failed to parse JSON: {}", e);
-            respond_with_error(request, "Invalid JSON", 400);
+            eprintln!("Failed to parse request body: {}", e);
+            respond_with_error(request, format, e.message(), e.status_code(), &rate_limit);
             return;
         }
     };
 
     // Handle different HTTP methods
     let response = match request.method() {
-        Method::Post => handle_post_request(conn, json_data),
-        Method::Get => handle_get_request(conn, json_data),
-        Method::Put => handle_put_request(conn, json_data),
-        Method::Delete => handle_delete_request(conn, json_data),
-        _ => respond_with_error_response("Unsupported HTTP method", 405),
+        Method::Post => handle_post_request(conn, format, person_request),
+        Method::Get => handle_get_request(conn, format, person_request),
+        Method::Put => handle_put_request(conn, format, person_request),
+        Method::Delete => handle_delete_request(conn, format, person_request),
+        _ => respond_with_error_response(format, "Unsupported HTTP method", 405),
     };
 
-    if let Err(e) = request.respond(response) {
+    if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
         eprintln!("Failed to respond to request: {}", e);
     }
 
@@ -81,148 +210,219 @@ pub fn handle_request(mut request: Request, conn: &Connection) {
     println!("Time taken to handle request: {:?}", duration);
 }
 
-fn handle_post_request(conn: &Connection, json_data: Value) -> Response<Cursor<Vec<u8>>> {
-    let person_request: PersonRequest = match serde_json::from_value(json_data) {
-        Ok(data) => data,
-        Err(_) => {
-            return respond_with_error_response("Invalid JSON structure for POST", 400);
+// The route groups served besides the person routes, in the order they are
+// tried; `api_spec` documents the same list
+const ROUTES: [RouteGroup; 6] = [
+    RouteGroup::Docs,
+    RouteGroup::Databases,
+    RouteGroup::Admin,
+    RouteGroup::Tables,
+    RouteGroup::Schema,
+    RouteGroup::Search,
+];
+
+// The database a request addresses; `named` is set for requests under
+// `/db/<name>/`, which only reach the data routes
+struct Addressed<'a> {
+    conn: &'a Connection,
+    path: &'a str,
+    named: bool,
+}
+
+// Answers `request` if it belongs to `group`, or hands it back. Every answer
+// carries the caller's rate limit headers.
+fn serve_group(
+    group: RouteGroup,
+    request: Request,
+    database: &Addressed,
+    principal: Option<&Principal>,
+    format: Format,
+    rate_limit: &[Header],
+) -> Option<Request> {
+    let Addressed { conn, path, named } = *database;
+    match group {
+        RouteGroup::Docs => {
+            let response = if named { None } else { tinyhttp_openapi::response(&request, || api_spec(conn)) };
+            let Some(response) = response else {
+                return Some(request);
+            };
+            if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+                eprintln!("Failed to respond to request: {}", e);
+            }
         }
-    };
+        RouteGroup::Databases => match tinyhttp_databases::route(&request) {
+            Some(route) => match tinyhttp_auth::authorize_admin(&request, principal) {
+                Ok(()) => tinyhttp_databases::handle(request, format, route, rate_limit),
+                Err(response) => {
+                    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+                        eprintln!("Failed to respond to request: {}", e);
+                    }
+                }
+            },
+            None => return Some(request),
+        },
+        RouteGroup::Admin => match tinyhttp_admin::route(&request).filter(|_| !named) {
+            Some(route) => match tinyhttp_auth::authorize_admin(&request, principal) {
+                Ok(()) => tinyhttp_admin::handle(request, db_path(), format, route, rate_limit.to_vec()),
+                Err(response) => {
+                    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+                        eprintln!("Failed to respond to request: {}", e);
+                    }
+                }
+            },
+            None => return Some(request),
+        },
+        RouteGroup::Tables => {
+            // Table routes are bounded by the caller's SQL policy
+            let policy = sql_policy::policies().for_principal(principal);
+            // CSV imports stream their own body; exports stream the table out.
+            // Generic row routes read their body only when they take one.
+            if let Some(route) = tinyhttp_table_csv::route(&request) {
+                tinyhttp_table_csv::handle(request, conn, path, policy, format, route, rate_limit);
+            } else if let Some(route) = tinyhttp_resources::route(&request) {
+                tinyhttp_resources::handle(request, conn, policy, format, route, rate_limit);
+            } else {
+                return Some(request);
+            }
+        }
+        RouteGroup::Schema => match tinyhttp_introspection::route(&request) {
+            Some(route) => tinyhttp_introspection::handle(request, conn, format, route, rate_limit),
+            None => return Some(request),
+        },
+        RouteGroup::Search => {
+            if !tinyhttp_search::is_search_request(&request) {
+                return Some(request);
+            }
+            tinyhttp_search::handle(request, conn, format, rate_limit);
+        }
+        // The change feed and WebSocket are only served by tide
+        RouteGroup::Realtime => return Some(request),
+    }
+    None
+}
 
+fn handle_post_request(conn: &Connection, format: Format, person_request: PersonRequest) -> Response<Cursor<Vec<u8>>> {
     match person_request.age {
         Some(age) => {
-            if let Err(e) = insert_person(&conn, &person_request.name, age) {
+            if let Err(e) = insert_person(conn, &person_request.name, age, person_request.attributes.as_ref().and_then(Option::as_ref)) {
+                if retry::is_busy(&e) {
+                    eprintln!("Database busy, giving up on insert: {}", e);
+                    return respond_with_busy_response(format);
+                }
                 eprintln!("Failed to insert person: {}", e);
-                return respond_with_error_response("Failed to insert person", 500);
+                return respond_with_error_response(format, "Failed to insert person", 500);
             }
-            respond_with_success_response("Person inserted successfully")
+            respond_with_success_response(format, "Person inserted successfully")
         }
         None => {
-            respond_with_error_response("Age is required for inserting person", 400)
+            respond_with_error_response(format, "Age is required for inserting person", 400)
         }
     }
 }
 
-fn handle_get_request(conn: &Connection, json_data: Value) -> Response<Cursor<Vec<u8>>> {
-    let person_request: PersonRequest = match serde_json::from_value(json_data) {
-        Ok(data) => data,
-        Err(_) => {
-            return respond_with_error_response("Invalid JSON structure for GET", 400);
+fn handle_get_request(conn: &Connection, format: Format, person_request: PersonRequest) -> Response<Cursor<Vec<u8>>> {
+    match select_person(conn, &person_request.name) {
+        Ok(person) => {
+            if let Some(person) = person {
+                println!("Found person {:?}", person);
+            }
+            respond_with_success_response(format, "Person selected successfully")
+        }
+        Err(e) => {
+            eprintln!("Failed to select person: {}", e);
+            respond_with_error_response(format, "Failed to select person", 500)
         }
-    };
-
-    if let Err(e) = select_person(&conn, &person_request.name) {
-        eprintln!("Failed to select person: {}", e);
-        respond_with_error_response("Failed to select person", 500)
-    } else {
-        respond_with_success_response("Person selected successfully")
     }
 }
 
-fn handle_put_request(conn: &Connection, json_data: Value) -> Response<Cursor<Vec<u8>>> {
-    let person_request: PersonRequest = match serde_json::from_value(json_data) {
-        Ok(data) => data,
-        Err(_) => {
-            return respond_with_error_response("Invalid JSON structure for PUT", 400);
-        }
-    };
-
+fn handle_put_request(conn: &Connection, format: Format, person_request: PersonRequest) -> Response<Cursor<Vec<u8>>> {
     match person_request.age {
         Some(age) => {
-            if let Err(e) = update_person_age(&conn, &person_request.name, age) {
+            if let Err(e) = update_person(conn, &person_request.name, age, person_request.attributes.as_ref().map(Option::as_ref)) {
+                if retry::is_busy(&e) {
+                    eprintln!("Database busy, giving up on update: {}", e);
+                    return respond_with_busy_response(format);
+                }
                 eprintln!("Failed to update person age: {}", e);
-                return respond_with_error_response("Failed to update person age", 500);
+                return respond_with_error_response(format, "Failed to update person age", 500);
             }
-            respond_with_success_response("Person age updated successfully")
+            respond_with_success_response(format, "Person age updated successfully")
         }
         None => {
-            respond_with_error_response("Age is required for updating person", 400)
+            respond_with_error_response(format, "Age is required for updating person", 400)
         }
     }
 }
 
-fn handle_delete_request(conn: &Connection, json_data: Value) -> Response<Cursor<Vec<u8>>> {
-    let person_request: PersonRequest = match serde_json::from_value(json_data) {
-        Ok(data) => data,
-        Err(_) => {
-            return respond_with_error_response("Invalid JSON structure for DELETE", 400);
+fn handle_delete_request(conn: &Connection, format: Format, person_request: PersonRequest) -> Response<Cursor<Vec<u8>>> {
+    match delete_person(conn, &person_request.name) {
+        Ok(_) => respond_with_success_response(format, "Person deleted successfully"),
+        Err(e) if retry::is_busy(&e) => {
+            eprintln!("Database busy, giving up on delete: {}", e);
+            respond_with_busy_response(format)
+        }
+        Err(e) => {
+            eprintln!("Failed to delete person: {}", e);
+            respond_with_error_response(format, "Failed to delete person", 500)
         }
-    };
-
-    if let Err(e) = delete_person(&conn, &person_request.name) {
-        eprintln!("Failed to delete person: {}", e);
-        respond_with_error_response("Failed to delete person", 500)
-    } else {
-        respond_with_success_response("Person deleted successfully")
     }
 }
 
-fn respond_with_error( request: Request, message: &str, status_code: u16) {
-    let response = Response::from_string(message)
-        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap())
-        .with_status_code(status_code);
-    if let Err(e) = request.respond(response) {
+fn respond_with_error(request: Request, format: Format, message: &str, status_code: u16, rate_limit: &[Header]) {
+    let response = respond_with_error_response(format, message, status_code);
+    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
         eprintln!("Failed to respond to request: {}", e);
     }
 }
 
-fn respond_with_error_response(message: &str, status_code: u16) -> Response<Cursor<Vec<u8>>> {
-    Response::from_string(message)
-        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap())
-        .with_status_code(status_code)
-}
-
-fn respond_with_success_response(message: &str) -> Response<Cursor<Vec<u8>>> {
-    let response_data = json!({ "message": message });
-    let response_body = response_data.to_string();
-    Response::from_string(response_body)
-        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
-        .with_status_code(200)
+fn respond_with_error_response(format: Format, message: &str, status_code: u16) -> Response<Cursor<Vec<u8>>> {
+    tinyhttp_format::error(format, message, status_code)
 }
 
-fn create_table(conn: &Connection) -> Result<()> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS person (
-            name TEXT NOT NULL,
-            age INTEGER
-        )",
-        [],
-    )?;
-    Ok(())
+// Writes that are still busy after every retry get a 503 so clients back off
+fn respond_with_busy_response(format: Format) -> Response<Cursor<Vec<u8>>> {
+    retry::tinyhttp_response(format, 503, &ErrorBody::new("Database is busy, retry later"), true)
 }
 
-fn select_person(conn: &Connection, name: &str) -> Result<()> {
-    let mut stmt = conn.prepare("SELECT name, age FROM person WHERE name = ?1")?;
-    let person_iter = stmt.query_map(params![name], |row| {
-        Ok(Person {
-            name: row.get(0)?,
-            age: row.get(1)?,
-        })
-    })?;
-
-    for person in person_iter {
-        println!("Found person {:?}", person.unwrap());
-    }
-    Ok(())
+fn respond_with_success_response(format: Format, message: &str) -> Response<Cursor<Vec<u8>>> {
+    tinyhttp_format::response(format, &Message { message: message.to_string() })
 }
 
-fn insert_person(conn: &Connection, name: &str, age: i32) -> Result<usize> {
-    conn.execute(
-        "INSERT INTO person (name, age) VALUES (?1, ?2)",
-        params![name, age],
-    )
+// Documents the routes answered by `handle_request`, rebuilt only when the
+// schema changes since row routes are listed per table
+fn api_spec(conn: &Connection) -> Arc<Value> {
+    static SPEC: SpecCache = SpecCache::new();
+    let version = schema::version(conn).unwrap_or_default();
+    SPEC.get(version, || build_spec(conn))
 }
 
-fn update_person_age(conn: &Connection, name: &str, new_age: i32) -> Result<usize> {
-    conn.execute(
-        "UPDATE person SET age = ?1 WHERE name = ?2",
-        params![new_age, name],
-    )
-}
-
-fn delete_person(conn: &Connection, name: &str) -> Result<usize> {
-    conn.execute(
-        "DELETE FROM person WHERE name = ?1",
-        params![name],
-    )
+fn build_spec(conn: &Connection) -> Value {
+    let mut spec = ApiSpec::new("tinysql CRUD (tiny_http)");
+    let person = spec.schema::<PersonRequest>();
+    let message = spec.schema::<Message>();
+    let error = spec.schema::<ErrorBody>();
+    let route = |method, summary| {
+        let operation = Operation::new(method, "/", summary)
+            .body(person.clone())
+            .response(200, "Done", message.clone())
+            .response(400, "Invalid body for this method", error.clone())
+            .response(500, "The database operation failed", error.clone());
+        // Only writes are retried and can end up busy
+        match method {
+            "get" => operation,
+            _ => operation.response(503, "Database is busy; see Retry-After", error.clone()),
+        }
+    };
+    spec.add(route("post", "Insert a person; `age` is required"));
+    spec.add(route("get", "Look up a person by the `name` in the body"));
+    spec.add(route("put", "Update a person's age; `age` is required"));
+    spec.add(route("delete", "Delete a person by the `name` in the body"));
+    spec.add(
+        Operation::new("get", "/metrics", "Retry, pool and rate limit counters")
+            .response_as(200, "Current counters", "application/json", spec.schema::<Snapshot>()),
+    );
+    for group in ROUTES {
+        group.document(&mut spec, Some(conn));
+    }
+    spec.document()
 }
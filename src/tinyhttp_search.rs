@@ -0,0 +1,46 @@
+use rusqlite::Connection;
+use std::io::Cursor;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::format::Format;
+use crate::retry;
+use crate::search::{self, SearchError};
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_url;
+
+// `/people/search?q=`, as on the tide stack
+pub fn is_search_request(request: &Request) -> bool {
+    tinyhttp_url::path(request) == "/people/search"
+}
+
+pub fn handle(request: Request, conn: &Connection, format: Format, rate_limit: &[Header]) {
+    let response = if request.method() != &Method::Get {
+        tinyhttp_format::error(format, "Method not allowed", 405)
+    } else {
+        let query = tinyhttp_url::query_param(&request, "q").unwrap_or_default();
+        match page(&request).and_then(|(limit, offset)| search::search(conn, &query, limit, offset)) {
+            Ok(results) => tinyhttp_format::response(format, &results),
+            Err(e) => error_response(format, &e),
+        }
+    };
+
+    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+}
+
+fn page(request: &Request) -> Result<(Option<u32>, Option<u32>), SearchError> {
+    let parse = |name| match tinyhttp_url::query_param(request, name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| SearchError::Invalid("Invalid limit or offset".to_string())),
+        None => Ok(None),
+    };
+    Ok((parse("limit")?, parse("offset")?))
+}
+
+fn error_response(format: Format, err: &SearchError) -> Response<Cursor<Vec<u8>>> {
+    retry::tinyhttp_response(format, err.status_code(), &err.body(), err.is_busy())
+}
@@ -0,0 +1,170 @@
+use rusqlite::{Connection, OpenFlags};
+use std::io::{self, Cursor};
+use std::sync::OnceLock;
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+use crate::config::env_or;
+use crate::format::Format;
+use crate::limits::{limits, BodyError};
+use crate::repository;
+use crate::retry;
+use crate::sql_policy::SqlPolicy;
+use crate::stream_io;
+use crate::table_csv::{self, TableError};
+use crate::tinyhttp_bounded_pool::BoundedPool;
+use crate::tinyhttp_cors;
+use crate::tinyhttp_format;
+use crate::tinyhttp_limits;
+use crate::tinyhttp_url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TableAction {
+    Import,
+    Export,
+}
+
+pub struct TableRoute {
+    action: TableAction,
+    table: String,
+    // `None` when the `delimiter` query parameter is invalid
+    delimiter: Option<u8>,
+}
+
+// Matches `/tables/<name>/import` and `/tables/<name>/export.csv`
+pub fn route(request: &Request) -> Option<TableRoute> {
+    let segments = tinyhttp_url::segments(tinyhttp_url::path(request), "/tables/")?;
+    let [table, action] = segments.as_slice() else {
+        return None;
+    };
+    let action = match action.as_str() {
+        "import" => TableAction::Import,
+        "export.csv" => TableAction::Export,
+        _ => return None,
+    };
+    let delimiter = tinyhttp_url::query_param(request, "delimiter");
+    Some(TableRoute {
+        action,
+        table: table.clone(),
+        delimiter: table_csv::parse_delimiter(delimiter.as_deref()),
+    })
+}
+
+// Imports and exports can run for minutes, so they go to their own pool
+// rather than holding up the server loop. The pool has no queue: a transfer
+// starts at once or is answered with a 503, and an export's two halves (one
+// reads the table into a pipe, the other sends it) always both get a thread.
+fn transfers() -> &'static BoundedPool {
+    static TRANSFERS: OnceLock<BoundedPool> = OnceLock::new();
+    TRANSFERS.get_or_init(|| BoundedPool::with_queue(env_or("TINYSQL_TRANSFER_THREADS", 8), 0))
+}
+
+// Answers a table route. Transfers open their own connection to `db_path`
+// on a `transfers()` thread.
+pub fn handle(
+    request: Request,
+    conn: &Connection,
+    db_path: &str,
+    policy: &'static SqlPolicy,
+    format: Format,
+    route: TableRoute,
+    rate_limit: &[Header],
+) {
+    let expected = match route.action {
+        TableAction::Import => Method::Post,
+        TableAction::Export => Method::Get,
+    };
+    let response = if request.method() != &expected {
+        tinyhttp_format::error(format, "Method not allowed", 405)
+    } else if let Some(delimiter) = route.delimiter {
+        let checked = match route.action {
+            TableAction::Import => table_csv::check_import(conn, policy, &route.table),
+            TableAction::Export => table_csv::check_export(conn, policy, &route.table),
+        };
+        match checked {
+            Ok(()) => {
+                let db_path = db_path.to_string();
+                let rate_limit = rate_limit.to_vec();
+                return match route.action {
+                    TableAction::Import => transfers().spawn(request, move |mut request| {
+                        let response = import(&mut request, &db_path, policy, &route.table, delimiter, format);
+                        if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+                            eprintln!("Failed to respond to request: {}", e);
+                        }
+                    }),
+                    TableAction::Export => export(request, db_path, policy, route.table, delimiter, rate_limit),
+                };
+            }
+            Err(e) => table_error_response(format, &e),
+        }
+    } else {
+        tinyhttp_format::error(format, "Invalid delimiter", 400)
+    };
+
+    if let Err(e) = tinyhttp_cors::respond(request, response, rate_limit) {
+        eprintln!("Failed to respond to request: {}", e);
+    }
+}
+
+fn import(
+    request: &mut Request,
+    db_path: &str,
+    policy: &SqlPolicy,
+    table: &str,
+    delimiter: u8,
+    format: Format,
+) -> Response<Cursor<Vec<u8>>> {
+    if !table_csv::is_csv_content_type(tinyhttp_cors::header(request, "Content-Type")) {
+        return tinyhttp_limits::body_error_response(format, &BodyError::UnsupportedMediaType);
+    }
+    let limits = limits();
+    if request.body_length().is_some_and(|len| len as u64 > limits.max_import_bytes) {
+        return tinyhttp_limits::body_error_response(format, &BodyError::TooLarge);
+    }
+
+    let conn = match Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_URI) {
+        Ok(conn) => conn,
+        Err(e) => return table_error_response(format, &TableError::from(e)),
+    };
+    repository::configure_connection(&conn);
+    let mut input = tinyhttp_limits::body_reader(request, limits.max_import_bytes, limits.import_timeout);
+    let result = table_csv::import(&conn, policy, table, &mut input, delimiter);
+    if let Some(e) = input.take_error() {
+        return tinyhttp_limits::body_error_response(format, &e);
+    }
+    match result {
+        Ok(report) => tinyhttp_format::response(format, &report),
+        Err(e) => table_error_response(format, &e),
+    }
+}
+
+fn export(request: Request, db_path: String, policy: &'static SqlPolicy, table: String, delimiter: u8, rate_limit: Vec<Header>) {
+    let (mut writer, reader) = stream_io::pipe();
+    let filename = table_csv::export_filename(&table);
+
+    let producer = move || {
+        let result = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI)
+            .map_err(TableError::from)
+            .and_then(|conn| table_csv::export(&conn, policy, &table, &mut writer, delimiter));
+        if let Err(e) = result {
+            eprintln!("Failed to export table {}: {:?}", table, e);
+            writer.fail(io::Error::other("export failed"));
+        }
+    };
+
+    transfers().spawn_with_helper(request, producer, move |request| {
+        let disposition = format!("attachment; filename=\"{}\"", filename);
+        let headers = vec![
+            Header::from_bytes(&b"Content-Type"[..], &b"text/csv"[..]).unwrap(),
+            Header::from_bytes(&b"Content-Disposition"[..], disposition.as_bytes()).unwrap(),
+        ];
+        // No length, so tiny_http sends the body chunked
+        let response = Response::new(StatusCode(200), headers, reader, None, None);
+        if let Err(e) = tinyhttp_cors::respond(request, response, &rate_limit) {
+            eprintln!("Failed to respond to request: {}", e);
+        }
+    });
+}
+
+fn table_error_response(format: Format, err: &TableError) -> Response<Cursor<Vec<u8>>> {
+    retry::tinyhttp_response(format, err.status_code(), &err.body(), err.is_busy())
+}
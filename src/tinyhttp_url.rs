@@ -0,0 +1,77 @@
+use percent_encoding::percent_decode_str;
+use tiny_http::Request;
+
+// tiny_http hands over the raw request target, so path segments and query
+// values are split and percent-decoded here
+
+fn full_path(request: &Request) -> &str {
+    request.url().split_once('?').map_or(request.url(), |(path, _)| path)
+}
+
+// The path below a `/db/<name>` prefix, which addresses a named database,
+// so the same routes serve every database
+pub fn path(request: &Request) -> &str {
+    let full_path = full_path(request);
+    match full_path.strip_prefix("/db/").and_then(|rest| rest.split_once('/')) {
+        Some((_, rest)) => &full_path[full_path.len() - rest.len() - 1..],
+        None if full_path.starts_with("/db/") => "/",
+        None => full_path,
+    }
+}
+
+// The named database a request addresses, if any
+pub fn database(request: &Request) -> Option<String> {
+    let rest = full_path(request).strip_prefix("/db/")?;
+    let name = rest.split('/').next().filter(|name| !name.is_empty())?;
+    Some(percent_decode_str(name).decode_utf8_lossy().into_owned())
+}
+
+// The decoded segments of a path under `prefix`, or `None` outside it. A
+// prefix only matches whole segments, so `/tablesx` isn't under `/tables`.
+pub fn segments(path: &str, prefix: &str) -> Option<Vec<String>> {
+    let rest = path.strip_prefix(prefix)?;
+    if !(rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')) {
+        return None;
+    }
+    Some(
+        rest.split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+            .collect(),
+    )
+}
+
+pub fn query_param(request: &Request, name: &str) -> Option<String> {
+    query_pairs(request).into_iter().find(|(key, _)| key == name).map(|(_, value)| value)
+}
+
+// Every `name=value` pair of the query string, in order
+pub fn query_pairs(request: &Request) -> Vec<(String, String)> {
+    let Some((_, query)) = request.url().split_once('?') else {
+        return Vec::new();
+    };
+    let decode = |s: &str| percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned();
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (decode(key), decode(value)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::segments;
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        assert_eq!(segments("/databases", "/databases"), Some(vec![]));
+        assert_eq!(segments("/databases/a%20b", "/databases"), Some(vec!["a b".to_string()]));
+        assert_eq!(segments("/databasesx", "/databases"), None);
+        assert_eq!(segments("/schemas", "/schema"), None);
+        assert_eq!(
+            segments("/tables/person/rows", "/tables/"),
+            Some(vec!["person".to_string(), "rows".to_string()])
+        );
+        assert_eq!(segments("/other", "/tables/"), None);
+    }
+}
@@ -0,0 +1,326 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_rustls::rustls::{self, Certificate, PrivateKey, ServerConfig};
+use futures_rustls::TlsAcceptor;
+use rustls_pemfile::Item;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::fmt;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use tiny_http::{Server, SslConfig};
+
+// Optional TLS for every listener. Setting TINYSQL_TLS_CERT and
+// TINYSQL_TLS_KEY to PEM files (certificate chain, PKCS#8, RSA or SEC1 EC key)
+// switches the servers to HTTPS; without them they stay plaintext.
+#[derive(Debug)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    // Only one of TINYSQL_TLS_CERT and TINYSQL_TLS_KEY is set
+    Incomplete,
+    Read(PathBuf, io::Error),
+    Certificate(PathBuf, String),
+    Key(PathBuf, String),
+    Rejected(rustls::Error),
+}
+
+impl TlsError {
+    pub fn message(&self) -> String {
+        match self {
+            // Refuse to fall back to plaintext when TLS was clearly intended
+            TlsError::Incomplete => "TINYSQL_TLS_CERT and TINYSQL_TLS_KEY must be set together".to_string(),
+            TlsError::Read(path, e) => format!("Failed to read {}: {}", path.display(), e),
+            TlsError::Certificate(path, reason) => format!("Invalid certificate in {}: {}", path.display(), reason),
+            TlsError::Key(path, reason) => format!("Invalid private key in {}: {}", path.display(), reason),
+            TlsError::Rejected(e) => format!("Invalid TLS certificate or key: {}", e),
+        }
+    }
+}
+
+// Both listeners' TLS settings, loaded and checked once at startup
+pub struct Tls {
+    server_config: Arc<ServerConfig>,
+    // tiny_http parses PEM itself and only knows PKCS#8 and RSA keys, so it
+    // gets the key re-encoded in the form rustls was given
+    cert_pem: Vec<u8>,
+    key_pem: Vec<u8>,
+}
+
+impl TlsConfig {
+    pub fn from_env() -> Result<Option<Self>, TlsError> {
+        TlsConfig::from_vars(env::var_os("TINYSQL_TLS_CERT"), env::var_os("TINYSQL_TLS_KEY"))
+    }
+
+    fn from_vars(cert: Option<OsString>, key: Option<OsString>) -> Result<Option<Self>, TlsError> {
+        match (cert, key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsConfig {
+                cert_path: cert.into(),
+                key_path: key.into(),
+            })),
+            (None, None) => Ok(None),
+            _ => Err(TlsError::Incomplete),
+        }
+    }
+
+    pub fn load(&self) -> Result<Tls, TlsError> {
+        let cert_pem = read(&self.cert_path)?;
+        let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_pem.as_slice()))
+            .map_err(|e| TlsError::Certificate(self.cert_path.clone(), e.to_string()))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(TlsError::Certificate(self.cert_path.clone(), "no certificate found".to_string()));
+        }
+
+        let (key, label) = private_key(&read(&self.key_path)?).map_err(|reason| TlsError::Key(self.key_path.clone(), reason))?;
+        let key_pem = pem(label, &key.0);
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(TlsError::Rejected)?;
+        Ok(Tls { server_config: Arc::new(server_config), cert_pem, key_pem })
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|e| TlsError::Read(path.to_path_buf(), e))
+}
+
+// The first private key in `pem` and the PEM label it is known by. SEC1 EC
+// keys are wrapped in PKCS#8, the only form rustls takes them in.
+fn private_key(pem: &[u8]) -> Result<(PrivateKey, &'static str), String> {
+    let items = rustls_pemfile::read_all(&mut BufReader::new(pem)).map_err(|e| e.to_string())?;
+    let key = items.into_iter().find_map(|item| match item {
+        Item::PKCS8Key(key) => Some((PrivateKey(key), "PRIVATE KEY")),
+        Item::RSAKey(key) => Some((PrivateKey(key), "RSA PRIVATE KEY")),
+        Item::X509Certificate(_) => None,
+    });
+    if let Some(key) = key {
+        return Ok(key);
+    }
+    // rustls-pemfile 0.2 skips sections it doesn't know, EC keys among them
+    match pem_section(pem, "EC PRIVATE KEY") {
+        Some(sec1) => {
+            let pkcs8 = der::sec1_to_pkcs8(&sec1?).ok_or("malformed EC private key")?;
+            Ok((PrivateKey(pkcs8), "PRIVATE KEY"))
+        }
+        None => Err("no private key found".to_string()),
+    }
+}
+
+// The DER inside the first `label` section of `pem`
+fn pem_section(pem: &[u8], label: &str) -> Option<Result<Vec<u8>, String>> {
+    let text = String::from_utf8_lossy(pem);
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let start = text.find(&begin)? + begin.len();
+    let Some(length) = text[start..].find(&end) else {
+        return Some(Err(format!("unterminated {} section", label)));
+    };
+    let base64: String = text[start..start + length].split_whitespace().collect();
+    Some(STANDARD.decode(base64).map_err(|e| e.to_string()))
+}
+
+fn pem(label: &str, der: &[u8]) -> Vec<u8> {
+    let base64 = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem.into_bytes()
+}
+
+// Just enough DER to rewrap an RFC 5915 EC key as PKCS#8 (RFC 5958)
+mod der {
+    const SEQUENCE: u8 = 0x30;
+    const INTEGER: u8 = 0x02;
+    const OCTET_STRING: u8 = 0x04;
+    const OID: u8 = 0x06;
+    const PARAMETERS: u8 = 0xa0;
+    // id-ecPublicKey, 1.2.840.10045.2.1
+    const EC_PUBLIC_KEY: [u8; 9] = [0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+
+    // The first element of some input and whatever follows it
+    struct Element<'a> {
+        tag: u8,
+        contents: &'a [u8],
+        encoding: &'a [u8],
+        rest: &'a [u8],
+    }
+
+    pub fn sec1_to_pkcs8(sec1: &[u8]) -> Option<Vec<u8>> {
+        let key = next(sec1).filter(|e| e.tag == SEQUENCE)?;
+        let version = next(key.contents).filter(|e| e.tag == INTEGER)?;
+        let private_key = next(version.rest).filter(|e| e.tag == OCTET_STRING)?;
+        // The named curve, which PKCS#8 moves into the algorithm identifier
+        let parameters = next(private_key.rest).filter(|e| e.tag == PARAMETERS)?;
+        let curve = next(parameters.contents).filter(|e| e.tag == OID)?;
+
+        let algorithm = encode(SEQUENCE, &[&EC_PUBLIC_KEY[..], curve.encoding].concat());
+        let body = [encode(INTEGER, &[0]), algorithm, encode(OCTET_STRING, sec1)].concat();
+        Some(encode(SEQUENCE, &body))
+    }
+
+    fn next(input: &[u8]) -> Option<Element<'_>> {
+        let (&tag, after_tag) = input.split_first()?;
+        let (&first, after_length) = after_tag.split_first()?;
+        let (length, header) = match first {
+            0..=0x7f => (first as usize, 2),
+            0x81..=0x82 => {
+                let count = (first & 0x7f) as usize;
+                let bytes = after_length.get(..count)?;
+                (bytes.iter().fold(0, |length, &b| length << 8 | b as usize), 2 + count)
+            }
+            _ => return None,
+        };
+        let encoding = input.get(..header + length)?;
+        Some(Element { tag, contents: &encoding[header..], encoding, rest: &input[header + length..] })
+    }
+
+    fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match contents.len() {
+            length @ 0..=0x7f => out.push(length as u8),
+            length @ 0x80..=0xff => out.extend([0x81, length as u8]),
+            length => out.extend([0x82, (length >> 8) as u8, length as u8]),
+        }
+        out.extend_from_slice(contents);
+        out
+    }
+}
+
+static TLS: OnceLock<Option<Tls>> = OnceLock::new();
+
+// Loads TLS from the environment. Run once before any server starts, so a bad
+// setting stops the process with a message instead of a panic mid-startup.
+pub fn init() -> Result<(), TlsError> {
+    let tls = match TlsConfig::from_env()? {
+        Some(config) => Some(config.load()?),
+        None => None,
+    };
+    let _ = TLS.set(tls);
+    Ok(())
+}
+
+// None until `init` has loaded a configuration
+pub fn tls() -> Option<&'static Tls> {
+    TLS.get().and_then(Option::as_ref)
+}
+
+pub fn scheme() -> &'static str {
+    if tls().is_some() {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+// Printed once a server is bound, with the address it got: on port 0 that
+// is how callers learn which port the OS picked
+pub fn announce(addr: impl fmt::Display) {
+    println!("Listening on {}://{}/", scheme(), addr);
+}
+
+// Replacement for `Server::http(addr)` that serves HTTPS when configured
+pub fn tinyhttp_server(addr: &str) -> Server {
+    let server = match tls() {
+        Some(tls) => Server::https(
+            addr,
+            SslConfig {
+                certificate: tls.cert_pem.clone(),
+                private_key: tls.key_pem.clone(),
+            },
+        )
+        .unwrap(),
+        None => Server::http(addr).unwrap(),
+    };
+    announce(server.server_addr());
+    server
+}
+
+pub fn tide_acceptor() -> Option<TlsAcceptor> {
+    tls().map(|tls| TlsAcceptor::from(Arc::clone(&tls.server_config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConnection, ServerName};
+    use std::io::{Read, Write};
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+
+    fn config(key: &str) -> TlsConfig {
+        TlsConfig {
+            cert_path: Path::new(FIXTURES).join("cert.pem"),
+            key_path: Path::new(FIXTURES).join(key),
+        }
+    }
+
+    // Shuttles TLS records from one end to the other; false once there are none
+    fn send(from: &mut Connection, to: &mut Connection) -> bool {
+        let mut records = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut records).unwrap();
+        }
+        if !records.is_empty() {
+            to.read_tls(&mut records.as_slice()).unwrap();
+            to.process_new_packets().unwrap();
+        }
+        !records.is_empty()
+    }
+
+    fn handshake(tls: &Tls) {
+        let mut roots = RootCertStore::empty();
+        let cert = rustls_pemfile::certs(&mut tls.cert_pem.as_slice()).unwrap().remove(0);
+        roots.add(&Certificate(cert)).unwrap();
+        let client_config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+        let client = ClientConnection::new(Arc::new(client_config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut client = Connection::from(client);
+        let mut server = Connection::from(ServerConnection::new(Arc::clone(&tls.server_config)).unwrap());
+
+        client.writer().write_all(b"ping").unwrap();
+        while send(&mut client, &mut server) | send(&mut server, &mut client) {}
+        assert!(!client.is_handshaking() && !server.is_handshaking());
+        let mut received = [0; 4];
+        server.reader().read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[test]
+    fn self_signed_certificates_serve_with_sec1_and_pkcs8_ec_keys() {
+        for key in ["ec.key", "ec-pkcs8.key"] {
+            let tls = config(key).load().unwrap_or_else(|e| panic!("{}: {}", key, e.message()));
+            handshake(&tls);
+            // What tiny_http reads
+            assert_eq!(rustls_pemfile::pkcs8_private_keys(&mut tls.key_pem.as_slice()).unwrap().len(), 1, "{}", key);
+        }
+    }
+
+    #[test]
+    fn cert_and_key_must_be_set_together() {
+        assert!(TlsConfig::from_vars(None, None).unwrap().is_none());
+        assert!(matches!(TlsConfig::from_vars(Some("cert.pem".into()), None), Err(TlsError::Incomplete)));
+        assert!(matches!(TlsConfig::from_vars(None, Some("key.pem".into())), Err(TlsError::Incomplete)));
+    }
+
+    #[test]
+    fn bad_files_are_errors() {
+        assert!(matches!(config("missing.key").load(), Err(TlsError::Read(..))));
+        let swapped = TlsConfig { cert_path: config("ec.key").key_path, key_path: config("ec.key").cert_path };
+        assert!(matches!(swapped.load(), Err(TlsError::Certificate(..))));
+        let no_key = TlsConfig { key_path: config("ec.key").cert_path, ..config("ec.key") };
+        assert!(matches!(no_key.load(), Err(TlsError::Key(..))));
+    }
+}
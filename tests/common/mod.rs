@@ -0,0 +1,288 @@
+// Not every test file uses every helper
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// End-to-end tests run the real binary: each `TestServer` starts one variant
+// in a child process, named on its command line, in a scratch directory of
+// its own, and kills it when dropped. Servers bind port 0 and announce the
+// port they got, so parallel tests never race for one. Variants keep
+// process-wide state (settings, the in-memory database, signal handlers), so
+// a process per server is what lets tests run in parallel.
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Database {
+    // The variant's usual database file, inside the scratch directory
+    Temporary,
+    // A shared-cache in-memory database (TINYSQL_MEMORY)
+    Memory,
+}
+
+pub struct TestServer {
+    child: Child,
+    dir: PathBuf,
+    base_url: String,
+}
+
+impl TestServer {
+    // Starts `variant`, one of the names the binary takes on its command line
+    // and any arguments, e.g. `serve tide pool`, with a temporary database
+    pub fn start(variant: &str) -> TestServer {
+        TestServer::builder(variant).start()
+    }
+
+    pub fn builder(variant: &str) -> TestServerBuilder {
+        TestServerBuilder { variant: variant.to_string(), database: Database::Temporary, env: Vec::new() }
+    }
+
+    // e.g. `http://127.0.0.1:40123`
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    pub fn client(&self) -> Client {
+        Client { addr: self.base_url.trim_start_matches("http://").to_string(), headers: Vec::new() }
+    }
+
+    // The scratch directory the server runs in
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    // Everything the server printed so far
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.join("server.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub struct TestServerBuilder {
+    variant: String,
+    database: Database,
+    env: Vec<(String, String)>,
+}
+
+impl TestServerBuilder {
+    pub fn database(mut self, database: Database) -> Self {
+        self.database = database;
+        self
+    }
+
+    // Sets a variable for the server, e.g. `TINYSQL_API_KEYS`. Any TINYSQL_*
+    // variables of the test process itself are not passed on.
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn start(self) -> TestServer {
+        let dir = scratch_dir(&self.variant);
+        // Some variants keep their database under `db/`
+        fs::create_dir_all(dir.join("db")).expect("Failed to create scratch directory");
+        let log = File::create(dir.join("server.log")).expect("Failed to create server log");
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_tinysql"));
+        command.current_dir(&dir);
+        for (name, _) in env::vars().filter(|(name, _)| name.starts_with("TINYSQL_")) {
+            command.env_remove(name);
+        }
+        command.args(self.variant.split_whitespace());
+        command.env("TINYSQL_ADDR", "127.0.0.1:0");
+        if self.database == Database::Memory {
+            let name = self.variant.split_whitespace().next().unwrap_or_default();
+            command.env("TINYSQL_MEMORY", name);
+        }
+        command.envs(self.env.iter().map(|(name, value)| (name, value)));
+        let child = command
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().expect("Failed to share server log"))
+            .stderr(log)
+            .spawn()
+            .expect("Failed to start server");
+
+        let mut server = TestServer { child, dir, base_url: String::new() };
+        server.base_url = server.wait_until_listening();
+        server
+    }
+}
+
+impl TestServer {
+    // The base URL from the server's `Listening on http://127.0.0.1:<port>/`
+    fn wait_until_listening(&mut self) -> String {
+        let start = Instant::now();
+        loop {
+            let announced = self.log().lines().find_map(|line| {
+                let url = line.strip_prefix("Listening on ")?.trim_end_matches('/');
+                Some(url.to_string())
+            });
+            if let Some(url) = announced {
+                return url;
+            }
+            if let Ok(Some(status)) = self.child.try_wait() {
+                panic!("Server exited with {} before listening:\n{}", status, self.log());
+            }
+            if start.elapsed() > STARTUP_TIMEOUT {
+                panic!("Server did not listen within {:?}:\n{}", STARTUP_TIMEOUT, self.log());
+            }
+            thread::sleep(Duration::from_millis(25));
+        }
+    }
+}
+
+fn scratch_dir(variant: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let variant = variant.split_whitespace().collect::<Vec<_>>().join("-");
+    let name = format!("tinysql-test-{}-{}-{}", variant, std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+    let dir = env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+// A blocking HTTP/1.1 client, one connection per request
+#[derive(Clone)]
+pub struct Client {
+    addr: String,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|e| panic!("Response is not JSON ({}): {}", e, self.text()))
+    }
+}
+
+impl Client {
+    // Sends `name: value` with every request, e.g. an `X-API-Key`
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn get(&self, path: &str) -> Response {
+        self.request("GET", path, &[], None)
+    }
+
+    pub fn delete(&self, path: &str) -> Response {
+        self.request("DELETE", path, &[], None)
+    }
+
+    pub fn post_json(&self, path: &str, body: &Value) -> Response {
+        self.send_json("POST", path, body)
+    }
+
+    pub fn put_json(&self, path: &str, body: &Value) -> Response {
+        self.send_json("PUT", path, body)
+    }
+
+    // Any method with a JSON body, e.g. the tiny_http CRUD server's GET
+    pub fn send_json(&self, method: &str, path: &str, body: &Value) -> Response {
+        let body = serde_json::to_vec(body).unwrap();
+        self.request(method, path, &[("Content-Type", "application/json")], Some(&body))
+    }
+
+    pub fn request(&self, method: &str, path: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> Response {
+        self.try_request(method, path, headers, body)
+            .unwrap_or_else(|e| panic!("{} {} failed: {}", method, path, e))
+    }
+
+    fn try_request(&self, method: &str, path: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> io::Result<Response> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, self.addr);
+        let extra = self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        for (name, value) in extra.chain(headers.iter().copied()) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let body = body.unwrap_or_default();
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        read_response(BufReader::new(stream))
+    }
+}
+
+fn read_response(mut reader: impl BufRead) -> io::Result<Response> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split_whitespace().nth(1).and_then(|s| s.parse().ok()).ok_or_else(|| invalid("status line"))?;
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| invalid("header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let header = |name: &str| headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+
+    let mut body = Vec::new();
+    if header("Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16).map_err(|_| invalid("chunk size"))?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = header("Content-Length") {
+        let length = length.parse().map_err(|_| invalid("Content-Length"))?;
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+
+    Ok(Response { status, headers, body })
+}
@@ -0,0 +1,250 @@
+mod common;
+
+use common::{Database, TestServer};
+use serde_json::json;
+
+#[test]
+fn tide_crud_round_trip() {
+    let server = TestServer::builder("tide_crud").database(Database::Memory).start();
+    let client = server.client();
+
+    let response = client.post_json("/", &json!({"name": "ada", "age": 36, "attributes": {"team": "infra"}}));
+    assert_eq!(response.status, 200, "{}", response.text());
+
+    let person = client.get("/ada").json();
+    assert_eq!(person["data"]["age"], 36);
+    assert_eq!(person["data"]["attributes"]["team"], "infra");
+
+    assert_eq!(client.put_json("/ada", &json!({"age": 37})).status, 200);
+    assert_eq!(client.get("/ada").json()["data"]["age"], 37);
+
+    let rows = client.get("/tables/person/rows?attr.team=infra").json();
+    assert_eq!(rows.as_array().map(Vec::len), Some(1));
+    let hits = client.get("/people/search?q=ada").json();
+    assert_eq!(hits["hits"][0]["name"], "ada");
+
+    assert_eq!(client.delete("/ada").status, 200);
+    assert!(client.get("/ada").json()["data"].is_null());
+
+    // The in-memory database never touches the disk
+    assert!(!server.dir().join("my_database.db").exists());
+}
+
+#[test]
+fn tide_crud_serves_people_from_the_pool_when_asked() {
+    let server = TestServer::builder("tide_crud").env("TINYSQL_TIDE_CRUD_POOL", "true").start();
+    let client = server.client();
+
+    assert_eq!(client.post_json("/", &json!({"name": "lin", "age": 29})).status, 200);
+    assert_eq!(client.get("/lin").json()["data"]["age"], 29);
+    assert!(server.log().contains("Person routes use the pool"), "{}", server.log());
+}
+
+#[test]
+fn tinyhttp_crud_round_trip() {
+    let server = TestServer::start("tinyhttp_crud");
+    let client = server.client();
+
+    let response = client.post_json("/", &json!({"name": "grace", "age": 45}));
+    assert_eq!(response.status, 200, "{}", response.text());
+
+    let row = client.get("/tables/person/rows/1").json();
+    assert_eq!(row["name"], "grace");
+    assert_eq!(client.send_json("GET", "/", &json!({"name": "grace"})).status, 200);
+
+    let updated = client.put_json("/", &json!({"name": "grace", "age": 46}));
+    assert_eq!(updated.status, 200, "{}", updated.text());
+    assert_eq!(client.get("/tables/person/rows/1").json()["age"], 46);
+
+    let export = client.get("/tables/person/export.csv");
+    assert_eq!(export.status, 200);
+    assert!(export.text().contains("grace,46"), "{}", export.text());
+
+    let deleted = client.send_json("DELETE", "/", &json!({"name": "grace"}));
+    assert_eq!(deleted.status, 200, "{}", deleted.text());
+    assert_eq!(client.get("/tables/person/rows/1").status, 404);
+
+    assert!(server.dir().join("db/my_database.db").exists());
+}
+
+#[test]
+fn pooled_servers_answer() {
+    for variant in ["tiny_pooled", "server_db_pooled"] {
+        let server = TestServer::builder(variant).database(Database::Memory).start();
+        let response = server.client().post_json("/", &json!({"field1": "a", "field2": 1, "name": "a", "age": 1}));
+        assert_eq!(response.status, 200, "{}: {}", variant, response.text());
+        assert_eq!(response.json()["status"], "Connection opened and closed successfully");
+    }
+}
+
+#[test]
+fn tide_single_route_servers_answer() {
+    let body = json!({"key": "k", "value": "v"});
+    for (variant, status) in [
+        ("tide_pooled_db", "Connection opened and query executed successfully"),
+        ("tide_embedded", "Connection opened and closed successfully"),
+    ] {
+        let server = TestServer::builder(variant).database(Database::Memory).start();
+        let response = server.client().post_json("/", &body);
+        assert_eq!(response.status, 200, "{}: {}", variant, response.text());
+        let response = response.json();
+        assert_eq!(response["status"], status, "{}", variant);
+        assert_eq!(response["received_data"], body, "{}", variant);
+    }
+}
+
+#[test]
+fn tiny_db_hosted_runs_statements_under_the_sql_policy() {
+    let server = TestServer::builder("tiny_db_hosted").database(Database::Memory).start();
+    let denied = server.client().post_json("/", &json!({"query": "CREATE TABLE t (x)"}));
+    assert_eq!(denied.status, 403, "{}", denied.text());
+    assert_eq!(denied.json()["action"], "ddl");
+
+    let server = TestServer::builder("tiny_db_hosted")
+        .database(Database::Memory)
+        .env("TINYSQL_SQL_ANONYMOUS_POLICY", "read:*,write:*,ddl")
+        .start();
+    let client = server.client();
+    for query in ["CREATE TABLE t (x)", "INSERT INTO t VALUES (1)"] {
+        let response = client.post_json("/", &json!({ "query": query }));
+        assert_eq!(response.status, 200, "{}: {}", query, response.text());
+        assert_eq!(response.json()["status"], "Query executed successfully", "{}", query);
+    }
+}
+
+#[test]
+fn servers_require_keys_when_configured() {
+    let server = TestServer::builder("tide_crud").database(Database::Memory).env("TINYSQL_API_KEYS", "ci:secret").start();
+
+    assert_eq!(server.client().get("/schema").status, 401);
+    let client = server.client().with_header("X-API-Key", "secret");
+    assert_eq!(client.get("/schema").status, 200);
+}
+
+#[test]
+fn every_frontend_and_strategy_serves_people() {
+    for frontend in ["tide", "tiny_serial", "tiny_rayon", "tiny_async"] {
+        for strategy in ["per_request", "mutex", "pool", "db_thread"] {
+            let combination = format!("serve {} {}", frontend, strategy);
+            let server = TestServer::builder(&combination).database(Database::Memory).start();
+            let client = server.client();
+
+            let created = client.post_json("/people", &json!({"name": "alan", "age": 41}));
+            assert_eq!(created.status, 201, "{}: {}", combination, created.text());
+            assert_eq!(client.get("/people/alan").json()["age"], 41, "{}", combination);
+            assert_eq!(client.delete("/people/alan").status, 204, "{}", combination);
+            assert_eq!(client.get("/people/alan").status, 404, "{}", combination);
+        }
+    }
+}
+
+#[test]
+fn tinyhttp_times_out_stalled_bodies() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    let server = TestServer::builder("tinyhttp_crud").env("TINYSQL_READ_TIMEOUT_MS", "300").start();
+    let mut stream = TcpStream::connect(server.base_url().trim_start_matches("http://")).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    // Promise a body too large for tiny_http to buffer itself, send part of
+    // it and stall
+    write!(stream, "POST / HTTP/1.1\r\nHost: test\r\nContent-Type: application/json\r\nContent-Length: 100000\r\n\r\n{{\"na").unwrap();
+
+    let start = Instant::now();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // The serial accept loop is free again
+    assert_eq!(server.client().get("/tables/person/rows").status, 200);
+}
+
+#[test]
+fn both_frontends_send_rate_limit_headers_on_every_response() {
+    for variant in ["tide_crud", "tinyhttp_crud"] {
+        let server = TestServer::builder(variant).database(Database::Memory).env("TINYSQL_RATE_READ", "2/60").start();
+        let client = server.client();
+
+        let allowed = client.get("/tables/person/rows");
+        assert_eq!(allowed.status, 200, "{}: {}", variant, allowed.text());
+        assert_eq!(allowed.header("X-RateLimit-Limit"), Some("2"), "{}", variant);
+        assert_eq!(allowed.header("X-RateLimit-Remaining"), Some("1"), "{}", variant);
+
+        client.get("/tables/person/rows");
+        let refused = client.get("/tables/person/rows");
+        assert_eq!(refused.status, 429, "{}", variant);
+        assert_eq!(refused.header("X-RateLimit-Remaining"), Some("0"), "{}", variant);
+        assert!(refused.header("Retry-After").is_some(), "{}", variant);
+    }
+}
+
+#[test]
+fn admin_routes_need_an_admin_principal() {
+    for variant in ["tide_crud", "tinyhttp_crud"] {
+        let server = TestServer::builder(variant)
+            .database(Database::Memory)
+            .env("TINYSQL_API_KEYS", "ci:rw-key:rw,ops:admin-key:admin")
+            .start();
+
+        let writer = server.client().with_header("X-API-Key", "rw-key");
+        assert_eq!(writer.get("/admin/backup").status, 403, "{}", variant);
+        assert_eq!(writer.request("POST", "/admin/backup", &[], None).status, 403, "{}", variant);
+        assert_eq!(writer.request("POST", "/admin/restore", &[], None).status, 403, "{}", variant);
+
+        let admin = server.client().with_header("X-API-Key", "admin-key");
+        let status = admin.get("/admin/backup");
+        assert_eq!(status.status, 200, "{}: {}", variant, status.text());
+    }
+}
+
+#[test]
+fn admin_routes_are_closed_without_authentication() {
+    for variant in ["tide_crud", "tinyhttp_crud"] {
+        let server = TestServer::builder(variant).database(Database::Memory).start();
+        assert_eq!(server.client().get("/admin/backup").status, 403, "{}", variant);
+    }
+}
+
+#[test]
+fn restores_check_the_user_version_and_keep_paths_private() {
+    let server = TestServer::builder("tinyhttp_crud").env("TINYSQL_ADMIN_OPEN", "true").start();
+    let client = server.client();
+
+    let backup = client.request("POST", "/admin/backup?path=kept.db", &[], None);
+    assert_eq!(backup.status, 200, "{}", backup.text());
+
+    let mismatch = client.request("POST", "/admin/restore?path=kept.db&schema_version=7", &[], None);
+    assert_eq!(mismatch.status, 422, "{}", mismatch.text());
+
+    let restored = client.request("POST", "/admin/restore?path=kept.db&schema_version=0", &[], None);
+    assert_eq!(restored.status, 200, "{}", restored.text());
+    let report = restored.json();
+    assert_eq!(report["user_version"], 0);
+    assert!(report.get("path").is_none(), "{}", report);
+}
+
+#[test]
+fn named_databases_are_managed_by_admins_within_a_cap() {
+    for variant in ["tide_crud", "tinyhttp_crud"] {
+        let closed = TestServer::builder(variant).database(Database::Memory).start();
+        assert_eq!(closed.client().get("/databases").status, 403, "{}", variant);
+
+        let server = TestServer::builder(variant)
+            .database(Database::Memory)
+            .env("TINYSQL_ADMIN_OPEN", "true")
+            .env("TINYSQL_DB_MAX_COUNT", "1")
+            .start();
+        let client = server.client();
+        let created = client.post_json("/databases", &json!({ "name": "first" }));
+        assert_eq!(created.status, 201, "{}: {}", variant, created.text());
+        let refused = client.post_json("/databases", &json!({ "name": "second" }));
+        assert_eq!(refused.status, 409, "{}: {}", variant, refused.text());
+        assert_eq!(client.get("/db/first/tables/person/rows").status, 200, "{}", variant);
+
+        assert_eq!(client.delete("/databases/first").status, 204, "{}", variant);
+        assert_eq!(client.get("/db/first/tables/person/rows").status, 404, "{}", variant);
+        assert!(!server.dir().join("data/first.db").exists(), "{}", variant);
+    }
+}